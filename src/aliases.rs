@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+
+use crate::pipeline;
+
+/// Parses `KEY=VALUE` given to `--alias`.
+pub fn parse_alias(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value))
+            if !key.trim().is_empty() && !key.trim().contains(char::is_whitespace) =>
+        {
+            Ok((key.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err(format!("expected KEY=VALUE, got {:?}", s)),
+    }
+}
+
+/// Replaces the first word of the command, or that of each branch of the fan-out `|>`,
+/// with the alias of it, if any.
+/// Only a single level is expanded, i.e. the expanded value is not looked up again.
+pub fn expand(cmd: &str, aliases: &HashMap<String, String>) -> String {
+    let branches = pipeline::split_fan_out(cmd);
+    if branches.len() > 1 {
+        return branches
+            .iter()
+            .map(|branch| expand_first_word(branch, aliases))
+            .collect::<Vec<_>>()
+            .join(" |> ");
+    }
+    expand_first_word(cmd, aliases)
+}
+
+fn expand_first_word(cmd: &str, aliases: &HashMap<String, String>) -> String {
+    let cmd = cmd.trim_start();
+    let (name, rest) = match cmd.split_once(char::is_whitespace) {
+        Some((name, rest)) => (name, Some(rest)),
+        None => (cmd, None),
+    };
+    match (aliases.get(name), rest) {
+        (Some(value), Some(rest)) => format!("{} {}", value, rest),
+        (Some(value), None) => value.clone(),
+        (None, _) => cmd.to_string(),
+    }
+}
+
+/// Returns the aliases which refer to each other in a cycle (e.g. `a -> b -> a`),
+/// which are not expanded as intended since the expansion is not recursive.
+/// An alias referring to itself (e.g. `ls = "ls --color"`) is fine as in the shell.
+pub fn find_cycle(aliases: &HashMap<String, String>) -> Option<Vec<String>> {
+    let mut names: Vec<&String> = aliases.keys().collect();
+    // Report the same cycle regardless of the order of the map.
+    names.sort();
+    for start in names {
+        let mut path = vec![start.clone()];
+        let mut cur = start;
+        while let Some(next) = aliases
+            .get(cur)
+            .and_then(|value| value.split_whitespace().next())
+            .and_then(|name| aliases.get_key_value(name).map(|(name, _)| name))
+            .filter(|next| *next != cur)
+        {
+            if let Some(i) = path.iter().position(|name| name == next) {
+                let mut cycle = path.split_off(i);
+                cycle.push(next.clone());
+                return Some(cycle);
+            }
+            path.push(next.clone());
+            cur = next;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aliases(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_expand() {
+        let aliases = aliases(&[("ll", "ls -la --color=always"), ("g", "grep"), ("ls", "ll")]);
+        assert_eq!(expand("ll", &aliases), "ls -la --color=always");
+        assert_eq!(expand("ll /tmp", &aliases), "ls -la --color=always /tmp");
+        assert_eq!(expand("g -v 'a b'", &aliases), "grep -v 'a b'");
+        // Only the first word is expanded.
+        assert_eq!(expand("echo ll", &aliases), "echo ll");
+        assert_eq!(expand("lll", &aliases), "lll");
+        // Not recursive.
+        assert_eq!(expand("ls", &aliases), "ll");
+        // Each branch of the fan-out.
+        assert_eq!(
+            expand("g a |> ll |> echo 'g |> ll'", &aliases),
+            "grep a |> ls -la --color=always |> echo 'g |> ll'"
+        );
+    }
+
+    #[test]
+    fn test_find_cycle() {
+        assert_eq!(
+            find_cycle(&aliases(&[("ll", "ls -la"), ("ls", "exa")])),
+            None
+        );
+        assert_eq!(
+            find_cycle(&aliases(&[("a", "b -x"), ("b", "a"), ("c", "a")])),
+            Some(vec![
+                String::from("a"),
+                String::from("b"),
+                String::from("a")
+            ])
+        );
+        assert_eq!(find_cycle(&aliases(&[("ls", "ls --color")])), None);
+    }
+
+    #[test]
+    fn test_parse_alias() {
+        assert_eq!(
+            parse_alias("ll=ls -la").unwrap(),
+            (String::from("ll"), String::from("ls -la"))
+        );
+        assert!(parse_alias("ll").is_err());
+        assert!(parse_alias("=ls").is_err());
+    }
+}
@@ -0,0 +1,78 @@
+use std::{io::Write, process::Stdio, time::Duration};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use tokio::process::Command;
+
+/// Give up on a clipboard command that does not finish in time.
+const PASTE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Copies the text to the system clipboard via the OSC 52 escape sequence,
+/// which is supported by many terminals and also works over SSH.
+pub fn copy(text: &str) -> anyhow::Result<()> {
+    let mut stdout = std::io::stdout();
+    write!(stdout, "\x1b]52;c;{}\x07", STANDARD.encode(text))?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// The commands to read the system clipboard, in the order of preference.
+fn paste_commands() -> Vec<(&'static str, &'static [&'static str])> {
+    if cfg!(target_os = "macos") {
+        vec![("pbpaste", &[])]
+    } else if cfg!(windows) {
+        vec![("powershell", &["-NoProfile", "-Command", "Get-Clipboard"])]
+    } else {
+        let mut commands: Vec<(&'static str, &'static [&'static str])> = vec![];
+        // wl-paste fails outside of Wayland sessions.
+        if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+            commands.push(("wl-paste", &["--no-newline"]));
+        }
+        commands.push(("xclip", &["-selection", "clipboard", "-out"]));
+        commands.push(("xsel", &["--clipboard", "--output"]));
+        commands
+    }
+}
+
+/// Reads the text from the system clipboard.
+/// Reading via OSC 52 is disabled in most terminals, so the platform commands are used instead.
+pub async fn paste() -> anyhow::Result<String> {
+    let commands = paste_commands();
+    for (program, args) in &commands {
+        let output = tokio::time::timeout(
+            PASTE_TIMEOUT,
+            Command::new(program)
+                .args(*args)
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true)
+                .output(),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("{} timed out", program))?;
+
+        match output {
+            Ok(output) if output.status.success() => {
+                return Ok(String::from_utf8_lossy(&output.stdout).into_owned());
+            }
+            Ok(output) => {
+                anyhow::bail!(
+                    "{} failed: {}",
+                    program,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+            // Try the next command if this is not installed.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    anyhow::bail!(
+        "No clipboard command is found (tried {})",
+        commands
+            .iter()
+            .map(|(program, _)| *program)
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+}
@@ -0,0 +1,201 @@
+use std::path::{Path, PathBuf};
+
+use crossterm::style::Color;
+
+use crate::{highlight::HighlightTheme, render};
+
+/// Returns the directory of the config files,
+/// `$XDG_CONFIG_HOME/epiq` or `~/.config/epiq`.
+pub fn dir() -> Option<PathBuf> {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|dir| dir.join("epiq"))
+}
+
+/// Returns the config file, whose `[theme]` section sets the colors.
+pub fn default_path() -> Option<PathBuf> {
+    dir().map(|dir| dir.join("config.toml"))
+}
+
+/// The colors of the stages, set by the `[theme]` section of the config file
+/// and by `--theme`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Theme {
+    pub head_prefix: Color,
+    pub pipe_prefix: Color,
+    pub cursor: Color,
+    pub program: Color,
+    pub flag: Color,
+    pub quoted: Color,
+    pub operator: Color,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            head_prefix: Color::DarkGreen,
+            pipe_prefix: Color::DarkYellow,
+            cursor: Color::DarkCyan,
+            program: Color::Blue,
+            flag: Color::DarkMagenta,
+            quoted: Color::DarkGreen,
+            operator: Color::DarkYellow,
+        }
+    }
+}
+
+impl Theme {
+    fn color_mut(&mut self, key: &str) -> anyhow::Result<&mut Color> {
+        Ok(match key {
+            "head_prefix" => &mut self.head_prefix,
+            "pipe_prefix" => &mut self.pipe_prefix,
+            "cursor" => &mut self.cursor,
+            "program" => &mut self.program,
+            "flag" => &mut self.flag,
+            "quoted" => &mut self.quoted,
+            "operator" => &mut self.operator,
+            _ => anyhow::bail!(
+                "Unknown theme key: {} (expected head_prefix, pipe_prefix, cursor, \
+                 program, flag, quoted or operator)",
+                key
+            ),
+        })
+    }
+
+    pub fn set(&mut self, key: &str, color: Color) -> anyhow::Result<()> {
+        *self.color_mut(key)? = color;
+        Ok(())
+    }
+
+    /// Replaces the RGB colors with the default ones on the terminals without true color.
+    pub fn fallback(self, true_color: bool) -> Self {
+        let default = Self::default();
+        let fallback = |color, default| render::true_color_or(color, default, true_color);
+        Self {
+            head_prefix: fallback(self.head_prefix, default.head_prefix),
+            pipe_prefix: fallback(self.pipe_prefix, default.pipe_prefix),
+            cursor: fallback(self.cursor, default.cursor),
+            program: fallback(self.program, default.program),
+            flag: fallback(self.flag, default.flag),
+            quoted: fallback(self.quoted, default.quoted),
+            operator: fallback(self.operator, default.operator),
+        }
+    }
+
+    pub fn highlight(&self) -> HighlightTheme {
+        HighlightTheme {
+            program_fg_color: self.program,
+            flag_fg_color: self.flag,
+            quoted_fg_color: self.quoted,
+            operator_fg_color: self.operator,
+        }
+    }
+}
+
+/// Parses `KEY=COLOR` of `--theme`, e.g. `program=#ff8800`.
+pub fn parse_theme_entry(s: &str) -> Result<(String, Color), String> {
+    let (key, color) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=COLOR, got {:?}", s))?;
+    let key = key.trim().to_string();
+    Theme::default()
+        .color_mut(&key)
+        .map_err(|e| e.to_string())?;
+    Ok((key, render::parse_color(color)?))
+}
+
+/// Loads the theme from the `[theme]` section of the config file,
+/// or the default one if the file or the section does not exist.
+pub fn load_theme(path: &Path) -> anyhow::Result<Theme> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Theme::default()),
+        Err(e) => anyhow::bail!("Failed to read the config {}: {}", path.display(), e),
+    };
+    let load = || -> anyhow::Result<Theme> {
+        let mut config: toml::Table = text.parse()?;
+        let mut theme = Theme::default();
+        let Some(section) = config.remove("theme") else {
+            return Ok(theme);
+        };
+        let section = section
+            .as_table()
+            .ok_or_else(|| anyhow::anyhow!("`theme` must be a table"))?;
+        for (key, color) in section {
+            let color = color
+                .as_str()
+                .ok_or_else(|| anyhow::anyhow!("`theme.{}` must be a string", key))?;
+            theme.set(key, render::color_from_str(color)?)?;
+        }
+        Ok(theme)
+    };
+    load().map_err(|e| anyhow::anyhow!("Failed to load the config {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_theme() {
+        let path =
+            std::env::temp_dir().join(format!("epiq_test_config_{}.toml", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(load_theme(&path).unwrap(), Theme::default());
+
+        std::fs::write(
+            &path,
+            "[theme]\nprogram = \"#ff8800\"\nflag = \"Red\"\n\n[other]\nkey = 1\n",
+        )
+        .unwrap();
+        let theme = load_theme(&path).unwrap();
+        assert_eq!(
+            theme,
+            Theme {
+                program: Color::Rgb {
+                    r: 0xff,
+                    g: 0x88,
+                    b: 0x00
+                },
+                flag: Color::Red,
+                ..Default::default()
+            }
+        );
+        // The RGB colors fall back to the default ones without true color.
+        assert_eq!(
+            theme.clone().fallback(false),
+            Theme {
+                flag: Color::Red,
+                ..Default::default()
+            }
+        );
+        assert_eq!(theme.clone().fallback(true), theme);
+
+        std::fs::write(&path, "[theme]\nno_such_key = \"red\"\n").unwrap();
+        assert!(load_theme(&path).is_err());
+        std::fs::write(&path, "[theme]\nprogram = \"#12\"\n").unwrap();
+        assert!(load_theme(&path).is_err());
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_parse_theme_entry() {
+        assert_eq!(
+            parse_theme_entry("cursor=#00f").unwrap(),
+            (
+                String::from("cursor"),
+                Color::Rgb {
+                    r: 0x00,
+                    g: 0x00,
+                    b: 0xff
+                }
+            )
+        );
+        assert!(parse_theme_entry("cursor").is_err());
+        assert!(parse_theme_entry("no_such_key=red").is_err());
+        assert!(parse_theme_entry("cursor=no_such_color").is_err());
+    }
+}
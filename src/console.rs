@@ -0,0 +1,79 @@
+use crossterm::style::Color;
+use promkit::{PaneFactory, pane::Pane, style::StyleBuilder, text_editor};
+use tokio::sync::mpsc;
+
+/// The buffer of the lines typed faster than the head stage reads them.
+const STDIN_BUFFER: usize = 100;
+
+/// The editor at the bottom of the screen to type the lines into the stdin of the head stage.
+pub struct State {
+    pub editor: text_editor::State,
+    // The stdin of the head stage of the current run.
+    stdin_tx: Option<mpsc::Sender<String>>,
+}
+
+impl State {
+    pub fn new(active_char_bg_color: Color) -> Self {
+        Self {
+            editor: text_editor::State {
+                prefix: String::from("stdin> "),
+                prefix_style: StyleBuilder::new().fgc(Color::DarkMagenta).build(),
+                active_char_style: StyleBuilder::new().bgc(active_char_bg_color).build(),
+                ..Default::default()
+            },
+            stdin_tx: None,
+        }
+    }
+
+    /// Returns the stdin for the head stage of a new run,
+    /// which is closed when the console is dismissed.
+    pub fn connect(&mut self) -> mpsc::Receiver<String> {
+        let (stdin_tx, stdin_rx) = mpsc::channel(STDIN_BUFFER);
+        self.stdin_tx = Some(stdin_tx);
+        stdin_rx
+    }
+
+    /// Sends the typed line to the head stage and clears it.
+    /// Returns false if the head stage no longer reads it (or is too busy to).
+    pub fn send(&mut self) -> bool {
+        let line = self.editor.texteditor.text_without_cursor().to_string();
+        let sent = self
+            .stdin_tx
+            .as_ref()
+            .is_some_and(|stdin_tx| stdin_tx.try_send(line).is_ok());
+        if sent {
+            self.editor.texteditor.erase_all();
+        }
+        sent
+    }
+}
+
+impl PaneFactory for State {
+    fn create_pane(&self, width: u16, height: u16) -> Pane {
+        self.editor.create_pane(width, height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_send() {
+        let mut state = State::new(Color::DarkCyan);
+        state.editor.texteditor.insert_chars(&vec!['a', 'b']);
+        // Not connected to any run yet.
+        assert!(!state.send());
+
+        let mut stdin_rx = state.connect();
+        assert!(state.send());
+        assert_eq!(stdin_rx.recv().await, Some(String::from("ab")));
+        assert_eq!(
+            state.editor.texteditor.text_without_cursor().to_string(),
+            ""
+        );
+
+        drop(stdin_rx);
+        assert!(!state.send());
+    }
+}
@@ -0,0 +1,167 @@
+use crossterm::style::{Attribute, Attributes, Color};
+use promkit::{
+    grapheme::{StyledGrapheme, StyledGraphemes},
+    style::StyleBuilder,
+};
+
+/// The delimiters tried in turn, TSV first since the fields of it often contain commas.
+const DELIMITERS: [char; 2] = ['\t', ','];
+
+/// The number of the leading lines the delimiter is detected from.
+pub const DETECT_LINES: usize = 5;
+
+/// Drawn between the columns.
+const COLUMN_SEPARATOR: &str = " │ ";
+
+/// Returns the delimiter which splits each of the first (non-empty) lines
+/// into the same number of fields, more than one.
+pub fn detect_delimiter(lines: &[&str]) -> Option<char> {
+    let lines: Vec<&str> = lines
+        .iter()
+        .filter(|line| !line.is_empty())
+        .take(DETECT_LINES)
+        .copied()
+        .collect();
+    if lines.len() < 2 {
+        return None;
+    }
+    DELIMITERS.into_iter().find(|delimiter| {
+        let columns = split_fields(lines[0], *delimiter).len();
+        columns > 1
+            && lines
+                .iter()
+                .all(|line| split_fields(line, *delimiter).len() == columns)
+    })
+}
+
+/// Formats the lines as a table whose columns are aligned to the widest field,
+/// with the first line as the bold header.
+/// The fields wider than the share of the width per column are truncated with `…`.
+pub fn format_as_table(lines: &[&str], delimiter: char, width: u16) -> Vec<StyledGraphemes> {
+    let rows: Vec<Vec<StyledGraphemes>> = lines
+        .iter()
+        .map(|line| {
+            split_fields(line, delimiter)
+                .into_iter()
+                .map(StyledGraphemes::from)
+                .collect()
+        })
+        .collect();
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+    if columns == 0 {
+        return vec![];
+    }
+
+    let separator_width = COLUMN_SEPARATOR.chars().count();
+    let max_width = (width as usize)
+        .saturating_sub(separator_width * (columns - 1))
+        .checked_div(columns)
+        .unwrap_or(0)
+        .max(1);
+    let widths: Vec<usize> = (0..columns)
+        .map(|column| {
+            rows.iter()
+                .filter_map(|row| row.get(column))
+                .map(StyledGraphemes::widths)
+                .max()
+                .unwrap_or(0)
+                .min(max_width)
+        })
+        .collect();
+
+    let separator_style = StyleBuilder::new().fgc(Color::DarkGrey).build();
+    let header_style = StyleBuilder::new()
+        .attrs(Attributes::from(Attribute::Bold))
+        .build();
+    rows.iter()
+        .enumerate()
+        .map(|(i, row)| {
+            let mut shown = StyledGraphemes::default();
+            for (column, width) in widths.iter().enumerate() {
+                if column > 0 {
+                    shown.append(&mut StyledGraphemes::from_str(
+                        COLUMN_SEPARATOR,
+                        separator_style,
+                    ));
+                }
+                let mut field = fit(row.get(column).cloned().unwrap_or_default(), *width);
+                if i == 0 {
+                    field = field.apply_style(header_style);
+                }
+                shown.append(&mut field);
+            }
+            shown
+        })
+        .collect()
+}
+
+/// Pads the field with spaces to the width, or truncates it with `…` if wider.
+fn fit(field: StyledGraphemes, width: usize) -> StyledGraphemes {
+    let mut fitted = StyledGraphemes::default();
+    if field.widths() <= width {
+        fitted = field;
+    } else {
+        let mut shown = 0;
+        for grapheme in field.iter() {
+            if shown + grapheme.width() >= width {
+                break;
+            }
+            shown += grapheme.width();
+            fitted.push_back(grapheme.clone());
+        }
+        fitted.push_back(StyledGrapheme::from('…'));
+    }
+    let padding = width.saturating_sub(fitted.widths());
+    fitted.extend((0..padding).map(|_| StyledGrapheme::from(' ')));
+    fitted
+}
+
+/// Splits the line by the delimiter, except in the double quotes
+/// where `""` stands for a quote as in CSV.
+fn split_fields(line: &str, delimiter: char) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' => quoted = !quoted,
+            ch if ch == delimiter && !quoted => fields.push(std::mem::take(&mut field)),
+            ch => field.push(ch),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_delimiter() {
+        assert_eq!(detect_delimiter(&["a,b", "1,2", "", "3,4"]), Some(','));
+        assert_eq!(detect_delimiter(&["a\tb,c", "1\t2,3"]), Some('\t'));
+        // The quoted delimiters are not counted.
+        assert_eq!(detect_delimiter(&["a,b", "\"1,5\",2"]), Some(','));
+        assert_eq!(detect_delimiter(&["a,b", "1,2,3"]), None);
+        assert_eq!(detect_delimiter(&["a,b"]), None);
+        assert_eq!(detect_delimiter(&["hello world", "foo bar"]), None);
+    }
+
+    #[test]
+    fn test_format_as_table() {
+        let table = format_as_table(&["name,size", "a,10", "\"b, c\",2"], ',', 80);
+        let table: Vec<String> = table.iter().map(ToString::to_string).collect();
+        assert_eq!(table, vec!["name │ size", "a    │ 10  ", "b, c │ 2   "]);
+
+        // Each column is given (13 - 3) / 2 = 5 columns at most.
+        let table = format_as_table(&["abcdefgh,x", "a,\"y\"\"z\""], ',', 13);
+        let table: Vec<String> = table.iter().map(ToString::to_string).collect();
+        assert_eq!(table, vec!["abcd… │ x  ", "a     │ y\"z"]);
+    }
+}
@@ -0,0 +1,56 @@
+use std::{
+    fs::File,
+    io::{LineWriter, Write},
+    path::Path,
+    sync::{Mutex, OnceLock},
+};
+
+use chrono::Local;
+
+/// The file given by `--log-file`, which is set at most once.
+static LOG_FILE: OnceLock<Mutex<LineWriter<File>>> = OnceLock::new();
+
+/// Starts logging into the file, truncating it.
+pub fn init(path: &Path) -> anyhow::Result<()> {
+    let file = File::create(path)
+        .map_err(|e| anyhow::anyhow!("Failed to open the log file {}: {}", path.display(), e))?;
+    LOG_FILE
+        .set(Mutex::new(LineWriter::new(file)))
+        .map_err(|_| anyhow::anyhow!("The log file is already set"))
+}
+
+/// Writes the message made by `f` with the timestamp if logging is enabled.
+/// Otherwise the message is not even made, which keeps the overhead negligible.
+pub fn log<F: FnOnce() -> String>(f: F) {
+    let Some(file) = LOG_FILE.get() else {
+        return;
+    };
+    let line = format!("{} {}", Local::now().format("%H:%M:%S%.3f"), f());
+    if let Ok(mut file) = file.lock() {
+        let _ = writeln!(file, "{}", line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log() {
+        let path = std::env::temp_dir().join(format!("epiq_test_log_{}", std::process::id()));
+        // Not even made before the log file is set.
+        log(|| unreachable!());
+
+        init(&path).unwrap();
+        assert!(init(&path).is_err());
+        log(|| String::from("spawned `cat foo`"));
+        log(|| String::from("events: [Key(['a'])]"));
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(" spawned `cat foo`"));
+        assert!(lines[1].ends_with(" events: [Key(['a'])]"));
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,141 @@
+use std::{process::Stdio, time::Duration};
+
+use crossterm::style::{Attribute, Attributes};
+use promkit::{Cursor, PaneFactory, grapheme::StyledGraphemes, pane::Pane, style::StyleBuilder};
+use tokio::{process::Command, task::JoinHandle};
+
+/// The maximum number of lines captured from the help text.
+const MAX_LINES: usize = 20;
+/// The maximum number of rows the popup occupies, including the title bar.
+const MAX_ROWS: usize = 10;
+/// Give up on a help command that does not finish in time (e.g. waiting for stdin).
+const FETCH_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Fetches the documentation for the given command.
+/// `<cmd> --help` is tried first because it is faster, and `man <cmd>` is used as a fallback.
+pub fn fetch_doc(cmd: &str) -> JoinHandle<Vec<String>> {
+    let cmd = cmd.to_string();
+    tokio::spawn(async move {
+        let mut help = Command::new(&cmd);
+        help.arg("--help");
+        if let Some(lines) = run(help).await {
+            return lines;
+        }
+
+        let mut man = Command::new("man");
+        man.arg(&cmd).env("MANPAGER", "cat").env("PAGER", "cat");
+        run(man).await.unwrap_or_default()
+    })
+}
+
+async fn run(mut command: Command) -> Option<Vec<String>> {
+    let output = tokio::time::timeout(
+        FETCH_TIMEOUT,
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .output(),
+    )
+    .await
+    .ok()?
+    .ok()?;
+
+    // Some commands print their usage to stderr.
+    let raw = if output.stdout.is_empty() {
+        output.stderr
+    } else {
+        output.stdout
+    };
+    let stripped = strip_ansi_escapes::strip(&raw);
+    let lines: Vec<String> = String::from_utf8_lossy(&stripped)
+        .lines()
+        .map(strip_overstrike)
+        .take(MAX_LINES)
+        .collect();
+
+    if lines.iter().all(|line| line.trim().is_empty()) {
+        None
+    } else {
+        Some(lines)
+    }
+}
+
+/// Removes the overstrike sequences (e.g. `N\x08N` for bold) that man emits.
+fn strip_overstrike(line: &str) -> String {
+    let mut ret = String::with_capacity(line.len());
+    for ch in line.chars() {
+        if ch == '\x08' {
+            ret.pop();
+        } else {
+            ret.push(ch);
+        }
+    }
+    ret
+}
+
+pub struct State {
+    title: String,
+    lines: Cursor<Vec<String>>,
+}
+
+impl State {
+    pub fn new(cmd: &str, lines: Vec<String>) -> Self {
+        Self {
+            title: cmd.to_string(),
+            lines: Cursor::new(lines, 0, false),
+        }
+    }
+
+    pub fn shift(&mut self, up: usize, down: usize) -> bool {
+        self.lines.shift(up, down)
+    }
+}
+
+impl PaneFactory for State {
+    fn create_pane(&self, width: u16, height: u16) -> Pane {
+        let rows = MAX_ROWS.min(height as usize).saturating_sub(1);
+        let total = self.lines.contents().len();
+
+        let mut layout = vec![StyledGraphemes::from_str(
+            format!(
+                " {} ({}/{}) ",
+                self.title,
+                (self.lines.position() + 1).min(total),
+                total
+            ),
+            StyleBuilder::new()
+                .attrs(Attributes::from(Attribute::Reverse))
+                .build(),
+        )];
+        layout.extend(
+            self.lines
+                .contents()
+                .iter()
+                .skip(self.lines.position())
+                .take(rows)
+                .flat_map(|line| {
+                    // Note: promkit::terminal::Terminal ignores empty items.
+                    let line = if line.is_empty() { "\0" } else { line };
+                    StyledGraphemes::from(line)
+                        .matrixify(width as usize, 1, 0)
+                        .0
+                }),
+        );
+
+        Pane::new(layout, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_overstrike() {
+        assert_eq!(strip_overstrike("N\x08NA\x08AM\x08ME\x08E"), "NAME");
+        assert_eq!(strip_overstrike("_\x08l_\x08s"), "ls");
+        assert_eq!(strip_overstrike("plain"), "plain");
+    }
+}
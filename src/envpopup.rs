@@ -0,0 +1,154 @@
+use crossterm::style::{Attribute, Attributes, Color};
+use promkit::{Cursor, PaneFactory, grapheme::StyledGraphemes, pane::Pane, style::StyleBuilder};
+
+/// The maximum number of rows the popup occupies, including the title bar.
+const MAX_ROWS: usize = 10;
+
+/// The list of the environment variables (or the other key-value pairs such as the snippets)
+/// filtered as typing.
+pub struct State {
+    title: &'static str,
+    // Shown between the key and the value.
+    separator: &'static str,
+    vars: Vec<(String, String)>,
+    query: String,
+    // The indices of the variables matching the query.
+    matches: Cursor<Vec<usize>>,
+}
+
+impl State {
+    pub fn new<I: IntoIterator<Item = (String, String)>>(vars: I) -> Self {
+        Self::with_title("env", "=", vars)
+    }
+
+    /// Lists the pairs under the title, shown as `key<separator>value`.
+    pub fn with_title<I: IntoIterator<Item = (String, String)>>(
+        title: &'static str,
+        separator: &'static str,
+        vars: I,
+    ) -> Self {
+        let mut vars: Vec<(String, String)> = vars.into_iter().collect();
+        vars.sort();
+        let mut ret = Self {
+            title,
+            separator,
+            vars,
+            query: String::new(),
+            matches: Cursor::new(vec![], 0, false),
+        };
+        ret.filter();
+        ret
+    }
+
+    /// Keeps the variables whose `KEY<separator>VALUE` contains the query, ignoring case.
+    fn filter(&mut self) {
+        let query = self.query.to_lowercase();
+        let matches = self
+            .vars
+            .iter()
+            .enumerate()
+            .filter(|(_, (key, value))| {
+                format!("{}{}{}", key, self.separator, value)
+                    .to_lowercase()
+                    .contains(&query)
+            })
+            .map(|(i, _)| i)
+            .collect();
+        self.matches = Cursor::new(matches, 0, false);
+    }
+
+    pub fn push_query(&mut self, chars: &[char]) {
+        self.query.extend(chars);
+        self.filter();
+    }
+
+    pub fn pop_query(&mut self, times: usize) {
+        for _ in 0..times {
+            self.query.pop();
+        }
+        self.filter();
+    }
+
+    pub fn shift(&mut self, up: usize, down: usize) -> bool {
+        self.matches.shift(up, down)
+    }
+
+    pub fn selected_key(&self) -> Option<&str> {
+        self.matches
+            .contents()
+            .get(self.matches.position())
+            .map(|i| self.vars[*i].0.as_str())
+    }
+}
+
+impl PaneFactory for State {
+    fn create_pane(&self, width: u16, height: u16) -> Pane {
+        let rows = MAX_ROWS.min(height as usize).saturating_sub(1).max(1);
+        let total = self.matches.contents().len();
+        let position = self.matches.position();
+
+        let mut layout = vec![StyledGraphemes::from_str(
+            format!(
+                " {} /{} ({}/{}) ",
+                self.title,
+                self.query,
+                (position + 1).min(total),
+                total
+            ),
+            StyleBuilder::new()
+                .attrs(Attributes::from(Attribute::Reverse))
+                .build(),
+        )];
+        // Scroll to keep the selected variable visible.
+        let start = (position + 1).saturating_sub(rows);
+        layout.extend(
+            self.matches
+                .contents()
+                .iter()
+                .enumerate()
+                .skip(start)
+                .take(rows)
+                .flat_map(|(i, var)| {
+                    let (key, value) = &self.vars[*var];
+                    let style = if i == position {
+                        StyleBuilder::new()
+                            .fgc(Color::DarkCyan)
+                            .attrs(Attributes::from(Attribute::Bold))
+                            .build()
+                    } else {
+                        StyleBuilder::new().build()
+                    };
+                    StyledGraphemes::from_str(format!("{}{}{}", key, self.separator, value), style)
+                        .matrixify(width as usize, 1, 0)
+                        .0
+                }),
+        );
+
+        Pane::new(layout, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        let mut state = State::new([
+            (String::from("PATH"), String::from("/usr/bin")),
+            (String::from("HOME"), String::from("/home/user")),
+            (String::from("EDITOR"), String::from("vim")),
+        ]);
+        assert_eq!(state.selected_key(), Some("EDITOR"));
+
+        state.push_query(&['U', 's']);
+        assert_eq!(state.selected_key(), Some("HOME"));
+        assert!(state.shift(0, 1));
+        assert_eq!(state.selected_key(), Some("PATH"));
+
+        state.push_query(&['x']);
+        assert_eq!(state.selected_key(), None);
+        state.pop_query(1);
+        assert_eq!(state.selected_key(), Some("HOME"));
+    }
+}
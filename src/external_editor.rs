@@ -0,0 +1,286 @@
+use std::{
+    io::{IsTerminal, Write},
+    path::Path,
+    process::Stdio,
+};
+
+use tokio::process::Command;
+
+use crate::{session::Stage, temp_file};
+
+/// The editor used if neither `$VISUAL` nor `$EDITOR` is set.
+const DEFAULT_EDITOR: &str = "vi";
+
+/// Splits the editor command (e.g. `code --wait`) into the program and the arguments,
+/// falling back to vi if it is unset or empty.
+fn parse_command(editor: Option<&str>) -> anyhow::Result<(String, Vec<String>)> {
+    let editor = editor
+        .map(str::trim)
+        .filter(|editor| !editor.is_empty())
+        .unwrap_or(DEFAULT_EDITOR);
+    let mut words = shlex::split(editor)
+        .ok_or_else(|| anyhow::anyhow!("Cannot parse the editor command: {}", editor))?
+        .into_iter();
+    let program = words
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("The editor command is empty"))?;
+    Ok((program, words.collect()))
+}
+
+/// Edits the text in `$VISUAL` or `$EDITOR` (vi by default) on a temporary file
+/// with the extension, and returns the edited text without the trailing newlines,
+/// or None if the editor exits with failure.
+/// The terminal must be handed over to the editor by the caller.
+pub async fn edit(text: &str, extension: &str) -> anyhow::Result<Option<String>> {
+    let editor = std::env::var("VISUAL")
+        .ok()
+        .filter(|editor| !editor.trim().is_empty())
+        .or_else(|| std::env::var("EDITOR").ok());
+    let (program, args) = parse_command(editor.as_deref())?;
+
+    let (path, mut file) = temp_file::create("epiq_edit", extension)?;
+    let written = writeln!(file, "{}", text);
+    drop(file);
+    let edited = match written {
+        Ok(()) => run(&program, &args, &path).await,
+        Err(e) => Err(e.into()),
+    };
+    let _ = std::fs::remove_file(&path);
+    edited
+}
+
+/// Writes the non-empty stages one per line to edit them all at once,
+/// the ignored ones commented out by `#`. The newlines in a stage are
+/// written as the line continuations (`\` at the end of the line),
+/// and the backslashes at the end of a line are doubled not to be taken for them.
+pub fn format_stages(stages: &[Stage]) -> String {
+    stages
+        .iter()
+        .filter(|stage| !stage.text.trim().is_empty())
+        .map(|stage| {
+            let text = stage
+                .text
+                .split('\n')
+                .map(|line| {
+                    let backslashes = line.len() - line.trim_end_matches('\\').len();
+                    format!("{}{}", line, "\\".repeat(backslashes))
+                })
+                .collect::<Vec<_>>()
+                .join("\\\n");
+            if stage.ignore {
+                format!("# {}", text)
+            } else {
+                text
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Parses the stages written by `format_stages`. The comments of the stages
+/// whose text is unchanged are carried over from `prev`, which is how they were.
+pub fn parse_stages(text: &str, prev: &[Stage]) -> Vec<Stage> {
+    let mut comments: Vec<(&str, &String)> = prev
+        .iter()
+        .filter_map(|stage| Some((stage.text.as_str(), stage.comment.as_ref()?)))
+        .collect();
+    let mut stages = vec![];
+    let mut lines = text.lines();
+    while let Some(line) = lines.next() {
+        let (mut stage, mut continued) = unescape_line(line);
+        while continued {
+            let Some(next) = lines.next() else {
+                // The line continuation at the end is kept as typed.
+                stage.push('\\');
+                break;
+            };
+            let (next, next_continued) = unescape_line(next);
+            stage.push('\n');
+            stage.push_str(&next);
+            continued = next_continued;
+        }
+        let (text, ignore) = match stage.strip_prefix('#') {
+            Some(text) => (text.strip_prefix(' ').unwrap_or(text), true),
+            None => (stage.as_str(), false),
+        };
+        if text.trim().is_empty() {
+            continue;
+        }
+        let comment = comments
+            .iter()
+            .position(|(prev, _)| *prev == text)
+            .map(|i| comments.remove(i).1.clone());
+        stages.push(Stage {
+            text: text.to_string(),
+            ignore,
+            comment,
+        });
+    }
+    stages
+}
+
+/// Halves the backslashes at the end of the line written by `format_stages`,
+/// and returns whether the line continues (an odd one is left).
+fn unescape_line(line: &str) -> (String, bool) {
+    let text = line.trim_end_matches('\\');
+    let backslashes = line.len() - text.len();
+    (
+        format!("{}{}", text, "\\".repeat(backslashes / 2)),
+        backslashes % 2 == 1,
+    )
+}
+
+/// Returns the terminal for the stdout of the editor if stdout is not the terminal,
+/// e.g. the pipe with `--record`, which makes the editors such as vi complain.
+fn terminal_stdout() -> Stdio {
+    if std::io::stdout().is_terminal() {
+        return Stdio::inherit();
+    }
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open("/dev/tty")
+        .map_or_else(|_| Stdio::inherit(), Stdio::from)
+}
+
+async fn run(program: &str, args: &[String], path: &Path) -> anyhow::Result<Option<String>> {
+    let status = Command::new(program)
+        .args(args)
+        .arg(path)
+        .stdout(terminal_stdout())
+        .status()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to run {}: {}", program, e))?;
+    if !status.success() {
+        return Ok(None);
+    }
+    let edited = std::fs::read_to_string(path)?;
+    Ok(Some(edited.trim_end_matches(['\n', '\r']).to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(
+            parse_command(Some("code --wait")).unwrap(),
+            (String::from("code"), vec![String::from("--wait")])
+        );
+        assert_eq!(
+            parse_command(Some("'/opt/my editor/bin/ed'")).unwrap(),
+            (String::from("/opt/my editor/bin/ed"), vec![])
+        );
+        assert_eq!(parse_command(None).unwrap(), (String::from("vi"), vec![]));
+        assert_eq!(
+            parse_command(Some("  ")).unwrap(),
+            (String::from("vi"), vec![])
+        );
+        assert!(parse_command(Some("vim \"unclosed")).is_err());
+    }
+
+    #[test]
+    fn test_format_and_parse_stages() {
+        let stages = vec![
+            Stage {
+                text: String::from("cat app.log"),
+                ..Default::default()
+            },
+            Stage {
+                text: String::from("grep ERROR"),
+                ignore: true,
+                comment: Some(String::from("too noisy")),
+            },
+            Stage::default(),
+            Stage {
+                text: String::from("awk '{\n  print $1\n}'"),
+                ..Default::default()
+            },
+        ];
+        let text = format_stages(&stages);
+        assert_eq!(
+            text,
+            "cat app.log\n# grep ERROR\nawk '{\\\n  print $1\\\n}'"
+        );
+        // The empty stage is dropped.
+        let mut expected = stages.clone();
+        expected.remove(2);
+        assert_eq!(parse_stages(&text, &stages), expected);
+
+        // Reorder, enable, remove and add the stages.
+        let text = "grep ERROR\n\ncat app.log\n#sort\nuniq -c\n";
+        assert_eq!(
+            parse_stages(text, &stages),
+            vec![
+                Stage {
+                    text: String::from("grep ERROR"),
+                    ignore: false,
+                    comment: Some(String::from("too noisy")),
+                },
+                Stage {
+                    text: String::from("cat app.log"),
+                    ..Default::default()
+                },
+                Stage {
+                    text: String::from("sort"),
+                    ignore: true,
+                    comment: None,
+                },
+                Stage {
+                    text: String::from("uniq -c"),
+                    ..Default::default()
+                },
+            ]
+        );
+        assert_eq!(parse_stages("\n# \n", &stages), vec![]);
+    }
+
+    #[test]
+    fn test_format_and_parse_trailing_backslashes() {
+        let stages = vec![
+            Stage {
+                text: String::from("echo C:\\"),
+                ..Default::default()
+            },
+            Stage {
+                text: String::from("tr -d \\\\\nsort"),
+                ..Default::default()
+            },
+            Stage {
+                text: String::from("sed 's/$/\\/'"),
+                ..Default::default()
+            },
+        ];
+        let text = format_stages(&stages);
+        assert_eq!(text, "echo C:\\\\\ntr -d \\\\\\\\\\\nsort\nsed 's/$/\\/'");
+        assert_eq!(parse_stages(&text, &stages), stages);
+        // The line continuation at the end is kept as typed.
+        assert_eq!(
+            parse_stages("cat foo \\", &[]),
+            vec![Stage {
+                text: String::from("cat foo \\"),
+                ..Default::default()
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let path = std::env::temp_dir().join(format!("epiq_test_edit_{}", std::process::id()));
+        std::fs::write(&path, "cat foo\n").unwrap();
+        // The editor which appends a stage.
+        let args = vec![
+            String::from("-c"),
+            String::from("printf 'cat foo | sort\\n\\n' > \"$0\""),
+        ];
+        assert_eq!(
+            run("sh", &args, &path).await.unwrap(),
+            Some(String::from("cat foo | sort"))
+        );
+        // The text is not read back if the editor fails.
+        let args = vec![String::from("-c"), String::from("exit 1")];
+        assert_eq!(run("sh", &args, &path).await.unwrap(), None);
+        assert!(run("epiq-no-such-editor", &[], &path).await.is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+}
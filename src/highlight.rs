@@ -0,0 +1,108 @@
+use crossterm::style::Color;
+
+/// Characters highlighted as shell operators.
+const OPERATORS: [char; 11] = ['|', '&', ';', '<', '>', '(', ')', '[', ']', '{', '}'];
+
+#[derive(Clone)]
+pub struct HighlightTheme {
+    pub program_fg_color: Color,
+    pub flag_fg_color: Color,
+    pub quoted_fg_color: Color,
+    pub operator_fg_color: Color,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Token {
+    Program,
+    Flag,
+    Quoted,
+    Operator,
+}
+
+impl HighlightTheme {
+    pub fn color(&self, token: Token) -> Color {
+        match token {
+            Token::Program => self.program_fg_color,
+            Token::Flag => self.flag_fg_color,
+            Token::Quoted => self.quoted_fg_color,
+            Token::Operator => self.operator_fg_color,
+        }
+    }
+}
+
+/// Classifies each character of the command into a token kind.
+/// Characters that do not belong to any recognized token are `None`.
+pub fn tokenize(chars: &[char]) -> Vec<Option<Token>> {
+    let mut ret = vec![None; chars.len()];
+    let mut expect_program = true;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let ch = chars[i];
+        if ch.is_whitespace() {
+            i += 1;
+        } else if OPERATORS.contains(&ch) {
+            ret[i] = Some(Token::Operator);
+            // A new command follows pipes and separators.
+            expect_program = matches!(ch, '|' | '&' | ';' | '(');
+            i += 1;
+        } else {
+            // Scan a word, which may contain quoted parts.
+            let start = i;
+            let mut quote: Option<char> = None;
+            while i < chars.len() {
+                let ch = chars[i];
+                match quote {
+                    Some(q) => {
+                        ret[i] = Some(Token::Quoted);
+                        if ch == q {
+                            quote = None;
+                        }
+                    }
+                    None if ch == '\'' || ch == '"' => {
+                        ret[i] = Some(Token::Quoted);
+                        quote = Some(ch);
+                    }
+                    None if ch.is_whitespace() || OPERATORS.contains(&ch) => break,
+                    None => {}
+                }
+                i += 1;
+            }
+
+            let word = if expect_program {
+                Some(Token::Program)
+            } else if chars[start] == '-' {
+                Some(Token::Flag)
+            } else {
+                None
+            };
+            for token in ret[start..i].iter_mut().filter(|token| token.is_none()) {
+                *token = word;
+            }
+            expect_program = false;
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize() {
+        let chars: Vec<char> = "grep -v 'a b' | wc".chars().collect();
+        let (p, f, q, o) = (
+            Some(Token::Program),
+            Some(Token::Flag),
+            Some(Token::Quoted),
+            Some(Token::Operator),
+        );
+        assert_eq!(
+            tokenize(&chars),
+            vec![
+                p, p, p, p, None, f, f, None, q, q, q, q, q, None, o, None, p, p
+            ]
+        );
+    }
+}
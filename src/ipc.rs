@@ -0,0 +1,202 @@
+use std::path::{Path, PathBuf};
+
+use serde_json::{Value, json};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+/// Sends the result of the command once it is applied:
+/// the output for `get_output` or nothing for the others, or the error.
+pub type Reply = oneshot::Sender<Result<Option<String>, String>>;
+
+/// The command sent from another process through the socket.
+#[derive(Debug)]
+pub enum IpcCommand {
+    /// Replace all the stages with the commands (or clear them if none).
+    SetPipeline(Vec<String>, Reply),
+    /// Run the pipeline as Enter does.
+    Run(Reply),
+    /// Reply with the lines in the output pane once the running pipeline finishes.
+    GetOutput(Reply),
+}
+
+/// Parses the line as a JSON command such as `{"action": "run"}`,
+/// which is replied through `reply_tx`.
+fn parse(line: &str, reply_tx: Reply) -> anyhow::Result<IpcCommand> {
+    let value: Value = serde_json::from_str(line)?;
+    match value.get("action").and_then(Value::as_str) {
+        Some("set_pipeline") => {
+            let stages = value
+                .get("stages")
+                .and_then(Value::as_array)
+                .and_then(|stages| {
+                    stages
+                        .iter()
+                        .map(|stage| stage.as_str().map(String::from))
+                        .collect::<Option<Vec<_>>>()
+                })
+                .ok_or_else(|| anyhow::anyhow!("`stages` must be an array of strings"))?;
+            Ok(IpcCommand::SetPipeline(stages, reply_tx))
+        }
+        Some("run") => Ok(IpcCommand::Run(reply_tx)),
+        Some("get_output") => Ok(IpcCommand::GetOutput(reply_tx)),
+        Some(action) => Err(anyhow::anyhow!("Unknown action: {}", action)),
+        None => Err(anyhow::anyhow!("`action` is missing")),
+    }
+}
+
+/// Handles the command and returns the JSON reply to it after it is applied.
+async fn reply(line: &str, cmd_tx: &mpsc::Sender<IpcCommand>) -> Value {
+    let replied = async {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        cmd_tx.send(parse(line, reply_tx)?).await?;
+        Ok::<_, anyhow::Error>(match reply_rx.await? {
+            Ok(Some(output)) => json!({"ok": true, "output": output}),
+            Ok(None) => json!({"ok": true}),
+            Err(e) => json!({"ok": false, "error": e}),
+        })
+    };
+    replied
+        .await
+        .unwrap_or_else(|e| json!({"ok": false, "error": e.to_string()}))
+}
+
+/// The task listening on the socket, which stops and removes the socket file when dropped,
+/// even if epiq exits with an error.
+pub struct Listener {
+    path: PathBuf,
+    task: JoinHandle<()>,
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Listens on the Unix domain socket at the path for the newline-delimited JSON commands,
+/// and sends them to `cmd_tx`. Each command is replied with a JSON line
+/// such as `{"ok": true}`, or `{"ok": false, "error": "..."}` if it fails.
+/// Only the user can connect to the socket.
+#[cfg(unix)]
+pub fn listen(path: &Path, cmd_tx: mpsc::Sender<IpcCommand>) -> anyhow::Result<Listener> {
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let listener = tokio::net::UnixListener::bind(path)
+        .map_err(|e| anyhow::anyhow!("Failed to listen on {}: {}", path.display(), e))?;
+    if let Err(e) = std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)) {
+        let _ = std::fs::remove_file(path);
+        anyhow::bail!("Failed to restrict {}: {}", path.display(), e);
+    }
+    let task = tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let cmd_tx = cmd_tx.clone();
+            tokio::spawn(async move {
+                let (reader, mut writer) = stream.into_split();
+                let mut lines = BufReader::new(reader).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let reply = format!("{}\n", reply(&line, &cmd_tx).await);
+                    if writer.write_all(reply.as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    Ok(Listener {
+        path: path.to_path_buf(),
+        task,
+    })
+}
+
+#[cfg(not(unix))]
+pub fn listen(path: &Path, _cmd_tx: mpsc::Sender<IpcCommand>) -> anyhow::Result<Listener> {
+    Err(anyhow::anyhow!(
+        "Failed to listen on {}: sockets are only supported on Unix",
+        path.display()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let parse = |line| parse(line, oneshot::channel().0);
+        assert!(matches!(
+            parse(r#"{"action": "set_pipeline", "stages": ["cat foo", "jq ."]}"#),
+            Ok(IpcCommand::SetPipeline(stages, _)) if stages == ["cat foo", "jq ."]
+        ));
+        assert!(matches!(
+            parse(r#"{"action": "run"}"#),
+            Ok(IpcCommand::Run(_))
+        ));
+        assert!(matches!(
+            parse(r#"{"action": "get_output"}"#),
+            Ok(IpcCommand::GetOutput(_))
+        ));
+        assert!(parse(r#"{"action": "set_pipeline", "stages": [1]}"#).is_err());
+        assert!(parse(r#"{"action": "quit"}"#).is_err());
+        assert!(parse(r#"{"stages": []}"#).is_err());
+        assert!(parse("run").is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_listen() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let path = std::env::temp_dir().join(format!("epiq_test_{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (cmd_tx, mut cmd_rx) = mpsc::channel(1);
+        let listener = listen(&path, cmd_tx).unwrap();
+        tokio::spawn(async move {
+            while let Some(cmd) = cmd_rx.recv().await {
+                let _ = match cmd {
+                    IpcCommand::SetPipeline(_, reply_tx) => reply_tx.send(Ok(None)),
+                    IpcCommand::Run(reply_tx) => reply_tx.send(Err(String::from("no commands"))),
+                    IpcCommand::GetOutput(reply_tx) => {
+                        reply_tx.send(Ok(Some(String::from("a\nb"))))
+                    }
+                };
+            }
+        });
+
+        let stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        // Compared as values, not depending on the order of the keys.
+        let mut next_reply = async || -> Value {
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap()
+        };
+        writer
+            .write_all(
+                b"{\"action\": \"set_pipeline\", \"stages\": []}\n{\"action\": \"run\"}\n\
+                  {\"action\": \"get_output\"}\nrun\n",
+            )
+            .await
+            .unwrap();
+        assert_eq!(next_reply().await, json!({"ok": true}));
+        assert_eq!(
+            next_reply().await,
+            json!({"ok": false, "error": "no commands"})
+        );
+        assert_eq!(next_reply().await, json!({"ok": true, "output": "a\nb"}));
+        let reply = next_reply().await;
+        assert_eq!(reply["ok"], json!(false));
+        assert!(reply["error"].is_string());
+
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        drop(listener);
+        assert!(!path.exists());
+    }
+}
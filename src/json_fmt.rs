@@ -0,0 +1,155 @@
+use crossterm::style::Color;
+use promkit::{grapheme::StyledGraphemes, style::StyleBuilder};
+use serde_json::Value;
+
+const INDENT: &str = "  ";
+
+/// Parses the line as a JSON object or array, which is worth pretty-printing.
+pub fn parse(line: &str) -> Option<Value> {
+    serde_json::from_str(line)
+        .ok()
+        .filter(|value| matches!(value, Value::Object(_) | Value::Array(_)))
+}
+
+/// Pretty-prints the lines if they make up a JSON object or array as a whole,
+/// or else line by line if any of them is a JSON object or array (e.g. NDJSON),
+/// passing the other lines through unchanged.
+/// Returns the pretty-printed lines and whether they are pretty-printed line by line.
+pub fn format_lines(lines: &[String]) -> Option<(Vec<StyledGraphemes>, bool)> {
+    if let Some(value) = parse(&lines.join("\n")) {
+        return Some((pretty_lines(&value), false));
+    }
+    let values: Vec<Option<Value>> = lines.iter().map(|line| parse(line)).collect();
+    if values.iter().all(Option::is_none) {
+        return None;
+    }
+    let formatted = lines
+        .iter()
+        .zip(values)
+        .flat_map(|(line, value)| match value {
+            Some(value) => pretty_lines(&value),
+            None => vec![StyledGraphemes::from(line.as_str())],
+        })
+        .collect();
+    Some((formatted, true))
+}
+
+/// Pretty-prints the value into lines as `serde_json::to_string_pretty` does,
+/// with the keys in cyan, the strings in green, the numbers in yellow,
+/// and the booleans and null in magenta.
+pub fn pretty_lines(value: &Value) -> Vec<StyledGraphemes> {
+    let mut lines = vec![StyledGraphemes::default()];
+    write_value(&mut lines, value, 0);
+    lines
+}
+
+fn write_value(out: &mut Vec<StyledGraphemes>, value: &Value, depth: usize) {
+    match value {
+        Value::Null => push(out, "null", Some(Color::DarkMagenta)),
+        Value::Bool(b) => push(out, &b.to_string(), Some(Color::DarkMagenta)),
+        Value::Number(n) => push(out, &n.to_string(), Some(Color::DarkYellow)),
+        Value::String(s) => push(out, &quote(s), Some(Color::DarkGreen)),
+        Value::Array(items) if items.is_empty() => push(out, "[]", None),
+        Value::Object(entries) if entries.is_empty() => push(out, "{}", None),
+        Value::Array(items) => {
+            push(out, "[\n", None);
+            for (i, item) in items.iter().enumerate() {
+                push(out, &INDENT.repeat(depth + 1), None);
+                write_value(out, item, depth + 1);
+                push(out, if i + 1 < items.len() { ",\n" } else { "\n" }, None);
+            }
+            push(out, &INDENT.repeat(depth), None);
+            push(out, "]", None);
+        }
+        Value::Object(entries) => {
+            push(out, "{\n", None);
+            for (i, (key, item)) in entries.iter().enumerate() {
+                push(out, &INDENT.repeat(depth + 1), None);
+                push(out, &quote(key), Some(Color::DarkCyan));
+                push(out, ": ", None);
+                write_value(out, item, depth + 1);
+                push(out, if i + 1 < entries.len() { ",\n" } else { "\n" }, None);
+            }
+            push(out, &INDENT.repeat(depth), None);
+            push(out, "}", None);
+        }
+    }
+}
+
+/// Appends the text to the last line, starting a new line at each newline in it.
+fn push(out: &mut Vec<StyledGraphemes>, text: &str, color: Option<Color>) {
+    let style = match color {
+        Some(color) => StyleBuilder::new().fgc(color).build(),
+        None => StyleBuilder::new().build(),
+    };
+    for (i, piece) in text.split('\n').enumerate() {
+        if i > 0 {
+            out.push(StyledGraphemes::default());
+        }
+        if let Some(line) = out.last_mut() {
+            line.append(&mut StyledGraphemes::from_str(piece, style));
+        }
+    }
+}
+
+/// Quotes the string with the escapes as JSON.
+fn quote(s: &str) -> String {
+    Value::String(s.to_string()).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pretty_lines() {
+        let value: Value =
+            serde_json::from_str(r#"{"a":[1,"x\"y\n",true,null],"b":{},"c":[],"d":{"e":1.5}}"#)
+                .unwrap();
+        assert_eq!(
+            pretty_lines(&value)
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("\n"),
+            serde_json::to_string_pretty(&value).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_format_lines() {
+        let lines = |lines: &[&str]| {
+            lines
+                .iter()
+                .map(|line| line.to_string())
+                .collect::<Vec<_>>()
+        };
+        let shown = |(lines, ndjson): (Vec<StyledGraphemes>, bool)| {
+            (
+                lines.iter().map(ToString::to_string).collect::<Vec<_>>(),
+                ndjson,
+            )
+        };
+
+        assert_eq!(
+            format_lines(&lines(&[r#"{"a":1}"#, "", "[2]"])).map(shown),
+            Some((lines(&["{", r#"  "a": 1"#, "}", "", "[", "  2", "]"]), true))
+        );
+        // The other lines pass through unchanged.
+        assert_eq!(
+            format_lines(&lines(&["INFO start", r#"{"a":1}"#, "1"])).map(shown),
+            Some((lines(&["INFO start", "{", r#"  "a": 1"#, "}", "1"]), true))
+        );
+        assert_eq!(
+            format_lines(&lines(&["{", r#""a": [1,"#, "2]", "}"])).map(shown),
+            Some((
+                lines(&["{", r#"  "a": ["#, "    1,", "    2", "  ]", "}"]),
+                false
+            ))
+        );
+        // Not worth pretty-printing.
+        assert_eq!(format_lines(&lines(&["1", "\"a\""])), None);
+        assert_eq!(format_lines(&lines(&["{", "\"a\": "])), None);
+        assert_eq!(format_lines(&lines(&[])), None);
+    }
+}
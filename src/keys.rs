@@ -0,0 +1,149 @@
+use std::fmt;
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+/// A key combined with modifiers, e.g. `ctrl+q`, given to the options choosing the keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyChord {
+    // Always lowercase, with the shift in the modifiers.
+    ch: char,
+    modifiers: KeyModifiers,
+}
+
+impl KeyChord {
+    pub fn matches(&self, event: &KeyEvent) -> bool {
+        let KeyCode::Char(ch) = event.code else {
+            return false;
+        };
+        // Terminals report Ctrl+Shift+Q as either `Q` or `q` with the shift.
+        let modifiers = if ch.is_uppercase() {
+            event.modifiers | KeyModifiers::SHIFT
+        } else {
+            event.modifiers
+        };
+        ch.to_lowercase().eq([self.ch]) && modifiers == self.modifiers
+    }
+}
+
+impl fmt::Display for KeyChord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (modifier, name) in [
+            (KeyModifiers::CONTROL, "Ctrl+"),
+            (KeyModifiers::ALT, "Alt+"),
+            (KeyModifiers::SHIFT, "Shift+"),
+        ] {
+            if self.modifiers.contains(modifier) {
+                write!(f, "{}", name)?;
+            }
+        }
+        write!(f, "{}", self.ch.to_uppercase())
+    }
+}
+
+/// Returns whether the modifiers are Ctrl+Shift, or Alt+Shift which is accepted instead
+/// since the terminals without the keyboard enhancement send Ctrl+Shift+X as Ctrl+X.
+pub fn is_ctrl_shift(modifiers: KeyModifiers) -> bool {
+    modifiers == KeyModifiers::CONTROL | KeyModifiers::SHIFT
+        || modifiers == KeyModifiers::ALT | KeyModifiers::SHIFT
+}
+
+/// The keys bound to the other functions (see the keymap in README.md) by the modifiers,
+/// which `--quit-key` and `--interrupt-key` cannot take.
+const BOUND_KEYS: &[(KeyModifiers, &str)] = &[
+    (KeyModifiers::CONTROL, "abdeghijkrtuvwx"),
+    (
+        KeyModifiers::CONTROL.union(KeyModifiers::SHIFT),
+        "beijlprsvx",
+    ),
+    (
+        KeyModifiers::CONTROL
+            .union(KeyModifiers::ALT)
+            .union(KeyModifiers::SHIFT),
+        "j",
+    ),
+    (KeyModifiers::ALT, "0123456789abcdefghjkmopsuvwx;=+-|"),
+    (
+        KeyModifiers::ALT.union(KeyModifiers::SHIFT),
+        "abcdeijklmoprsvx\\",
+    ),
+];
+
+/// Returns whether the chord is bound to another function than quitting and cancelling.
+pub fn is_bound(chord: &KeyChord) -> bool {
+    BOUND_KEYS
+        .iter()
+        .any(|(modifiers, keys)| *modifiers == chord.modifiers && keys.contains(chord.ch))
+}
+
+/// Parses a key chord such as `ctrl+q` or `alt+shift+x` (case-insensitive).
+/// Either Ctrl or Alt is required, since the plain keys are for typing the commands.
+pub fn parse_key_chord(s: &str) -> Result<KeyChord, String> {
+    let mut modifiers = KeyModifiers::NONE;
+    let mut ch = None;
+    for part in s.split('+') {
+        match part.trim().to_lowercase().as_str() {
+            "ctrl" | "control" => modifiers |= KeyModifiers::CONTROL,
+            "alt" | "meta" => modifiers |= KeyModifiers::ALT,
+            "shift" => modifiers |= KeyModifiers::SHIFT,
+            key if ch.is_none() && key.chars().count() == 1 => ch = key.chars().next(),
+            _ => return Err(format!("invalid key chord {:?}", s)),
+        }
+    }
+    match ch {
+        Some(ch) if modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) => {
+            Ok(KeyChord { ch, modifiers })
+        }
+        Some(_) => Err(format!(
+            "{:?} needs Ctrl or Alt not to conflict with typing",
+            s
+        )),
+        None => Err(format!("{:?} has no key", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_chord() {
+        let key = |ch: char, modifiers: KeyModifiers| KeyEvent::new(KeyCode::Char(ch), modifiers);
+
+        let chord = parse_key_chord("ctrl+q").unwrap();
+        assert_eq!(chord.to_string(), "Ctrl+Q");
+        assert!(chord.matches(&key('q', KeyModifiers::CONTROL)));
+        assert!(!chord.matches(&key('q', KeyModifiers::ALT)));
+        assert!(!chord.matches(&key('Q', KeyModifiers::CONTROL)));
+
+        let chord = parse_key_chord("Ctrl+Shift+X").unwrap();
+        assert_eq!(chord.to_string(), "Ctrl+Shift+X");
+        assert!(chord.matches(&key('X', KeyModifiers::CONTROL)));
+        assert!(chord.matches(&key('x', KeyModifiers::CONTROL | KeyModifiers::SHIFT)));
+
+        assert!(parse_key_chord("q").is_err());
+        assert!(parse_key_chord("shift+q").is_err());
+        assert!(parse_key_chord("ctrl+").is_err());
+        assert!(parse_key_chord("ctrl+qq").is_err());
+    }
+
+    #[test]
+    fn test_is_bound() {
+        assert!(is_bound(&parse_key_chord("ctrl+b").unwrap()));
+        assert!(is_bound(&parse_key_chord("Alt+Shift+X").unwrap()));
+        assert!(is_bound(&parse_key_chord("alt+|").unwrap()));
+        assert!(is_bound(&parse_key_chord("alt+shift+\\").unwrap()));
+        assert!(!is_bound(&parse_key_chord("ctrl+c").unwrap()));
+        assert!(!is_bound(&parse_key_chord("ctrl+q").unwrap()));
+        assert!(!is_bound(&parse_key_chord("ctrl+shift+c").unwrap()));
+    }
+
+    #[test]
+    fn test_is_ctrl_shift() {
+        assert!(is_ctrl_shift(KeyModifiers::CONTROL | KeyModifiers::SHIFT));
+        assert!(is_ctrl_shift(KeyModifiers::ALT | KeyModifiers::SHIFT));
+        assert!(!is_ctrl_shift(KeyModifiers::CONTROL));
+        assert!(!is_ctrl_shift(
+            KeyModifiers::CONTROL | KeyModifiers::ALT | KeyModifiers::SHIFT
+        ));
+    }
+}
@@ -1,30 +1,107 @@
-use std::{collections::HashSet, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+    path::PathBuf,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
 
 use chrono::Local;
 use clap::Parser;
 use crossterm::{
     self,
-    event::{Event, KeyCode, KeyEvent, KeyEventKind, KeyEventState, KeyModifiers},
+    event::{
+        Event, KeyCode, KeyEvent, KeyEventKind, KeyEventState, KeyModifiers, MouseButton,
+        MouseEvent, MouseEventKind,
+    },
     style::Color,
 };
 use promkit::{PaneFactory, grapheme::StyledGraphemes, text};
-use tokio::sync::{broadcast, mpsc};
+use tokio::sync::{broadcast, mpsc, oneshot};
 
+mod aliases;
+mod clipboard;
+mod config;
+mod console;
+mod csv;
+mod debug_log;
+mod docpopup;
+mod envpopup;
+mod external_editor;
+mod highlight;
+mod ipc;
+use ipc::IpcCommand;
+mod json_fmt;
+mod keys;
+use keys::KeyChord;
 mod operator;
 mod pipeline;
+mod pipeline_export;
 mod prompt;
 use prompt::EditorTheme;
 mod queue;
+mod recording;
 mod render;
-use render::NotifyMessage;
+use render::{NotifyMessage, NotifyTheme};
+mod session;
+mod snippets;
+mod status;
+mod temp_file;
+use recording::Recording;
+use status::{StatusItem, StatusMessage};
+mod validate;
+mod watch;
+use watch::WatchEvent;
 
 use crate::{
-    operator::{Buffer, EventOperator, EventStream},
-    pipeline::Pipeline,
-    prompt::Prompt,
+    operator::{Buffer, Debounce, EventOperator, EventStream, OperatorConfig},
+    pipeline::{Exit, Origin, Pipeline, PipelineEvent},
+    prompt::{Prompt, StageStatus},
     render::{PaneIndex, SharedRenderer},
 };
 
+/// The upper bound of the output rendering interval while the output is flooding.
+const MAX_RENDER_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Show the spinner when the running pipeline produces no output for this period.
+const SPINNER_DELAY: Duration = Duration::from_millis(500);
+const SPINNER_FRAMES: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
+/// The interval to report the number of the lines and bytes each stage has emitted.
+const THROUGHPUT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The interval to update the elapsed time of each stage shown after its command.
+const TIMING_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The rows Alt+= and Alt+- change the output height by, and the minimum height.
+const OUTPUT_HEIGHT_STEP: u16 = 2;
+const MIN_OUTPUT_ROWS: u16 = 3;
+
+/// The interval to save the session, not to lose it if crashed.
+const SESSION_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The period in which the second Ctrl+C quits after cancelling the pipeline.
+const QUIT_GRACE_PERIOD: Duration = Duration::from_secs(3);
+
+/// The time to wait for the prompt to replace the stages by `set_pipeline` of the socket.
+const IPC_LOAD_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Asks the output stream for the text of the output and the bookmarked lines (0-based) in it.
+type OutputRequest = oneshot::Sender<(String, Vec<usize>)>;
+
+/// The panics in the spawned tasks, which are reported on exit
+/// because the messages are mangled while the raw mode is enabled.
+static TASK_PANICS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Whether the terminal supports the keyboard enhancement, which is asked only once
+/// since it is not answered while the external editor has the terminal.
+static KEYBOARD_ENHANCEMENT: OnceLock<bool> = OnceLock::new();
+
+/// The (minimum, maximum) intervals in milliseconds. Smaller ones make busy loops,
+/// and larger ones make the UI appear frozen (only warned).
+const EVENT_OPERATE_INTERVAL_RANGE: (u64, u64) = (5, 500);
+const OUTPUT_RENDER_INTERVAL_RANGE: (u64, u64) = (5, 1000);
+
 /// Laboratory for pipeline construction with feedback
 #[derive(Parser)]
 #[command(name = "epiq", version)]
@@ -39,6 +116,17 @@ pub struct Args {
     )]
     output_queue_size: usize,
 
+    #[arg(
+        long,
+        default_value = "1",
+        help = "Minimum rows of the output pane",
+        long_help = "Sets the number of rows always left for the output pane. \
+                    The stages which do not fit above it are scrolled \
+                    instead of squeezing the output pane, \
+                    and Alt+- does not shrink the output pane below it."
+    )]
+    min_output_height: u16,
+
     #[arg(
         long,
         default_value = "32",
@@ -49,7 +137,8 @@ pub struct Args {
                     Setting a smaller value improves responsiveness, but may cause internal \
                     processing to bottleneck when a large number of events are issued during \
                     scrolling or pasting. Setting an appropriate value enables efficient \
-                    operation by buffering and processing events in batches."
+                    operation by buffering and processing events in batches. \
+                    The minimum is 5 milliseconds."
     )]
     event_operate_interval: u64,
 
@@ -59,28 +148,707 @@ pub struct Args {
         help = "Output rendering interval (milliseconds)",
         long_help = "Specifies the interval in milliseconds for rendering pipeline output to the screen. \
                     Setting a smaller value increases the frequency of display updates, \
-                    but may cause screen flickering due to frequent rendering operations."
+                    but may cause screen flickering due to frequent rendering operations. \
+                    While the output arrives faster than it can be read, \
+                    the interval is automatically extended up to 200 milliseconds. \
+                    The minimum is 5 milliseconds."
     )]
     output_render_interval: u64,
+
+    #[arg(
+        long,
+        default_value = "1",
+        help = "Number of lines to scroll per mouse wheel tick",
+        long_help = "Specifies how many lines the output (and the popups) \
+                    scrolls per mouse wheel tick."
+    )]
+    scroll_speed: usize,
+
+    #[arg(
+        long,
+        default_value = "0",
+        help = "Stop the stage after emitting the number of lines (0 means unlimited)",
+        long_help = "Stops reading from the command of a stage and kills it \
+                    after it has emitted the specified number of lines, \
+                    which protects the screen and memory from the commands \
+                    producing output endlessly (e.g. `yes`). 0 means unlimited."
+    )]
+    max_lines_per_stage: usize,
+
+    #[arg(
+        long,
+        help = "Disable syntax highlighting in editors",
+        long_help = "Disables the coloring of program names, flags, quoted strings \
+                    and shell operators in the editors."
+    )]
+    no_highlight: bool,
+
+    #[arg(
+        long,
+        default_value = "0",
+        help = "Re-run the pipeline after no inputs for the duration (milliseconds)",
+        long_help = "Automatically re-runs the pipeline, as if Enter is pressed, \
+                    when there are no inputs for the specified duration in milliseconds. \
+                    This is useful for monitoring the output that changes over time. \
+                    The countdown is shown in the status bar. 0 disables the feature."
+    )]
+    auto_run: u64,
+
+    #[arg(
+        long,
+        value_name = "GLOB",
+        help = "Re-run the pipeline when the files matching the pattern change",
+        long_help = "Watches the files matching the glob pattern, and re-runs the pipeline, \
+                    as if Enter is pressed, when any of them is created or modified. \
+                    The changed file is shown in the notification. \
+                    This option can be specified multiple times."
+    )]
+    watch: Vec<glob::Pattern>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Accept commands from other processes through the Unix domain socket",
+        long_help = "Creates the Unix domain socket at the path, which accepts newline-delimited \
+                    JSON commands to replace the stages (`set_pipeline`), run the pipeline (`run`) \
+                    and get the output (`get_output`), e.g. `{\"action\": \"run\"}`. \
+                    Each command is replied with a JSON line. The socket is removed on exit."
+    )]
+    socket: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Save the stages and output to the file to restore them on the next launch",
+        long_help = "Restores the stages (with the disabled ones and the comments), \
+                    the focused stage and the output saved in the file if it exists, \
+                    and saves them to the file on exit and every few seconds. \
+                    The file of an incompatible version is refused."
+    )]
+    session: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Do not save the output in the session file given by --session",
+        long_help = "Leaves the output (and the bookmarks in it) out of the session file, \
+                    e.g. not to leave the output of the commands on the disk. \
+                    The output saved in the file before is not restored either."
+    )]
+    no_session_output: bool,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Record the screen into the file in the asciinema format",
+        long_help = "Records everything drawn on the screen into the file \
+                    in the asciinema v2 format, e.g. `--record demo.cast`, \
+                    which can be played back with `asciinema play demo.cast`. \
+                    If the file cannot be written, epiq runs without recording."
+    )]
+    record: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Log the events and the pipeline activity into the file",
+        long_help = "Logs the events (as aggregated from the terminal inputs), \
+                    the spawned commands, the exits of the stages and the errors \
+                    into the file with timestamps, e.g. to attach it to a bug report. \
+                    The file is truncated on start."
+    )]
+    log_file: Option<PathBuf>,
+
+    #[arg(long, help = "Do not record the screen even if --record is given")]
+    no_record: bool,
+
+    #[arg(
+        long,
+        value_name = "COLOR",
+        default_value = "dark_cyan",
+        value_parser = render::parse_color,
+        help = "Color of the informational notifications",
+        long_help = "Sets the color of the informational notifications, \
+                    e.g. `green` or `dark_cyan`. The colors are named as crossterm names them: \
+                    black, dark_grey, red, dark_red, green, dark_green, yellow, dark_yellow, \
+                    blue, dark_blue, magenta, dark_magenta, cyan, dark_cyan, white and grey."
+    )]
+    notify_info_color: Color,
+
+    #[arg(
+        long,
+        value_name = "COLOR",
+        default_value = "dark_yellow",
+        value_parser = render::parse_color,
+        help = "Color of the warning notifications"
+    )]
+    notify_warning_color: Color,
+
+    #[arg(
+        long,
+        value_name = "COLOR",
+        default_value = "dark_red",
+        value_parser = render::parse_color,
+        help = "Color of the error notifications"
+    )]
+    notify_error_color: Color,
+
+    #[arg(
+        long,
+        default_value = "0",
+        help = "Clear the notifications after the duration (milliseconds)",
+        long_help = "Clears the notification after the specified duration in milliseconds \
+                    unless another one replaces it, so that a stale error does not linger \
+                    after the command is fixed. The questions waiting for the answer \
+                    (e.g. y/n) and the comments of the stages are kept. \
+                    0 (the default) keeps all the notifications until replaced."
+    )]
+    notify_timeout: u64,
+
+    #[arg(
+        long,
+        value_name = "KEY=COLOR",
+        value_parser = config::parse_theme_entry,
+        help = "Set the color of the theme",
+        long_help = "Sets the color of the theme, overriding the `[theme]` section \
+                    of ~/.config/epiq/config.toml, e.g. `--theme program=#ff8800`. \
+                    The keys are head_prefix, pipe_prefix, cursor, program, flag, quoted \
+                    and operator, and the colors are #RRGGBB, #RGB or the names \
+                    such as DarkGreen. The RGB colors fall back to the default ones \
+                    unless $COLORTERM is truecolor or 24bit. Can be specified multiple times."
+    )]
+    theme: Vec<(String, Color)>,
+
+    #[arg(
+        long,
+        help = "Show the result of each stage if any command is invalid on running",
+        long_help = "The commands are always checked before running the pipeline, \
+                    and nothing is run if any of them is invalid. With this flag, \
+                    the result of each stage is shown in the notification then, \
+                    instead of only the problems."
+    )]
+    validate: bool,
+
+    #[arg(
+        long,
+        help = "Replay the output of the unchanged head stage instead of re-running it",
+        long_help = "Keeps the output of the head stage, and feeds it into the next stages \
+                    instead of re-running the head while it is unchanged, \
+                    even if no stage is changed. This is useful when the head is slow \
+                    or expensive (e.g. `curl`). Press Ctrl+R to run it again."
+    )]
+    cache_head: bool,
+
+    #[arg(
+        long,
+        help = "Replay the output of the unchanged leading stages instead of re-running them",
+        long_help = "Keeps the output of each stage, and when only the later stages \
+                    have been edited since the previous run completed, replays the output \
+                    of the unchanged leading stages into the first edited one \
+                    instead of re-running them. Off by default, since the stages may read \
+                    something changing besides their input (e.g. files or the clock)."
+    )]
+    cache_stages: bool,
+
+    #[arg(
+        long,
+        visible_alias = "json",
+        help = "Pretty-print the output while it is JSON",
+        long_help = "Starts with the JSON mode (toggled by Alt+Shift+J) enabled, \
+                    which pretty-prints the output while it is JSON as a whole, \
+                    or else the lines which are JSON (e.g. NDJSON) one by one \
+                    while passing the other lines through unchanged."
+    )]
+    auto_json: bool,
+
+    #[arg(
+        long,
+        help = "Show the stage which produced each output line",
+        long_help = "Prefixes each line in the output pane with the badge of the stage \
+                    which produced it, e.g. `#3`, to tell the stderr of each stage, \
+                    the output of the previewed stage or the replayed output \
+                    from that of the last stage."
+    )]
+    stage_badges: bool,
+
+    #[arg(
+        long,
+        visible_alias = "alt-screen",
+        help = "Run inside the alternate screen",
+        long_help = "Draws on the alternate screen from the top of the terminal, \
+                    and leaves it on exit so that the previous contents of the terminal \
+                    (and the scrollback) reappear untouched."
+    )]
+    alternate_screen: bool,
+
+    #[arg(
+        long,
+        help = "Disable mouse capture",
+        long_help = "Never captures the mouse events, so that the native text selection \
+                    of the terminal works throughout the session. \
+                    Esc no longer toggles the mouse capture, and the mouse wheel scrolling, \
+                    clicking on the stages and selecting the output are not available."
+    )]
+    no_mouse: bool,
+
+    #[arg(
+        long,
+        value_name = "KEY=VALUE",
+        value_parser = aliases::parse_alias,
+        help = "Define an alias for the command of a stage",
+        long_help = "Replaces the first word of each stage with VALUE if it is KEY \
+                    before running the pipeline, e.g. `--alias 'll=ls -la --color=always'`. \
+                    Aliases are not expanded recursively. \
+                    This option can be specified multiple times."
+    )]
+    alias: Vec<(String, String)>,
+
+    #[arg(
+        long,
+        value_name = "CHARS",
+        default_value = ".|()[]",
+        help = "Characters which break words in editors",
+        long_help = "Sets the characters at which the word movement and deletion \
+                    (e.g. Alt+B, Ctrl+W) stop besides the spaces. \
+                    A stage can override them with the prefix `!wbc=<CHARS>`, \
+                    e.g. `!wbc=.: jq .a.b`, which is removed before running it."
+    )]
+    word_break_chars: String,
+
+    #[arg(
+        long,
+        help = "Insert `|` as is instead of creating the next stage",
+        long_help = "By default, typing `|` at the end of a stage (outside of quotes) \
+                    creates the next stage as Ctrl+B does. This option inserts it as is."
+    )]
+    no_auto_split: bool,
+
+    #[arg(
+        long,
+        value_name = "CHORD",
+        default_value = "ctrl+c",
+        value_parser = keys::parse_key_chord,
+        help = "Key to quit",
+        long_help = "Chooses the key to quit, e.g. `ctrl+q`. \
+                    If it is the same as --interrupt-key, the first press cancels the running \
+                    pipeline and the second one quits. Otherwise, quitting while a pipeline is \
+                    running asks for confirmation."
+    )]
+    quit_key: KeyChord,
+
+    #[arg(
+        long,
+        value_name = "CHORD",
+        default_value = "ctrl+c",
+        value_parser = keys::parse_key_chord,
+        help = "Key to cancel the running pipeline without quitting"
+    )]
+    interrupt_key: KeyChord,
+
+    #[arg(
+        long,
+        value_name = "PIPELINE",
+        help = "Run the pipeline once without the interactive screen",
+        long_help = "Runs the pipeline such as `cat log | grep ERROR` once, \
+                    printing its output to stdout, and exits with the exit code of the last \
+                    stage. The terminal is left as is, which suits scripts."
+    )]
+    run_once: Option<String>,
+
+    #[arg(
+        long,
+        requires = "run_once",
+        help = "Exit with the first non-zero exit code of the stages with --run-once",
+        long_help = "With --run-once, exits with the exit code of the first stage \
+                    which has failed instead of the last stage, as `set -o pipefail` does."
+    )]
+    pipefail: bool,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
+    // Exit before initializing the terminal not to corrupt it.
+    validate_args(&args)?;
+    if let Some(path) = &args.log_file {
+        debug_log::init(path)?;
+        debug_log::log(|| format!("epiq {} started", env!("CARGO_PKG_VERSION")));
+    }
+    if let Some(text) = &args.run_once {
+        let code = run_once(text, &args).await?;
+        std::process::exit(code);
+    }
+    let session = match &args.session {
+        Some(path) => session::load(path)?,
+        None => None,
+    };
+    let mut theme = match config::default_path() {
+        Some(path) => config::load_theme(&path)?,
+        None => config::Theme::default(),
+    };
+    for (key, color) in &args.theme {
+        theme.set(key, *color)?;
+    }
+    // Start recording before the terminal is initialized to record it also.
+    let recording = match &args.record {
+        Some(path) if !args.no_record => {
+            match Recording::start(path, crossterm::terminal::size()?) {
+                Ok(recording) => Some(recording),
+                Err(e) => {
+                    eprintln!("Warning: not recording to {}: {}", path.display(), e);
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+    let alternate_screen = args.alternate_screen;
+
+    init_terminal(alternate_screen)?;
+
+    // Restore the terminal before printing the panic message.
+    // Note: tokio catches the panics in the spawned tasks (on the worker threads),
+    // so keep them to report on exit instead of breaking the terminal while running.
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if std::thread::current().name() == Some("main") {
+            let _ = restore_terminal(alternate_screen);
+            default_hook(info);
+        } else if let Ok(mut panics) = TASK_PANICS.lock() {
+            panics.push(info.to_string());
+        }
+    }));
+
+    // Restore the terminal also when exiting with an error.
+    let result = run(args, session, theme).await;
+    debug_log::log(|| format!("exited: {:?}", result));
+    restore_terminal(alternate_screen)?;
+    if let Some(recording) = recording
+        && let Err(e) = recording.finish()
+    {
+        eprintln!("Warning: failed to finish recording: {}", e);
+    }
+
+    let panics = TASK_PANICS
+        .lock()
+        .map(|mut panics| std::mem::take(&mut *panics))
+        .unwrap_or_default();
+    for panic in &panics {
+        eprintln!("A background task {}", panic);
+    }
+    match result {
+        Ok(()) if !panics.is_empty() => Err(anyhow::anyhow!(
+            "{} background task(s) panicked",
+            panics.len()
+        )),
+        result => result,
+    }
+}
+
+/// Runs the pipeline without touching the terminal, printing its output to stdout
+/// (and the stderr of the stages to stderr), and returns the exit code of the last stage
+/// (or the first failed one with `--pipefail`).
+async fn run_once(text: &str, args: &Args) -> anyhow::Result<i32> {
+    let aliases: HashMap<String, String> = args.alias.iter().cloned().collect();
+    let cmds = pipeline::split_pipeline(text)
+        .iter()
+        .map(|cmd| aliases::expand(cmd, &aliases))
+        .collect();
+    let (output_tx, mut output_rx) = mpsc::channel(1);
+    let (events_tx, mut events_rx) = mpsc::channel(1);
+    let _pipeline = Pipeline::spawn(
+        cmds,
+        output_tx,
+        events_tx,
+        args.max_lines_per_stage,
+        None,
+        None,
+        None,
+    )?;
+
+    let print = async move {
+        let mut stdout = std::io::stdout();
+        while let Some((origin, line)) = output_rx.recv().await {
+            if origin.stderr {
+                let _ = writeln!(std::io::stderr(), "{}", line);
+                continue;
+            }
+            // Stop printing once the reader has gone (e.g. `epiq --run-once ... | head`).
+            if writeln!(stdout, "{}", line).is_err() {
+                break;
+            }
+        }
+        let _ = stdout.flush();
+    };
+    let wait = async move {
+        let mut exits = BTreeMap::new();
+        while let Some((_, event)) = events_rx.recv().await {
+            if let PipelineEvent::StageExited(index, exit) = event {
+                exits.insert(index, exit.code());
+            }
+        }
+        exits
+    };
+    let ((), exits) = tokio::join!(print, wait);
+
+    Ok(if args.pipefail {
+        exits.values().copied().find(|code| *code != 0).unwrap_or(0)
+    } else {
+        exits.values().last().copied().unwrap_or(0)
+    })
+}
+
+/// Rejects the intervals below the minimum and the keys bound to the other functions,
+/// and warns about the intervals above the maximum.
+fn validate_args(args: &Args) -> anyhow::Result<()> {
+    for (name, chord) in [
+        ("--quit-key", &args.quit_key),
+        ("--interrupt-key", &args.interrupt_key),
+    ] {
+        if keys::is_bound(chord) {
+            anyhow::bail!(
+                "{} cannot be {}, which is bound to another function (see the keymap)",
+                name,
+                chord
+            );
+        }
+    }
+    for (name, value, (min, max)) in [
+        (
+            "--event-operate-interval",
+            args.event_operate_interval,
+            EVENT_OPERATE_INTERVAL_RANGE,
+        ),
+        (
+            "--output-render-interval",
+            args.output_render_interval,
+            OUTPUT_RENDER_INTERVAL_RANGE,
+        ),
+    ] {
+        if value < min {
+            anyhow::bail!(
+                "{} must be at least {}ms to avoid busy looping (got {}ms)",
+                name,
+                min,
+                value
+            );
+        }
+        if value > max {
+            eprintln!(
+                "Warning: {} of {}ms may make the screen unresponsive (recommended: up to {}ms)",
+                name, value, max
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Replaces all the stages with the commands from the source (e.g. the clipboard),
+/// or appends them to the current stages, and focuses the last one of them.
+async fn load_commands(
+    cmds: Vec<String>,
+    source: &str,
+    append: bool,
+    prompt: &Prompt,
+    broadcast_event_tx: &broadcast::Sender<EventStream>,
+    notify_tx: &mpsc::Sender<NotifyMessage>,
+) -> anyhow::Result<()> {
+    let loaded = cmds.len();
+    let mut stages: Vec<session::Stage> = if append {
+        prompt
+            .session_stages()
+            .await
+            .0
+            .into_iter()
+            .filter(|stage| !stage.text.trim().is_empty())
+            .collect()
+    } else {
+        vec![]
+    };
+    stages.extend(cmds.into_iter().map(|text| session::Stage {
+        text,
+        ..Default::default()
+    }));
+    let focus = stages.len().saturating_sub(1);
+    broadcast_event_tx.send(EventStream::LoadStages(stages, focus))?;
+    let _ = notify_tx
+        .send(NotifyMessage::Info(format!(
+            "Loaded {} stage(s) from {}",
+            loaded, source
+        )))
+        .await;
+    Ok(())
+}
+
+/// Edits the text in the external editor (see `external_editor::edit`),
+/// handing the terminal over to it: the inputs are not read and nothing is rendered
+/// until it exits. The screen is cleared afterwards, to be redrawn by the caller.
+async fn edit_externally(
+    text: &str,
+    extension: &str,
+    event_operator: &mut EventOperator,
+    shared_renderer: &SharedRenderer,
+    mouse_capture: &MouseCapture,
+    alternate_screen: bool,
+) -> anyhow::Result<Option<String>> {
+    event_operator.suspend().await;
+    // Hold the renderer not to let the background tasks draw over the editor.
+    let mut renderer = shared_renderer.lock().await;
+    let edited = match restore_terminal(alternate_screen) {
+        Ok(()) => external_editor::edit(text, extension).await,
+        Err(e) => Err(e),
+    };
+    let restored = init_terminal(alternate_screen)
+        .and_then(|()| Ok(mouse_capture.apply(&mut std::io::stdout())?))
+        .and_then(|()| renderer.clear());
+    drop(renderer);
+    event_operator.resume();
+    restored?;
+    edited
+}
 
+/// Keeps the mouse capture of the terminal in sync with the desired state,
+/// which all the changes of the mouse capture go through.
+struct MouseCapture {
+    enabled: bool,
+}
+
+impl MouseCapture {
+    fn new(enabled: bool) -> Self {
+        Self { enabled }
+    }
+
+    fn toggle<W: std::io::Write>(&mut self, w: &mut W) -> std::io::Result<()> {
+        self.enabled = !self.enabled;
+        self.apply(w)
+    }
+
+    /// Emits the desired state to the terminal, also to re-assert it
+    /// in case it has drifted (e.g. after resizing).
+    fn apply<W: std::io::Write>(&self, w: &mut W) -> std::io::Result<()> {
+        if self.enabled {
+            crossterm::execute!(w, crossterm::event::EnableMouseCapture)
+        } else {
+            crossterm::execute!(w, crossterm::event::DisableMouseCapture)
+        }
+    }
+}
+
+fn init_terminal(alternate_screen: bool) -> anyhow::Result<()> {
     crossterm::terminal::enable_raw_mode()?;
+    // Let the terminal report Ctrl+Shift+<key> apart from Ctrl+<key>,
+    // which the others send for both.
+    if *KEYBOARD_ENHANCEMENT
+        .get_or_init(|| crossterm::terminal::supports_keyboard_enhancement().unwrap_or(false))
+    {
+        crossterm::execute!(
+            std::io::stdout(),
+            crossterm::event::PushKeyboardEnhancementFlags(
+                crossterm::event::KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES
+            ),
+        )?;
+    }
     crossterm::execute!(
         std::io::stdout(),
         crossterm::cursor::Hide,
-        crossterm::event::EnableMouseCapture,
+        crossterm::event::EnableBracketedPaste,
+    )?;
+    if alternate_screen {
+        crossterm::execute!(
+            std::io::stdout(),
+            crossterm::terminal::EnterAlternateScreen,
+            crossterm::cursor::MoveTo(0, 0),
+        )?;
+    }
+    Ok(())
+}
+
+fn restore_terminal(alternate_screen: bool) -> anyhow::Result<()> {
+    crossterm::terminal::disable_raw_mode()?;
+    if KEYBOARD_ENHANCEMENT.get() == Some(&true) {
+        crossterm::execute!(
+            std::io::stdout(),
+            crossterm::event::PopKeyboardEnhancementFlags
+        )?;
+    }
+    crossterm::execute!(
+        std::io::stdout(),
+        crossterm::cursor::Show,
+        crossterm::event::DisableMouseCapture,
+        crossterm::event::DisableBracketedPaste,
     )?;
+    if alternate_screen {
+        crossterm::execute!(std::io::stdout(), crossterm::terminal::LeaveAlternateScreen)?;
+    }
+    Ok(())
+}
+
+async fn run(
+    args: Args,
+    session: Option<session::Session>,
+    theme: config::Theme,
+) -> anyhow::Result<()> {
+    let (watch_tx, mut watch_rx) = mpsc::channel(1);
+    let watcher = if args.watch.is_empty() {
+        None
+    } else {
+        Some(watch::spawn(args.watch.clone(), watch_tx)?)
+    };
+    let (ipc_tx, mut ipc_rx) = mpsc::channel(1);
+    let ipc_listener = match &args.socket {
+        Some(path) => Some(ipc::listen(path, ipc_tx)?),
+        None => None,
+    };
 
-    let mut enable_mouse_capture = true;
+    let mut mouse_capture = MouseCapture::new(!args.no_mouse);
+    mouse_capture.apply(&mut std::io::stdout())?;
+    let mut doc_popup: Option<docpopup::State> = None;
+    // The documentation being fetched in the background for the command (Alt+H).
+    let mut pending_doc: Option<(String, tokio::task::JoinHandle<Vec<String>>)> = None;
+    let mut list_popup: Option<(ListPopup, envpopup::State)> = None;
+    let mut input_console: Option<console::State> = None;
+    // Whether the form (e.g. find-and-replace) is open in the prompt,
+    // which consumes Enter and Esc instead of this loop.
+    let mut prompt_form = false;
+    let mut cancelled_at: Option<Instant> = None;
+    // Waiting for y/n to quit while the pipeline is still running.
+    let mut confirm_quit = false;
+    // Waiting for y/n (or `a` to append) to replace the stages with the commands
+    // and where they come from, e.g. pasted from the clipboard
+    // (or with nothing to clear all the stages).
+    let mut confirm_load: Option<(Vec<String>, String)> = None;
+    // The command of the head stage replaced to read the frozen output,
+    // and the temporary file the output is frozen into.
+    let mut frozen_head: Option<(String, PathBuf)> = None;
+    // The output of the stage before the focused one in the previous run, fed into
+    // the focused stage alone in the solo mode, with the number of the earlier lines not kept.
+    let mut solo: Option<(Arc<[String]>, usize)> = None;
+    // The number being typed to jump to.
+    let mut goto: Option<(GotoTarget, String)> = None;
     let mut cur_pipeline: Option<Pipeline> = None;
+    // The `get_output` commands of the socket waiting for the pipeline to finish.
+    let mut pending_outputs: Vec<ipc::Reply> = vec![];
+    // Receives the events of the pipeline with the instant it started at.
+    let (pipeline_event_tx, mut pipeline_event_rx) = mpsc::channel(1);
+    // The elapsed time of the running pipeline last shown (in 0.1s),
+    // or the instant the completed pipeline started at.
+    let mut elapsed_shown: Option<u128> = None;
+    let mut completed: Option<Instant> = None;
+    let mut throughput_at = Instant::now();
+    let mut shown_throughput: Vec<(usize, usize)> = vec![];
+    let mut timing_at = Instant::now();
+    let mut shown_timings: Vec<(Duration, bool)> = vec![];
     let (event_tx, mut event_rx) = mpsc::channel(1);
-    let event_operator = EventOperator::spawn(
+    // The events recorded as a macro with the elapsed times since the recording started.
+    let mut recording: Option<(Instant, Vec<(Duration, EventStream)>)> = None;
+    let mut macro_buffer: Vec<(Duration, EventStream)> = vec![];
+    let mut replay: Option<tokio::task::JoinHandle<()>> = None;
+    let replay_tx = event_tx.clone();
+    let mut event_operator = EventOperator::spawn(
         event_tx,
         tokio::time::interval(Duration::from_millis(args.event_operate_interval)),
+        OperatorConfig {
+            scroll_speed: args.scroll_speed,
+        },
     );
     let shared_renderer = SharedRenderer::try_new()?;
     let (broadcast_event_tx, _) = broadcast::channel(1);
@@ -88,119 +856,1637 @@ async fn main() -> anyhow::Result<()> {
 
     let (notify_tx, notify_rx) = mpsc::channel(1);
     let notify_renderer = shared_renderer.clone();
+    let true_color = render::supports_true_color();
+    let notify_theme = NotifyTheme {
+        info_fg_color: args.notify_info_color,
+        warning_fg_color: args.notify_warning_color,
+        error_fg_color: args.notify_error_color,
+    }
+    .fallback(true_color);
+    let notify_timeout =
+        (args.notify_timeout > 0).then(|| Duration::from_millis(args.notify_timeout));
     let notify_stream = tokio::spawn(async move {
-        notify_stream(text::State::default(), notify_rx, notify_renderer).await
+        notify_stream(
+            text::State::default(),
+            notify_rx,
+            notify_theme,
+            notify_timeout,
+            notify_renderer,
+        )
+        .await
+    });
+
+    let (status_tx, status_rx) = mpsc::channel(1);
+    let status_renderer = shared_renderer.clone();
+    let status_stream = tokio::spawn(async move {
+        status_stream(status::State::default(), status_rx, status_renderer).await
     });
 
+    // The maximum rows of the output pane, which fills the rest of the terminal if None,
+    // restored from the last session.
+    let (output_height_tx, output_height_rx) =
+        tokio::sync::watch::channel(session.as_ref().and_then(|session| session.output_height));
+
+    // When the output stream received the last line.
+    let (last_output_tx, last_output_rx) = tokio::sync::watch::channel(Instant::now());
+    let mut spinner_frame: Option<usize> = None;
+    // The frame of the spinners in the prefixes of the running stages.
+    let mut stage_frame: Option<usize> = None;
+
     let (output_tx, output_rx) = mpsc::channel(1);
+    // Requests the output stream for the text of the output.
+    let (text_tx, text_rx) = mpsc::channel(1);
     let output_renderer = shared_renderer.clone();
     let output_event_subscriber = broadcast_event_tx.subscribe();
     let output_reset_subscriber = broadcast_reset_tx.subscribe();
+    let output_height = output_height_rx.clone();
+    let output_notify_tx = notify_tx.clone();
+    let output_status_tx = status_tx.clone();
+    let mut queue = queue::State::new(args.output_queue_size, args.stage_badges, args.auto_json);
+    // Restore the output of the last session.
+    let restored = session
+        .iter()
+        .filter(|_| !args.no_session_output)
+        .flat_map(|session| &session.output);
+    for line in restored {
+        queue.push(Origin::default(), StyledGraphemes::from(line.as_str()));
+    }
+    if let Some(session) = session.as_ref().filter(|_| !args.no_session_output) {
+        queue.bookmark_lines(&session.bookmarks);
+    }
     let output_stream = tokio::spawn(async move {
         output_stream(
-            queue::State::new(args.output_queue_size),
+            queue,
             output_rx,
+            text_rx,
             output_event_subscriber,
             output_reset_subscriber,
+            output_height,
+            last_output_tx,
+            output_notify_tx,
+            output_status_tx,
             output_renderer,
             Duration::from_millis(args.output_render_interval),
         )
         .await
     });
 
-    let mut prompt = Prompt::spawn(
+    let theme = theme.fallback(true_color);
+    let highlight_theme = (!args.no_highlight).then(|| theme.highlight());
+
+    let aliases: HashMap<String, String> = args.alias.iter().cloned().collect();
+    if let Some(cycle) = aliases::find_cycle(&aliases) {
+        let _ = notify_tx
+            .send(NotifyMessage::Warning(format!(
+                "Circular aliases are expanded only once: {}",
+                cycle.join(" -> ")
+            )))
+            .await;
+    }
+    let prompt = Prompt::spawn(
         broadcast_event_tx.subscribe(),
         notify_tx.clone(),
-        // TODO: Configurable theme
         (
             // Head theme
             EditorTheme {
                 prefix: String::from("❯❯ "),
-                prefix_fg_color: Color::DarkGreen,
-                active_char_bg_color: Color::DarkCyan,
-                word_break_chars: HashSet::from(['.', '|', '(', ')', '[', ']']),
+                prefix_fg_color: theme.head_prefix,
+                active_char_bg_color: theme.cursor,
+                word_break_chars: args.word_break_chars.chars().collect(),
+                highlight: highlight_theme.clone(),
             },
             // Pipe theme
             EditorTheme {
                 prefix: String::from("❚ "),
-                prefix_fg_color: Color::DarkYellow,
-                active_char_bg_color: Color::DarkCyan,
-                word_break_chars: HashSet::from(['.', '|', '(', ')', '[', ']']),
+                prefix_fg_color: theme.pipe_prefix,
+                active_char_bg_color: theme.cursor,
+                word_break_chars: args.word_break_chars.chars().collect(),
+                highlight: highlight_theme,
             },
         ),
         crossterm::terminal::size()?,
         shared_renderer.clone(),
-    );
+        output_height_rx,
+        args.min_output_height,
+        aliases,
+        !args.no_auto_split,
+        session.as_ref(),
+    )?;
+    let mut session_save = tokio::time::interval(SESSION_SAVE_INTERVAL);
+    // The session last saved, not to write the file again if nothing has changed.
+    let mut saved_session = None;
+    let run_ctx = RunContext {
+        prompt: &prompt,
+        output_tx: &output_tx,
+        pipeline_event_tx: &pipeline_event_tx,
+        broadcast_reset_tx: &broadcast_reset_tx,
+        notify_tx: &notify_tx,
+        max_lines_per_stage: args.max_lines_per_stage,
+        cache_stages: args.cache_stages,
+        cache_head: args.cache_head,
+        validate: args.validate,
+    };
 
-    'outer: while let Some(events) = event_rx.recv().await {
-        for event in events {
-            match event {
-                EventStream::Buffer(Buffer::Other(
-                    Event::Key(KeyEvent {
-                        code: KeyCode::Char('c'),
-                        modifiers: KeyModifiers::CONTROL,
-                        kind: KeyEventKind::Press,
-                        state: KeyEventState::NONE,
-                    }),
-                    _,
-                )) => break 'outer,
-                // There is no way to capture ONLY mouse scroll events,
-                // so, toggle enabling and disabling of capturing all mouse events with Esc.
-                // https://github.com/crossterm-rs/crossterm/issues/640
-                EventStream::Buffer(Buffer::Other(
-                    Event::Key(KeyEvent {
-                        code: KeyCode::Esc,
-                        modifiers: KeyModifiers::NONE,
-                        kind: KeyEventKind::Press,
-                        state: KeyEventState::NONE,
-                    }),
-                    times,
-                )) => {
-                    if times % 2 != 0 {
-                        enable_mouse_capture = !enable_mouse_capture;
-                        if enable_mouse_capture {
-                            crossterm::execute!(
-                                std::io::stdout(),
-                                crossterm::event::EnableMouseCapture,
-                            )?;
-                        } else {
-                            crossterm::execute!(
-                                std::io::stdout(),
-                                crossterm::event::DisableMouseCapture,
-                            )?;
-                        }
-                    }
+    let auto_run_interval = Duration::from_millis(args.auto_run);
+    let auto_run = tokio::time::sleep(auto_run_interval);
+    tokio::pin!(auto_run);
+    let mut auto_run_countdown: Option<u64> = None;
+
+    'outer: loop {
+        let events = tokio::select! {
+            maybe_events = event_rx.recv() => match maybe_events {
+                Some(events) => events,
+                None => break,
+            },
+            _ = &mut auto_run, if args.auto_run > 0 => {
+                auto_run
+                    .as_mut()
+                    .reset(tokio::time::Instant::now() + auto_run_interval);
+                let _ = rerun_pipeline(
+                    &mut cur_pipeline,
+                    &run_ctx,
+                    prompt.get_all_stages().await,
+                    0,
+                    true,
+                    input_console.as_mut().map(console::State::connect),
+                )
+                .await?;
+                continue;
+            },
+            Some((started_at, event)) = pipeline_event_rx.recv() => {
+                // Ignore the previous runs which reported just before re-running.
+                if cur_pipeline.as_ref().is_none_or(|pipeline| pipeline.started_at() != started_at) {
+                    continue;
                 }
-                EventStream::Buffer(Buffer::Other(
-                    Event::Key(KeyEvent {
-                        code: KeyCode::Enter,
-                        modifiers: KeyModifiers::NONE,
-                        kind: KeyEventKind::Press,
-                        state: KeyEventState::NONE,
-                    }),
-                    _,
-                )) => {
-                    // First of all, abort the current command if it is running.
-                    if let Some(ref mut pipeline) = cur_pipeline {
-                        pipeline.abort_all();
-                        broadcast_reset_tx.send(())?;
-                        let _ = notify_tx.send(NotifyMessage::None).await;
+                match event {
+                    PipelineEvent::Completed => {
+                        // The commands may have changed the terminal through /dev/tty.
+                        mouse_capture.apply(&mut std::io::stdout())?;
+                        completed = Some(started_at);
+                        elapsed_shown = None;
+                        reply_outputs(&mut pending_outputs, &text_tx).await?;
+                        let _ = status_tx
+                            .send(StatusMessage::Set(
+                                StatusItem::Elapsed,
+                                format!("took {:.1}s", started_at.elapsed().as_secs_f64()),
+                            ))
+                            .await;
                     }
-
-                    match Pipeline::spawn(prompt.get_all_texts().await, output_tx.clone()) {
-                        Ok(pipeline) => {
-                            cur_pipeline = Some(pipeline);
-                        }
+                    PipelineEvent::StageExited(index, exit) => {
+                        prompt
+                            .set_stage_status(
+                                index,
+                                if exit == Exit::Success {
+                                    StageStatus::Succeeded
+                                } else {
+                                    StageStatus::Failed
+                                },
+                            )
+                            .await;
+                    }
+                    PipelineEvent::Broken(index, code) => {
+                        let program = cur_pipeline
+                            .as_ref()
+                            .and_then(|pipeline| pipeline.program(index))
+                            .unwrap_or_default();
+                        let exited = match code {
+                            Some(code) => format!("exited with code {}", code),
+                            None => String::from("was killed"),
+                        };
+                        let _ = notify_tx
+                            .send(NotifyMessage::Error(format!(
+                                "Stage {} ({}) {} — pipeline broken",
+                                index + 1,
+                                program,
+                                exited
+                            )))
+                            .await;
+                    }
+                    PipelineEvent::LineLimitReached(index) => {
+                        let _ = notify_tx
+                            .send(NotifyMessage::Warning(format!(
+                                "Stage {} was stopped after {} lines (--max-lines-per-stage)",
+                                index + 1,
+                                args.max_lines_per_stage
+                            )))
+                            .await;
+                    }
+                }
+                continue;
+            },
+            Some(lines) = async {
+                match pending_doc.as_mut() {
+                    Some((_, fetching)) => Some(fetching.await),
+                    None => None,
+                }
+            }, if pending_doc.is_some() => {
+                let Some((cmd, _)) = pending_doc.take() else {
+                    continue;
+                };
+                // Not shown if the cursor has moved off the command meanwhile.
+                if prompt.get_command_under_cursor().await.as_ref() != Some(&cmd) {
+                    let _ = notify_tx.send(NotifyMessage::None).await;
+                    continue;
+                }
+                let lines = lines.unwrap_or_default();
+                if lines.is_empty() {
+                    let _ = notify_tx
+                        .send(NotifyMessage::Error(format!(
+                            "No documentation found for {}",
+                            cmd
+                        )))
+                        .await;
+                    continue;
+                }
+                let _ = notify_tx.send(NotifyMessage::None).await;
+                let popup = docpopup::State::new(&cmd, lines);
+                let (width, height) = crossterm::terminal::size()?;
+                let _ = shared_renderer
+                    .lock()
+                    .await
+                    .update([(PaneIndex::DocPopup, popup.create_pane(width, height))])
+                    .render();
+                doc_popup = Some(popup);
+                continue;
+            },
+            Some(WatchEvent(path)) = watch_rx.recv(), if watcher.is_some() => {
+                // Saving a file often emits several events at once.
+                while watch_rx.try_recv().is_ok() {}
+                let spawned = rerun_pipeline(
+                    &mut cur_pipeline,
+                    &run_ctx,
+                    prompt.get_all_stages().await,
+                    0,
+                    // The files read by the unchanged stages may have changed.
+                    false,
+                    input_console.as_mut().map(console::State::connect),
+                )
+                .await?;
+                if spawned.is_ok() {
+                    let _ = notify_tx
+                        .send(NotifyMessage::Info(format!("{} changed", path.display())))
+                        .await;
+                }
+                continue;
+            },
+            _ = session_save.tick(), if args.session.is_some() => {
+                if let Some(path) = &args.session
+                    && let Err(e) = save_session(
+                        path,
+                        !args.no_session_output,
+                        *output_height_tx.borrow(),
+                        &prompt,
+                        &text_tx,
+                        &mut saved_session,
+                    )
+                    .await
+                {
+                    let _ = notify_tx
+                        .send(NotifyMessage::Error(format!("Failed to save the session: {}", e)))
+                        .await;
+                }
+                continue;
+            },
+            Some(cmd) = ipc_rx.recv(), if ipc_listener.is_some() => {
+                match cmd {
+                    IpcCommand::SetPipeline(cmds, reply_tx) => {
+                        // Reply once the prompt has replaced the stages,
+                        // so that `run` right after this runs them.
+                        let mut loaded = prompt.loaded();
+                        loaded.borrow_and_update();
+                        broadcast_event_tx.send(EventStream::Load(cmds))?;
+                        let _ = reply_tx.send(
+                            match tokio::time::timeout(IPC_LOAD_TIMEOUT, loaded.changed()).await {
+                                Ok(Ok(())) => Ok(None),
+                                _ => Err(String::from("The stages were not replaced")),
+                            },
+                        );
+                    }
+                    IpcCommand::Run(reply_tx) => {
+                        let spawned = rerun_pipeline(
+                            &mut cur_pipeline,
+                            &run_ctx,
+                            prompt.get_all_stages().await,
+                            0,
+                            true,
+                            input_console.as_mut().map(console::State::connect),
+                        )
+                        .await?;
+                        let _ = reply_tx.send(spawned.map(|()| None));
+                    }
+                    IpcCommand::GetOutput(reply_tx) => {
+                        pending_outputs.push(reply_tx);
+                        if !cur_pipeline.as_ref().is_some_and(Pipeline::is_running) {
+                            reply_outputs(&mut pending_outputs, &text_tx).await?;
+                        }
+                    }
+                }
+                continue;
+            },
+        };
+
+        if args.auto_run > 0 {
+            // Restart the countdown on any inputs.
+            if events
+                .iter()
+                .any(|event| matches!(event, EventStream::Buffer(_)))
+            {
+                auto_run
+                    .as_mut()
+                    .reset(tokio::time::Instant::now() + auto_run_interval);
+            }
+            let remaining = auto_run
+                .deadline()
+                .saturating_duration_since(tokio::time::Instant::now());
+            let countdown = remaining.as_secs_f64().ceil() as u64;
+            if auto_run_countdown != Some(countdown) {
+                auto_run_countdown = Some(countdown);
+                let _ = status_tx
+                    .send(StatusMessage::Set(
+                        StatusItem::AutoRun,
+                        format!("auto-run in {}s", countdown),
+                    ))
+                    .await;
+            }
+        }
+
+        // Count up the elapsed time of the running pipeline.
+        if let Some(pipeline) = cur_pipeline.as_ref()
+            && pipeline.is_running()
+            && completed != Some(pipeline.started_at())
+        {
+            let elapsed = pipeline.started_at().elapsed().as_millis() / 100;
+            if elapsed_shown != Some(elapsed) {
+                elapsed_shown = Some(elapsed);
+                let _ = status_tx
+                    .send(StatusMessage::Set(
+                        StatusItem::Elapsed,
+                        format!("running {:.1}s", elapsed as f64 / 10.0),
+                    ))
+                    .await;
+            }
+        }
+
+        if throughput_at.elapsed() >= THROUGHPUT_INTERVAL
+            && let Some(pipeline) = cur_pipeline.as_ref()
+        {
+            throughput_at = Instant::now();
+            let stats = pipeline.stats();
+            if stats != shown_throughput {
+                let _ = status_tx
+                    .send(StatusMessage::Set(
+                        StatusItem::Throughput,
+                        format_throughput(&stats),
+                    ))
+                    .await;
+                shown_throughput = stats;
+            }
+        }
+
+        if timing_at.elapsed() >= TIMING_INTERVAL
+            && let Some(pipeline) = cur_pipeline.as_ref()
+        {
+            timing_at = Instant::now();
+            let timings = pipeline.timings();
+            if timings != shown_timings {
+                prompt.set_stage_timings(&timings).await;
+                shown_timings = timings;
+            }
+        }
+
+        // Animate the spinner while the running pipeline produces no output.
+        let frame = cur_pipeline
+            .as_ref()
+            .filter(|pipeline| pipeline.is_running())
+            .map(|pipeline| {
+                pipeline
+                    .started_at()
+                    .max(*last_output_rx.borrow())
+                    .elapsed()
+            })
+            .filter(|idle| *idle > SPINNER_DELAY)
+            .map(|idle| (idle.as_millis() / 100) as usize % SPINNER_FRAMES.len());
+        if frame != spinner_frame {
+            spinner_frame = frame;
+            let _ = status_tx
+                .send(match frame {
+                    Some(frame) => StatusMessage::Set(
+                        StatusItem::Spinner,
+                        format!("{} waiting for output", SPINNER_FRAMES[frame]),
+                    ),
+                    None => StatusMessage::Clear(StatusItem::Spinner),
+                })
+                .await;
+        }
+
+        // Spin the status of the running stages in their prefixes.
+        let frame = cur_pipeline
+            .as_ref()
+            .filter(|pipeline| pipeline.is_running())
+            .map(|pipeline| {
+                (pipeline.started_at().elapsed().as_millis() / 100) as usize % SPINNER_FRAMES.len()
+            });
+        if frame != stage_frame {
+            stage_frame = frame;
+            if let Some(frame) = frame {
+                prompt.animate_stages(SPINNER_FRAMES[frame]).await;
+            }
+        }
+
+        if let Some(at) = cancelled_at
+            && at.elapsed() > QUIT_GRACE_PERIOD
+        {
+            cancelled_at = None;
+            let _ = notify_tx.send(NotifyMessage::None).await;
+        }
+
+        if !events.is_empty() {
+            debug_log::log(|| {
+                format!(
+                    "events: [{}]",
+                    events
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            });
+        }
+        for event in events {
+            if let Some((started_at, recorded)) = recording.as_mut()
+                && matches!(event, EventStream::Buffer(_))
+                && macro_key(&event).is_none()
+            {
+                recorded.push((started_at.elapsed(), event.clone()));
+                let _ = notify_tx
+                    .send(NotifyMessage::Sticky(format!("[REC {}]", recorded.len())))
+                    .await;
+            }
+
+            // Intercept the key inputs before broadcasting them while confirming to quit.
+            if confirm_quit {
+                match &event {
+                    EventStream::Buffer(Buffer::Key(chars)) => {
+                        if matches!(chars.first(), Some('y' | 'Y')) {
+                            break 'outer;
+                        }
+                        confirm_quit = false;
+                        let _ = notify_tx.send(NotifyMessage::None).await;
+                        continue;
+                    }
+                    EventStream::Buffer(Buffer::Other(Event::Key(_), _))
+                    | EventStream::Buffer(Buffer::VerticalCursor(..))
+                    | EventStream::Buffer(Buffer::HorizontalCursor(..)) => {
+                        confirm_quit = false;
+                        let _ = notify_tx.send(NotifyMessage::None).await;
+                        continue;
+                    }
+                    _ => {}
+                }
+            }
+
+            if let Some((cmds, source)) = confirm_load.take() {
+                match &event {
+                    EventStream::Buffer(Buffer::Key(chars)) => {
+                        match chars.first() {
+                            Some('y' | 'Y') if cmds.is_empty() => {
+                                broadcast_event_tx.send(EventStream::Load(cmds))?;
+                                let _ = notify_tx.send(NotifyMessage::None).await;
+                            }
+                            Some(answer @ ('y' | 'Y' | 'a' | 'A')) if !cmds.is_empty() => {
+                                let append = matches!(answer, 'a' | 'A');
+                                load_commands(
+                                    cmds,
+                                    &source,
+                                    append,
+                                    &prompt,
+                                    &broadcast_event_tx,
+                                    &notify_tx,
+                                )
+                                .await?;
+                            }
+                            _ => {
+                                let _ = notify_tx.send(NotifyMessage::None).await;
+                            }
+                        }
+                        continue;
+                    }
+                    EventStream::Buffer(Buffer::Other(Event::Key(_), _))
+                    | EventStream::Buffer(Buffer::VerticalCursor(..))
+                    | EventStream::Buffer(Buffer::HorizontalCursor(..)) => {
+                        let _ = notify_tx.send(NotifyMessage::None).await;
+                        continue;
+                    }
+                    _ => confirm_load = Some((cmds, source)),
+                }
+            }
+
+            // Intercept the key inputs while typing the number to jump to.
+            if let Some((target, mut input)) = goto.take() {
+                match &event {
+                    EventStream::Buffer(Buffer::Key(chars)) => {
+                        input.extend(chars.iter().filter(|ch| ch.is_ascii_digit()));
+                        let _ = notify_tx
+                            .send(NotifyMessage::Sticky(format!(
+                                "Go to {}: {}",
+                                target.name(),
+                                input
+                            )))
+                            .await;
+                        goto = Some((target, input));
+                        continue;
+                    }
+                    EventStream::Buffer(Buffer::Other(
+                        Event::Key(KeyEvent {
+                            code: KeyCode::Backspace,
+                            ..
+                        }),
+                        times,
+                    )) => {
+                        for _ in 0..*times {
+                            input.pop();
+                        }
+                        let _ = notify_tx
+                            .send(NotifyMessage::Sticky(format!(
+                                "Go to {}: {}",
+                                target.name(),
+                                input
+                            )))
+                            .await;
+                        goto = Some((target, input));
+                        continue;
+                    }
+                    EventStream::Buffer(Buffer::Other(
+                        Event::Key(KeyEvent {
+                            code: KeyCode::Enter,
+                            modifiers: KeyModifiers::NONE,
+                            kind: KeyEventKind::Press,
+                            state: KeyEventState::NONE,
+                        }),
+                        _,
+                    )) => {
+                        if let Ok(n) = input.parse() {
+                            broadcast_event_tx.send(target.event(n))?;
+                        }
+                        let _ = notify_tx.send(NotifyMessage::None).await;
+                        continue;
+                    }
+                    // Cancel by the other keys (e.g. Esc).
+                    EventStream::Buffer(Buffer::Other(Event::Key(_), _))
+                    | EventStream::Buffer(Buffer::VerticalCursor(..))
+                    | EventStream::Buffer(Buffer::HorizontalCursor(..)) => {
+                        let _ = notify_tx.send(NotifyMessage::None).await;
+                        continue;
+                    }
+                    _ => goto = Some((target, input)),
+                }
+            }
+
+            // Intercept the key inputs for the incremental search while the list is shown.
+            if let Some((list, popup)) = list_popup.as_mut() {
+                let handled = match &event {
+                    EventStream::Buffer(Buffer::Key(chars)) => {
+                        popup.push_query(chars);
+                        Some(false)
+                    }
+                    EventStream::Buffer(Buffer::Other(
+                        Event::Key(KeyEvent {
+                            code: KeyCode::Backspace,
+                            ..
+                        }),
+                        times,
+                    )) => {
+                        popup.pop_query(*times);
+                        Some(false)
+                    }
+                    EventStream::Buffer(
+                        Buffer::VerticalCursor(up, down) | Buffer::VerticalScroll(up, down),
+                    ) => {
+                        popup.shift(*up, *down);
+                        Some(false)
+                    }
+                    // Insert the selected variable into the current editor,
+                    // or replace the stages with the selected snippet.
+                    EventStream::Buffer(Buffer::Other(
+                        Event::Key(KeyEvent {
+                            code: KeyCode::Enter,
+                            modifiers: KeyModifiers::NONE,
+                            kind: KeyEventKind::Press,
+                            state: KeyEventState::NONE,
+                        }),
+                        _,
+                    )) => {
+                        match (list, popup.selected_key()) {
+                            (ListPopup::Env, Some(key)) => {
+                                broadcast_event_tx.send(EventStream::Buffer(Buffer::Paste(
+                                    format!("${}", key),
+                                )))?;
+                            }
+                            (ListPopup::Snippets(snippets), Some(name)) => {
+                                let cmds = snippets.get(name).cloned().unwrap_or_default();
+                                let source = format!("the snippet {}", name);
+                                if prompt.is_empty().await {
+                                    load_commands(
+                                        cmds,
+                                        &source,
+                                        false,
+                                        &prompt,
+                                        &broadcast_event_tx,
+                                        &notify_tx,
+                                    )
+                                    .await?;
+                                } else {
+                                    let _ = notify_tx
+                                        .send(NotifyMessage::Sticky(format!(
+                                            "replace all stages with snippet {}? y/n (a: append)",
+                                            name
+                                        )))
+                                        .await;
+                                    confirm_load = Some((cmds, source));
+                                }
+                            }
+                            (_, None) => {}
+                        }
+                        Some(true)
+                    }
+                    EventStream::Buffer(Buffer::Other(
+                        Event::Key(KeyEvent {
+                            code: KeyCode::Esc,
+                            modifiers: KeyModifiers::NONE,
+                            kind: KeyEventKind::Press,
+                            state: KeyEventState::NONE,
+                        }),
+                        _,
+                    )) => Some(true),
+                    // The keys to quit and cancel still work.
+                    EventStream::Buffer(Buffer::Other(Event::Key(key), _))
+                        if args.quit_key.matches(key) || args.interrupt_key.matches(key) =>
+                    {
+                        None
+                    }
+                    // Do not edit the editors behind the popup.
+                    EventStream::Buffer(
+                        Buffer::Other(Event::Key(_), _)
+                        | Buffer::HorizontalCursor(..)
+                        | Buffer::Paste(_),
+                    ) => Some(false),
+                    _ => None,
+                };
+
+                if let Some(close) = handled {
+                    let mut renderer = shared_renderer.lock().await;
+                    if close {
+                        list_popup = None;
+                        renderer.remove([PaneIndex::EnvPopup]);
+                    } else {
+                        let (width, height) = crossterm::terminal::size()?;
+                        renderer.update([(PaneIndex::EnvPopup, popup.create_pane(width, height))]);
+                    }
+                    let _ = renderer.render();
+                    continue;
+                }
+            }
+
+            // Intercept the editing keys while the input console is open.
+            // The others (e.g. Ctrl+R to re-run) are handled as usual.
+            if let Some(console) = input_console.as_mut() {
+                let handled = match &event {
+                    EventStream::Buffer(
+                        Buffer::Key(_) | Buffer::Paste(_) | Buffer::HorizontalCursor(..),
+                    )
+                    | EventStream::Buffer(Buffer::Other(
+                        Event::Key(KeyEvent {
+                            code: KeyCode::Backspace,
+                            modifiers: KeyModifiers::NONE,
+                            kind: KeyEventKind::Press,
+                            state: KeyEventState::NONE,
+                        }),
+                        _,
+                    )) => {
+                        prompt::edit(&event, &mut console.editor);
+                        Some(false)
+                    }
+                    EventStream::Buffer(Buffer::Other(
+                        Event::Key(KeyEvent {
+                            code: KeyCode::Enter,
+                            modifiers: KeyModifiers::NONE,
+                            kind: KeyEventKind::Press,
+                            state: KeyEventState::NONE,
+                        }),
+                        _,
+                    )) => {
+                        if !console.send() {
+                            let _ = notify_tx
+                                .send(NotifyMessage::Warning(String::from(
+                                    "The head stage is not reading the input (press Ctrl+R to re-run it)",
+                                )))
+                                .await;
+                        }
+                        Some(false)
+                    }
+                    event if is_console_key(event) => Some(true),
+                    _ => None,
+                };
+
+                if let Some(close) = handled {
+                    let mut renderer = shared_renderer.lock().await;
+                    if close {
+                        // Dropping the console closes the stdin of the head stage.
+                        input_console = None;
+                        renderer.remove([PaneIndex::InputConsole]);
+                    } else {
+                        let (width, height) = crossterm::terminal::size()?;
+                        renderer.update([(
+                            PaneIndex::InputConsole,
+                            console.create_pane(width, height),
+                        )]);
+                    }
+                    let _ = renderer.render();
+                    continue;
+                }
+            }
+
+            match event {
+                // Consume the other kinds (e.g. the release) of the keys to quit and cancel,
+                // not to let them reach the prompt.
+                EventStream::Buffer(Buffer::Other(Event::Key(key), _))
+                    if key.kind != KeyEventKind::Press
+                        && (args.quit_key.matches(&key) || args.interrupt_key.matches(&key)) => {}
+                EventStream::Buffer(Buffer::Other(Event::Key(key), _))
+                    if key.kind == KeyEventKind::Press
+                        && args.interrupt_key.matches(&key)
+                        && args.interrupt_key == args.quit_key =>
+                {
+                    // The first press only cancels the running pipeline,
+                    // and the second one within the grace period quits.
+                    let running = cur_pipeline.as_ref().is_some_and(Pipeline::is_running);
+                    if !running {
+                        break 'outer;
+                    }
+                    // The pipeline has been restarted after cancelling it.
+                    if cancelled_at.take().is_some() {
+                        confirm_quit = true;
+                        let _ = notify_tx
+                            .send(NotifyMessage::Sticky(String::from(
+                                "pipeline still running — quit? y/n",
+                            )))
+                            .await;
+                        continue;
+                    }
+
+                    if let Some(mut pipeline) = cur_pipeline.take() {
+                        reply_outputs(&mut pending_outputs, &text_tx).await?;
+                        pipeline.abort_all();
+                        broadcast_reset_tx.send(())?;
+                        prompt.stop_stages().await;
+                    }
+                    let _ = notify_tx
+                        .send(NotifyMessage::Info(format!(
+                            "pipeline cancelled — press {} again to quit",
+                            args.quit_key
+                        )))
+                        .await;
+                    cancelled_at = Some(Instant::now());
+                }
+                EventStream::Buffer(Buffer::Other(Event::Key(key), _))
+                    if key.kind == KeyEventKind::Press && args.interrupt_key.matches(&key) =>
+                {
+                    let running = cur_pipeline.as_ref().is_some_and(Pipeline::is_running);
+                    let message = match cur_pipeline.take() {
+                        Some(mut pipeline) if running => {
+                            reply_outputs(&mut pending_outputs, &text_tx).await?;
+                            pipeline.abort_all();
+                            broadcast_reset_tx.send(())?;
+                            prompt.stop_stages().await;
+                            "pipeline cancelled"
+                        }
+                        pipeline => {
+                            cur_pipeline = pipeline;
+                            "no pipeline is running"
+                        }
+                    };
+                    let _ = notify_tx
+                        .send(NotifyMessage::Info(String::from(message)))
+                        .await;
+                }
+                EventStream::Buffer(Buffer::Other(Event::Key(key), _))
+                    if key.kind == KeyEventKind::Press && args.quit_key.matches(&key) =>
+                {
+                    if !cur_pipeline.as_ref().is_some_and(Pipeline::is_running) {
+                        break 'outer;
+                    }
+                    confirm_quit = true;
+                    let _ = notify_tx
+                        .send(NotifyMessage::Sticky(String::from(
+                            "pipeline still running — quit? y/n",
+                        )))
+                        .await;
+                }
+                event @ EventStream::Buffer(Buffer::Other(
+                    Event::Key(KeyEvent {
+                        code: KeyCode::Char('h'),
+                        modifiers: KeyModifiers::CONTROL,
+                        kind: KeyEventKind::Press,
+                        state: KeyEventState::NONE,
+                    }),
+                    _,
+                )) => {
+                    prompt_form = true;
+                    broadcast_event_tx.send(event)?;
+                }
+                event @ EventStream::Buffer(Buffer::Other(
+                    Event::Key(KeyEvent {
+                        code: KeyCode::Char(';'),
+                        modifiers: KeyModifiers::ALT,
+                        kind: KeyEventKind::Press,
+                        state: KeyEventState::NONE,
+                    }),
+                    _,
+                )) => {
+                    prompt_form = true;
+                    broadcast_event_tx.send(event)?;
+                }
+                event @ EventStream::Buffer(Buffer::Other(
+                    Event::Key(KeyEvent {
+                        code: KeyCode::Char('x' | 'X'),
+                        modifiers,
+                        kind: KeyEventKind::Press,
+                        state: KeyEventState::NONE,
+                    }),
+                    _,
+                )) if keys::is_ctrl_shift(modifiers) => {
+                    prompt_form = true;
+                    broadcast_event_tx.send(event)?;
+                }
+                event @ EventStream::Buffer(Buffer::Other(
+                    Event::Key(KeyEvent {
+                        code: KeyCode::Char('s' | 'S'),
+                        modifiers,
+                        kind: KeyEventKind::Press,
+                        state: KeyEventState::NONE,
+                    }),
+                    _,
+                )) if keys::is_ctrl_shift(modifiers) => {
+                    prompt_form = true;
+                    broadcast_event_tx.send(event)?;
+                }
+                // Edit all the stages in $EDITOR, one per line.
+                EventStream::Buffer(Buffer::Other(
+                    Event::Key(KeyEvent {
+                        code: KeyCode::Char('e' | 'E'),
+                        modifiers,
+                        kind: KeyEventKind::Press,
+                        state: KeyEventState::NONE,
+                    }),
+                    _,
+                )) if !prompt_form && modifiers == KeyModifiers::ALT | KeyModifiers::SHIFT => {
+                    let stages: Vec<session::Stage> = prompt
+                        .session_stages()
+                        .await
+                        .0
+                        .into_iter()
+                        .filter(|stage| !stage.text.trim().is_empty())
+                        .collect();
+                    let edited = edit_externally(
+                        &external_editor::format_stages(&stages),
+                        "sh",
+                        &mut event_operator,
+                        &shared_renderer,
+                        &mouse_capture,
+                        args.alternate_screen,
+                    )
+                    .await;
+                    // Redraw everything on the cleared screen, which may have been resized.
+                    let (width, height) = crossterm::terminal::size()?;
+                    broadcast_event_tx
+                        .send(EventStream::Debounce(Debounce::Resize(width, height)))?;
+                    match edited {
+                        Ok(Some(edited)) => {
+                            let edited = external_editor::parse_stages(&edited, &stages);
+                            if edited != stages {
+                                broadcast_event_tx.send(EventStream::LoadStages(edited, 0))?;
+                            }
+                        }
+                        Ok(None) => {
+                            let _ = notify_tx
+                                .send(NotifyMessage::Warning(String::from(
+                                    "The editor exited with failure, so the stages are kept unchanged",
+                                )))
+                                .await;
+                        }
+                        Err(e) => {
+                            let _ = notify_tx
+                                .send(NotifyMessage::Error(format!(
+                                    "Cannot edit the stages: {}",
+                                    e
+                                )))
+                                .await;
+                        }
+                    }
+                }
+                // Edit the focused stage in $EDITOR.
+                EventStream::Buffer(Buffer::Other(
+                    Event::Key(KeyEvent {
+                        code,
+                        modifiers,
+                        kind: KeyEventKind::Press,
+                        state: KeyEventState::NONE,
+                    }),
+                    _,
+                )) if !prompt_form
+                    && match code {
+                        KeyCode::Char('e' | 'E') => {
+                            modifiers == KeyModifiers::CONTROL | KeyModifiers::SHIFT
+                        }
+                        // Alt+Shift+E edits all the stages, so Alt+Shift+O opens the stage
+                        // on the terminals sending Ctrl+Shift+E as Ctrl+E.
+                        KeyCode::Char('o' | 'O') => {
+                            modifiers == KeyModifiers::ALT | KeyModifiers::SHIFT
+                        }
+                        _ => false,
+                    } =>
+                {
+                    let text = prompt.get_focused_input().await;
+                    let edited = edit_externally(
+                        &text,
+                        "sh",
+                        &mut event_operator,
+                        &shared_renderer,
+                        &mouse_capture,
+                        args.alternate_screen,
+                    )
+                    .await;
+                    // Redraw everything on the cleared screen, which may have been resized.
+                    let (width, height) = crossterm::terminal::size()?;
+                    broadcast_event_tx
+                        .send(EventStream::Debounce(Debounce::Resize(width, height)))?;
+                    match edited {
+                        Ok(Some(edited)) => {
+                            if edited != text {
+                                broadcast_event_tx.send(EventStream::ReplaceStage(edited))?;
+                            }
+                        }
+                        Ok(None) => {
+                            let _ = notify_tx
+                                .send(NotifyMessage::Warning(String::from(
+                                    "The editor exited with failure, so the stage is kept unchanged",
+                                )))
+                                .await;
+                        }
+                        Err(e) => {
+                            let _ = notify_tx
+                                .send(NotifyMessage::Error(format!(
+                                    "Cannot edit the stage: {}",
+                                    e
+                                )))
+                                .await;
+                        }
+                    }
+                }
+                // List the snippets to load one of them.
+                EventStream::Buffer(Buffer::Other(
+                    Event::Key(KeyEvent {
+                        code: KeyCode::Char('l' | 'L'),
+                        modifiers,
+                        kind: KeyEventKind::Press,
+                        state: KeyEventState::NONE,
+                    }),
+                    _,
+                )) if !prompt_form && keys::is_ctrl_shift(modifiers) => {
+                    let loaded = snippets::default_path()
+                        .ok_or_else(|| anyhow::anyhow!("the config directory is unknown"))
+                        .and_then(|path| snippets::load(&path));
+                    match loaded {
+                        Ok(snippets) if snippets.is_empty() => {
+                            let _ = notify_tx
+                                .send(NotifyMessage::Info(String::from(
+                                    "No snippets saved yet (Ctrl+Shift+S or Alt+Shift+S saves one)",
+                                )))
+                                .await;
+                        }
+                        Ok(snippets) => {
+                            let popup = envpopup::State::with_title(
+                                "snippets",
+                                ": ",
+                                snippets
+                                    .iter()
+                                    .map(|(name, stages)| (name.clone(), stages.join(" | "))),
+                            );
+                            let (width, height) = crossterm::terminal::size()?;
+                            let _ = shared_renderer
+                                .lock()
+                                .await
+                                .update([(PaneIndex::EnvPopup, popup.create_pane(width, height))])
+                                .render();
+                            list_popup = Some((ListPopup::Snippets(snippets), popup));
+                        }
                         Err(e) => {
                             let _ = notify_tx
                                 .send(NotifyMessage::Error(format!(
-                                    "Cannot spawn commands: {:?}",
+                                    "Cannot load the snippets: {}",
                                     e
                                 )))
                                 .await;
                         }
                     }
                 }
+                // Open the input console to type into the stdin of the head stage.
+                event if !prompt_form && is_console_key(&event) => {
+                    let console = console::State::new(Color::DarkCyan);
+                    let (width, height) = crossterm::terminal::size()?;
+                    let _ = shared_renderer
+                        .lock()
+                        .await
+                        .update([(PaneIndex::InputConsole, console.create_pane(width, height))])
+                        .render();
+                    input_console = Some(console);
+                    let _ = notify_tx
+                        .send(NotifyMessage::Info(String::from(
+                            "input console: the next run reads the lines typed here (press Ctrl+R to run)",
+                        )))
+                        .await;
+                }
+                // Stop the pipeline and clear its output, without quitting or re-running.
+                EventStream::Buffer(Buffer::Other(
+                    Event::Key(KeyEvent {
+                        code: KeyCode::Char('s'),
+                        modifiers: KeyModifiers::ALT,
+                        kind: KeyEventKind::Press,
+                        state: KeyEventState::NONE,
+                    }),
+                    _,
+                )) => {
+                    let message = match cur_pipeline.take() {
+                        Some(mut pipeline) => {
+                            reply_outputs(&mut pending_outputs, &text_tx).await?;
+                            pipeline.abort_all();
+                            broadcast_reset_tx.send(())?;
+                            prompt.stop_stages().await;
+                            "pipeline stopped"
+                        }
+                        None => "no pipeline to stop",
+                    };
+                    let _ = notify_tx
+                        .send(NotifyMessage::Info(String::from(message)))
+                        .await;
+                }
+                // Cycle which stage the output pane shows the output of, the last one by default.
+                EventStream::Buffer(Buffer::Other(
+                    Event::Key(KeyEvent {
+                        code: KeyCode::Char('v'),
+                        modifiers: KeyModifiers::ALT,
+                        kind: KeyEventKind::Press,
+                        state: KeyEventState::NONE,
+                    }),
+                    _,
+                )) if !prompt_form => {
+                    let Some(pipeline) = cur_pipeline.as_ref() else {
+                        let _ = notify_tx
+                            .send(NotifyMessage::Error(String::from("no pipeline to view")))
+                            .await;
+                        continue;
+                    };
+                    // Not counting the `SET` stages, which may be all of them.
+                    let stages = pipeline.stats().len();
+                    if stages == 0 {
+                        let _ = notify_tx
+                            .send(NotifyMessage::Error(String::from("no stage to view")))
+                            .await;
+                        continue;
+                    }
+                    let stage = (pipeline.shown_stage() + 1) % stages;
+                    let lines = pipeline.show_stage(stage);
+                    broadcast_reset_tx.send(())?;
+                    let mut message = format!("output after stage {}/{}", stage + 1, stages);
+                    match lines {
+                        // The lines streamed just at the switch may come before the replayed ones.
+                        Some((lines, evicted)) => {
+                            if evicted > 0 {
+                                message.push_str(&format!(
+                                    " (the first {} lines are not kept)",
+                                    format_count(evicted)
+                                ));
+                            }
+                            let output_tx = output_tx.clone();
+                            tokio::spawn(async move {
+                                for line in lines {
+                                    if output_tx.send((Origin::from(stage), line)).await.is_err() {
+                                        break;
+                                    }
+                                }
+                            });
+                        }
+                        None => message.push_str(" (the earlier lines are not kept)"),
+                    }
+                    let _ = notify_tx.send(NotifyMessage::Info(message)).await;
+                }
+                // Show how the focused stage is split into the arguments, without running it.
+                EventStream::Buffer(Buffer::Other(
+                    Event::Key(KeyEvent {
+                        code: KeyCode::Char('a' | 'A'),
+                        modifiers,
+                        kind: KeyEventKind::Press,
+                        state: KeyEventState::NONE,
+                    }),
+                    _,
+                )) if !prompt_form && modifiers == KeyModifiers::ALT | KeyModifiers::SHIFT => {
+                    let message = match prompt.get_focused_text().await {
+                        Some(cmd) => match pipeline::describe_argv(&cmd) {
+                            Ok(argv) => NotifyMessage::Info(argv),
+                            Err(e) => NotifyMessage::Error(e.to_string()),
+                        },
+                        None => {
+                            NotifyMessage::Error(String::from("the focused stage runs no command"))
+                        }
+                    };
+                    let _ = notify_tx.send(message).await;
+                }
+                // Toggle the solo mode, which feeds the output of the previous stage
+                // into the focused stage alone.
+                EventStream::Buffer(Buffer::Other(
+                    Event::Key(KeyEvent {
+                        code: KeyCode::Char('o'),
+                        modifiers: KeyModifiers::ALT,
+                        kind: KeyEventKind::Press,
+                        state: KeyEventState::NONE,
+                    }),
+                    _,
+                )) if !prompt_form => {
+                    if solo.take().is_some() {
+                        let _ = notify_tx
+                            .send(NotifyMessage::Info(String::from("solo: off")))
+                            .await;
+                        continue;
+                    }
+                    let message = match solo_commands(&prompt).await {
+                        Ok((_, stage)) => {
+                            let mut cmds = prompt.get_texts_until_focused().await;
+                            cmds.pop();
+                            match cur_pipeline
+                                .as_ref()
+                                .and_then(|pipeline| pipeline.output_of(&cmds))
+                            {
+                                Some((lines, evicted)) if !lines.is_empty() => {
+                                    let input: Arc<[String]> = lines.into();
+                                    let message = solo_message(stage, input.len(), evicted);
+                                    solo = Some((input, evicted));
+                                    NotifyMessage::Info(message)
+                                }
+                                _ => NotifyMessage::Error(format!(
+                                    "solo: no output of stage {} kept from the previous run",
+                                    stage
+                                )),
+                            }
+                        }
+                        Err(e) => NotifyMessage::Error(e.to_string()),
+                    };
+                    let _ = notify_tx.send(message).await;
+                }
+                // Freeze the output to feed it into the head stage, or restore the head stage.
+                EventStream::Buffer(Buffer::Other(
+                    Event::Key(KeyEvent {
+                        code: KeyCode::Char('i' | 'I'),
+                        modifiers,
+                        kind: KeyEventKind::Press,
+                        state: KeyEventState::NONE,
+                    }),
+                    _,
+                )) if !prompt_form && keys::is_ctrl_shift(modifiers) => {
+                    if let Some((head, path)) = frozen_head.take() {
+                        prompt.replace_head(&head).await;
+                        let _ = std::fs::remove_file(&path);
+                        let _ = notify_tx
+                            .send(NotifyMessage::Info(String::from("Input unfrozen")))
+                            .await;
+                        continue;
+                    }
+                    let frozen = async {
+                        let text = output_text(&text_tx).await?;
+                        let (path, mut file) = temp_file::create("epiq_output", "txt")?;
+                        let written = file
+                            .write_all(text.as_bytes())
+                            .map_err(anyhow::Error::from)
+                            .and_then(|()| {
+                                Ok(shlex::try_quote(&path.to_string_lossy())?.into_owned())
+                            });
+                        match written {
+                            Ok(quoted) => Ok((path, quoted)),
+                            Err(e) => {
+                                let _ = std::fs::remove_file(&path);
+                                Err(e)
+                            }
+                        }
+                    };
+                    let message = match frozen.await {
+                        Ok((path, quoted)) => {
+                            let message =
+                                NotifyMessage::Info(format!("Input frozen to {}", path.display()));
+                            let head = prompt.replace_head(&format!("cat {}", quoted)).await;
+                            frozen_head = Some((head, path));
+                            message
+                        }
+                        Err(e) => NotifyMessage::Error(format!("Cannot freeze the output: {}", e)),
+                    };
+                    let _ = notify_tx.send(message).await;
+                }
+                event @ EventStream::Buffer(Buffer::Other(
+                    Event::Key(KeyEvent {
+                        code: KeyCode::Enter | KeyCode::Esc,
+                        modifiers: KeyModifiers::NONE,
+                        kind: KeyEventKind::Press,
+                        state: KeyEventState::NONE,
+                    }),
+                    _,
+                )) if prompt_form => {
+                    prompt_form = false;
+                    broadcast_event_tx.send(event)?;
+                }
+                // There is no way to capture ONLY mouse scroll events,
+                // so, toggle enabling and disabling of capturing all mouse events with Esc.
+                // https://github.com/crossterm-rs/crossterm/issues/640
+                EventStream::Buffer(Buffer::Other(
+                    Event::Key(KeyEvent {
+                        code: KeyCode::Esc,
+                        modifiers: KeyModifiers::NONE,
+                        kind: KeyEventKind::Press,
+                        state: KeyEventState::NONE,
+                    }),
+                    times,
+                )) => {
+                    // Esc cancels fetching the documentation, or dismisses the popup
+                    // first if it is shown.
+                    if let Some((_, fetching)) = pending_doc.take() {
+                        fetching.abort();
+                        let _ = notify_tx.send(NotifyMessage::None).await;
+                    } else if doc_popup.take().is_some() {
+                        let _ = shared_renderer
+                            .lock()
+                            .await
+                            .remove([PaneIndex::DocPopup])
+                            .render();
+                    } else if times % 2 != 0 && !args.no_mouse {
+                        mouse_capture.toggle(&mut std::io::stdout())?;
+                    }
+                }
+                EventStream::Buffer(Buffer::Other(
+                    Event::Key(KeyEvent {
+                        code: KeyCode::Char('h'),
+                        modifiers: KeyModifiers::ALT,
+                        kind: KeyEventKind::Press,
+                        state: KeyEventState::NONE,
+                    }),
+                    _,
+                )) => {
+                    let Some(cmd) = prompt.get_command_under_cursor().await else {
+                        let _ = notify_tx
+                            .send(NotifyMessage::Error(String::from(
+                                "Place the cursor on a command name to show its documentation",
+                            )))
+                            .await;
+                        continue;
+                    };
+                    // Shown when fetched, not to block the inputs and the output meanwhile.
+                    if let Some((_, fetching)) = pending_doc.take() {
+                        fetching.abort();
+                    }
+                    let _ = notify_tx
+                        .send(NotifyMessage::Info(format!(
+                            "Fetching the documentation for {} (Esc to cancel)",
+                            cmd
+                        )))
+                        .await;
+                    let fetching = docpopup::fetch_doc(&cmd);
+                    pending_doc = Some((cmd, fetching));
+                }
+                EventStream::Buffer(Buffer::Other(
+                    Event::Key(KeyEvent {
+                        code: KeyCode::Char('v'),
+                        modifiers: KeyModifiers::CONTROL,
+                        kind: KeyEventKind::Press,
+                        state: KeyEventState::NONE,
+                    }),
+                    _,
+                )) if !prompt_form => {
+                    let text = match clipboard::paste().await {
+                        Ok(text) => text,
+                        Err(e) => {
+                            let _ = notify_tx
+                                .send(NotifyMessage::Error(format!(
+                                    "Cannot read the clipboard: {}",
+                                    e
+                                )))
+                                .await;
+                            continue;
+                        }
+                    };
+                    let cmds: Vec<String> = pipeline::split_pipeline(&text)
+                        .into_iter()
+                        .filter(|cmd| !cmd.is_empty())
+                        .collect();
+                    if cmds.is_empty() {
+                        let _ = notify_tx
+                            .send(NotifyMessage::Info(String::from("The clipboard is empty")))
+                            .await;
+                    } else if prompt.is_empty().await {
+                        load_commands(
+                            cmds,
+                            "the clipboard",
+                            false,
+                            &prompt,
+                            &broadcast_event_tx,
+                            &notify_tx,
+                        )
+                        .await?;
+                    } else {
+                        let _ = notify_tx
+                            .send(NotifyMessage::Sticky(format!(
+                                "replace all stages with {} pasted stage(s)? y/n (a: append)",
+                                cmds.len()
+                            )))
+                            .await;
+                        confirm_load = Some((cmds, String::from("the clipboard")));
+                    }
+                }
+                // Clear all the stages back to a single empty head editor.
+                EventStream::Buffer(Buffer::Other(
+                    Event::Key(KeyEvent {
+                        code: KeyCode::Char('c' | 'C'),
+                        modifiers,
+                        kind: KeyEventKind::Press,
+                        state: KeyEventState::NONE,
+                    }),
+                    _,
+                )) if !prompt_form && modifiers == KeyModifiers::ALT | KeyModifiers::SHIFT => {
+                    if prompt.is_empty().await {
+                        broadcast_event_tx.send(EventStream::Load(vec![]))?;
+                    } else {
+                        let _ = notify_tx
+                            .send(NotifyMessage::Sticky(String::from("clear all stages? y/n")))
+                            .await;
+                        confirm_load = Some((vec![], String::new()));
+                    }
+                }
+                EventStream::Buffer(Buffer::Other(
+                    Event::Key(KeyEvent {
+                        code: KeyCode::Char('g'),
+                        modifiers: KeyModifiers::CONTROL,
+                        kind: KeyEventKind::Press,
+                        state: KeyEventState::NONE,
+                    }),
+                    _,
+                )) => {
+                    goto = Some((GotoTarget::Line, String::new()));
+                    let _ = notify_tx
+                        .send(NotifyMessage::Sticky(String::from("Go to line: ")))
+                        .await;
+                }
+                EventStream::Buffer(Buffer::Other(
+                    Event::Key(KeyEvent {
+                        code: KeyCode::Char('g'),
+                        modifiers: KeyModifiers::ALT,
+                        kind: KeyEventKind::Press,
+                        state: KeyEventState::NONE,
+                    }),
+                    _,
+                )) if !prompt_form => {
+                    goto = Some((GotoTarget::Stage, String::new()));
+                    let _ = notify_tx
+                        .send(NotifyMessage::Sticky(String::from("Go to stage: ")))
+                        .await;
+                }
+                event if macro_key(&event) == Some('r') => match recording.take() {
+                    Some((_, recorded)) => {
+                        let replay_key = if KEYBOARD_ENHANCEMENT.get() == Some(&true) {
+                            "Ctrl+Shift+P"
+                        } else {
+                            "Alt+Shift+P"
+                        };
+                        let _ = notify_tx
+                            .send(NotifyMessage::Info(format!(
+                                "recorded {} events — press {} to replay",
+                                recorded.len(),
+                                replay_key
+                            )))
+                            .await;
+                        macro_buffer = recorded;
+                    }
+                    None => {
+                        let _ = notify_tx
+                            .send(NotifyMessage::Sticky(String::from("[REC 0]")))
+                            .await;
+                        recording = Some((Instant::now(), vec![]));
+                    }
+                },
+                event if macro_key(&event) == Some('p') => {
+                    if recording.is_some() || replay.as_ref().is_some_and(|r| !r.is_finished()) {
+                        continue;
+                    }
+                    // Feed the events into this loop again with the original timing.
+                    let replay_tx = replay_tx.clone();
+                    let recorded = macro_buffer.clone();
+                    replay = Some(tokio::spawn(async move {
+                        let started_at = tokio::time::Instant::now();
+                        for (elapsed, event) in recorded {
+                            tokio::time::sleep_until(started_at + elapsed).await;
+                            if replay_tx.send(vec![event]).await.is_err() {
+                                break;
+                            }
+                        }
+                    }));
+                }
+                // Grow or shrink the output pane.
+                EventStream::Buffer(Buffer::Other(
+                    Event::Key(KeyEvent {
+                        code: code @ KeyCode::Char('=' | '+' | '-'),
+                        modifiers: KeyModifiers::ALT,
+                        kind: KeyEventKind::Press,
+                        state: KeyEventState::NONE,
+                    }),
+                    times,
+                )) => {
+                    // Keep the notify pane and a row for each editor visible.
+                    let editors = prompt.editor_count().await as u16;
+                    let min_rows = MIN_OUTPUT_ROWS.max(args.min_output_height);
+                    let max_rows = crossterm::terminal::size()?
+                        .1
+                        .saturating_sub(1 + editors)
+                        .max(min_rows);
+                    let rows = output_height_tx.borrow().unwrap_or(max_rows).min(max_rows);
+                    let step = (times as u16).saturating_mul(OUTPUT_HEIGHT_STEP);
+                    let rows = if code == KeyCode::Char('-') {
+                        Some(rows.saturating_sub(step).max(min_rows))
+                    } else {
+                        Some(rows.saturating_add(step)).filter(|rows| *rows < max_rows)
+                    };
+                    output_height_tx.send_replace(rows);
+                    let _ = notify_tx
+                        .send(NotifyMessage::Info(match rows {
+                            Some(rows) => format!("output height: {} rows", rows),
+                            None => String::from("output height: fill"),
+                        }))
+                        .await;
+                }
+                EventStream::Buffer(Buffer::Other(
+                    Event::Key(KeyEvent {
+                        code: KeyCode::Char('e'),
+                        modifiers: KeyModifiers::ALT,
+                        kind: KeyEventKind::Press,
+                        state: KeyEventState::NONE,
+                    }),
+                    _,
+                )) => {
+                    let popup = envpopup::State::new(std::env::vars());
+                    let (width, height) = crossterm::terminal::size()?;
+                    let _ = shared_renderer
+                        .lock()
+                        .await
+                        .update([(PaneIndex::EnvPopup, popup.create_pane(width, height))])
+                        .render();
+                    list_popup = Some((ListPopup::Env, popup));
+                }
+                // Scroll the documentation popup instead of the output while it is shown.
+                EventStream::Buffer(Buffer::VerticalScroll(up, down)) if doc_popup.is_some() => {
+                    if let Some(popup) = doc_popup.as_mut()
+                        && popup.shift(up, down)
+                    {
+                        let (width, height) = crossterm::terminal::size()?;
+                        let _ = shared_renderer
+                            .lock()
+                            .await
+                            .update([(PaneIndex::DocPopup, popup.create_pane(width, height))])
+                            .render();
+                    }
+                }
+                EventStream::Buffer(Buffer::Other(
+                    Event::Key(KeyEvent {
+                        code: KeyCode::Enter,
+                        modifiers: KeyModifiers::NONE,
+                        kind: KeyEventKind::Press,
+                        state: KeyEventState::NONE,
+                    }),
+                    _,
+                )) => {
+                    if let Some((input, evicted)) = &solo {
+                        let (stages, stage) = match solo_commands(&prompt).await {
+                            Ok(solo) => solo,
+                            Err(e) => {
+                                let _ = notify_tx.send(NotifyMessage::Error(e.to_string())).await;
+                                continue;
+                            }
+                        };
+                        let (stdin_tx, stdin_rx) = mpsc::channel(100);
+                        let lines = input.clone();
+                        tokio::spawn(async move {
+                            for line in lines.iter() {
+                                // The stage has exited or been aborted.
+                                if stdin_tx.send(line.clone()).await.is_err() {
+                                    break;
+                                }
+                            }
+                        });
+                        let spawned = rerun_pipeline(
+                            &mut cur_pipeline,
+                            &run_ctx,
+                            stages,
+                            stage,
+                            false,
+                            Some(stdin_rx),
+                        )
+                        .await?;
+                        if spawned.is_ok() {
+                            let _ = notify_tx
+                                .send(NotifyMessage::Info(solo_message(
+                                    stage,
+                                    input.len(),
+                                    *evicted,
+                                )))
+                                .await;
+                        }
+                        continue;
+                    }
+                    let _ = rerun_pipeline(
+                        &mut cur_pipeline,
+                        &run_ctx,
+                        prompt.get_all_stages().await,
+                        0,
+                        true,
+                        input_console.as_mut().map(console::State::connect),
+                    )
+                    .await?;
+                }
+                // Run all the stages again, without replaying the cached output.
+                EventStream::Buffer(Buffer::Other(
+                    Event::Key(KeyEvent {
+                        code: KeyCode::Char('r'),
+                        modifiers: KeyModifiers::CONTROL,
+                        kind: KeyEventKind::Press,
+                        state: KeyEventState::NONE,
+                    }),
+                    _,
+                )) => {
+                    let _ = rerun_pipeline(
+                        &mut cur_pipeline,
+                        &run_ctx,
+                        prompt.get_all_stages().await,
+                        0,
+                        false,
+                        input_console.as_mut().map(console::State::connect),
+                    )
+                    .await?;
+                }
+                // Run only the stages up to the focused one, to see the output in the middle.
+                EventStream::Buffer(Buffer::Other(
+                    Event::Key(KeyEvent {
+                        code: KeyCode::Enter,
+                        modifiers: KeyModifiers::ALT,
+                        kind: KeyEventKind::Press,
+                        state: KeyEventState::NONE,
+                    }),
+                    _,
+                )) if !prompt_form => {
+                    let stages = prompt.get_stages_until_focused().await;
+                    let (run, total) = (stages.len(), prompt.get_all_texts().await.len());
+                    let spawned = rerun_pipeline(
+                        &mut cur_pipeline,
+                        &run_ctx,
+                        stages,
+                        0,
+                        true,
+                        input_console.as_mut().map(console::State::connect),
+                    )
+                    .await?;
+                    if spawned.is_ok() {
+                        let _ = notify_tx
+                            .send(NotifyMessage::Info(format!(
+                                "partial run: {} of {} stages",
+                                run, total
+                            )))
+                            .await;
+                    }
+                }
+                EventStream::Buffer(Buffer::Other(
+                    Event::Key(KeyEvent {
+                        code: KeyCode::Char('v' | 'V'),
+                        modifiers,
+                        kind: KeyEventKind::Press,
+                        state: KeyEventState::NONE,
+                    }),
+                    _,
+                )) if keys::is_ctrl_shift(modifiers) => {
+                    notify_validation(&prompt.get_all_stages().await, &notify_tx).await;
+                }
+                event @ EventStream::Debounce(Debounce::Resize(width, height)) => {
+                    // Some terminals reset the mouse capture on resizing.
+                    mouse_capture.apply(&mut std::io::stdout())?;
+                    // Redraw from the top since the contents of the alternate screen
+                    // are not reflowed on resizing.
+                    if args.alternate_screen {
+                        shared_renderer.lock().await.clear()?;
+                    } else {
+                        shared_renderer.lock().await.resize(width, height);
+                    }
+                    broadcast_event_tx.send(event)?;
+                }
                 event => {
                     broadcast_event_tx.send(event)?;
                 }
@@ -208,30 +2494,439 @@ async fn main() -> anyhow::Result<()> {
         }
     }
 
+    // Save the session before stopping the output stream which holds the output.
+    let saved = match &args.session {
+        Some(path) => {
+            save_session(
+                path,
+                !args.no_session_output,
+                *output_height_tx.borrow(),
+                &prompt,
+                &text_tx,
+                &mut saved_session,
+            )
+            .await
+        }
+        None => Ok(()),
+    };
     event_operator.background.abort();
+    if let Some(replay) = replay {
+        replay.abort();
+    }
+    if let Some(watcher) = watcher {
+        watcher.abort();
+    }
+    if let Some((_, fetching)) = pending_doc {
+        fetching.abort();
+    }
+    drop(ipc_listener);
     if let Some(mut pipeline) = cur_pipeline {
         pipeline.abort_all();
     }
+    if let Some((_, path)) = frozen_head {
+        let _ = std::fs::remove_file(path);
+    }
     prompt.background.abort();
     output_stream.abort();
     notify_stream.abort();
+    status_stream.abort();
+    saved
+}
 
-    crossterm::terminal::disable_raw_mode()?;
-    crossterm::execute!(
-        std::io::stdout(),
-        crossterm::cursor::Show,
-        crossterm::event::DisableMouseCapture,
-    )?;
+/// Saves the stages, the focused one, the output with the bookmarks (if `with_output`)
+/// and the height of the output pane to the session file, unless they are the same
+/// as `saved`, the last saved ones.
+async fn save_session(
+    path: &std::path::Path,
+    with_output: bool,
+    output_height: Option<u16>,
+    prompt: &Prompt,
+    text_tx: &mpsc::Sender<OutputRequest>,
+    saved: &mut Option<session::Session>,
+) -> anyhow::Result<()> {
+    let (stages, focus) = prompt.session_stages().await;
+    let (output, bookmarks) = if with_output {
+        let (text, bookmarks) = output_with_bookmarks(text_tx).await?;
+        (text.lines().map(String::from).collect(), bookmarks)
+    } else {
+        (vec![], vec![])
+    };
+    let session = session::Session {
+        stages,
+        focus,
+        output,
+        bookmarks,
+        output_height,
+    };
+    if saved.as_ref() == Some(&session) {
+        return Ok(());
+    }
+    session::save(path, &session)?;
+    *saved = Some(session);
     Ok(())
 }
 
+/// What the list popup (Alt+E or Ctrl+Shift+L) lists.
+enum ListPopup {
+    Env,
+    Snippets(snippets::Snippets),
+}
+
+/// What the number typed after Ctrl+G (line) or Alt+G (stage) jumps to.
+#[derive(Clone, Copy)]
+enum GotoTarget {
+    Line,
+    Stage,
+}
+
+impl GotoTarget {
+    fn name(self) -> &'static str {
+        match self {
+            GotoTarget::Line => "line",
+            GotoTarget::Stage => "stage",
+        }
+    }
+
+    fn event(self, n: usize) -> EventStream {
+        match self {
+            GotoTarget::Line => EventStream::JumpToLine(n),
+            GotoTarget::Stage => EventStream::FocusStage(n),
+        }
+    }
+}
+
+/// Returns whether the event is Ctrl+I, which most terminals send as Tab.
+fn is_console_key(event: &EventStream) -> bool {
+    matches!(
+        event,
+        EventStream::Buffer(Buffer::Other(
+            Event::Key(KeyEvent {
+                code: KeyCode::Tab,
+                modifiers: KeyModifiers::NONE,
+                kind: KeyEventKind::Press,
+                ..
+            }),
+            _,
+        )) | EventStream::Buffer(Buffer::Other(
+            Event::Key(KeyEvent {
+                code: KeyCode::Char('i'),
+                modifiers: KeyModifiers::CONTROL,
+                kind: KeyEventKind::Press,
+                ..
+            }),
+            _,
+        ))
+    )
+}
+
+/// Returns 'r' for Ctrl+Shift+R (record) and 'p' for Ctrl+Shift+P (replay),
+/// or for Alt+Shift+R and Alt+Shift+P.
+fn macro_key(event: &EventStream) -> Option<char> {
+    match event {
+        EventStream::Buffer(Buffer::Other(
+            Event::Key(KeyEvent {
+                code: KeyCode::Char(ch @ ('r' | 'R' | 'p' | 'P')),
+                modifiers,
+                kind: KeyEventKind::Press,
+                state: KeyEventState::NONE,
+            }),
+            _,
+        )) if keys::is_ctrl_shift(*modifiers) => Some(ch.to_ascii_lowercase()),
+        _ => None,
+    }
+}
+
+/// What the pipelines are spawned with, the same on every run.
+struct RunContext<'a> {
+    prompt: &'a Prompt,
+    output_tx: &'a mpsc::Sender<(Origin, String)>,
+    pipeline_event_tx: &'a mpsc::Sender<(Instant, PipelineEvent)>,
+    broadcast_reset_tx: &'a broadcast::Sender<()>,
+    notify_tx: &'a mpsc::Sender<NotifyMessage>,
+    max_lines_per_stage: usize,
+    cache_stages: bool,
+    cache_head: bool,
+    validate: bool,
+}
+
+/// Aborts the current pipeline if it is running, and spawns the new one of `cmds`,
+/// which starts from the `first_stage` (not counting the `SET` stages) in the solo mode.
+/// If `reuse` is set, the stages are replayed as `Pipeline::reusable_stages` tells,
+/// i.e. with `cache_stages`, the leading stages unchanged since the current one has completed
+/// are not re-run, and with `cache_head`, the unchanged head is replayed.
+/// Given `head_stdin`, the lines from it are written into the stdin of the head stage.
+/// The `stages` are the commands with the positions of their editors, by which
+/// the stages are numbered in the reports.
+/// Nothing is spawned (nor aborted) if any of the commands is invalid, and all the problems
+/// are reported at once instead, or the result of each stage with `--validate`.
+/// Returns the reported problem if the new pipeline has not been spawned.
+async fn rerun_pipeline(
+    cur_pipeline: &mut Option<Pipeline>,
+    ctx: &RunContext<'_>,
+    stages: Vec<(usize, String)>,
+    first_stage: usize,
+    reuse: bool,
+    head_stdin: Option<mpsc::Receiver<String>>,
+) -> anyhow::Result<Result<(), String>> {
+    let RunContext {
+        prompt,
+        output_tx,
+        pipeline_event_tx,
+        broadcast_reset_tx,
+        notify_tx,
+        max_lines_per_stage,
+        cache_stages,
+        cache_head,
+        validate,
+    } = *ctx;
+    let errors = validate::check_commands(&stages);
+    if !errors.is_empty() {
+        let report = validate::report(&errors);
+        let message = match validate {
+            true => validate::summarize(&stages, &errors),
+            false => report.clone(),
+        };
+        let _ = notify_tx.send(NotifyMessage::Error(message)).await;
+        return Ok(Err(report));
+    }
+    let cmds: Vec<String> = stages.into_iter().map(|(_, cmd)| cmd).collect();
+
+    let preview = prompt
+        .preview_stage()
+        .await
+        .and_then(|preview| preview.checked_sub(first_stage));
+    // First of all, abort the current command if it is running.
+    if let Some(pipeline) = cur_pipeline {
+        pipeline.abort_all();
+        broadcast_reset_tx.send(())?;
+        let _ = notify_tx.send(NotifyMessage::None).await;
+    }
+
+    let reuse = cur_pipeline
+        .as_ref()
+        .filter(|_| reuse)
+        .map(|prev| (prev, prev.reusable_stages(&cmds, cache_stages, cache_head)));
+    match Pipeline::spawn(
+        cmds,
+        output_tx.clone(),
+        pipeline_event_tx.clone(),
+        max_lines_per_stage,
+        reuse,
+        preview,
+        head_stdin,
+    ) {
+        Ok(pipeline) => {
+            // Not counting the `SET` stages.
+            let cmds_len = pipeline.stats().len();
+            if pipeline.reused_stages() > 0 {
+                let _ = notify_tx
+                    .send(NotifyMessage::Info(format!(
+                        "Replayed the output through stage {}",
+                        pipeline.reused_stages()
+                    )))
+                    .await;
+            }
+            if let Some(preview) = preview.filter(|preview| preview + 1 < cmds_len) {
+                let _ = notify_tx
+                    .send(NotifyMessage::Info(format!(
+                        "preview: showing the output of stage {} of {}",
+                        preview + 1,
+                        cmds_len
+                    )))
+                    .await;
+            }
+            prompt
+                .start_stages(
+                    first_stage..first_stage + cmds_len,
+                    pipeline.reused_stages(),
+                    SPINNER_FRAMES[0],
+                )
+                .await;
+            *cur_pipeline = Some(pipeline);
+            Ok(Ok(()))
+        }
+        Err(e) => {
+            let message = format!("Cannot spawn commands: {:?}", e);
+            let _ = notify_tx.send(NotifyMessage::Error(message.clone())).await;
+            Ok(Err(message))
+        }
+    }
+}
+
+/// Replies to the `get_output` commands of the socket with the text of the output.
+async fn reply_outputs(
+    pending: &mut Vec<ipc::Reply>,
+    text_tx: &mpsc::Sender<OutputRequest>,
+) -> anyhow::Result<()> {
+    if pending.is_empty() {
+        return Ok(());
+    }
+    let text = output_text(text_tx).await?;
+    for reply_tx in pending.drain(..) {
+        let _ = reply_tx.send(Ok(Some(text.clone())));
+    }
+    Ok(())
+}
+
+/// Returns the text of the output from the output stream.
+async fn output_text(text_tx: &mpsc::Sender<OutputRequest>) -> anyhow::Result<String> {
+    Ok(output_with_bookmarks(text_tx).await?.0)
+}
+
+/// Returns the text of the output with the bookmarked lines in it from the output stream.
+async fn output_with_bookmarks(
+    text_tx: &mpsc::Sender<OutputRequest>,
+) -> anyhow::Result<(String, Vec<usize>)> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    text_tx.send(reply_tx).await?;
+    Ok(reply_rx.await?)
+}
+
+/// Returns the stages to run the focused one alone in the solo mode,
+/// preceded by the `SET` stages before it, with the position of the stage
+/// in the pipeline (not counting the `SET` stages).
+async fn solo_commands(prompt: &Prompt) -> Result<(Vec<(usize, String)>, usize), &'static str> {
+    if prompt.get_focused_text().await.is_none() {
+        return Err("solo: the focused stage runs no command");
+    }
+    let mut stages = prompt.get_stages_until_focused().await;
+    let focused = match stages.pop() {
+        Some(focused) if !pipeline::is_set_command(&focused.1) => focused,
+        _ => return Err("solo: the focused stage runs no command"),
+    };
+    let stage = stages
+        .iter()
+        .filter(|(_, cmd)| !pipeline::is_set_command(cmd))
+        .count();
+    if stage == 0 {
+        return Err("solo: the head stage takes no input");
+    }
+    stages.retain(|(_, cmd)| pipeline::is_set_command(cmd));
+    stages.push(focused);
+    Ok((stages, stage))
+}
+
+/// Formats the notification of the solo mode, e.g. "solo: stage 3 (input: stage 2, 5,321 lines)",
+/// telling how many earlier lines of the input are not kept if any.
+fn solo_message(stage: usize, lines: usize, evicted: usize) -> String {
+    let mut message = format!(
+        "solo: stage {} (input: stage {}, {} lines",
+        stage + 1,
+        stage,
+        format_count(lines)
+    );
+    if evicted > 0 {
+        message.push_str(&format!(
+            ", truncated: the first {} lines are not kept",
+            format_count(evicted)
+        ));
+    }
+    message.push(')');
+    message
+}
+
+/// Formats the number with the thousands separators, e.g. "5,321".
+fn format_count(n: usize) -> String {
+    let digits = n.to_string();
+    let mut formatted = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            formatted.push(',');
+        }
+        formatted.push(digit);
+    }
+    formatted
+}
+
+/// Formats the (lines, bytes) of the stages as "#1 120L/4.0 KB #2 12L/310 B".
+fn format_throughput(stats: &[(usize, usize)]) -> String {
+    stats
+        .iter()
+        .enumerate()
+        .map(|(i, (lines, bytes))| format!("#{} {}L/{}", i + 1, lines, format_bytes(*bytes)))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Formats the size as "310 B", "1.2 KB" or "4.5 MB" (in units of 1024).
+fn format_bytes(bytes: usize) -> String {
+    const UNITS: [&str; 3] = ["KB", "MB", "GB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Validates the commands, and shows the result of each stage.
+async fn notify_validation(stages: &[(usize, String)], notify_tx: &mpsc::Sender<NotifyMessage>) {
+    let message = if stages.is_empty() {
+        NotifyMessage::Error(String::from("No commands to validate"))
+    } else {
+        let errors = validate::check_commands(stages);
+        let summary = validate::summarize(stages, &errors);
+        if errors.is_empty() {
+            NotifyMessage::Info(summary)
+        } else {
+            NotifyMessage::Error(summary)
+        }
+    };
+    let _ = notify_tx.send(message).await;
+}
+
+async fn status_stream(
+    mut state: status::State,
+    mut stream: mpsc::Receiver<StatusMessage>,
+    shared_renderer: SharedRenderer,
+) {
+    while let Some(message) = stream.recv().await {
+        state.apply(message);
+
+        let mut renderer = shared_renderer.lock().await;
+        if let Ok((width, height)) = crossterm::terminal::size() {
+            let _ = renderer
+                .update([(PaneIndex::Status, state.create_pane(width, height))])
+                .render();
+        }
+    }
+}
+
 async fn notify_stream(
     mut text: text::State,
     mut stream: mpsc::Receiver<NotifyMessage>,
+    theme: NotifyTheme,
+    timeout: Option<Duration>,
     shared_renderer: SharedRenderer,
 ) {
-    while let Some(message) = stream.recv().await {
-        text.replace(message.into());
+    // Clears the message shown at the deadline unless another one replaces it before.
+    let clear = tokio::time::sleep(Duration::ZERO);
+    tokio::pin!(clear);
+    let mut clearing = false;
+
+    loop {
+        let message = tokio::select! {
+            maybe_message = stream.recv() => match maybe_message {
+                Some(message) => message,
+                None => break,
+            },
+            _ = &mut clear, if clearing => NotifyMessage::None,
+        };
+        if let NotifyMessage::Warning(message) | NotifyMessage::Error(message) = &message {
+            debug_log::log(|| format!("notified: {}", message));
+        }
+        clearing = match timeout {
+            Some(timeout) if message.expires() => {
+                clear.as_mut().reset(tokio::time::Instant::now() + timeout);
+                true
+            }
+            _ => false,
+        };
+        text.replace(message.into_state(&theme));
 
         let mut renderer = shared_renderer.lock().await;
         if let Ok((width, height)) = crossterm::terminal::size() {
@@ -242,20 +2937,57 @@ async fn notify_stream(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn output_stream(
     mut queue: queue::State,
-    mut stdout_stream: mpsc::Receiver<String>,
+    mut stdout_stream: mpsc::Receiver<(Origin, String)>,
+    mut text_rx: mpsc::Receiver<OutputRequest>,
     mut event_stream: broadcast::Receiver<EventStream>,
     mut reset: broadcast::Receiver<()>,
+    mut output_height: tokio::sync::watch::Receiver<Option<u16>>,
+    last_output: tokio::sync::watch::Sender<Instant>,
+    notify_tx: mpsc::Sender<NotifyMessage>,
+    status_tx: mpsc::Sender<StatusMessage>,
     shared_renderer: SharedRenderer,
     render_interval: Duration,
 ) {
-    let mut delay = tokio::time::interval(render_interval);
+    let max_render_interval = MAX_RENDER_INTERVAL.max(render_interval);
+    let mut cur_render_interval = render_interval;
+    let mut delay = tokio::time::interval(cur_render_interval);
     let mut last_modified_time = Local::now();
     let mut last_render_time = Local::now();
+    // The number of lines received since the last tick.
+    let mut received = 0;
+    // The ((top line, total lines), total bytes) last shown in the status bar.
+    let mut shown_lines = ((0, 0), 0);
+    // Whether to show the counts of the output as `wc` does, with the ones last shown.
+    let mut counts_enabled = false;
+    let mut shown_counts = None;
+
+    // Show the output restored from the last session.
+    if queue.lines().1 > 0
+        && let Ok((width, height)) = crossterm::terminal::size()
+    {
+        let height = output_height
+            .borrow()
+            .map_or(height, |rows| rows.min(height));
+        let _ = shared_renderer
+            .lock()
+            .await
+            .update([(PaneIndex::Output, queue.create_pane(width, height))])
+            .render();
+    }
 
     loop {
         tokio::select! {
+            Some(reply) = text_rx.recv() => {
+                // Take the lines already sent first, e.g. the last ones of the completed pipeline.
+                while let Ok((origin, line)) = stdout_stream.try_recv() {
+                    queue.push(origin, StyledGraphemes::from(line));
+                    last_modified_time = Local::now();
+                }
+                let _ = reply.send((queue.text(), queue.bookmarked_lines()));
+            },
             _ = reset.recv() => {
                 queue.reset();
                 last_modified_time = Local::now();
@@ -266,27 +2998,236 @@ async fn output_stream(
                 ]).render();
             },
             _ = delay.tick() => {
+                let Ok((width, height)) = crossterm::terminal::size() else {
+                    continue;
+                };
+                let height = output_height.borrow().map_or(height, |rows| rows.min(height));
+
                 if last_modified_time > last_render_time {
-                    if let Ok((width, height)) = crossterm::terminal::size() {
-                        let _ = shared_renderer.lock().await.update([
-                            (PaneIndex::Output, queue.create_pane(width, height)),
-                        ]).render();
+                    let _ = shared_renderer.lock().await.update([
+                        (PaneIndex::Output, queue.create_pane(width, height)),
+                    ]).render();
 
-                        last_render_time = Local::now();
-                    }
+                    last_render_time = Local::now();
+                }
+
+                let lines = (queue.lines(), queue.total_bytes());
+                // Skip the update if the status bar is busy, the next tick will retry.
+                if lines != shown_lines
+                    && status_tx
+                        .try_send(match lines {
+                            ((_, 0), _) => StatusMessage::Clear(StatusItem::OutputLines),
+                            ((top, total), bytes) => StatusMessage::Set(
+                                StatusItem::OutputLines,
+                                format!("[{}/{}] {}", top, total, format_bytes(bytes)),
+                            ),
+                        })
+                        .is_ok()
+                {
+                    shown_lines = lines;
+                }
+                let counts = counts_enabled.then(|| queue.counts());
+                if counts != shown_counts
+                    && status_tx
+                        .try_send(match counts {
+                            Some(counts) => StatusMessage::Set(
+                                StatusItem::OutputCounts,
+                                format!(
+                                    "{} lines {} words {}",
+                                    format_count(counts.lines),
+                                    format_count(counts.words),
+                                    format_bytes(counts.bytes)
+                                ),
+                            ),
+                            None => StatusMessage::Clear(StatusItem::OutputCounts),
+                        })
+                        .is_ok()
+                {
+                    shown_counts = counts;
+                }
+
+                // Back off the rendering while more lines than the pane can show arrive
+                // in each interval (the intermediate frames are unreadable anyway),
+                // and render promptly again once the output slows down.
+                let next_render_interval = if received > height as usize {
+                    (cur_render_interval * 2).min(max_render_interval)
+                } else {
+                    (cur_render_interval / 2).max(render_interval)
+                };
+                received = 0;
+                if next_render_interval != cur_render_interval {
+                    cur_render_interval = next_render_interval;
+                    delay = tokio::time::interval_at(
+                        tokio::time::Instant::now() + cur_render_interval,
+                        cur_render_interval,
+                    );
                 }
             },
-            Ok(EventStream::Buffer(Buffer::VerticalScroll(up, down))) = event_stream.recv() => {
-                let shifted = queue.shift(up, down);
-                if shifted {
+            Ok(()) = output_height.changed() => {
+                last_modified_time = Local::now();
+            },
+            Ok(event) = event_stream.recv() => {
+                let modified = match event {
+                    EventStream::Buffer(Buffer::VerticalScroll(up, down)) => queue.shift(up, down),
+                    // Bookmark the top line, and jump between the bookmarks.
+                    // Alt instead of Ctrl, since Ctrl+M and Ctrl+J arrive as Enter and
+                    // a line feed without the keyboard enhancement, and Ctrl+K kills the line.
+                    EventStream::Buffer(Buffer::Other(
+                        Event::Key(KeyEvent {
+                            code: KeyCode::Char(ch @ ('m' | 'M' | 'j' | 'k')),
+                            modifiers,
+                            kind: KeyEventKind::Press,
+                            ..
+                        }),
+                        _,
+                    )) if modifiers.contains(KeyModifiers::ALT) => match ch {
+                        'm' if modifiers == KeyModifiers::ALT => {
+                            queue.toggle_bookmark();
+                            true
+                        }
+                        // Alt+Shift+M
+                        'M' => queue.clear_bookmarks(),
+                        'j' => queue.jump_to_bookmark(true),
+                        'k' => queue.jump_to_bookmark(false),
+                        _ => false,
+                    },
+                    // Pretty-print the JSON output.
+                    EventStream::Buffer(Buffer::Other(
+                        Event::Key(KeyEvent {
+                            code: KeyCode::Char(ch @ ('j' | 'J')),
+                            modifiers,
+                            kind: KeyEventKind::Press,
+                            ..
+                        }),
+                        _,
+                    )) if modifiers.contains(KeyModifiers::ALT)
+                        && !modifiers.contains(KeyModifiers::CONTROL)
+                        && (ch == 'J' || modifiers.contains(KeyModifiers::SHIFT)) =>
+                    {
+                        let message = match (queue.toggle_json(), queue.shows_json()) {
+                            (true, true) => "json: on",
+                            (true, false) => "json: on (no JSON output detected yet)",
+                            (false, _) => "json: off",
+                        };
+                        let _ = notify_tx
+                            .send(NotifyMessage::Info(String::from(message)))
+                            .await;
+                        true
+                    }
+                    // Render the CSV/TSV output as a table.
+                    EventStream::Buffer(Buffer::Other(
+                        Event::Key(KeyEvent {
+                            code: KeyCode::Char('t'),
+                            modifiers: KeyModifiers::CONTROL,
+                            kind: KeyEventKind::Press,
+                            ..
+                        }),
+                        _,
+                    )) => {
+                        let message = match (queue.toggle_table(), queue.delimiter()) {
+                            (true, Some('\t')) => "table: on (TSV)",
+                            (true, Some(_)) => "table: on (CSV)",
+                            (true, None) => "table: on (no CSV/TSV output detected yet)",
+                            (false, _) => "table: off",
+                        };
+                        let _ = notify_tx
+                            .send(NotifyMessage::Info(String::from(message)))
+                            .await;
+                        true
+                    }
+                    // Show the counts of the output in the status bar.
+                    EventStream::Buffer(Buffer::Other(
+                        Event::Key(KeyEvent {
+                            code: KeyCode::Char('w'),
+                            modifiers: KeyModifiers::ALT,
+                            kind: KeyEventKind::Press,
+                            ..
+                        }),
+                        _,
+                    )) => {
+                        counts_enabled = !counts_enabled;
+                        false
+                    }
+                    // Fold the consecutive duplicate lines from now on.
+                    EventStream::Buffer(Buffer::Other(
+                        Event::Key(KeyEvent {
+                            code: KeyCode::Char('d' | 'D'),
+                            modifiers,
+                            kind: KeyEventKind::Press,
+                            ..
+                        }),
+                        _,
+                    )) if modifiers == KeyModifiers::ALT | KeyModifiers::SHIFT => {
+                        let dedup = queue.toggle_dedup();
+                        let _ = notify_tx
+                            .send(NotifyMessage::Info(format!(
+                                "dedup: {}",
+                                if dedup { "on" } else { "off" }
+                            )))
+                            .await;
+                        false
+                    }
+                    EventStream::JumpToLine(line) => {
+                        if !queue.jump_to_line(line) {
+                            let _ = notify_tx
+                                .send(NotifyMessage::Error(format!(
+                                    "Line {} not available (max: {})",
+                                    line,
+                                    queue.lines().1
+                                )))
+                                .await;
+                        }
+                        true
+                    }
+                    // Select the output by dragging, and copy it on release.
+                    EventStream::Buffer(Buffer::Other(
+                        Event::Mouse(MouseEvent {
+                            kind: MouseEventKind::Down(MouseButton::Left),
+                            column,
+                            row,
+                            ..
+                        }),
+                        _,
+                    )) => match output_position(&queue, &shared_renderer, column, row).await {
+                        Some(pos) => {
+                            queue.start_selection(pos);
+                            true
+                        }
+                        None => queue.clear_selection(),
+                    },
+                    EventStream::Buffer(Buffer::MouseDrag(column, row)) => {
+                        match output_position(&queue, &shared_renderer, column, row).await {
+                            Some(pos) => queue.extend_selection(pos),
+                            None => false,
+                        }
+                    }
+                    EventStream::Buffer(Buffer::Other(
+                        Event::Mouse(MouseEvent {
+                            kind: MouseEventKind::Up(MouseButton::Left),
+                            ..
+                        }),
+                        _,
+                    )) => {
+                        if let Some(text) = queue.selected_text() {
+                            // Hold the renderer not to interleave with the rendering.
+                            let _renderer = shared_renderer.lock().await;
+                            let _ = clipboard::copy(&text);
+                        }
+                        false
+                    }
+                    _ => false,
+                };
+                if modified {
                     last_modified_time = Local::now();
                 }
             },
             maybe_line = stdout_stream.recv() => {
                 match maybe_line {
-                    Some(line) => {
-                        queue.push(StyledGraphemes::from(line));
+                    Some((origin, line)) => {
+                        queue.push(origin, StyledGraphemes::from(line));
                         last_modified_time = Local::now();
+                        received += 1;
+                        last_output.send_replace(Instant::now());
                     }
                     None => {
                         break;
@@ -296,3 +3237,68 @@ async fn output_stream(
         }
     }
 }
+
+/// Returns the position in the output queue for the mouse position on the screen,
+/// if it is on the output pane.
+async fn output_position(
+    queue: &queue::State,
+    shared_renderer: &SharedRenderer,
+    column: u16,
+    row: u16,
+) -> Option<queue::Position> {
+    let (width, _) = crossterm::terminal::size().ok()?;
+    match shared_renderer.lock().await.pane_at(row)? {
+        (PaneIndex::Output, row_in_pane) => queue.position_at(row_in_pane, column as usize, width),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_args() {
+        let validate = |args: &[&str]| {
+            validate_args(&Args::parse_from(
+                std::iter::once("epiq").chain(args.iter().copied()),
+            ))
+        };
+        assert!(validate(&[]).is_ok());
+        assert!(validate(&["--event-operate-interval", "5"]).is_ok());
+        assert!(validate(&["--event-operate-interval", "4"]).is_err());
+        assert!(validate(&["--event-operate-interval", "0"]).is_err());
+        // Too large values are only warned.
+        assert!(validate(&["--event-operate-interval", "501"]).is_ok());
+        assert!(validate(&["--output-render-interval", "5"]).is_ok());
+        assert!(validate(&["--output-render-interval", "4"]).is_err());
+        assert!(validate(&["--output-render-interval", "1001"]).is_ok());
+        assert!(Args::try_parse_from(["epiq", "--quit-key", "ctrl+q"]).is_ok());
+        assert!(Args::try_parse_from(["epiq", "--interrupt-key", "q"]).is_err());
+        // The keys bound to the other functions are rejected.
+        assert!(validate(&["--quit-key", "ctrl+q", "--interrupt-key", "ctrl+x"]).is_err());
+        assert!(validate(&["--quit-key", "alt+shift+v"]).is_err());
+        assert!(Args::try_parse_from(["epiq", "--no-cache"]).is_err());
+        let args = Args::parse_from(["epiq", "--cache-stages", "--cache-head"]);
+        assert!(args.cache_stages && args.cache_head);
+        assert!(Args::parse_from(["epiq", "--alt-screen"]).alternate_screen);
+    }
+
+    #[test]
+    fn test_mouse_capture() {
+        let emitted = |enabled: bool| {
+            let mut buf = vec![];
+            MouseCapture::new(enabled).apply(&mut buf).unwrap();
+            buf
+        };
+        let mut mouse_capture = MouseCapture::new(true);
+        let mut buf = vec![];
+        mouse_capture.toggle(&mut buf).unwrap();
+        assert!(!mouse_capture.enabled);
+        assert_eq!(buf, emitted(false));
+        buf.clear();
+        mouse_capture.toggle(&mut buf).unwrap();
+        assert_eq!(buf, emitted(true));
+        assert_ne!(emitted(true), emitted(false));
+    }
+}
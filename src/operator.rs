@@ -1,6 +1,6 @@
-use std::{borrow::Borrow, fmt};
+use std::{borrow::Borrow, fmt, time::Duration};
 
-use crossterm::event::{MouseEvent, MouseEventKind};
+use crossterm::event::{MouseButton, MouseEvent, MouseEventKind};
 use futures::StreamExt;
 use promkit::crossterm::{
     self,
@@ -8,6 +8,8 @@ use promkit::crossterm::{
 };
 use tokio::{sync::mpsc, task::JoinHandle, time::Interval};
 
+use crate::session::Stage;
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Buffer {
     Key(Vec<char>),                        // (chars)
@@ -15,6 +17,8 @@ pub enum Buffer {
     VerticalScroll(usize, usize),          // (up, down)
     HorizontalCursor(usize, usize),        // (left, right)
     HorizontalScroll(usize, usize),        // (left, right)
+    MouseDrag(u16, u16),                   // (column, row)
+    Paste(String),                         // (text)
     Other(crossterm::event::Event, usize), // (event, count)
 }
 
@@ -30,6 +34,8 @@ impl fmt::Display for Buffer {
             Buffer::HorizontalScroll(left, right) => {
                 write!(f, "HorizontalScroll({}, {})", left, right)
             }
+            Buffer::MouseDrag(column, row) => write!(f, "MouseDrag({}, {})", column, row),
+            Buffer::Paste(text) => write!(f, "Paste({:?})", text),
             Buffer::Other(event, count) => write!(f, "Other({:?}, {})", event, count),
         }
     }
@@ -52,6 +58,17 @@ impl fmt::Display for Debounce {
 pub enum EventStream {
     Buffer(Buffer),
     Debounce(Debounce),
+    // Issued by the application (not the terminal) to replace all the stages.
+    Load(Vec<String>),
+    // Issued by the application to replace all the stages, keeping whether each is ignored
+    // and its comment, and to focus the stage (0-based).
+    LoadStages(Vec<Stage>, usize),
+    // Issued by the application to scroll the output to the line (1-based).
+    JumpToLine(usize),
+    // Issued by the application to focus the stage (1-based, or the last one if zero).
+    FocusStage(usize),
+    // Issued by the application to replace the text of the focused stage.
+    ReplaceStage(String),
 }
 
 impl fmt::Display for EventStream {
@@ -59,36 +76,91 @@ impl fmt::Display for EventStream {
         match self {
             EventStream::Buffer(buffer) => write!(f, "{}", buffer),
             EventStream::Debounce(debounce) => write!(f, "{}", debounce),
+            EventStream::Load(cmds) => write!(f, "Load({:?})", cmds),
+            EventStream::LoadStages(stages, focus) => {
+                write!(f, "LoadStages({:?}, {})", stages, focus)
+            }
+            EventStream::JumpToLine(line) => write!(f, "JumpToLine({})", line),
+            EventStream::FocusStage(n) => write!(f, "FocusStage({})", n),
+            EventStream::ReplaceStage(text) => write!(f, "ReplaceStage({:?})", text),
         }
     }
 }
 
+#[derive(Clone)]
+pub struct OperatorConfig {
+    // The number of lines to move per mouse wheel tick.
+    pub scroll_speed: usize,
+}
+
+impl Default for OperatorConfig {
+    fn default() -> Self {
+        Self { scroll_speed: 1 }
+    }
+}
+
 pub struct EventOperator {
     pub background: JoinHandle<()>,
+    // Kept to spawn the background again on resuming.
+    tx: mpsc::Sender<Vec<EventStream>>,
+    period: Duration,
+    config: OperatorConfig,
 }
 
 impl EventOperator {
-    pub fn spawn(tx: mpsc::Sender<Vec<EventStream>>, mut interval: Interval) -> Self {
+    pub fn spawn(
+        tx: mpsc::Sender<Vec<EventStream>>,
+        interval: Interval,
+        config: OperatorConfig,
+    ) -> Self {
         Self {
-            background: tokio::spawn(async move {
-                let mut event_stream = crossterm::event::EventStream::new();
-                let mut buf = vec![];
-
-                loop {
-                    tokio::select! {
-                        _ = interval.tick() => {
-                            let _ = tx.send(Self::operate(buf.drain(..))).await;
-                        },
-                        Some(Ok(event)) = event_stream.next() => {
-                            buf.push(event);
-                        },
-                    }
-                }
-            }),
+            period: interval.period(),
+            background: Self::spawn_background(tx.clone(), interval, config.clone()),
+            tx,
+            config,
         }
     }
 
-    fn operate<I, E>(events: I) -> Vec<EventStream>
+    /// Stops reading the terminal, e.g. while another program runs on it,
+    /// and waits until the event stream is dropped not to steal the inputs from it.
+    /// The events not yet sent are discarded.
+    pub async fn suspend(&mut self) {
+        self.background.abort();
+        let _ = (&mut self.background).await;
+    }
+
+    /// Starts reading the terminal again after `suspend`.
+    pub fn resume(&mut self) {
+        self.background = Self::spawn_background(
+            self.tx.clone(),
+            tokio::time::interval(self.period),
+            self.config.clone(),
+        );
+    }
+
+    fn spawn_background(
+        tx: mpsc::Sender<Vec<EventStream>>,
+        mut interval: Interval,
+        config: OperatorConfig,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut event_stream = crossterm::event::EventStream::new();
+            let mut buf = vec![];
+
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {
+                        let _ = tx.send(Self::operate(buf.drain(..), &config)).await;
+                    },
+                    Some(Ok(event)) = event_stream.next() => {
+                        buf.push(event);
+                    },
+                }
+            }
+        })
+    }
+
+    fn operate<I, E>(events: I, config: &OperatorConfig) -> Vec<EventStream>
     where
         I: IntoIterator<Item = E>,
         E: Borrow<crossterm::event::Event>,
@@ -99,6 +171,7 @@ impl EventOperator {
         let mut current_horizontal = (0, 0);
         let mut current_vertical_scroll = (0, 0);
         let mut current_horizontal_scroll = (0, 0);
+        let mut current_drag: Option<(u16, u16)> = None;
         let mut current_others: Option<(crossterm::event::Event, usize)> = None;
         let mut last_resize: Option<(u16, u16)> = None;
         let mut resize_index: Option<usize> = None;
@@ -114,11 +187,34 @@ impl EventOperator {
                         &mut current_horizontal,
                         &mut current_vertical_scroll,
                         &mut current_horizontal_scroll,
+                        &mut current_drag,
                         &mut current_others,
                     );
                     last_resize = Some((*width, *height));
                     resize_index = Some(result.len());
                 }
+                // Mouse moves and drags except for the left button (for selection)
+                // are not used anywhere, so skip them not to wake up the renderers
+                // and not to break the runs of the other events (e.g. scrolls).
+                crossterm::event::Event::Mouse(MouseEvent {
+                    kind:
+                        MouseEventKind::Moved
+                        | MouseEventKind::Drag(MouseButton::Right | MouseButton::Middle),
+                    ..
+                }) => {}
+                crossterm::event::Event::Paste(text) => {
+                    Self::flush_all_buffers(
+                        &mut result,
+                        &mut current_chars,
+                        &mut current_vertical,
+                        &mut current_horizontal,
+                        &mut current_vertical_scroll,
+                        &mut current_horizontal_scroll,
+                        &mut current_drag,
+                        &mut current_others,
+                    );
+                    result.push(EventStream::Buffer(Buffer::Paste(text.clone())));
+                }
                 event => {
                     if let Some(ch) = Self::extract_char(event) {
                         Self::flush_non_char_buffers(
@@ -127,6 +223,7 @@ impl EventOperator {
                             &mut current_horizontal,
                             &mut current_vertical_scroll,
                             &mut current_horizontal_scroll,
+                            &mut current_drag,
                             &mut current_others,
                         );
                         current_chars.push(ch);
@@ -141,10 +238,13 @@ impl EventOperator {
                             &mut result,
                             &mut current_horizontal_scroll,
                         );
+                        Self::flush_drag_buffer(&mut result, &mut current_drag);
                         Self::flush_others_buffer(&mut result, &mut current_others);
                         current_vertical.0 += up;
                         current_vertical.1 += down;
-                    } else if let Some((up, down)) = Self::detect_vertical_scroll(event) {
+                    } else if let Some((up, down)) =
+                        Self::detect_vertical_scroll(event, config.scroll_speed)
+                    {
                         Self::flush_char_buffer(&mut result, &mut current_chars);
                         Self::flush_vertical_buffer(&mut result, &mut current_vertical);
                         Self::flush_horizontal_buffer(&mut result, &mut current_horizontal);
@@ -152,6 +252,7 @@ impl EventOperator {
                             &mut result,
                             &mut current_horizontal_scroll,
                         );
+                        Self::flush_drag_buffer(&mut result, &mut current_drag);
                         Self::flush_others_buffer(&mut result, &mut current_others);
                         current_vertical_scroll.0 += up;
                         current_vertical_scroll.1 += down;
@@ -166,6 +267,7 @@ impl EventOperator {
                             &mut result,
                             &mut current_horizontal_scroll,
                         );
+                        Self::flush_drag_buffer(&mut result, &mut current_drag);
                         Self::flush_others_buffer(&mut result, &mut current_others);
                         current_horizontal.0 += left;
                         current_horizontal.1 += right;
@@ -177,9 +279,25 @@ impl EventOperator {
                             &mut current_vertical_scroll,
                         );
                         Self::flush_horizontal_buffer(&mut result, &mut current_horizontal);
+                        Self::flush_drag_buffer(&mut result, &mut current_drag);
                         Self::flush_others_buffer(&mut result, &mut current_others);
                         current_horizontal_scroll.0 += left;
                         current_horizontal_scroll.1 += right;
+                    } else if let Some((column, row)) = Self::detect_mouse_drag(event) {
+                        Self::flush_char_buffer(&mut result, &mut current_chars);
+                        Self::flush_vertical_buffer(&mut result, &mut current_vertical);
+                        Self::flush_vertical_scroll_buffer(
+                            &mut result,
+                            &mut current_vertical_scroll,
+                        );
+                        Self::flush_horizontal_buffer(&mut result, &mut current_horizontal);
+                        Self::flush_horizontal_scroll_buffer(
+                            &mut result,
+                            &mut current_horizontal_scroll,
+                        );
+                        Self::flush_others_buffer(&mut result, &mut current_others);
+                        // Only the latest position matters while dragging.
+                        current_drag = Some((column, row));
                     } else {
                         Self::flush_char_buffer(&mut result, &mut current_chars);
                         Self::flush_vertical_buffer(&mut result, &mut current_vertical);
@@ -192,6 +310,7 @@ impl EventOperator {
                             &mut result,
                             &mut current_horizontal_scroll,
                         );
+                        Self::flush_drag_buffer(&mut result, &mut current_drag);
 
                         match &mut current_others {
                             Some((last_event, count)) if last_event == event => {
@@ -215,6 +334,7 @@ impl EventOperator {
             &mut current_horizontal,
             &mut current_vertical_scroll,
             &mut current_horizontal_scroll,
+            &mut current_drag,
             &mut current_others,
         );
 
@@ -226,6 +346,7 @@ impl EventOperator {
         result
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn flush_all_buffers(
         result: &mut Vec<EventStream>,
         chars: &mut Vec<char>,
@@ -233,6 +354,7 @@ impl EventOperator {
         horizontal: &mut (usize, usize),
         vertical_scroll: &mut (usize, usize),
         horizontal_scroll: &mut (usize, usize),
+        drag: &mut Option<(u16, u16)>,
         others: &mut Option<(crossterm::event::Event, usize)>,
     ) {
         Self::flush_char_buffer(result, chars);
@@ -240,6 +362,7 @@ impl EventOperator {
         Self::flush_horizontal_buffer(result, horizontal);
         Self::flush_vertical_scroll_buffer(result, vertical_scroll);
         Self::flush_horizontal_scroll_buffer(result, horizontal_scroll);
+        Self::flush_drag_buffer(result, drag);
         Self::flush_others_buffer(result, others);
     }
 
@@ -295,6 +418,12 @@ impl EventOperator {
         }
     }
 
+    fn flush_drag_buffer(result: &mut Vec<EventStream>, drag: &mut Option<(u16, u16)>) {
+        if let Some((column, row)) = drag.take() {
+            result.push(EventStream::Buffer(Buffer::MouseDrag(column, row)));
+        }
+    }
+
     fn flush_others_buffer(
         result: &mut Vec<EventStream>,
         others: &mut Option<(crossterm::event::Event, usize)>,
@@ -310,12 +439,14 @@ impl EventOperator {
         horizontal: &mut (usize, usize),
         vertical_scroll: &mut (usize, usize),
         horizontal_scroll: &mut (usize, usize),
+        drag: &mut Option<(u16, u16)>,
         others: &mut Option<(crossterm::event::Event, usize)>,
     ) {
         Self::flush_vertical_buffer(result, vertical);
         Self::flush_horizontal_buffer(result, horizontal);
         Self::flush_vertical_scroll_buffer(result, vertical_scroll);
         Self::flush_horizontal_scroll_buffer(result, horizontal_scroll);
+        Self::flush_drag_buffer(result, drag);
         Self::flush_others_buffer(result, others);
     }
 
@@ -350,30 +481,49 @@ impl EventOperator {
         }
     }
 
-    fn detect_vertical_scroll(event: &crossterm::event::Event) -> Option<(usize, usize)> {
+    fn detect_vertical_scroll(
+        event: &crossterm::event::Event,
+        speed: usize,
+    ) -> Option<(usize, usize)> {
         match event {
             crossterm::event::Event::Mouse(MouseEvent {
                 kind: MouseEventKind::ScrollUp,
                 ..
-            }) => Some((1, 0)),
+            }) => Some((speed, 0)),
             crossterm::event::Event::Mouse(MouseEvent {
                 kind: MouseEventKind::ScrollDown,
                 ..
-            }) => Some((0, 1)),
+            }) => Some((0, speed)),
             _ => None,
         }
     }
 
     fn detect_horizontal_direction(event: &crossterm::event::Event) -> Option<(usize, usize)> {
+        // Ctrl+Left/Right are word-wise movements, while the other modifiers
+        // (e.g. Shift) still move by characters.
         match event {
             crossterm::event::Event::Key(KeyEvent {
                 code: KeyCode::Left,
+                modifiers,
                 ..
-            }) => Some((1, 0)),
+            }) if !modifiers.contains(KeyModifiers::CONTROL) => Some((1, 0)),
             crossterm::event::Event::Key(KeyEvent {
                 code: KeyCode::Right,
+                modifiers,
                 ..
-            }) => Some((0, 1)),
+            }) if !modifiers.contains(KeyModifiers::CONTROL) => Some((0, 1)),
+            _ => None,
+        }
+    }
+
+    fn detect_mouse_drag(event: &crossterm::event::Event) -> Option<(u16, u16)> {
+        match event {
+            crossterm::event::Event::Mouse(MouseEvent {
+                kind: MouseEventKind::Drag(MouseButton::Left),
+                column,
+                row,
+                ..
+            }) => Some((*column, *row)),
             _ => None,
         }
     }
@@ -543,7 +693,197 @@ mod tests {
                 EventStream::Buffer(Buffer::Key(vec!['d'])),
             ];
 
-            assert_eq!(EventOperator::operate(&events), expected);
+            assert_eq!(
+                EventOperator::operate(&events, &OperatorConfig::default()),
+                expected
+            );
+        }
+
+        #[test]
+        fn test_vertical_scroll() {
+            // Input:
+            // ScrollUp, Moved, ScrollUp, Moved, Moved, ScrollUp
+            let mouse = |kind| {
+                crossterm::event::Event::Mouse(MouseEvent {
+                    kind,
+                    modifiers: KeyModifiers::NONE,
+                    row: 0,
+                    column: 0,
+                })
+            };
+            let events = vec![
+                mouse(MouseEventKind::ScrollUp),
+                mouse(MouseEventKind::Moved),
+                mouse(MouseEventKind::ScrollUp),
+                mouse(MouseEventKind::Moved),
+                mouse(MouseEventKind::Moved),
+                mouse(MouseEventKind::ScrollUp),
+            ];
+
+            let expected = vec![EventStream::Buffer(Buffer::VerticalScroll(3, 0))];
+
+            assert_eq!(
+                EventOperator::operate(&events, &OperatorConfig::default()),
+                expected
+            );
+
+            let expected = vec![EventStream::Buffer(Buffer::VerticalScroll(9, 0))];
+
+            assert_eq!(
+                EventOperator::operate(&events, &OperatorConfig { scroll_speed: 3 }),
+                expected
+            );
+        }
+
+        #[test]
+        fn test_word_movement() {
+            // Input:
+            // Left, Ctrl+Left, Ctrl+Left, Right, Ctrl+Right, Left
+            let key = |code, modifiers| {
+                crossterm::event::Event::Key(KeyEvent {
+                    code,
+                    modifiers,
+                    kind: KeyEventKind::Press,
+                    state: KeyEventState::NONE,
+                })
+            };
+            let events = vec![
+                key(KeyCode::Left, KeyModifiers::NONE),
+                key(KeyCode::Left, KeyModifiers::CONTROL),
+                key(KeyCode::Left, KeyModifiers::CONTROL),
+                key(KeyCode::Right, KeyModifiers::NONE),
+                key(KeyCode::Right, KeyModifiers::CONTROL),
+                key(KeyCode::Left, KeyModifiers::NONE),
+            ];
+
+            let expected = vec![
+                EventStream::Buffer(Buffer::HorizontalCursor(1, 0)),
+                EventStream::Buffer(Buffer::Other(key(KeyCode::Left, KeyModifiers::CONTROL), 2)),
+                EventStream::Buffer(Buffer::HorizontalCursor(0, 1)),
+                EventStream::Buffer(Buffer::Other(key(KeyCode::Right, KeyModifiers::CONTROL), 1)),
+                EventStream::Buffer(Buffer::HorizontalCursor(1, 0)),
+            ];
+
+            assert_eq!(
+                EventOperator::operate(&events, &OperatorConfig::default()),
+                expected
+            );
+
+            // Input:
+            // Shift+Left, Left, Ctrl+Shift+Left, Shift+Right
+            let events = vec![
+                key(KeyCode::Left, KeyModifiers::SHIFT),
+                key(KeyCode::Left, KeyModifiers::NONE),
+                key(
+                    KeyCode::Left,
+                    KeyModifiers::CONTROL.union(KeyModifiers::SHIFT),
+                ),
+                key(KeyCode::Right, KeyModifiers::SHIFT),
+            ];
+
+            let expected = vec![
+                EventStream::Buffer(Buffer::HorizontalCursor(2, 0)),
+                EventStream::Buffer(Buffer::Other(
+                    key(
+                        KeyCode::Left,
+                        KeyModifiers::CONTROL.union(KeyModifiers::SHIFT),
+                    ),
+                    1,
+                )),
+                EventStream::Buffer(Buffer::HorizontalCursor(0, 1)),
+            ];
+
+            assert_eq!(
+                EventOperator::operate(&events, &OperatorConfig::default()),
+                expected
+            );
+        }
+
+        #[test]
+        fn test_paste() {
+            // Input:
+            // 'a', Paste("b | c"), 'd'
+            let key = |ch| {
+                crossterm::event::Event::Key(KeyEvent {
+                    code: KeyCode::Char(ch),
+                    modifiers: KeyModifiers::NONE,
+                    kind: KeyEventKind::Press,
+                    state: KeyEventState::NONE,
+                })
+            };
+            let events = vec![
+                key('a'),
+                crossterm::event::Event::Paste(String::from("b | c")),
+                key('d'),
+            ];
+
+            let expected = vec![
+                EventStream::Buffer(Buffer::Key(vec!['a'])),
+                EventStream::Buffer(Buffer::Paste(String::from("b | c"))),
+                EventStream::Buffer(Buffer::Key(vec!['d'])),
+            ];
+
+            assert_eq!(
+                EventOperator::operate(&events, &OperatorConfig::default()),
+                expected
+            );
+        }
+
+        #[test]
+        fn test_mouse_moved() {
+            let events: Vec<_> = (0..5)
+                .map(|column| {
+                    crossterm::event::Event::Mouse(MouseEvent {
+                        kind: MouseEventKind::Moved,
+                        modifiers: KeyModifiers::NONE,
+                        row: 0,
+                        column,
+                    })
+                })
+                .collect();
+
+            assert_eq!(
+                EventOperator::operate(&events, &OperatorConfig::default()),
+                vec![]
+            );
+        }
+
+        #[test]
+        fn test_mouse_drag() {
+            // Input:
+            // Down(Left), Drag(Left) x 3, Up(Left)
+            let mouse = |kind, column, row| {
+                crossterm::event::Event::Mouse(MouseEvent {
+                    kind,
+                    modifiers: KeyModifiers::NONE,
+                    row,
+                    column,
+                })
+            };
+            let events = vec![
+                mouse(MouseEventKind::Down(MouseButton::Left), 1, 10),
+                mouse(MouseEventKind::Drag(MouseButton::Left), 2, 10),
+                mouse(MouseEventKind::Drag(MouseButton::Left), 3, 11),
+                mouse(MouseEventKind::Drag(MouseButton::Left), 4, 12),
+                mouse(MouseEventKind::Up(MouseButton::Left), 4, 12),
+            ];
+
+            let expected = vec![
+                EventStream::Buffer(Buffer::Other(
+                    mouse(MouseEventKind::Down(MouseButton::Left), 1, 10),
+                    1,
+                )),
+                EventStream::Buffer(Buffer::MouseDrag(4, 12)),
+                EventStream::Buffer(Buffer::Other(
+                    mouse(MouseEventKind::Up(MouseButton::Left), 4, 12),
+                    1,
+                )),
+            ];
+
+            assert_eq!(
+                EventOperator::operate(&events, &OperatorConfig::default()),
+                expected
+            );
         }
     }
 }
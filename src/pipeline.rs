@@ -1,12 +1,23 @@
-use std::{marker::PhantomData, process::Stdio};
+use std::{
+    collections::{HashMap, VecDeque},
+    marker::PhantomData,
+    process::Stdio,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
 
 use tokio::{
     io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter, Lines},
-    process::{ChildStderr, ChildStdin, ChildStdout, Command},
-    sync::mpsc,
+    process::{Child, ChildStderr, ChildStdin, ChildStdout, Command},
+    sync::{mpsc, watch},
     task::JoinHandle,
 };
 
+use crate::debug_log;
+
 pub trait StageKind {}
 
 pub struct Head;
@@ -15,19 +26,226 @@ impl StageKind for Head {}
 pub struct Pipe;
 impl StageKind for Pipe {}
 
+/// A stage which sends its input to all of the commands separated by `|>`.
+pub struct FanOut;
+impl StageKind for FanOut {}
+
+/// Where an output line comes from.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Origin {
+    // The index (0-based) of the stage.
+    pub stage: usize,
+    // The index (0-based) of the branch, if the stage fans out.
+    pub branch: Option<usize>,
+    // Whether the line comes from the stderr of the stage.
+    pub stderr: bool,
+}
+
+impl From<usize> for Origin {
+    fn from(stage: usize) -> Self {
+        Self {
+            stage,
+            branch: None,
+            stderr: false,
+        }
+    }
+}
+
+/// The number of the lines and bytes a stage has emitted (both stdout and stderr),
+/// and how long it has been running.
+pub struct StageStats {
+    lines: AtomicUsize,
+    bytes: AtomicUsize,
+    started_at: Instant,
+    // Set when the stage has exited or been stopped.
+    finished_in: Mutex<Option<Duration>>,
+}
+
+impl Default for StageStats {
+    fn default() -> Self {
+        Self {
+            lines: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+            started_at: Instant::now(),
+            finished_in: Mutex::new(None),
+        }
+    }
+}
+
+impl StageStats {
+    fn record(&self, line: &str) {
+        self.lines.fetch_add(1, Ordering::Relaxed);
+        // Count the newline also.
+        self.bytes.fetch_add(line.len() + 1, Ordering::Relaxed);
+    }
+
+    /// Returns the (lines, bytes).
+    pub fn get(&self) -> (usize, usize) {
+        (
+            self.lines.load(Ordering::Relaxed),
+            self.bytes.load(Ordering::Relaxed),
+        )
+    }
+
+    fn finish(&self) {
+        let mut finished_in = self.finished_in.lock().unwrap_or_else(|e| e.into_inner());
+        finished_in.get_or_insert_with(|| self.started_at.elapsed());
+    }
+
+    /// Returns the elapsed time, and whether the stage has finished.
+    pub fn elapsed(&self) -> (Duration, bool) {
+        match *self.finished_in.lock().unwrap_or_else(|e| e.into_inner()) {
+            Some(elapsed) => (elapsed, true),
+            None => (self.started_at.elapsed(), false),
+        }
+    }
+}
+
+/// The total size of the output all the stages can capture, shared by the pipelines
+/// (including the captures reused from the previous run).
+const CAPTURE_BUDGET_BYTES: usize = 64 * 1024 * 1024;
+
+/// The bytes captured by all the stages, charged against CAPTURE_BUDGET_BYTES.
+static CAPTURED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// The latest lines captured from a stage and their total size in bytes,
+/// with the number of the earlier lines evicted to keep the captures within the budget.
+#[derive(Default)]
+struct Captured {
+    lines: VecDeque<String>,
+    bytes: usize,
+    evicted: usize,
+}
+
+impl Captured {
+    /// Appends the line, charging it to `total`, and evicts the oldest lines
+    /// while `total` exceeds the budget.
+    fn push(&mut self, line: &str, total: &AtomicUsize, budget: usize) {
+        // Count the newline also.
+        let bytes = line.len() + 1;
+        let mut charged = total.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.lines.push_back(line.to_string());
+        self.bytes += bytes;
+        while charged > budget
+            && let Some(oldest) = self.lines.pop_front()
+        {
+            let freed = oldest.len() + 1;
+            self.bytes -= freed;
+            self.evicted += 1;
+            charged = total.fetch_sub(freed, Ordering::Relaxed) - freed;
+        }
+    }
+}
+
+/// The lines a stage has emitted, kept to replay them on the next run
+/// instead of re-running the stage if it is unchanged.
+pub struct StageCapture {
+    // None for the stage whose output is not captured.
+    lines: Mutex<Option<Captured>>,
+    // Set when the stage has exited by itself, i.e. neither aborted nor stopped.
+    complete: AtomicBool,
+}
+
+impl Default for StageCapture {
+    fn default() -> Self {
+        Self {
+            lines: Mutex::new(Some(Captured::default())),
+            complete: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Drop for StageCapture {
+    fn drop(&mut self) {
+        if let Some(captured) = self.lines.get_mut().unwrap_or_else(|e| e.into_inner()) {
+            CAPTURED_BYTES.fetch_sub(captured.bytes, Ordering::Relaxed);
+        }
+    }
+}
+
+impl StageCapture {
+    /// Returns the capture which keeps nothing, for the stage whose output is not captured.
+    fn disabled() -> Self {
+        Self {
+            lines: Mutex::new(None),
+            complete: AtomicBool::new(false),
+        }
+    }
+
+    /// Captures the line, evicting the oldest lines of the stage if the captures
+    /// of all the stages exceed the budget, and returns the stage shown at that time
+    /// (see `StageCapture::switch`).
+    fn record(&self, line: &str, shown: &watch::Receiver<Option<usize>>) -> Option<usize> {
+        let mut lines = self.lines.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(captured) = lines.as_mut() {
+            captured.push(line, &CAPTURED_BYTES, CAPTURE_BUDGET_BYTES);
+        }
+        *shown.borrow()
+    }
+
+    /// Returns the captured lines if the stage has completed with all of them captured.
+    fn lines(&self) -> Option<Vec<String>> {
+        if !self.is_complete() {
+            return None;
+        }
+        self.snapshot().map(|(lines, _)| lines)
+    }
+
+    /// Returns the lines captured so far with the number of the evicted ones,
+    /// or None if they are not kept.
+    fn snapshot(&self) -> Option<(Vec<String>, usize)> {
+        self.switch(|| ())
+    }
+
+    /// Calls `f` to switch the shown stage, and returns the lines captured so far
+    /// (even if the stage is still running) with the number of the evicted ones,
+    /// or None if they are not kept. Since `record` takes the same lock, each line
+    /// is either returned here or sent as shown after the switch, never both nor neither.
+    fn switch(&self, f: impl FnOnce()) -> Option<(Vec<String>, usize)> {
+        let lines = self.lines.lock().unwrap_or_else(|e| e.into_inner());
+        f();
+        lines
+            .as_ref()
+            .map(|captured| (captured.lines.iter().cloned().collect(), captured.evicted))
+    }
+
+    fn is_complete(&self) -> bool {
+        self.complete.load(Ordering::Relaxed)
+            && self
+                .lines
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .as_ref()
+                .is_some_and(|captured| captured.evicted == 0)
+    }
+}
+
+/// The period to wait for a stage to exit after closing its stdin when aborted,
+/// before killing it.
+const STOP_TIMEOUT: Duration = Duration::from_millis(100);
+
 pub struct Stage<S: StageKind> {
     waiter: JoinHandle<()>,
+    // Requests the stage to close the stdin of the command and wait for its exit.
+    cancel: Option<watch::Sender<bool>>,
+    stats: Arc<StageStats>,
+    capture: Arc<StageCapture>,
     _marker: PhantomData<S>,
 }
 
-fn parse_command(cmd: &str) -> anyhow::Result<Command> {
+/// Splits the command into the program and its arguments, as it is spawned.
+fn parse_argv(cmd: &str) -> anyhow::Result<Vec<String>> {
     let parts = shlex::split(cmd.trim())
         .ok_or_else(|| anyhow::anyhow!("Failed to parse {}: invalid shell syntax", cmd))?;
 
     if parts.is_empty() {
         return Err(anyhow::anyhow!("The command is empty"));
     }
+    Ok(parts)
+}
 
+fn parse_command(cmd: &str) -> anyhow::Result<Command> {
+    let parts = parse_argv(cmd)?;
     let mut command = Command::new(&parts[0]);
     for arg in parts.iter().skip(1) {
         command.arg(arg);
@@ -35,11 +253,163 @@ fn parse_command(cmd: &str) -> anyhow::Result<Command> {
     Ok(command)
 }
 
+/// Describes how the stage is run without spawning it, i.e. the argv of the command
+/// (e.g. `["awk", "{print", "$1}"]`), that of each branch of the fan-out joined with `|>`,
+/// or the variable set by the `SET` stage.
+pub fn describe_argv(cmd: &str) -> anyhow::Result<String> {
+    if is_set_command(cmd) {
+        let (key, value) = parse_set_command(cmd)?;
+        return Ok(format!("SET {}={:?}", key, value));
+    }
+    let branches = split_fan_out(cmd)
+        .iter()
+        .map(|branch| Ok(format!("{:?}", parse_argv(branch)?)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(branches.join(" |> "))
+}
+
+/// Returns whether the command is the pseudo-command `SET KEY=VALUE`.
+pub fn is_set_command(cmd: &str) -> bool {
+    cmd.trim_start().starts_with("SET ")
+}
+
+/// Parses the pseudo-command `SET KEY=VALUE` into (KEY, VALUE),
+/// where VALUE can be quoted as in the shell.
+pub fn parse_set_command(cmd: &str) -> anyhow::Result<(String, String)> {
+    let assignment = cmd
+        .trim_start()
+        .strip_prefix("SET ")
+        .ok_or_else(|| anyhow::anyhow!("{} is not a SET command", cmd))?;
+    match shlex::split(assignment.trim()).as_deref() {
+        Some([assignment]) => match assignment.split_once('=') {
+            Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+            _ => Err(anyhow::anyhow!(
+                "Failed to parse {}: expected SET KEY=VALUE",
+                cmd
+            )),
+        },
+        _ => Err(anyhow::anyhow!(
+            "Failed to parse {}: expected SET KEY=VALUE",
+            cmd
+        )),
+    }
+}
+
+/// A command of a stage with the environment variables set by the preceding `SET` stages.
+#[derive(Clone, PartialEq)]
+struct StageCommand {
+    cmd: String,
+    env: HashMap<String, String>,
+}
+
+/// Removes the `SET KEY=VALUE` stages, which do not spawn a process,
+/// and sets the variables to all the following stages instead.
+fn resolve_commands(cmds: &[String]) -> anyhow::Result<Vec<StageCommand>> {
+    let mut env_overrides = HashMap::new();
+    let mut resolved = vec![];
+    for cmd in cmds {
+        if is_set_command(cmd) {
+            let (key, value) = parse_set_command(cmd)?;
+            env_overrides.insert(key, value);
+        } else {
+            resolved.push(StageCommand {
+                cmd: cmd.clone(),
+                env: env_overrides.clone(),
+            });
+        }
+    }
+    Ok(resolved)
+}
+
+/// Splits the text into the commands at the pipes `|` which are not quoted.
+/// Line continuations and newlines are treated as spaces.
+/// The fan-outs `|>` are kept in the commands.
+pub fn split_pipeline(text: &str) -> Vec<String> {
+    let text = text
+        .replace("\r\n", "\n")
+        .replace("\\\n", " ")
+        .replace(['\r', '\n'], " ");
+    split_unquoted(&text, false)
+}
+
+/// Returns whether `|` typed after the text would be a pipe, i.e. neither quoted nor escaped.
+pub fn pipe_follows(text: &str) -> bool {
+    split_pipeline(&format!("{}|", text))
+        .last()
+        .is_some_and(String::is_empty)
+}
+
+/// Splits the command into the branches at the fan-outs `|>` which are not quoted.
+pub fn split_fan_out(cmd: &str) -> Vec<String> {
+    split_unquoted(cmd, true)
+}
+
+/// Splits the text at the unquoted fan-outs `|>` if `fan_out` is set,
+/// or at the unquoted pipes `|` otherwise.
+fn split_unquoted(text: &str, fan_out: bool) -> Vec<String> {
+    let mut cmds = vec![];
+    let mut cur = String::new();
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    let mut chars = text.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match (quote, ch) {
+            _ if escaped => escaped = false,
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('"') | None, '\\') => escaped = true,
+            (None, '\'' | '"') => quote = Some(ch),
+            (None, '|') if (chars.peek() == Some(&'>')) == fan_out => {
+                if fan_out {
+                    chars.next();
+                }
+                cmds.push(cur.trim().to_string());
+                cur.clear();
+                continue;
+            }
+            _ => {}
+        }
+        cur.push(ch);
+    }
+    cmds.push(cur.trim().to_string());
+    cmds
+}
+
+/// Joins the lines of a multi-line command with spaces, like the shell does,
+/// keeping the newlines in quotes as they are.
+pub fn join_lines(cmd: &str) -> String {
+    let mut joined = String::new();
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for ch in cmd.replace("\r\n", "\n").chars() {
+        match (quote, ch) {
+            // Line continuation.
+            (_, '\n') if escaped => {
+                joined.pop();
+                joined.push(' ');
+                escaped = false;
+                continue;
+            }
+            _ if escaped => escaped = false,
+            (None, '\n') => {
+                joined.push(' ');
+                continue;
+            }
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('"') | None, '\\') => escaped = true,
+            (None, '\'' | '"') => quote = Some(ch),
+            _ => {}
+        }
+        joined.push(ch);
+    }
+    joined
+}
+
 #[allow(clippy::type_complexity)]
 fn setup_command(
     mut command: Command,
     use_stdin: bool,
 ) -> anyhow::Result<(
+    Child,
     Option<BufWriter<ChildStdin>>,
     Lines<BufReader<ChildStdout>>,
     Lines<BufReader<ChildStderr>>,
@@ -54,10 +424,13 @@ fn setup_command(
         .stdin(stdin_config)
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
+        // Kill the process when the stage is aborted.
+        .kill_on_drop(true)
         .spawn()
     {
         Ok(child) => child,
         Err(e) => {
+            debug_log::log(|| format!("failed to spawn {:?}: {}", command.as_std(), e));
             if e.kind() == std::io::ErrorKind::NotFound {
                 anyhow::bail!("Command {:?} is not found", command.as_std().get_program());
             }
@@ -65,6 +438,8 @@ fn setup_command(
         }
     };
 
+    debug_log::log(|| format!("spawned {:?} (pid {:?})", command.as_std(), child.id()));
+
     let stdout = child
         .stdout
         .take()
@@ -74,144 +449,854 @@ fn setup_command(
         .take()
         .ok_or_else(|| anyhow::anyhow!("stderr is not available"))?;
 
+    let stdin = if use_stdin {
+        let stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| anyhow::anyhow!("stdin is not available"))?;
+        Some(BufWriter::new(stdin))
+    } else {
+        None
+    };
+
     Ok((
-        if use_stdin {
-            let stdin = child
-                .stdin
-                .take()
-                .ok_or_else(|| anyhow::anyhow!("stdin is not available"))?;
-            Some(BufWriter::new(stdin))
-        } else {
-            None
-        },
+        child,
+        stdin,
         BufReader::new(stdout).lines(),
         BufReader::new(stderr).lines(),
     ))
 }
 
-fn spawn_process_output(
+/// Where a stage sends its output lines.
+#[derive(Clone)]
+enum Sink {
+    // The stdin of the next stage.
+    Stage(mpsc::Sender<String>),
+    // The output pane, with where the lines come from.
+    Output(Origin, mpsc::Sender<(Origin, String)>),
+}
+
+impl Sink {
+    /// Returns false if the receiver has been dropped.
+    async fn send(&self, line: String) -> bool {
+        match self {
+            Sink::Stage(tx) => tx.send(line).await.is_ok(),
+            Sink::Output(origin, tx) => tx.send((*origin, line)).await.is_ok(),
+        }
+    }
+
+    /// Returns the sink for the branch (0-based) of the stage fanning out.
+    fn for_branch(&self, branch: usize) -> Self {
+        match self {
+            Sink::Output(origin, tx) => Sink::Output(
+                Origin {
+                    branch: Some(branch),
+                    ..*origin
+                },
+                tx.clone(),
+            ),
+            sink => sink.clone(),
+        }
+    }
+}
+
+/// Reported to the main loop with the instant the pipeline started at,
+/// to tell it from the previous runs.
+#[derive(Debug, PartialEq)]
+pub enum PipelineEvent {
+    // The last stage has completed (not reported when aborted).
+    Completed,
+    // The stage (0-based) has been stopped by reaching the maximum lines.
+    LineLimitReached(usize),
+    // The stage (0-based) has exited, and how it has ended.
+    StageExited(usize, Exit),
+    // The stage (0-based) has failed with the exit code (None if killed by a signal)
+    // before reading all the input, i.e. while the previous stages are still running.
+    Broken(usize, Option<i32>),
+}
+
+/// How the command of a stage has ended.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Exit {
+    // Exited successfully, or stopped since the next stage no longer reads the output.
+    Success,
+    // Exited unsuccessfully with the code (None if it could not be waited for).
+    Failure(Option<i32>),
+    // Killed by the signal.
+    Killed(i32),
+    // Stopped by reaching the maximum lines.
+    LineLimit,
+}
+
+impl Exit {
+    /// Returns the exit code as the shell's `$?` (128 plus the signal if killed),
+    /// or 1 if stopped.
+    pub fn code(&self) -> i32 {
+        match self {
+            Exit::Success => 0,
+            Exit::Failure(code) => code.unwrap_or(1),
+            Exit::Killed(signal) => 128 + signal,
+            Exit::LineLimit => 1,
+        }
+    }
+}
+
+/// Where a stage is in the pipeline, and where it reports the events.
+#[derive(Clone)]
+struct StageContext {
+    index: usize,
+    // The index of the branch in the stage fanning out.
+    branch: Option<usize>,
+    last: bool,
+    // Zero means unlimited.
+    max_lines: usize,
+    // The output pane, where the shown stage also sends its output besides the next stage.
+    preview: mpsc::Sender<(Origin, String)>,
+    // The stage whose output is shown instead of the last one's, if any.
+    shown: watch::Receiver<Option<usize>>,
+    events: mpsc::Sender<(Instant, PipelineEvent)>,
+    started_at: Instant,
+    // Set when the stage is being stopped gracefully.
+    cancel: Option<watch::Receiver<bool>>,
+}
+
+impl StageContext {
+    fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|cancel| *cancel.borrow())
+    }
+
+    fn origin(&self) -> Origin {
+        Origin {
+            stage: self.index,
+            branch: self.branch,
+            stderr: false,
+        }
+    }
+
+    /// Sends the line to the output pane too if the stage is `shown`.
+    async fn send_preview(&self, line: &str, shown: Option<usize>) {
+        if !self.last && shown == Some(self.index) {
+            let _ = self.preview.send((self.origin(), line.to_string())).await;
+        }
+    }
+
+    /// Sends the line of the stderr straight to the output pane, whichever stage is shown,
+    /// instead of passing it to the next stage as the shell does without `|&`.
+    async fn send_stderr(&self, line: String) {
+        let origin = Origin {
+            stderr: true,
+            ..self.origin()
+        };
+        let _ = self.preview.send((origin, line)).await;
+    }
+
+    /// Returns whether the output of the last stage is discarded
+    /// while the output of another stage is `shown`.
+    fn hides_output(&self, shown: Option<usize>) -> bool {
+        self.last && shown.is_some()
+    }
+
+    async fn report(&self, event: PipelineEvent) {
+        debug_log::log(|| {
+            format!(
+                "stage {} (branch {:?}): {:?}",
+                self.index, self.branch, event
+            )
+        });
+        let _ = self.events.send((self.started_at, event)).await;
+    }
+
+    /// Reports the end of the stage.
+    async fn finish(&self, exit: Exit) {
+        if exit == Exit::LineLimit {
+            self.report(PipelineEvent::LineLimitReached(self.index))
+                .await;
+        }
+        self.report(PipelineEvent::StageExited(self.index, exit))
+            .await;
+        self.complete().await;
+    }
+
+    /// Reports the completion of the pipeline if this is the last stage.
+    async fn complete(&self) {
+        if self.last {
+            self.report(PipelineEvent::Completed).await;
+        }
+    }
+}
+
+async fn process_output(
+    mut child: Child,
     mut stdout_reader: Lines<BufReader<ChildStdout>>,
     mut stderr_reader: Lines<BufReader<ChildStderr>>,
-    tx: mpsc::Sender<String>,
-) -> JoinHandle<()> {
-    tokio::spawn(async move {
-        loop {
-            tokio::select! {
-                Ok(Some(out)) = stdout_reader.next_line() => {
-                    // Remove ANSI escape sequences and properly decode the byte array as UTF-8 string
-                    let stripped = strip_ansi_escapes::strip(&out);
-                    let decoded = String::from_utf8_lossy(&stripped).into_owned();
-                    let _ = tx.send(decoded).await;
-                },
-                Ok(Some(err)) = stderr_reader.next_line() => {
-                    let _ = tx.send(err).await;
-                },
-                else => {
-                    // NOTE: BufReader will be closed when the command is terminated.
-                    // Without a return here, all outputs may not be rendered correctly.
-                    // (they may not display properly unless the Enter key is pressed repeatedly)
-                    return;
+    tx: Sink,
+    stats: &StageStats,
+    // None not to capture the output.
+    capture: Option<&StageCapture>,
+    ctx: &StageContext,
+) -> Exit {
+    loop {
+        // Whether the next stage still reads the output.
+        let sent = tokio::select! {
+            Ok(Some(out)) = stdout_reader.next_line() => {
+                // Remove ANSI escape sequences and properly decode the byte array as UTF-8 string
+                let stripped = strip_ansi_escapes::strip(&out);
+                let decoded = String::from_utf8_lossy(&stripped).into_owned();
+                stats.record(&decoded);
+                let shown = match capture {
+                    Some(capture) => capture.record(&decoded, &ctx.shown),
+                    None => *ctx.shown.borrow(),
+                };
+                // Drain the rest of the output, which is no longer shown, once cancelled.
+                ctx.is_cancelled() || {
+                    ctx.send_preview(&decoded, shown).await;
+                    ctx.hides_output(shown) || tx.send(decoded).await
                 }
+            },
+            Ok(Some(err)) = stderr_reader.next_line() => {
+                // Counted, but not captured since it is not passed to the next stage.
+                stats.record(&err);
+                if !ctx.is_cancelled() {
+                    ctx.send_stderr(err).await;
+                }
+                true
+            },
+            else => {
+                // NOTE: BufReader will be closed when the command is terminated.
+                // Without a break here, all outputs may not be rendered correctly.
+                // (they may not display properly unless the Enter key is pressed repeatedly)
+                break;
             }
+        };
+        // Stop the command once the next stage has exited (e.g. `head`),
+        // which is not a failure like SIGPIPE in the shell.
+        if !sent {
+            let _ = child.kill().await;
+            return Exit::Success;
         }
-    })
+        // Stop reading from the runaway command (e.g. `yes`).
+        if ctx.max_lines > 0 && stats.get().0 >= ctx.max_lines {
+            let _ = child.kill().await;
+            return Exit::LineLimit;
+        }
+    }
+    let status = child.wait().await;
+    if let Some(capture) = capture
+        && !ctx.is_cancelled()
+    {
+        capture.complete.store(true, Ordering::Relaxed);
+    }
+    match status {
+        Ok(status) if status.success() => Exit::Success,
+        Ok(status) => match exit_signal(&status) {
+            Some(signal) => Exit::Killed(signal),
+            None => Exit::Failure(status.code()),
+        },
+        Err(_) => Exit::Failure(None),
+    }
+}
+
+/// Returns the signal which killed the command, if any.
+#[cfg(unix)]
+fn exit_signal(status: &std::process::ExitStatus) -> Option<i32> {
+    std::os::unix::process::ExitStatusExt::signal(status)
+}
+
+#[cfg(not(unix))]
+fn exit_signal(_status: &std::process::ExitStatus) -> Option<i32> {
+    None
+}
+
+/// Writes the lines from `rx` into the stdin of the command,
+/// and sends EOF once `rx` is closed or the stage is cancelled.
+/// Returns false if the command has stopped reading the input before that.
+async fn feed_input(
+    mut rx: mpsc::Receiver<String>,
+    mut stdin_writer: BufWriter<ChildStdin>,
+    mut cancel_rx: watch::Receiver<bool>,
+) -> bool {
+    let mut closed = true;
+    loop {
+        let line = tokio::select! {
+            line = rx.recv() => match line {
+                Some(line) => line,
+                None => break,
+            },
+            // Close the stdin also when cancelled, so that the command
+            // (e.g. `sort`, `tee`) flushes what it has received.
+            Ok(_) = cancel_rx.wait_for(|cancelled| *cancelled) => break,
+        };
+        let written = match stdin_writer
+            .write_all(format!("{}\n", line).as_bytes())
+            .await
+        {
+            Ok(()) => stdin_writer.flush().await,
+            Err(e) => Err(e),
+        };
+        // The command has exited without reading all the input (e.g. `head`).
+        // Dropping the receiver stops the previous stage in turn.
+        if written.is_err_and(|e| e.kind() == std::io::ErrorKind::BrokenPipe) {
+            closed = false;
+            break;
+        }
+    }
+    let _ = stdin_writer.shutdown().await;
+    closed
+}
+
+impl<S: StageKind> Stage<S> {
+    /// Aborts the stage if it is still running. The stages reading the input
+    /// close the stdin of the commands first to let them exit by themselves,
+    /// and are aborted if still running after STOP_TIMEOUT.
+    pub fn abort_if_running(&mut self) {
+        if self.waiter.is_finished() {
+            return;
+        }
+        let Some(cancel) = &self.cancel else {
+            self.waiter.abort();
+            return;
+        };
+        let _ = cancel.send(true);
+        let waiter = self.waiter.abort_handle();
+        tokio::spawn(async move {
+            tokio::time::sleep(STOP_TIMEOUT).await;
+            waiter.abort();
+        });
+    }
+
+    pub fn is_running(&self) -> bool {
+        !self.waiter.is_finished()
+    }
 }
 
 impl Stage<Head> {
-    pub fn spawn(cmd: &str, tx: mpsc::Sender<String>) -> anyhow::Result<Self> {
-        let command = parse_command(cmd)?;
-        let (_, stdout_reader, stderr_reader) = setup_command(command, false)?;
+    /// Spawns the head stage, whose stdin is fed from `stdin` if given (e.g. the input console),
+    /// or null otherwise.
+    fn spawn(
+        cmd: &StageCommand,
+        stdin: Option<mpsc::Receiver<String>>,
+        tx: Sink,
+        ctx: StageContext,
+    ) -> anyhow::Result<Self> {
+        let mut command = parse_command(&cmd.cmd)?;
+        command.envs(&cmd.env);
+        let (child, stdin_writer, stdout_reader, stderr_reader) =
+            setup_command(command, stdin.is_some())?;
+        let stats = Arc::new(StageStats::default());
+        let capture = Arc::new(StageCapture::default());
 
         Ok(Self {
-            waiter: spawn_process_output(stdout_reader, stderr_reader, tx),
+            waiter: tokio::spawn({
+                let stats = stats.clone();
+                let capture = capture.clone();
+                async move {
+                    let output = process_output(
+                        child,
+                        stdout_reader,
+                        stderr_reader,
+                        tx,
+                        &stats,
+                        Some(&capture),
+                        &ctx,
+                    );
+                    let exit = match stdin.zip(stdin_writer) {
+                        Some((rx, stdin_writer)) => {
+                            // Never cancelled, since the head is aborted at once.
+                            let (_cancel_tx, cancel_rx) = watch::channel(false);
+                            tokio::pin!(output);
+                            // Stop feeding the input once the command has exited.
+                            tokio::select! {
+                                exit = &mut output => exit,
+                                _ = feed_input(rx, stdin_writer, cancel_rx) => output.await,
+                            }
+                        }
+                        None => output.await,
+                    };
+                    stats.finish();
+                    ctx.finish(exit).await;
+                }
+            }),
+            cancel: None,
+            stats,
+            capture,
             _marker: PhantomData,
         })
     }
-
-    pub fn abort_if_running(&mut self) {
-        self.waiter.abort();
-    }
 }
 
 impl Stage<Pipe> {
-    pub fn spawn(
-        cmd: &str,
-        mut rx: mpsc::Receiver<String>,
-        tx: mpsc::Sender<String>,
+    fn spawn(
+        cmd: &StageCommand,
+        rx: mpsc::Receiver<String>,
+        tx: Sink,
+        mut ctx: StageContext,
     ) -> anyhow::Result<Self> {
-        let command = parse_command(cmd)?;
-        let (stdin_writer, stdout_reader, stderr_reader) = setup_command(command, true)?;
-        let mut stdin_writer = stdin_writer.expect("stdin should be available for Pipe stage");
+        let mut command = parse_command(&cmd.cmd)?;
+        command.envs(&cmd.env);
+        let (child, stdin_writer, stdout_reader, stderr_reader) = setup_command(command, true)?;
+        let stdin_writer = stdin_writer.expect("stdin should be available for Pipe stage");
+        let stats = Arc::new(StageStats::default());
+        let capture = Arc::new(StageCapture::default());
+        let (cancel_tx, cancel_rx) = watch::channel(false);
+        ctx.cancel = Some(cancel_rx.clone());
 
+        // Note: Do not spawn the input and output as separate tasks,
+        // so that aborting the waiter also terminates both of them.
+        let output_stats = stats.clone();
+        let output_capture = capture.clone();
         let waiter = tokio::spawn(async move {
-            let input_task = tokio::spawn(async move {
-                while let Some(line) = rx.recv().await {
-                    let _ = stdin_writer
-                        .write_all(format!("{}\n", line).as_bytes())
-                        .await;
-                    let _ = stdin_writer.flush().await;
+            let output = async {
+                let exit = process_output(
+                    child,
+                    stdout_reader,
+                    stderr_reader,
+                    tx,
+                    &output_stats,
+                    Some(&output_capture),
+                    &ctx,
+                )
+                .await;
+                output_stats.finish();
+                exit
+            };
+            tokio::pin!(output);
+
+            // Stop feeding the input once the command has exited, which stops the previous
+            // stage in turn, and tell whether it has exited before reading all the input.
+            let (exit, input_closed) = tokio::select! {
+                exit = &mut output => (exit, false),
+                closed = feed_input(rx, stdin_writer, cancel_rx) => (output.await, closed),
+            };
+            // The pipeline has been aborted, so nothing is reported.
+            if ctx.is_cancelled() {
+                return;
+            }
+            match (exit, input_closed) {
+                (Exit::Failure(code), false) => {
+                    ctx.report(PipelineEvent::Broken(ctx.index, code)).await
                 }
-                let _ = stdin_writer.flush().await;
+                (Exit::Killed(_), false) => {
+                    ctx.report(PipelineEvent::Broken(ctx.index, None)).await
+                }
+                _ => {}
+            }
+            ctx.finish(exit).await;
+        });
+
+        Ok(Self {
+            waiter,
+            cancel: Some(cancel_tx),
+            stats,
+            capture,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl Stage<FanOut> {
+    /// Spawns the commands separated by `|>` as the branches, which all receive the input,
+    /// and send their output to `tx` together.
+    /// The output is not captured, so the stage is never reused on the next run.
+    fn spawn(
+        cmd: &StageCommand,
+        mut rx: mpsc::Receiver<String>,
+        tx: Sink,
+        mut ctx: StageContext,
+    ) -> anyhow::Result<Self> {
+        // The lines and bytes are summed up over the branches.
+        let stats = Arc::new(StageStats::default());
+        let (cancel_tx, mut cancel_rx) = watch::channel(false);
+        ctx.cancel = Some(cancel_rx.clone());
+
+        let mut branch_txs = vec![];
+        let mut branches = vec![];
+        for (branch, branch_cmd) in split_fan_out(&cmd.cmd).iter().enumerate() {
+            let mut command = parse_command(branch_cmd)?;
+            command.envs(&cmd.env);
+            let (child, stdin_writer, stdout_reader, stderr_reader) = setup_command(command, true)?;
+            let stdin_writer = stdin_writer.expect("stdin should be available for FanOut stage");
+            let (branch_tx, branch_rx) = mpsc::channel::<String>(100);
+            branch_txs.push(branch_tx);
+
+            let tx = tx.for_branch(branch);
+            let ctx = StageContext {
+                branch: Some(branch),
+                ..ctx.clone()
+            };
+            let stats = stats.clone();
+            let cancel_rx = cancel_rx.clone();
+            branches.push(async move {
+                let input = feed_input(branch_rx, stdin_writer, cancel_rx);
+                let output =
+                    process_output(child, stdout_reader, stderr_reader, tx, &stats, None, &ctx);
+                let (_, exit) = tokio::join!(input, output);
+                exit
             });
+        }
 
-            let output_task = spawn_process_output(stdout_reader, stderr_reader, tx);
+        let output_stats = stats.clone();
+        let waiter = tokio::spawn(async move {
+            let distribute = async move {
+                loop {
+                    let line = tokio::select! {
+                        line = rx.recv() => match line {
+                            Some(line) => line,
+                            None => break,
+                        },
+                        Ok(_) = cancel_rx.wait_for(|cancelled| *cancelled) => break,
+                    };
+                    // Stop sending to the branches which no longer read the input,
+                    // and stop the previous stage once none of them does.
+                    let mut open_txs = vec![];
+                    for branch_tx in branch_txs {
+                        if branch_tx.send(line.clone()).await.is_ok() {
+                            open_txs.push(branch_tx);
+                        }
+                    }
+                    if open_txs.is_empty() {
+                        break;
+                    }
+                    branch_txs = open_txs;
+                }
+                // Dropping the senders closes the stdin of the branches.
+            };
 
-            let _ = tokio::join!(input_task, output_task);
+            let (_, results) = tokio::join!(distribute, futures::future::join_all(branches));
+            output_stats.finish();
+            // The pipeline has been aborted, so nothing is reported.
+            if ctx.is_cancelled() {
+                return;
+            }
+            // Stopped if any branch has reached the maximum lines, or failed if any has failed.
+            let exit = results
+                .into_iter()
+                .max_by_key(|exit| match exit {
+                    Exit::Success => 0,
+                    Exit::Failure(_) | Exit::Killed(_) => 1,
+                    Exit::LineLimit => 2,
+                })
+                .unwrap_or(Exit::Success);
+            ctx.finish(exit).await;
         });
 
         Ok(Self {
             waiter,
+            cancel: Some(cancel_tx),
+            stats,
+            capture: Arc::new(StageCapture::disabled()),
             _marker: PhantomData,
         })
     }
+}
 
-    pub fn abort_if_running(&mut self) {
+/// A stage reading the output of the previous one.
+enum Downstream {
+    Pipe(Stage<Pipe>),
+    FanOut(Stage<FanOut>),
+}
+
+impl Downstream {
+    fn outputs(&self) -> (&Arc<StageStats>, &Arc<StageCapture>) {
+        match self {
+            Downstream::Pipe(stage) => (&stage.stats, &stage.capture),
+            Downstream::FanOut(stage) => (&stage.stats, &stage.capture),
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        match self {
+            Downstream::Pipe(stage) => stage.is_running(),
+            Downstream::FanOut(stage) => stage.is_running(),
+        }
+    }
+
+    fn abort_if_running(&mut self) {
+        match self {
+            Downstream::Pipe(stage) => stage.abort_if_running(),
+            Downstream::FanOut(stage) => stage.abort_if_running(),
+        }
+    }
+}
+
+/// Feeds the captured output of the stages reused from the previous run
+/// into the first spawned one, in place of spawning the head.
+struct CachedHead {
+    waiter: JoinHandle<()>,
+}
+
+impl CachedHead {
+    /// Replays the lines, reporting the completion as the stage in `ctx`.
+    fn spawn(lines: Vec<String>, tx: Sink, ctx: StageContext) -> Self {
+        Self {
+            waiter: tokio::spawn(async move {
+                for line in lines {
+                    if !ctx.hides_output(*ctx.shown.borrow()) && !tx.send(line).await {
+                        return;
+                    }
+                }
+                ctx.complete().await;
+            }),
+        }
+    }
+
+    fn abort_if_running(&mut self) {
         self.waiter.abort();
     }
+
+    fn is_running(&self) -> bool {
+        !self.waiter.is_finished()
+    }
 }
 
 pub struct Pipeline {
+    cmds: Vec<StageCommand>,
+    // The (stats, capture) of the unchanged stages reused from the previous run.
+    reused: Vec<(Arc<StageStats>, Arc<StageCapture>)>,
+    cached_head: Option<CachedHead>,
     head: Option<Stage<Head>>,
-    pipes: Vec<Stage<Pipe>>,
+    pipes: Vec<Downstream>,
+    // Whether the head stage reads the lines given to `Pipeline::spawn`,
+    // which the output of it depends on besides the command.
+    head_fed: bool,
+    // The stage whose output is shown instead of the last one's, if any.
+    shown: watch::Sender<Option<usize>>,
+    started_at: Instant,
 }
 
 impl Pipeline {
-    pub fn spawn(cmds: Vec<String>, tx: mpsc::Sender<String>) -> anyhow::Result<Self> {
+    /// Spawns the stages, which send the output to `tx` with the index of the stage,
+    /// and report the events to `events` with the instant the pipeline started at.
+    /// Each stage is stopped after `max_lines_per_stage` lines (zero means unlimited).
+    /// Given `reuse` as (previous pipeline, n), the first n stages are not spawned again,
+    /// and their captured output is replayed instead (see `Pipeline::reusable_stages`),
+    /// unless `head_stdin` is given, which the captured output did not come from.
+    /// Given the `preview` stage, its output is sent to `tx` instead of the last one's,
+    /// while it is still passed to the next stage (see also `Pipeline::show_stage`).
+    /// Given `head_stdin`, the lines from it are written into the stdin of the head stage.
+    #[allow(clippy::too_many_arguments)]
+    pub fn spawn(
+        cmds: Vec<String>,
+        tx: mpsc::Sender<(Origin, String)>,
+        events: mpsc::Sender<(Instant, PipelineEvent)>,
+        max_lines_per_stage: usize,
+        reuse: Option<(&Pipeline, usize)>,
+        preview: Option<usize>,
+        mut head_stdin: Option<mpsc::Receiver<String>>,
+    ) -> anyhow::Result<Self> {
+        let cmds = resolve_commands(&cmds)?;
         if cmds.is_empty() {
             return Err(anyhow::anyhow!("No commands provided"));
         }
+        let reuse = reuse.filter(|_| head_stdin.is_none());
+        debug_log::log(|| {
+            format!(
+                "spawning the pipeline {:?} (reusing {} stages, preview {:?})",
+                cmds.iter().map(|cmd| &cmd.cmd).collect::<Vec<_>>(),
+                reuse.map_or(0, |(_, reused)| reused),
+                preview
+            )
+        });
 
+        let started_at = Instant::now();
+        // Previewing the last stage is the same as usual.
+        let preview = preview.filter(|preview| preview + 1 < cmds.len());
+        let (shown, shown_rx) = watch::channel(preview);
         let mut pipeline = Self {
+            cmds: cmds.clone(),
+            reused: Vec::new(),
+            cached_head: None,
             head: None,
             pipes: Vec::new(),
+            head_fed: head_stdin.is_some(),
+            shown,
+            started_at,
+        };
+        // Discarded while another stage is shown.
+        let last_tx = Sink::Output(Origin::from(cmds.len() - 1), tx.clone());
+        let ctx = |index: usize| StageContext {
+            index,
+            branch: None,
+            last: index + 1 == cmds.len(),
+            max_lines: max_lines_per_stage,
+            preview: tx.clone(),
+            shown: shown_rx.clone(),
+            events: events.clone(),
+            started_at,
+            cancel: None,
         };
 
-        if cmds.len() == 1 {
-            let head = Stage::<Head>::spawn(&cmds[0], tx)?;
-            pipeline.head = Some(head);
-            return Ok(pipeline);
+        let mut prev_rx = None;
+        if let Some((prev, reused)) = reuse
+            // The preview stage is run again to send its output.
+            && let reused = reused.min(preview.unwrap_or(usize::MAX))
+            && reused <= cmds.len()
+            && let Some((_, capture)) = reused.checked_sub(1).and_then(|i| prev.outputs().nth(i))
+            && let Some(lines) = capture.lines()
+        {
+            pipeline.reused = prev
+                .outputs()
+                .take(reused)
+                .map(|(stats, capture)| (stats.clone(), capture.clone()))
+                .collect();
+            // Replay into the output directly if all the stages are reused.
+            let replay_tx = if reused == cmds.len() {
+                Sink::Output(Origin::from(reused - 1), tx.clone())
+            } else {
+                let (replay_tx, replay_rx) = mpsc::channel::<String>(100);
+                prev_rx = Some(replay_rx);
+                Sink::Stage(replay_tx)
+            };
+            pipeline.cached_head = Some(CachedHead::spawn(lines, replay_tx, ctx(reused - 1)));
         }
 
-        let (prev_tx, mut prev_rx) = mpsc::channel::<String>(100);
+        for (i, cmd) in cmds.iter().enumerate().skip(pipeline.reused.len()) {
+            let (next_tx, next_rx) = if i + 1 == cmds.len() {
+                (last_tx.clone(), None)
+            } else {
+                let (next_tx, next_rx) = mpsc::channel::<String>(100);
+                (Sink::Stage(next_tx), Some(next_rx))
+            };
+            let fan_out = split_fan_out(&cmd.cmd).len() > 1;
+            match prev_rx {
+                None if fan_out => anyhow::bail!("The head stage cannot fan out with |>"),
+                None => {
+                    pipeline.head = Some(Stage::<Head>::spawn(
+                        cmd,
+                        head_stdin.take(),
+                        next_tx,
+                        ctx(i),
+                    )?)
+                }
+                Some(rx) if fan_out => {
+                    pipeline
+                        .pipes
+                        .push(Downstream::FanOut(Stage::<FanOut>::spawn(
+                            cmd,
+                            rx,
+                            next_tx,
+                            ctx(i),
+                        )?))
+                }
+                Some(rx) => pipeline.pipes.push(Downstream::Pipe(Stage::<Pipe>::spawn(
+                    cmd,
+                    rx,
+                    next_tx,
+                    ctx(i),
+                )?)),
+            }
+            prev_rx = next_rx;
+        }
 
-        let head = Stage::<Head>::spawn(&cmds[0], prev_tx)?;
-        pipeline.head = Some(head);
+        Ok(pipeline)
+    }
 
-        for cmd in cmds.iter().take(cmds.len() - 1).skip(1) {
-            let (next_tx, next_rx) = mpsc::channel::<String>(100);
-            let tx_clone = next_tx.clone();
-            let pipe = Stage::<Pipe>::spawn(cmd, prev_rx, tx_clone)?;
-            pipeline.pipes.push(pipe);
-            prev_rx = next_rx;
+    /// Returns the (stats, capture) of all the stages, in the order of the stages.
+    fn outputs(&self) -> impl Iterator<Item = (&Arc<StageStats>, &Arc<StageCapture>)> {
+        self.reused
+            .iter()
+            .map(|(stats, capture)| (stats, capture))
+            .chain(self.head.iter().map(|head| (&head.stats, &head.capture)))
+            .chain(self.pipes.iter().map(Downstream::outputs))
+    }
+
+    /// Returns the number of the leading stages which are the same as `cmds`
+    /// and have completed with all their output captured, if `cache_stages` is set.
+    /// If no stage is changed, returns zero so that pressing Enter again re-runs them all.
+    /// With `cache_head`, returns one to reuse only the head if it is unchanged
+    /// and no other stage is reused.
+    pub fn reusable_stages(&self, cmds: &[String], cache_stages: bool, cache_head: bool) -> usize {
+        let Ok(cmds) = resolve_commands(cmds) else {
+            return 0;
+        };
+        if self.head_fed {
+            return 0;
         }
+        let reusable = self
+            .cmds
+            .iter()
+            .zip(&cmds)
+            .zip(self.outputs())
+            .take_while(|((prev, cmd), (_, capture))| prev == cmd && capture.is_complete())
+            .count();
+        match reusable {
+            reusable if cache_stages && reusable < cmds.len() => reusable,
+            reusable if cache_head && reusable > 0 => 1,
+            _ => 0,
+        }
+    }
 
-        let last_pipe = Stage::<Pipe>::spawn(&cmds[cmds.len() - 1], prev_rx, tx)?;
-        pipeline.pipes.push(last_pipe);
+    /// Returns the program of the stage (0-based), e.g. `jq` of `jq .a`.
+    pub fn program(&self, stage: usize) -> Option<&str> {
+        self.cmds.get(stage)?.cmd.split_whitespace().next()
+    }
 
-        Ok(pipeline)
+    /// Returns the stage (0-based) whose output is shown, which is the last one by default.
+    pub fn shown_stage(&self) -> usize {
+        self.shown
+            .borrow()
+            .unwrap_or_else(|| self.cmds.len().saturating_sub(1))
+    }
+
+    /// Shows the output of the stage (0-based) from now on, instead of the last one's,
+    /// and returns the lines it has emitted so far with the number of the earlier ones
+    /// evicted over the size to capture, or None if they are not kept
+    /// (from the stage fanning out).
+    pub fn show_stage(&self, stage: usize) -> Option<(Vec<String>, usize)> {
+        let switch = || {
+            self.shown
+                .send_replace(Some(stage).filter(|stage| stage + 1 < self.cmds.len()));
+        };
+        match self.outputs().nth(stage) {
+            Some((_, capture)) => capture.switch(switch),
+            None => {
+                switch();
+                None
+            }
+        }
+    }
+
+    /// Returns the lines the last stage of `cmds` has emitted so far with the number of
+    /// the earlier ones evicted over the size to capture, if this pipeline has started
+    /// with the same stages as `cmds`, or None otherwise or if they are not kept.
+    pub fn output_of(&self, cmds: &[String]) -> Option<(Vec<String>, usize)> {
+        let cmds = resolve_commands(cmds).ok()?;
+        if cmds.is_empty() || !self.cmds.starts_with(&cmds) {
+            return None;
+        }
+        self.outputs().nth(cmds.len() - 1)?.1.snapshot()
+    }
+
+    /// Returns the number of the stages whose output is replayed from the previous run.
+    pub fn reused_stages(&self) -> usize {
+        self.reused.len()
+    }
+
+    pub fn started_at(&self) -> Instant {
+        self.started_at
+    }
+
+    /// Returns the (lines, bytes) each stage has emitted, in the order of the stages.
+    pub fn stats(&self) -> Vec<(usize, usize)> {
+        self.outputs().map(|(stats, _)| stats.get()).collect()
+    }
+
+    /// Returns the (elapsed time, finished) of each stage, in the order of the stages.
+    pub fn timings(&self) -> Vec<(Duration, bool)> {
+        self.outputs().map(|(stats, _)| stats.elapsed()).collect()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.cached_head
+            .as_ref()
+            .is_some_and(CachedHead::is_running)
+            || self.head.as_ref().is_some_and(Stage::<Head>::is_running)
+            || self.pipes.iter().any(Downstream::is_running)
     }
 
     pub fn abort_all(&mut self) {
+        if let Some(cached_head) = &mut self.cached_head {
+            cached_head.abort_if_running();
+        }
         if let Some(head) = &mut self.head {
             head.abort_if_running();
         }
@@ -220,3 +1305,546 @@ impl Pipeline {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_pipeline() {
+        assert_eq!(
+            split_pipeline("cat x | grep 'a|b' | sed \"s/|/-/\" \\\n  | sort"),
+            vec!["cat x", "grep 'a|b'", "sed \"s/|/-/\"", "sort"],
+        );
+        assert_eq!(
+            split_pipeline("cat file.txt | grep \"a|b\" | sort"),
+            vec!["cat file.txt", "grep \"a|b\"", "sort"],
+        );
+        assert_eq!(split_pipeline("echo a\\|b"), vec!["echo a\\|b"]);
+        assert_eq!(split_pipeline("ls -l"), vec!["ls -l"]);
+    }
+
+    #[test]
+    fn test_split_fan_out() {
+        assert_eq!(
+            split_pipeline("curl x | jq .a |> jq '.b |> .c'"),
+            vec!["curl x", "jq .a |> jq '.b |> .c'"],
+        );
+        assert_eq!(
+            split_fan_out("jq .a |> jq '.b |> .c' | x"),
+            vec!["jq .a", "jq '.b |> .c' | x"],
+        );
+        assert_eq!(split_fan_out("sort"), vec!["sort"]);
+    }
+
+    #[test]
+    fn test_describe_argv() {
+        assert_eq!(
+            describe_argv("awk {print $1}").unwrap(),
+            r#"["awk", "{print", "$1}"]"#
+        );
+        assert_eq!(
+            describe_argv("awk '{print $1}' |> grep \"a b\"").unwrap(),
+            r#"["awk", "{print $1}"] |> ["grep", "a b"]"#
+        );
+        assert_eq!(
+            describe_argv("SET NAME='a b'").unwrap(),
+            r#"SET NAME="a b""#
+        );
+        assert!(describe_argv("echo 'a").is_err());
+        assert!(describe_argv("SET NAME").is_err());
+    }
+
+    #[test]
+    fn test_pipe_follows() {
+        assert!(pipe_follows("grep a"));
+        assert!(pipe_follows("grep 'a|b' "));
+        assert!(!pipe_follows("grep 'a"));
+        assert!(!pipe_follows("sed \"s/"));
+        assert!(!pipe_follows("echo a\\"));
+    }
+
+    #[tokio::test]
+    async fn test_head_stdin() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let (events_tx, _events_rx) = mpsc::channel(10);
+        let (stdin_tx, stdin_rx) = mpsc::channel(10);
+        let pipeline = Pipeline::spawn(
+            vec![String::from("cat"), String::from("cat")],
+            tx,
+            events_tx,
+            0,
+            None,
+            None,
+            Some(stdin_rx),
+        )
+        .unwrap();
+
+        stdin_tx.send(String::from("a")).await.unwrap();
+        // The line is passed through while the head is still running.
+        assert_eq!(rx.recv().await, Some((Origin::from(1), String::from("a"))));
+        // Closing the input lets the head exit.
+        drop(stdin_tx);
+        assert_eq!(rx.recv().await, None);
+        // The output depends on the input besides the commands.
+        let cmds = ["cat", "cat", "wc -l"].map(String::from);
+        assert_eq!(pipeline.reusable_stages(&cmds, true, true), 0);
+    }
+
+    #[tokio::test]
+    async fn test_fan_out() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let (events_tx, mut events_rx) = mpsc::channel(10);
+        let _pipeline = Pipeline::spawn(
+            vec![
+                String::from("printf 'a\\nb\\n'"),
+                String::from("sed s/^/x/ |> sed s/^/y/"),
+            ],
+            tx,
+            events_tx,
+            0,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+
+        let mut lines = vec![];
+        while let Some(line) = rx.recv().await {
+            lines.push(line);
+        }
+        let branch = |branch: usize, line: &str| {
+            (
+                Origin {
+                    branch: Some(branch),
+                    ..Origin::from(1)
+                },
+                String::from(line),
+            )
+        };
+        // The branches run concurrently, but each keeps the order of the lines.
+        let of = |i: usize| {
+            lines
+                .iter()
+                .filter(|(origin, _)| origin.branch == Some(i))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(of(0), vec![branch(0, "xa"), branch(0, "xb")]);
+        assert_eq!(of(1), vec![branch(1, "ya"), branch(1, "yb")]);
+        let mut events = vec![];
+        while let Some((_, event)) = events_rx.recv().await {
+            events.push(event);
+        }
+        assert!(events.contains(&PipelineEvent::StageExited(1, Exit::Success)));
+        assert!(events.contains(&PipelineEvent::Completed));
+    }
+
+    #[test]
+    fn test_join_lines() {
+        assert_eq!(
+            join_lines("jq '.items[]\n  | .name'\n  -r"),
+            "jq '.items[]\n  | .name'   -r"
+        );
+        assert_eq!(join_lines("grep \\\n  foo"), "grep    foo");
+        assert_eq!(join_lines("echo \"a\nb\" \\n"), "echo \"a\nb\" \\n");
+    }
+
+    #[tokio::test]
+    async fn test_max_lines_per_stage() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let (events_tx, mut events_rx) = mpsc::channel(10);
+        let pipeline = Pipeline::spawn(
+            vec![String::from("yes"), String::from("grep -c y")],
+            tx,
+            events_tx,
+            5,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+
+        let mut lines = vec![];
+        while let Some(line) = rx.recv().await {
+            lines.push(line);
+        }
+        assert_eq!(lines, vec![(Origin::from(1), String::from("5"))]);
+        let mut events = vec![];
+        while let Some((started_at, event)) = events_rx.recv().await {
+            assert_eq!(started_at, pipeline.started_at());
+            events.push(event);
+        }
+        // The stages report concurrently.
+        assert_eq!(events.len(), 4);
+        for event in [
+            PipelineEvent::Completed,
+            PipelineEvent::LineLimitReached(0),
+            PipelineEvent::StageExited(0, Exit::LineLimit),
+            PipelineEvent::StageExited(1, Exit::Success),
+        ] {
+            assert!(events.contains(&event), "{:?} in {:?}", event, events);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_broken() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let (events_tx, mut events_rx) = mpsc::channel(10);
+        let _pipeline = Pipeline::spawn(
+            vec![
+                String::from("yes"),
+                String::from("sh -c 'echo oops >&2; exit 3'"),
+                String::from("sed s/^/piped:/"),
+            ],
+            tx,
+            events_tx,
+            0,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+
+        let mut lines = vec![];
+        while let Some(line) = rx.recv().await {
+            lines.push(line);
+        }
+        // The stderr of the failed stage is still shown as its own,
+        // without going through the next stage.
+        assert_eq!(
+            lines,
+            vec![(
+                Origin {
+                    stderr: true,
+                    ..Origin::from(1)
+                },
+                String::from("oops")
+            )]
+        );
+        let mut events = vec![];
+        while let Some((_, event)) = events_rx.recv().await {
+            events.push(event);
+        }
+        for event in [
+            PipelineEvent::Broken(1, Some(3)),
+            PipelineEvent::StageExited(0, Exit::Success),
+            PipelineEvent::StageExited(1, Exit::Failure(Some(3))),
+            PipelineEvent::Completed,
+        ] {
+            assert!(events.contains(&event), "{:?} in {:?}", event, events);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_killed() {
+        let (tx, _rx) = mpsc::channel(10);
+        let (events_tx, mut events_rx) = mpsc::channel(10);
+        let _pipeline = Pipeline::spawn(
+            vec![String::from("sh -c 'kill -9 $$'")],
+            tx,
+            events_tx,
+            0,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+
+        let mut events = vec![];
+        while let Some((_, event)) = events_rx.recv().await {
+            events.push(event);
+        }
+        assert!(events.contains(&PipelineEvent::StageExited(0, Exit::Killed(9))));
+        assert_eq!(Exit::Killed(9).code(), 137);
+    }
+
+    #[tokio::test]
+    async fn test_stderr_to_output() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let (events_tx, _events_rx) = mpsc::channel(10);
+        let _pipeline = Pipeline::spawn(
+            vec![
+                String::from("sh -c 'echo out; echo err >&2'"),
+                String::from("sed s/^/piped:/"),
+            ],
+            tx,
+            events_tx,
+            0,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+
+        let mut lines = vec![];
+        while let Some(line) = rx.recv().await {
+            lines.push(line);
+        }
+        lines.sort_by_key(|(origin, _)| origin.stage);
+        // The stderr of the head is not piped into the next stage.
+        assert_eq!(
+            lines,
+            vec![
+                (
+                    Origin {
+                        stderr: true,
+                        ..Origin::from(0)
+                    },
+                    String::from("err")
+                ),
+                (Origin::from(1), String::from("piped:out")),
+            ]
+        );
+    }
+
+    async fn collect(
+        cmds: &[&str],
+        prev: Option<&Pipeline>,
+        cache_head: bool,
+    ) -> (Pipeline, Vec<String>) {
+        let cmds: Vec<String> = cmds.iter().map(|cmd| cmd.to_string()).collect();
+        let (tx, mut rx) = mpsc::channel(10);
+        let (events_tx, _events_rx) = mpsc::channel(10);
+        let reuse = prev.map(|prev| (prev, prev.reusable_stages(&cmds, true, cache_head)));
+        let pipeline = Pipeline::spawn(cmds, tx, events_tx, 0, reuse, None, None).unwrap();
+        let mut lines = vec![];
+        while let Some((_, line)) = rx.recv().await {
+            lines.push(line);
+        }
+        (pipeline, lines)
+    }
+
+    #[tokio::test]
+    async fn test_reuse_unchanged_stages() {
+        // The head prints a different pid every time it is spawned.
+        let head = "sh -c 'echo $$'";
+        let (first, lines) = collect(&[head, "cat"], None, false).await;
+        let pid = lines[0].clone();
+
+        assert_eq!(
+            first.reusable_stages(&[head.to_string(), "cat".to_string()], true, false),
+            0
+        );
+        assert_eq!(
+            first.reusable_stages(&["ls".to_string(), "cat".to_string()], true, false),
+            0
+        );
+        assert_eq!(
+            first.reusable_stages(&[head.to_string(), "wc".to_string()], true, false),
+            1
+        );
+        // Nothing is reused unless opted in.
+        assert_eq!(
+            first.reusable_stages(&[head.to_string(), "wc".to_string()], false, false),
+            0
+        );
+
+        let (second, lines) = collect(&[head, "sed s/^/pid:/"], Some(&first), false).await;
+        assert_eq!(second.reused_stages(), 1);
+        assert_eq!(lines, vec![format!("pid:{}", pid)]);
+        assert_eq!(second.stats()[0], first.stats()[0]);
+
+        // The reused stages can be reused again.
+        let (third, lines) = collect(&[head, "sed s/^/pid:/", "cat"], Some(&second), false).await;
+        assert_eq!(third.reused_stages(), 2);
+        assert_eq!(lines, vec![format!("pid:{}", pid)]);
+
+        // Nothing is reused if the head reads the given lines this time.
+        let cmds = [head, "sed s/^/pid:/", "sed s/^/fed:/"]
+            .map(String::from)
+            .to_vec();
+        let (tx, mut rx) = mpsc::channel(10);
+        let (events_tx, _events_rx) = mpsc::channel(10);
+        let (stdin_tx, stdin_rx) = mpsc::channel(10);
+        drop(stdin_tx);
+        assert_eq!(third.reusable_stages(&cmds, true, false), 2);
+        let reuse = Some((&third, 2));
+        let fourth = Pipeline::spawn(cmds, tx, events_tx, 0, reuse, None, Some(stdin_rx)).unwrap();
+        assert_eq!(fourth.reused_stages(), 0);
+        let (_, line) = rx.recv().await.unwrap();
+        assert_ne!(line, format!("fed:pid:{}", pid));
+    }
+
+    #[tokio::test]
+    async fn test_downstream_exits_early() {
+        let (pipeline, lines) = collect(&["seq 1000000", "head -n 3"], None, false).await;
+        assert_eq!(lines, vec!["1", "2", "3"]);
+        // The head is stopped instead of producing the whole output.
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while pipeline.is_running() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(pipeline.stats()[0].0 < 1000000);
+    }
+
+    #[tokio::test]
+    async fn test_abort_closes_stdin() {
+        let path = std::env::temp_dir().join(format!("epiq-test-{}", std::process::id()));
+        let (tx, _rx) = mpsc::channel(10);
+        let (events_tx, _events_rx) = mpsc::channel(10);
+        let mut pipeline = Pipeline::spawn(
+            vec![
+                String::from("sh -c 'printf \"b\\na\\n\"; sleep 10'"),
+                format!("sort -o {}", path.display()),
+            ],
+            tx,
+            events_tx,
+            0,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        while pipeline.stats()[0].0 < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // `sort` writes the lines it has received on EOF instead of being killed.
+        pipeline.abort_all();
+        tokio::time::sleep(STOP_TIMEOUT * 2).await;
+        let sorted = std::fs::read_to_string(&path).unwrap_or_default();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(sorted, "a\nb\n");
+        assert!(!pipeline.is_running());
+    }
+
+    #[tokio::test]
+    async fn test_set_command() {
+        let (pipeline, lines) = collect(
+            &[
+                "SET GREETING=hello",
+                "sh -c 'echo $GREETING $NAME'",
+                "SET NAME='big world'",
+                "sh -c 'cat; echo $GREETING $NAME'",
+            ],
+            None,
+            false,
+        )
+        .await;
+        assert_eq!(lines, vec!["hello", "hello big world"]);
+        // The SET stages are not spawned.
+        assert_eq!(pipeline.stats().len(), 2);
+        assert!(parse_set_command("SET NAME").is_err());
+        assert!(parse_set_command("SET =value").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_preview() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let (events_tx, _events_rx) = mpsc::channel(10);
+        let _pipeline = Pipeline::spawn(
+            vec![
+                String::from("printf 'a\\nb\\n'"),
+                String::from("sed s/^/x/"),
+                String::from("wc -l"),
+            ],
+            tx,
+            events_tx,
+            0,
+            None,
+            Some(1),
+            None,
+        )
+        .unwrap();
+
+        let mut lines = vec![];
+        while let Some(line) = rx.recv().await {
+            lines.push(line);
+        }
+        assert_eq!(
+            lines,
+            vec![
+                (Origin::from(1), String::from("xa")),
+                (Origin::from(1), String::from("xb"))
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_show_stage() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let (events_tx, _events_rx) = mpsc::channel(10);
+        let (stdin_tx, stdin_rx) = mpsc::channel(10);
+        let pipeline = Pipeline::spawn(
+            vec![String::from("cat"), String::from("cat")],
+            tx,
+            events_tx,
+            0,
+            None,
+            None,
+            Some(stdin_rx),
+        )
+        .unwrap();
+        assert_eq!(pipeline.shown_stage(), 1);
+
+        stdin_tx.send(String::from("a")).await.unwrap();
+        assert_eq!(rx.recv().await, Some((Origin::from(1), String::from("a"))));
+        assert_eq!(pipeline.show_stage(0), Some((vec![String::from("a")], 0)));
+        assert_eq!(
+            pipeline.output_of(&[String::from("cat"), String::from("SET X=1")]),
+            Some((vec![String::from("a")], 0))
+        );
+        assert_eq!(pipeline.output_of(&[String::from("sort")]), None);
+        assert_eq!(pipeline.shown_stage(), 0);
+        // The output of the head streams instead of the last one's.
+        stdin_tx.send(String::from("b")).await.unwrap();
+        assert_eq!(rx.recv().await, Some((Origin::from(0), String::from("b"))));
+        // The output of the last stage is discarded meanwhile.
+        drop(stdin_tx);
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[test]
+    fn test_captured_push() {
+        let total = AtomicUsize::new(4);
+        let mut captured = Captured::default();
+        for line in ["a", "bb", "c"] {
+            captured.push(line, &total, 10);
+        }
+        // The other captures hold 4 bytes, so only 6 bytes are left for this one.
+        assert_eq!(captured.lines, ["bb", "c"]);
+        assert_eq!((captured.bytes, captured.evicted), (5, 1));
+        assert_eq!(total.load(Ordering::Relaxed), 9);
+        // The line over the budget by itself is evicted at once.
+        captured.push("0123456789", &total, 10);
+        assert!(captured.lines.is_empty());
+        assert_eq!((captured.bytes, captured.evicted), (0, 4));
+        assert_eq!(total.load(Ordering::Relaxed), 4);
+    }
+
+    #[tokio::test]
+    async fn test_cache_head() {
+        let head = "sh -c 'echo $$'";
+        let (first, lines) = collect(&[head], None, true).await;
+
+        // The head is replayed even if no stage is changed.
+        let (second, replayed) = collect(&[head], Some(&first), true).await;
+        assert_eq!(second.reused_stages(), 1);
+        assert_eq!(replayed, lines);
+
+        let (third, _) = collect(&[head, "cat"], Some(&second), true).await;
+        assert_eq!(
+            third.reusable_stages(&[head.to_string(), "cat".to_string()], true, true),
+            1
+        );
+        assert_eq!(
+            third.reusable_stages(&[head.to_string(), "cat".to_string()], true, false),
+            0
+        );
+        // Only the head is reused without `cache_stages`.
+        let (fourth, _) = collect(&[head, "cat", "cat"], Some(&third), true).await;
+        assert_eq!(
+            fourth.reusable_stages(
+                &[head.to_string(), "cat".to_string(), "wc".to_string()],
+                false,
+                true
+            ),
+            1
+        );
+    }
+}
@@ -0,0 +1,185 @@
+use std::path::Path;
+
+use crate::pipeline;
+
+/// A pipeline stage to be exported.
+pub struct StageSpec {
+    pub cmd: String,
+    pub ignore: bool,
+    pub comment: Option<String>,
+}
+
+/// Renders the stages as a shell script, one stage per line.
+///
+/// Ignored stages are commented out as `# cmd` above the pipeline,
+/// because comments cannot be placed between the continued lines.
+/// For the same reason, the comments of the other stages are put above the pipeline
+/// as `# cmd: comment`, except for the first stage.
+/// The `SET KEY=VALUE` stages become the assignments before the following commands.
+/// The fan-outs `a |> b` become `tee >(a) >(b) > /dev/null` in bash,
+/// since the shell has no fan-out.
+pub fn to_shell_script(stages: &[StageSpec]) -> String {
+    let mut script = String::new();
+
+    let stages = stages.iter().filter(|stage| !stage.cmd.trim().is_empty());
+    let (ignored, active): (Vec<_>, Vec<_>) = stages.partition(|stage| stage.ignore);
+
+    for stage in ignored {
+        if let Some(comment) = &stage.comment {
+            script.push_str(&format!("# {}\n", comment));
+        }
+        script.push_str(&format!("# {}\n", stage.cmd.trim()));
+    }
+    let mut assignments = String::new();
+    // The process substitutions of the fan-outs need bash.
+    let mut bash = false;
+    let active: Vec<(String, Option<&String>)> = active
+        .into_iter()
+        .filter_map(|stage| match pipeline::parse_set_command(&stage.cmd) {
+            Ok((key, value)) => {
+                let value = shlex::try_quote(&value).map_or(value.clone(), |v| v.into_owned());
+                assignments.push_str(&format!("{}={} ", key, value));
+                None
+            }
+            Err(_) => {
+                let branches = pipeline::split_fan_out(stage.cmd.trim());
+                let cmd = if branches.len() > 1 {
+                    bash = true;
+                    let outputs: Vec<String> = branches
+                        .iter()
+                        .map(|branch| format!(">({}{})", assignments, branch))
+                        .collect();
+                    format!("tee {} > /dev/null", outputs.join(" "))
+                } else {
+                    format!("{}{}", assignments, stage.cmd.trim())
+                };
+                Some((cmd, stage.comment.as_ref()))
+            }
+        })
+        .collect();
+    script.insert_str(0, if bash { "#!/bin/bash\n" } else { "#!/bin/sh\n" });
+
+    for (cmd, comment) in active.iter().skip(1) {
+        if let Some(comment) = comment {
+            script.push_str(&format!("# {}: {}\n", cmd, comment));
+        }
+    }
+    if let Some(comment) = active.first().and_then(|(_, comment)| *comment) {
+        script.push_str(&format!("# {}\n", comment));
+    }
+    let lines: Vec<String> = active
+        .iter()
+        .enumerate()
+        .map(|(i, (cmd, _))| match i {
+            0 => cmd.clone(),
+            _ => format!("  | {}", cmd),
+        })
+        .collect();
+    if !lines.is_empty() {
+        script.push_str(&lines.join(" \\\n"));
+        script.push('\n');
+    }
+    script
+}
+
+/// Writes the script to the path and makes it executable.
+pub fn write_script<P: AsRef<Path>>(path: P, script: &str) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    if path.as_os_str().is_empty() {
+        anyhow::bail!("The file name is empty");
+    }
+    std::fs::write(path, script)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mut permissions = std::fs::metadata(path)?.permissions();
+        permissions.set_mode(permissions.mode() | 0o111);
+        std::fs::set_permissions(path, permissions)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_shell_script() {
+        let stages = [
+            StageSpec {
+                cmd: String::from("cat x"),
+                ignore: false,
+                comment: Some(String::from("read the input")),
+            },
+            StageSpec {
+                cmd: String::from("grep y"),
+                ignore: true,
+                comment: None,
+            },
+            StageSpec {
+                cmd: String::from(" "),
+                ignore: false,
+                comment: None,
+            },
+            StageSpec {
+                cmd: String::from("sort"),
+                ignore: false,
+                comment: Some(String::from("for uniq")),
+            },
+            StageSpec {
+                cmd: String::from("uniq -c"),
+                ignore: false,
+                comment: None,
+            },
+        ];
+        assert_eq!(
+            to_shell_script(&stages),
+            "#!/bin/sh\n# grep y\n# sort: for uniq\n# read the input\ncat x \\\n  | sort \\\n  | uniq -c\n"
+        );
+
+        let stages = [
+            StageSpec {
+                cmd: String::from("cat x"),
+                ignore: false,
+                comment: None,
+            },
+            StageSpec {
+                cmd: String::from("SET LANG='en US'"),
+                ignore: false,
+                comment: None,
+            },
+            StageSpec {
+                cmd: String::from("sort"),
+                ignore: false,
+                comment: None,
+            },
+        ];
+        assert_eq!(
+            to_shell_script(&stages),
+            "#!/bin/sh\ncat x \\\n  | LANG='en US' sort\n"
+        );
+
+        let stages = [
+            StageSpec {
+                cmd: String::from("cat x"),
+                ignore: false,
+                comment: None,
+            },
+            StageSpec {
+                cmd: String::from("SET LC_ALL=C"),
+                ignore: false,
+                comment: None,
+            },
+            StageSpec {
+                cmd: String::from("jq .a |> jq '.b |> .c'"),
+                ignore: false,
+                comment: None,
+            },
+        ];
+        assert_eq!(
+            to_shell_script(&stages),
+            "#!/bin/bash\ncat x \\\n  | tee >(LC_ALL=C jq .a) >(LC_ALL=C jq '.b |> .c') > /dev/null\n"
+        );
+    }
+}
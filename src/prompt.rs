@@ -1,26 +1,44 @@
 use std::{
     cmp::Ordering,
-    collections::{BTreeMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
+    ops::Range,
     sync::Arc,
+    time::Duration,
 };
 
 use anyhow::bail;
 use crossterm::{
-    event::{Event, KeyCode, KeyEvent, KeyEventKind, KeyEventState, KeyModifiers},
-    style::{Attribute, Color},
+    event::{
+        Event, KeyCode, KeyEvent, KeyEventKind, KeyEventState, KeyModifiers, MouseButton,
+        MouseEvent, MouseEventKind,
+    },
+    style::{Attribute, Attributes, Color, ContentStyle},
+};
+use promkit::{
+    PaneFactory,
+    grapheme::{StyledGrapheme, StyledGraphemes},
+    pane::Pane,
+    style::StyleBuilder,
+    text_editor,
 };
-use promkit::{PaneFactory, pane::Pane, style::StyleBuilder, text_editor};
 use tokio::{
-    sync::{Mutex, broadcast, mpsc},
+    sync::{Mutex, broadcast, mpsc, watch},
     task::JoinHandle,
 };
 
 use crate::{
+    aliases,
+    highlight::{self, HighlightTheme},
+    keys,
     operator::{Buffer, Debounce, EventStream},
-    render::{EditorIndex, HEAD_INDEX, NotifyMessage, PaneIndex, SharedRenderer},
+    pipeline,
+    pipeline_export::{self, StageSpec},
+    render::{self, EditorIndex, FormField, HEAD_INDEX, NotifyMessage, PaneIndex, SharedRenderer},
+    session, snippets,
+    validate::command_exists,
 };
 
-fn edit(event: &EventStream, editor: &mut text_editor::State) {
+pub fn edit(event: &EventStream, editor: &mut text_editor::State) {
     match event {
         // Move cursor.
         EventStream::Buffer(Buffer::HorizontalCursor(left, right)) => {
@@ -58,6 +76,15 @@ fn edit(event: &EventStream, editor: &mut text_editor::State) {
                 state: KeyEventState::NONE,
             }),
             times,
+        ))
+        | EventStream::Buffer(Buffer::Other(
+            Event::Key(KeyEvent {
+                code: KeyCode::Left,
+                modifiers: KeyModifiers::CONTROL,
+                kind: KeyEventKind::Press,
+                state: KeyEventState::NONE,
+            }),
+            times,
         )) => {
             for _ in 0..*times {
                 editor
@@ -73,6 +100,15 @@ fn edit(event: &EventStream, editor: &mut text_editor::State) {
                 state: KeyEventState::NONE,
             }),
             times,
+        ))
+        | EventStream::Buffer(Buffer::Other(
+            Event::Key(KeyEvent {
+                code: KeyCode::Right,
+                modifiers: KeyModifiers::CONTROL,
+                kind: KeyEventKind::Press,
+                state: KeyEventState::NONE,
+            }),
+            times,
         )) => {
             for _ in 0..*times {
                 editor
@@ -103,9 +139,59 @@ fn edit(event: &EventStream, editor: &mut text_editor::State) {
                 state: KeyEventState::NONE,
             }),
             _,
+        ))
+        | EventStream::Buffer(Buffer::Other(
+            Event::Key(KeyEvent {
+                code: KeyCode::Char('c'),
+                modifiers: KeyModifiers::ALT,
+                kind: KeyEventKind::Press,
+                state: KeyEventState::NONE,
+            }),
+            _,
         )) => {
             editor.texteditor.erase_all();
         }
+        // Insert a newline to break a long command into lines.
+        // Ctrl+Enter is reported only by the terminals supporting the keyboard enhancement,
+        // and the others send it as Ctrl+J.
+        EventStream::Buffer(Buffer::Other(
+            Event::Key(KeyEvent {
+                code: KeyCode::Enter | KeyCode::Char('j'),
+                modifiers: KeyModifiers::CONTROL,
+                kind: KeyEventKind::Press,
+                state: KeyEventState::NONE,
+            }),
+            times,
+        )) => {
+            editor.texteditor.insert_chars(&vec!['\n'; *times]);
+        }
+        // Kill from the cursor to the end of the line, keeping the following lines
+        // of a multi-line stage. Repeating it is a no-op since the cursor is left at the end.
+        EventStream::Buffer(Buffer::Other(
+            Event::Key(KeyEvent {
+                code: KeyCode::Char('k'),
+                modifiers: KeyModifiers::CONTROL,
+                kind: KeyEventKind::Press,
+                state: KeyEventState::NONE,
+            }),
+            _,
+        )) => {
+            let chars: Vec<char> = editor
+                .texteditor
+                .text_without_cursor()
+                .to_string()
+                .chars()
+                .collect();
+            let position = editor.texteditor.position();
+            let end = chars[position..]
+                .iter()
+                .position(|ch| *ch == '\n')
+                .map_or(chars.len(), |len| position + len);
+            let kept: String = chars[..position].iter().chain(&chars[end..]).collect();
+            editor.texteditor.replace(&kept);
+            editor.texteditor.move_to_head();
+            editor.texteditor.shift(0, position);
+        }
 
         // Erase to the nearest character.
         EventStream::Buffer(Buffer::Other(
@@ -144,41 +230,517 @@ fn edit(event: &EventStream, editor: &mut text_editor::State) {
             text_editor::Mode::Insert => editor.texteditor.insert_chars(chars),
             text_editor::Mode::Overwrite => editor.texteditor.overwrite_chars(chars),
         },
+        // Paste text at once, as a single line.
+        EventStream::Buffer(Buffer::Paste(text)) => {
+            let chars: Vec<char> = text
+                .chars()
+                .filter(|ch| *ch != '\r')
+                .map(|ch| if ch == '\n' { ' ' } else { ch })
+                .collect();
+            match editor.edit_mode {
+                text_editor::Mode::Insert => editor.texteditor.insert_chars(&chars),
+                text_editor::Mode::Overwrite => editor.texteditor.overwrite_chars(&chars),
+            }
+        }
 
         _ => {}
     }
 }
 
+/// Wait for typing to settle before looking up the command.
+const VALIDATE_DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Returns the first token (command name) of the text.
+fn command_name(text: &str) -> Option<&str> {
+    text.split_whitespace().next()
+}
+
+/// Returns the separator to merge the stage into the previous one with:
+/// a space for Ctrl+Shift+J, or a pipe for Ctrl+Alt+Shift+J.
+/// The terminals without the keyboard enhancement send them as Ctrl+J,
+/// so they are also bound to Alt+Shift+K and Alt+| respectively.
+fn merge_separator(code: KeyCode, modifiers: KeyModifiers) -> Option<&'static str> {
+    let ctrl_shift = KeyModifiers::CONTROL | KeyModifiers::SHIFT;
+    let alt_shift = KeyModifiers::ALT | KeyModifiers::SHIFT;
+    match code {
+        KeyCode::Char('j' | 'J') if modifiers == ctrl_shift => Some(" "),
+        KeyCode::Char('j' | 'J') if modifiers == ctrl_shift | KeyModifiers::ALT => Some(" | "),
+        KeyCode::Char('k' | 'K') if modifiers == alt_shift => Some(" "),
+        // Some terminals report Alt+| as Alt+Shift+\.
+        KeyCode::Char('|') if modifiers == KeyModifiers::ALT || modifiers == alt_shift => {
+            Some(" | ")
+        }
+        KeyCode::Char('\\') if modifiers == alt_shift => Some(" | "),
+        _ => None,
+    }
+}
+
 #[derive(Clone)]
 pub struct EditorTheme {
     pub prefix: String,
     pub prefix_fg_color: Color,
     pub active_char_bg_color: Color,
     pub word_break_chars: HashSet<char>,
+    pub highlight: Option<HighlightTheme>,
+}
+
+/// The status of the stage in the last run, shown in the prefix of the editor.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StageStatus {
+    // With the frame of the spinner.
+    Running(char),
+    Succeeded,
+    Failed,
+    Ignored,
+}
+
+#[derive(Clone, PartialEq)]
+struct StageRun {
+    // The position (0-based) in the pipeline, or None if ignored.
+    stage: Option<usize>,
+    status: StageStatus,
+    // The text which ran, to hide the status once edited.
+    text: String,
+    // The elapsed time, e.g. `[0.3s]` or `[done 1.2s]`.
+    timing: Option<String>,
+}
+
+/// Formats the elapsed time of a stage shown after its command.
+fn format_timing(elapsed: Duration, finished: bool) -> String {
+    if finished {
+        format!("[done {:.1}s]", elapsed.as_secs_f64())
+    } else {
+        format!("[{:.1}s]", elapsed.as_secs_f64())
+    }
+}
+
+/// Prefixed to the text of a stage to override the word break characters of it,
+/// e.g. `!wbc=.: jq .a.b`.
+const WORD_BREAK_CHARS_PREFIX: &str = "!wbc=";
+
+/// Splits the word break characters given as the prefix `!wbc=<chars>` off the text,
+/// returning them (if given) and the rest of the text.
+fn split_word_break_chars(text: &str) -> (Option<HashSet<char>>, &str) {
+    let Some(rest) = text.trim_start().strip_prefix(WORD_BREAK_CHARS_PREFIX) else {
+        return (None, text);
+    };
+    let (chars, rest) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    (Some(chars.chars().collect()), rest.trim_start())
+}
+
+/// The stages removed with Ctrl+D are kept up to this number to restore them.
+const MAX_REMOVED_STAGES: usize = 20;
+
+/// A stage removed with Ctrl+D, kept to restore it with Alt+U.
+struct RemovedStage {
+    // The index it was at.
+    index: EditorIndex,
+    text: String,
+    ignore: bool,
+    comment: Option<String>,
 }
 
 struct Editor {
     state: text_editor::State,
+    // Unless overridden by the prefix of the text.
+    word_break_chars: HashSet<char>,
+    highlight: Option<HighlightTheme>,
     ignore: bool,
+    // The command name last looked up, and whether it was not found.
+    validated_command: Option<String>,
+    command_not_found: bool,
+    comment: Option<String>,
+    run: Option<StageRun>,
+    // Whether the output of this stage is shown instead of the last one.
+    preview: bool,
 }
 
-impl From<text_editor::State> for Editor {
-    fn from(state: text_editor::State) -> Self {
+impl From<&EditorTheme> for Editor {
+    fn from(theme: &EditorTheme) -> Self {
         Self {
-            state,
+            state: text_editor::State {
+                prefix: theme.prefix.clone(),
+                prefix_style: StyleBuilder::new().fgc(theme.prefix_fg_color).build(),
+                active_char_style: StyleBuilder::new().bgc(theme.active_char_bg_color).build(),
+                word_break_chars: theme.word_break_chars.clone(),
+                ..Default::default()
+            },
+            word_break_chars: theme.word_break_chars.clone(),
+            highlight: theme.highlight.clone(),
             ignore: false,
+            validated_command: None,
+            command_not_found: false,
+            comment: None,
+            run: None,
+            preview: false,
         }
     }
 }
 
 impl Editor {
+    /// Disables the stage if enabled, or vice versa.
+    fn toggle_ignore(&mut self) {
+        self.ignore = !self.ignore;
+        self.state
+            .prefix_style
+            .attributes
+            .toggle(Attribute::CrossedOut);
+        self.state
+            .active_char_style
+            .attributes
+            .toggle(Attribute::CrossedOut);
+        self.state
+            .inactive_char_style
+            .attributes
+            .toggle(Attribute::CrossedOut);
+    }
+
+    /// Returns the text to run, without the word break characters given as the prefix.
+    fn command_text(&self) -> String {
+        let text = self.state.texteditor.text_without_cursor().to_string();
+        split_word_break_chars(&text).1.to_string()
+    }
+
+    /// Applies the word break characters given as the prefix of the text, if any,
+    /// or the default ones.
+    fn apply_word_break_chars(&mut self) {
+        let text = self.state.texteditor.text_without_cursor().to_string();
+        self.state.word_break_chars = split_word_break_chars(&text)
+            .0
+            .unwrap_or_else(|| self.word_break_chars.clone());
+    }
+
+    /// Returns whether `|` typed at the cursor should create the next stage,
+    /// i.e. the cursor is at the end of the text which is not left in quotes.
+    fn splits_at_cursor(&self) -> bool {
+        let text = self.state.texteditor.text_without_cursor().to_string();
+        !text.trim().is_empty()
+            && self.state.texteditor.position() == text.chars().count()
+            && pipeline::pipe_follows(&text)
+    }
+
+    /// Returns the prefix with the status of the stage and the indicators
+    /// of the preview and the comment.
+    fn prefix(&self) -> String {
+        let mut prefix = self.state.prefix.clone();
+        if let Some((glyph, _)) = self.status_glyph() {
+            prefix.push(glyph);
+            prefix.push(' ');
+        }
+        if self.preview {
+            prefix.push_str("◉ ");
+        }
+        if self.comment.is_some() {
+            prefix.push_str("ⓘ ");
+        }
+        prefix
+    }
+
+    /// Returns the last run unless the text has been edited since then.
+    fn current_run(&self) -> Option<&StageRun> {
+        self.run
+            .as_ref()
+            .filter(|run| run.text == self.state.texteditor.text_without_cursor().to_string())
+    }
+
+    /// Returns the glyph of the stage status unless the text has been edited since the run.
+    fn status_glyph(&self) -> Option<(char, ContentStyle)> {
+        let run = self.current_run()?;
+        Some(match run.status {
+            StageStatus::Running(frame) => {
+                (frame, StyleBuilder::new().fgc(Color::DarkYellow).build())
+            }
+            StageStatus::Succeeded => ('✔', StyleBuilder::new().fgc(Color::DarkGreen).build()),
+            StageStatus::Failed => ('✘', StyleBuilder::new().fgc(Color::DarkRed).build()),
+            StageStatus::Ignored => (
+                '-',
+                StyleBuilder::new()
+                    .attrs(Attributes::from(Attribute::Dim))
+                    .build(),
+            ),
+        })
+    }
+
     fn create_pane(&self, width: u16, height: u16) -> Pane {
-        self.state.create_pane(width, height)
+        // Same as text_editor::State::create_pane,
+        // but colors the prefix by validation and the recognized tokens in addition.
+        let prefix_style = if self.command_not_found {
+            ContentStyle {
+                foreground_color: Some(Color::DarkRed),
+                ..self.state.prefix_style
+            }
+        } else if pipeline::is_set_command(&self.command_text()) {
+            // Not a process but sets the environment variable.
+            ContentStyle {
+                foreground_color: Some(Color::DarkGrey),
+                ..self.state.prefix_style
+            }
+        } else {
+            self.state.prefix_style
+        };
+        let mut buf = StyledGraphemes::from_str(&self.state.prefix, prefix_style);
+        if let Some((glyph, style)) = self.status_glyph() {
+            buf.append(&mut StyledGraphemes::from_str(format!("{} ", glyph), style));
+        }
+        if self.preview {
+            buf.append(&mut StyledGraphemes::from_str(
+                "◉ ",
+                StyleBuilder::new().fgc(Color::DarkCyan).build(),
+            ));
+        }
+        if self.comment.is_some() {
+            buf.append(&mut StyledGraphemes::from_str("ⓘ ", prefix_style));
+        }
+
+        let text = self.state.texteditor.text();
+        let tokens = match &self.highlight {
+            Some(_) => highlight::tokenize(&text.chars()),
+            None => vec![None; text.len()],
+        };
+        // Break the lines at the newlines (shown as spaces) before wrapping them.
+        let position = self.state.texteditor.position();
+        let mut lines = vec![buf];
+        // The (line, width before the cursor in the line).
+        let mut cursor = (0, 0);
+        for (i, (ch, token)) in text.chars().into_iter().zip(tokens).enumerate() {
+            let style = if i == position {
+                self.state.active_char_style
+            } else if let (Some(highlight), Some(token)) = (&self.highlight, token) {
+                ContentStyle {
+                    foreground_color: Some(highlight.color(token)),
+                    ..self.state.inactive_char_style
+                }
+            } else {
+                self.state.inactive_char_style
+            };
+            let line_index = lines.len() - 1;
+            let line = &mut lines[line_index];
+            if i == position {
+                cursor = (line_index, line.widths());
+            }
+            if ch == '\n' {
+                line.append(&mut StyledGraphemes::from_str(" ", style));
+                lines.push(StyledGraphemes::default());
+            } else {
+                line.append(&mut StyledGraphemes::from_str(ch.to_string(), style));
+            }
+        }
+        if let Some(timing) = self.current_run().and_then(|run| run.timing.as_ref()) {
+            let line_index = lines.len() - 1;
+            lines[line_index].append(&mut StyledGraphemes::from_str(
+                format!(" {}", timing),
+                StyleBuilder::new()
+                    .attrs(Attributes::from(Attribute::Dim))
+                    .build(),
+            ));
+        }
+
+        let width = width as usize;
+        let mut rows = vec![];
+        let mut cursor_row = 0;
+        for (i, line) in lines.iter().enumerate() {
+            if i == cursor.0 {
+                cursor_row = rows.len() + cursor.1 / width.max(1);
+            }
+            rows.extend(line.matrixify(width, line.len().max(1), 0).0);
+        }
+
+        // Keep the cursor row visible in the same way as StyledGraphemes::matrixify.
+        let height = match self.state.lines {
+            Some(lines) => lines.min(height as usize),
+            None => height as usize,
+        };
+        let end = (cursor_row + height).min(rows.len());
+        let start = end.saturating_sub(height).min(cursor_row);
+        Pane::new(rows[start..end].to_vec(), cursor_row - start)
+    }
+}
+
+/// Replaces all matches of the regex in the text of the editor.
+fn replace_with_regex(editor: &mut Editor, find: &str, replace: &str) -> anyhow::Result<()> {
+    let regex = regex::Regex::new(find)?;
+    let text = editor.state.texteditor.text_without_cursor().to_string();
+    let replaced = regex.replace_all(&text, replace);
+    editor.state.texteditor.replace(&replaced);
+    Ok(())
+}
+
+/// Returns the range of the editors to render, which keeps the focused one visible
+/// and scrolls from `top` as little as possible.
+/// If not all the editors fit in the rows, two of them are left for the indicators.
+fn editor_window(len: usize, focus: usize, rows: usize, top: &mut usize) -> std::ops::Range<usize> {
+    if len <= rows {
+        *top = 0;
+        return 0..len;
+    }
+    let visible = rows.saturating_sub(2).max(1);
+    if focus < *top {
+        *top = focus;
+    } else if focus >= *top + visible {
+        *top = focus + 1 - visible;
+    }
+    *top = (*top).min(len - visible);
+    *top..*top + visible
+}
+
+/// Returns the pane of the stages joined with `|` as the command line,
+/// truncated with `…` if it is wider than the terminal.
+fn command_line_pane(cmds: &[String], width: u16) -> Pane {
+    if cmds.is_empty() {
+        return Pane::new(vec![], 0);
+    }
+    let mut line = StyledGraphemes::from(format!("$ {}", cmds.join(" | ")));
+    let width = width as usize;
+    if line.widths() > width {
+        let mut shown = StyledGraphemes::default();
+        for grapheme in line.iter() {
+            if shown.widths() + grapheme.width() >= width {
+                break;
+            }
+            shown.push_back(grapheme.clone());
+        }
+        shown.push_back(StyledGrapheme::from('…'));
+        line = shown;
+    }
+    Pane::new(
+        vec![
+            line.apply_style(
+                StyleBuilder::new()
+                    .attrs(Attributes::from(Attribute::Dim))
+                    .build(),
+            ),
+        ],
+        0,
+    )
+}
+
+/// Returns the commands of the editors to run, except the ignored or empty ones,
+/// with the aliases expanded.
+fn collect_texts<'a>(
+    editors: impl Iterator<Item = &'a Editor>,
+    aliases: &HashMap<String, String>,
+) -> Vec<String> {
+    collect_stages(editors, aliases)
+        .into_iter()
+        .map(|(_, cmd)| cmd)
+        .collect()
+}
+
+/// Returns the commands as `collect_texts` does, each with the position (0-based)
+/// of its editor, which is the stage number shown to the user.
+fn collect_stages<'a>(
+    editors: impl Iterator<Item = &'a Editor>,
+    aliases: &HashMap<String, String>,
+) -> Vec<(usize, String)> {
+    editors
+        .enumerate()
+        .filter(|(_, editor)| !editor.ignore)
+        .map(|(i, editor)| (i, pipeline::join_lines(&editor.command_text())))
+        .filter(|(_, cmd)| !cmd.trim().is_empty())
+        .map(|(i, cmd)| (i, aliases::expand(&cmd, aliases)))
+        .collect()
+}
+
+/// Returns the indicator of the editors hidden above or below, e.g. "▲ 2 more".
+fn hidden_editors_pane(arrow: &str, hidden: usize) -> Pane {
+    if hidden == 0 {
+        return Pane::new(vec![], 0);
+    }
+    Pane::new(
+        vec![StyledGraphemes::from_str(
+            format!("{} {} more", arrow, hidden),
+            StyleBuilder::new()
+                .attrs(Attributes::from(Attribute::Dim))
+                .build(),
+        )],
+        0,
+    )
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FormKind {
+    FindReplace,
+    Export,
+    Comment,
+    Snippet,
+}
+
+/// Stacked single-line editors rendered just below (or above) the editor at `index`.
+struct Form {
+    kind: FormKind,
+    index: EditorIndex,
+    fields: Vec<(FormField, text_editor::State)>,
+    focus: usize,
+}
+
+impl Form {
+    fn new(kind: FormKind, index: EditorIndex, theme: &EditorTheme) -> Self {
+        let field = |prefix: &str| text_editor::State {
+            prefix: String::from(prefix),
+            prefix_style: StyleBuilder::new().fgc(Color::DarkCyan).build(),
+            active_char_style: StyleBuilder::new().bgc(theme.active_char_bg_color).build(),
+            word_break_chars: theme.word_break_chars.clone(),
+            ..Default::default()
+        };
+        let fields = match kind {
+            FormKind::FindReplace => vec![
+                (FormField::Find, field("  Find: ")),
+                (FormField::Replace, field("  Replace: ")),
+            ],
+            FormKind::Export => vec![(FormField::ExportPath, field("  Export to: "))],
+            FormKind::Comment => vec![(FormField::Comment, field("  # "))],
+            FormKind::Snippet => vec![(FormField::SnippetName, field("  Save as snippet: "))],
+        };
+        Self {
+            kind,
+            index,
+            fields,
+            focus: 0,
+        }
+    }
+
+    fn text(&self, field: FormField) -> String {
+        self.fields
+            .iter()
+            .find(|(f, _)| *f == field)
+            .map(|(_, state)| state.texteditor.text_without_cursor().to_string())
+            .unwrap_or_default()
+    }
+
+    fn focused_mut(&mut self) -> &mut text_editor::State {
+        &mut self.fields[self.focus].1
+    }
+
+    fn switch_focus(&mut self) {
+        self.focus = (self.focus + 1) % self.fields.len();
+    }
+
+    fn panes(&self, width: u16, height: u16) -> Vec<(PaneIndex, Pane)> {
+        self.fields
+            .iter()
+            .map(|(field, state)| {
+                (
+                    PaneIndex::Form(self.index.clone(), *field),
+                    state.create_pane(width, height),
+                )
+            })
+            .collect()
+    }
+
+    fn pane_indices(&self) -> Vec<PaneIndex> {
+        self.fields
+            .iter()
+            .map(|(field, _)| PaneIndex::Form(self.index.clone(), *field))
+            .collect()
     }
 }
 
 struct EditorMap(BTreeMap<EditorIndex, Editor>);
 
+/// Renumber the editors when the numerator or denominator of any index exceeds this,
+/// which grows fast by inserting between the same editors repeatedly.
+const RENORMALIZE_THRESHOLD: usize = 1 << 16;
+
 enum Direction {
     Up(usize),
     Down(usize),
@@ -194,11 +756,8 @@ impl Direction {
 }
 
 impl EditorMap {
-    fn from(state: text_editor::State) -> Self {
-        Self(BTreeMap::from_iter([(
-            HEAD_INDEX.clone(),
-            Editor::from(state),
-        )]))
+    fn from(editor: Editor) -> Self {
+        Self(BTreeMap::from_iter([(HEAD_INDEX.clone(), editor)]))
     }
 
     fn len(&self) -> usize {
@@ -213,18 +772,18 @@ impl EditorMap {
         self.0.get_mut(index)
     }
 
-    fn insert(&mut self, index: EditorIndex, state: text_editor::State) -> Option<Editor> {
-        self.0.insert(index, Editor::from(state))
-    }
-
-    fn pop_last(&mut self) -> Option<(EditorIndex, Editor)> {
-        self.0.pop_last()
+    fn insert(&mut self, index: EditorIndex, editor: Editor) -> Option<Editor> {
+        self.0.insert(index, editor)
     }
 
     fn iter(&self) -> impl Iterator<Item = (&EditorIndex, &Editor)> {
         self.0.iter()
     }
 
+    fn iter_mut(&mut self) -> impl Iterator<Item = (&EditorIndex, &mut Editor)> {
+        self.0.iter_mut()
+    }
+
     fn remove(&mut self, index: &EditorIndex) -> Option<Editor> {
         self.0.remove(index)
     }
@@ -233,6 +792,25 @@ impl EditorMap {
         self.0.values()
     }
 
+    /// Returns the texts of the stages saved as a snippet, except the ignored or empty ones.
+    fn snippet_stages(&self) -> Vec<String> {
+        self.values()
+            .filter(|editor| !editor.ignore)
+            .map(|editor| editor.state.texteditor.text_without_cursor().to_string())
+            .filter(|text| !text.trim().is_empty())
+            .collect()
+    }
+
+    fn stage_specs(&self) -> Vec<StageSpec> {
+        self.values()
+            .map(|editor| StageSpec {
+                cmd: pipeline::join_lines(&editor.command_text()),
+                ignore: editor.ignore,
+                comment: editor.comment.clone(),
+            })
+            .collect()
+    }
+
     fn last_index(&self) -> Option<&EditorIndex> {
         self.0.keys().last()
     }
@@ -241,6 +819,36 @@ impl EditorMap {
         self.0.contains_key(index)
     }
 
+    fn needs_renormalize(&self) -> bool {
+        self.0
+            .keys()
+            .any(|index| index.0 > RENORMALIZE_THRESHOLD || index.1 > RENORMALIZE_THRESHOLD)
+    }
+
+    /// Reassigns 1/1, 2/1, 3/1... to the editors in order,
+    /// and returns the (old, new) indices which have changed.
+    fn renormalize(&mut self) -> Vec<(EditorIndex, EditorIndex)> {
+        let mut changes = vec![];
+        for (i, (index, editor)) in std::mem::take(&mut self.0).into_iter().enumerate() {
+            let new_index = EditorIndex(i + 1, 1);
+            if new_index != index {
+                changes.push((index, new_index.clone()));
+            }
+            self.0.insert(new_index, editor);
+        }
+        changes
+    }
+
+    fn try_get(&self, index: &EditorIndex) -> anyhow::Result<&Editor> {
+        self.get(index)
+            .ok_or_else(|| anyhow::anyhow!("editor {} not found", index))
+    }
+
+    fn try_get_mut(&mut self, index: &EditorIndex) -> anyhow::Result<&mut Editor> {
+        self.get_mut(index)
+            .ok_or_else(|| anyhow::anyhow!("editor {} not found", index))
+    }
+
     fn is_last(&self, index: &EditorIndex) -> bool {
         if let Some(last) = self.0.keys().last() {
             last.0 == index.0 && last.1 == index.1
@@ -252,8 +860,8 @@ impl EditorMap {
     fn new_index(&self, index: &EditorIndex) -> anyhow::Result<EditorIndex> {
         if self.is_last(index) {
             // If this is the last index, create a new index that is greater
-            // A simple way is to add 1 to the numerator while keeping the denominator
-            Ok(EditorIndex(index.0 + 1, index.1))
+            // A simple way is the next integer, which is also a reduced fraction
+            Ok(EditorIndex(index.0 / index.1 + 1, 1))
         } else {
             Ok(EditorIndex::mediant(
                 index,
@@ -268,6 +876,10 @@ impl EditorMap {
         up: usize,
         down: usize,
     ) -> anyhow::Result<EditorIndex> {
+        // Do not return the stale index as is even if not moving.
+        if !self.contains_key(index) {
+            bail!("{} not found", index);
+        }
         match up.cmp(&down) {
             Ordering::Less => self.seek_index(index, Direction::Down(down.saturating_sub(up))),
             Ordering::Greater => self.seek_index(index, Direction::Up(up.saturating_sub(down))),
@@ -280,7 +892,7 @@ impl EditorMap {
             bail!("{} not found", index);
         }
 
-        let mut iter = match direction {
+        let iter = match direction {
             Direction::Up(_) => {
                 Box::new(
                     self.0
@@ -304,7 +916,7 @@ impl EditorMap {
 
         let (mut cur, mut remaining) = (index.clone(), direction.distance());
 
-        while let Some(next) = iter.next() {
+        for next in iter {
             if remaining == 0 {
                 break;
             }
@@ -320,33 +932,86 @@ impl EditorMap {
 pub struct Prompt {
     // TODO: reconsider whether mutex is necessary only for get_all_texts
     shared_editors: Arc<Mutex<EditorMap>>,
+    // Expanded in the texts returned by get_all_texts.
+    aliases: Arc<HashMap<String, String>>,
+    shared_cur_index: Arc<Mutex<EditorIndex>>,
+    shared_renderer: SharedRenderer,
+    // Counts the times the stages were replaced by `Load` or `LoadStages`.
+    loaded: watch::Receiver<usize>,
     pub background: JoinHandle<()>,
 }
 
 impl Prompt {
+    #[allow(clippy::too_many_arguments)]
     pub fn spawn(
         mut rx: broadcast::Receiver<EventStream>,
         notify_tx: mpsc::Sender<NotifyMessage>,
         themes: (EditorTheme, EditorTheme), // (head, pipe)
         init_terminal_shape: (u16, u16),
         shared_renderer: SharedRenderer,
-    ) -> Self {
-        let shared_editors = Arc::new(Mutex::new(EditorMap::from(text_editor::State {
-            prefix: themes.0.prefix.clone(),
-            prefix_style: StyleBuilder::new().fgc(themes.0.prefix_fg_color).build(),
-            active_char_style: StyleBuilder::new()
-                .bgc(themes.0.active_char_bg_color)
-                .build(),
-            word_break_chars: themes.0.word_break_chars.clone(),
-            ..Default::default()
-        })));
+        output_height: watch::Receiver<Option<u16>>,
+        min_output_height: u16,
+        aliases: HashMap<String, String>,
+        auto_split: bool,
+        session: Option<&session::Session>,
+    ) -> anyhow::Result<Self> {
+        let aliases = Arc::new(aliases);
+        let mut editors = EditorMap::from(Editor::from(&themes.0));
+        // Restore the stages of the last session (focused in the background task).
+        let focus = match session {
+            Some(session) => {
+                Self::load_editors(&mut editors, &session.stages, &themes)?;
+                session.focus
+            }
+            None => 0,
+        };
+        let shared_editors = Arc::new(Mutex::new(editors));
+
+        let shared_cur_index = Arc::new(Mutex::new(HEAD_INDEX.clone()));
+
+        // Note: The validator task terminates when validate_tx is dropped with the prompt task.
+        let (validate_tx, validate_rx) = mpsc::channel(1);
+        Self::spawn_validator(
+            validate_rx,
+            shared_editors.clone(),
+            shared_renderer.clone(),
+            aliases.clone(),
+        );
+        if session.is_some() {
+            let _ = validate_tx.try_send(());
+        }
+        let (loaded_tx, loaded) = watch::channel(0);
 
         let background = {
             let mut terminal_shape = init_terminal_shape;
             let shared_editors = shared_editors.clone();
+            let shared_cur_index = shared_cur_index.clone();
+            let shared_renderer = shared_renderer.clone();
+            let aliases = aliases.clone();
 
             tokio::spawn(async move {
-                let mut cur_index = HEAD_INDEX.clone();
+                let mut cur_index = {
+                    let mut editors = shared_editors.lock().await;
+                    let focus_index = editors
+                        .iter()
+                        .nth(focus)
+                        .map_or(HEAD_INDEX.clone(), |(index, _)| index.clone());
+                    let _ =
+                        Self::switch_theme(&mut editors, Some(&HEAD_INDEX), &focus_index, &themes);
+                    *shared_cur_index.lock().await = focus_index.clone();
+                    focus_index
+                };
+                // The position of the first editor rendered.
+                let mut editor_top = 0;
+                let mut form: Option<Form> = None;
+                // The comment shown in the notify pane with the index of the editor.
+                let mut shown_comment: Option<(EditorIndex, String)> = None;
+                // The most recently removed stage is the last.
+                let mut removed_stages: Vec<RemovedStage> = vec![];
+                // The editor just created by typing `|`.
+                let mut auto_split_at: Option<EditorIndex> = None;
+                // Whether the whole pipeline is shown as a command line above the editors.
+                let mut command_line_shown = true;
 
                 // Initial renderings
                 {
@@ -360,284 +1025,1631 @@ impl Prompt {
                                 editor.create_pane(terminal_shape.0, terminal_shape.1),
                             )
                         }))
+                        .update([(
+                            PaneIndex::Preview,
+                            command_line_pane(
+                                &collect_texts(editors.values(), &aliases),
+                                terminal_shape.0,
+                            ),
+                        )])
                         .render();
                 }
 
                 loop {
                     if let Ok(event) = rx.recv().await {
-                        match event {
-                            EventStream::Debounce(Debounce::Resize(width, height)) => {
-                                terminal_shape = (width, height);
-
-                                let (mut editors, mut renderer) =
-                                    tokio::join!(shared_editors.lock(), shared_renderer.lock());
-
-                                // Resize the editors also
-                                // Note to consider the notify and output panes...
-                                if height < editors.len() as u16 + 2 {
-                                    let removals = {
-                                        let times =
-                                            (editors.len() + 2).saturating_sub(height as usize);
-                                        Self::pop_editors(&mut editors, times)
-                                    };
-                                    renderer.remove(removals.into_iter().map(PaneIndex::Editor));
-
-                                    // Update the current index
-                                    cur_index = HEAD_INDEX.clone();
-                                    // Change theme because of switching focus
-                                    Self::switch_theme(&mut editors, None, &cur_index, &themes);
-                                }
-
-                                renderer.update(editors.iter().map(|(index, editor)| {
-                                    (
-                                        PaneIndex::Editor(index.clone()),
-                                        editor.create_pane(terminal_shape.0, terminal_shape.1),
-                                    )
-                                }));
+                        // Typing `|` at the end of the stage creates the next stage as Ctrl+B does,
+                        // and `>` just after that joins them back with the fan-out `|>`.
+                        let split_at = auto_split_at.take();
+                        // The keys typed quickly come in a batch (e.g. `grep|`), so the chars
+                        // before `|` are inserted first to tell whether `|` ends the stage.
+                        let (event, splitting) = match event {
+                            EventStream::Buffer(Buffer::Key(mut chars))
+                                if auto_split && form.is_none() && chars.last() == Some(&'|') =>
+                            {
+                                let typed = chars.split_off(chars.len() - 1);
+                                let mut editors = shared_editors.lock().await;
+                                let splitting = match editors.get_mut(&cur_index) {
+                                    Some(editor) => {
+                                        if !chars.is_empty() {
+                                            editor.apply_word_break_chars();
+                                            edit(
+                                                &EventStream::Buffer(Buffer::Key(chars)),
+                                                &mut editor.state,
+                                            );
+                                            let _ = validate_tx.try_send(());
+                                        }
+                                        editor.splits_at_cursor()
+                                    }
+                                    None => false,
+                                };
+                                (EventStream::Buffer(Buffer::Key(typed)), splitting)
                             }
+                            event => (event, false),
+                        };
+                        let event = match event {
+                            _ if splitting => EventStream::Buffer(Buffer::Other(
+                                Event::Key(KeyEvent::new(
+                                    KeyCode::Char('b'),
+                                    KeyModifiers::CONTROL,
+                                )),
+                                1,
+                            )),
+                            // Jump to the n-th stage, or the last one with Alt+0.
                             EventStream::Buffer(Buffer::Other(
                                 Event::Key(KeyEvent {
-                                    code: KeyCode::Char('b'),
-                                    modifiers: KeyModifiers::CONTROL,
+                                    code: KeyCode::Char(digit @ '0'..='9'),
+                                    modifiers: KeyModifiers::ALT,
                                     kind: KeyEventKind::Press,
                                     state: KeyEventState::NONE,
                                 }),
-                                times,
-                            )) => {
-                                let mut new_index = cur_index.clone();
-                                let mut inserts = HashSet::from([new_index.clone()]);
+                                _,
+                            )) if form.is_none() => {
+                                EventStream::FocusStage(digit.to_digit(10).unwrap() as usize)
+                            }
+                            EventStream::Load(cmds) => EventStream::LoadStages(
+                                cmds.into_iter()
+                                    .map(|text| session::Stage {
+                                        text,
+                                        ..Default::default()
+                                    })
+                                    .collect(),
+                                0,
+                            ),
+                            event => event,
+                        };
 
-                                let mut editors = shared_editors.lock().await;
-                                // Insert new editors
-                                for _ in 0..times {
-                                    // 2 represents the notify and output panes
-                                    if editors.len() >= terminal_shape.1.saturating_sub(2) as usize
-                                    {
-                                        let _ = notify_tx
-                                            .send(NotifyMessage::Error(String::from(
-                                                "Cannot create more editors",
-                                            )))
-                                            .await;
-                                        break;
-                                    }
-                                    new_index =
-                                        Self::insert_editor(&new_index, &mut editors, &themes.1);
-                                    inserts.insert(new_index.clone());
-                                }
-                                // Change theme because of switching focus
-                                Self::switch_theme(
-                                    &mut editors,
-                                    Some(&cur_index),
-                                    &new_index,
-                                    &themes,
-                                );
-                                // Update changes for rendering
-                                shared_renderer.lock().await.update(inserts.into_iter().map(
-                                    |index| {
+                        // Returns whether the event is handled (false if it is ignored).
+                        let handled: anyhow::Result<bool> = async {
+                            match event {
+                                EventStream::Debounce(Debounce::Resize(width, height)) => {
+                                    terminal_shape = (width, height);
+
+                                    let (editors, mut renderer) =
+                                        tokio::join!(shared_editors.lock(), shared_renderer.lock());
+
+                                    // Resize the editors also
+                                    // (the editors which no longer fit are hidden, not removed)
+                                    renderer.update(editors.iter().map(|(index, editor)| {
                                         (
                                             PaneIndex::Editor(index.clone()),
-                                            editors
-                                                .get(&index)
-                                                .unwrap()
-                                                .create_pane(terminal_shape.0, terminal_shape.1),
+                                            editor.create_pane(terminal_shape.0, terminal_shape.1),
                                         )
-                                    },
-                                ));
-                                // Update the current index
-                                cur_index = new_index;
-                            }
-                            EventStream::Buffer(Buffer::Other(
-                                Event::Key(KeyEvent {
-                                    code: KeyCode::Char('d'),
-                                    modifiers: KeyModifiers::CONTROL,
-                                    kind: KeyEventKind::Press,
-                                    state: KeyEventState::NONE,
-                                }),
-                                times,
-                            )) => {
-                                let mut prev_index = cur_index.clone();
-                                let mut removals = HashSet::new();
-
-                                {
-                                    let mut editors = shared_editors.lock().await;
-                                    // Remove editors
-                                    for _ in 0..times {
-                                        // Early return if the head editor is removed
-                                        if prev_index == HEAD_INDEX {
-                                            break;
-                                        }
-                                        removals.insert(prev_index.clone());
-                                        prev_index = Self::remove_editor(&prev_index, &mut editors);
+                                    }));
+                                    if let Some(form) = &form {
+                                        renderer
+                                            .update(form.panes(terminal_shape.0, terminal_shape.1));
                                     }
-                                    // Change theme because of switching focus
-                                    Self::switch_theme(&mut editors, None, &prev_index, &themes);
                                 }
+                                // Replace all the stages with the given ones (or clear them if none).
+                                EventStream::LoadStages(stages, focus) => {
+                                    let mut editors = shared_editors.lock().await;
+                                    let removals: Vec<PaneIndex> = editors
+                                        .iter()
+                                        .map(|(index, _)| PaneIndex::Editor(index.clone()))
+                                        .collect();
+                                    Self::load_editors(&mut editors, &stages, &themes)?;
+                                    removed_stages.clear();
+                                    let _ = validate_tx.try_send(());
+
+                                    // Update the current index
+                                    cur_index = editors
+                                        .iter()
+                                        .nth(focus)
+                                        .map_or(HEAD_INDEX.clone(), |(index, _)| index.clone());
+                                    Self::switch_theme(
+                                        &mut editors,
+                                        Some(&HEAD_INDEX),
+                                        &cur_index,
+                                        &themes,
+                                    )?;
 
-                                // Update changes for rendering
-                                {
                                     let mut renderer = shared_renderer.lock().await;
-                                    let _ = renderer
-                                        .remove(removals.into_iter().map(PaneIndex::Editor))
-                                        .update([(
-                                            PaneIndex::Editor(prev_index.clone()),
-                                            shared_editors
-                                                .lock()
-                                                .await
-                                                .get(&prev_index)
-                                                .unwrap()
-                                                .create_pane(terminal_shape.0, terminal_shape.1),
-                                        )]);
+                                    renderer.remove(removals);
+                                    renderer.update(editors.iter().map(|(index, editor)| {
+                                        (
+                                            PaneIndex::Editor(index.clone()),
+                                            editor.create_pane(terminal_shape.0, terminal_shape.1),
+                                        )
+                                    }));
+                                    loaded_tx.send_modify(|loaded| *loaded += 1);
                                 }
-
-                                // Update the current index
-                                cur_index = prev_index;
-                            }
-                            EventStream::Buffer(Buffer::Other(
-                                Event::Key(KeyEvent {
-                                    code: KeyCode::Char('x'),
-                                    modifiers: KeyModifiers::CONTROL,
-                                    kind: KeyEventKind::Press,
-                                    state: KeyEventState::NONE,
-                                }),
-                                times,
-                            )) => {
-                                if times % 2 != 0 {
+                                // Replace the text of the focused stage (e.g. edited in $EDITOR).
+                                EventStream::ReplaceStage(text) => {
                                     let mut editors = shared_editors.lock().await;
-                                    let cur_editor = editors.get_mut(&cur_index).unwrap();
-                                    cur_editor.ignore = !cur_editor.ignore;
-                                    cur_editor
-                                        .state
-                                        .prefix_style
-                                        .attributes
-                                        .toggle(Attribute::CrossedOut);
-                                    cur_editor
+                                    let editor = editors.try_get_mut(&cur_index)?;
+                                    editor.state.texteditor.replace(&text);
+                                    let _ = validate_tx.try_send(());
+                                    shared_renderer.lock().await.update([(
+                                        PaneIndex::Editor(cur_index.clone()),
+                                        editor.create_pane(terminal_shape.0, terminal_shape.1),
+                                    )]);
+                                }
+                                // While the form is open, all inputs go to the form.
+                                event if form.is_some() => {
+                                    let cur_form = form.as_mut().unwrap();
+                                    match event {
+                                        EventStream::Buffer(Buffer::Other(
+                                            Event::Key(KeyEvent {
+                                                code: KeyCode::Tab,
+                                                modifiers: KeyModifiers::NONE,
+                                                kind: KeyEventKind::Press,
+                                                state: KeyEventState::NONE,
+                                            }),
+                                            times,
+                                        )) => {
+                                            if times % 2 != 0 {
+                                                cur_form.switch_focus();
+                                            }
+                                        }
+                                        EventStream::Buffer(Buffer::Other(
+                                            Event::Key(KeyEvent {
+                                                code: KeyCode::Enter,
+                                                modifiers: KeyModifiers::NONE,
+                                                kind: KeyEventKind::Press,
+                                                state: KeyEventState::NONE,
+                                            }),
+                                            _,
+                                        )) => {
+                                            let message = match cur_form.kind {
+                                                FormKind::FindReplace => {
+                                                    let mut editors = shared_editors.lock().await;
+                                                    match editors.get_mut(&cur_form.index) {
+                                                        Some(editor) => match replace_with_regex(
+                                                            editor,
+                                                            &cur_form.text(FormField::Find),
+                                                            &cur_form.text(FormField::Replace),
+                                                        ) {
+                                                            Ok(()) => {
+                                                                let _ = validate_tx.try_send(());
+                                                                shared_renderer
+                                                                    .lock()
+                                                                    .await
+                                                                    .update([(
+                                                                        PaneIndex::Editor(
+                                                                            cur_form.index.clone(),
+                                                                        ),
+                                                                        editor.create_pane(
+                                                                            terminal_shape.0,
+                                                                            terminal_shape.1,
+                                                                        ),
+                                                                    )]);
+                                                                None
+                                                            }
+                                                            Err(e) => Some(NotifyMessage::Error(
+                                                                format!("Invalid regex: {}", e),
+                                                            )),
+                                                        },
+                                                        None => None,
+                                                    }
+                                                }
+                                                FormKind::Comment => {
+                                                    let comment = cur_form.text(FormField::Comment);
+                                                    let mut editors = shared_editors.lock().await;
+                                                    if let Some(editor) =
+                                                        editors.get_mut(&cur_form.index)
+                                                    {
+                                                        editor.comment = Some(comment.trim())
+                                                            .filter(|comment| !comment.is_empty())
+                                                            .map(str::to_string);
+                                                        shared_renderer.lock().await.update([(
+                                                            PaneIndex::Editor(
+                                                                cur_form.index.clone(),
+                                                            ),
+                                                            editor.create_pane(
+                                                                terminal_shape.0,
+                                                                terminal_shape.1,
+                                                            ),
+                                                        )]);
+                                                    }
+                                                    None
+                                                }
+                                                FormKind::Snippet => {
+                                                    let name = cur_form.text(FormField::SnippetName);
+                                                    let stages = shared_editors.lock().await.snippet_stages();
+                                                    let saved = snippets::default_path()
+                                                        .ok_or_else(|| {
+                                                            anyhow::anyhow!("the config directory is unknown")
+                                                        })
+                                                        .and_then(|path| {
+                                                            snippets::save(&path, name.trim(), &stages)
+                                                        });
+                                                    Some(match saved {
+                                                        Ok(false) => NotifyMessage::Info(format!(
+                                                            "Saved the snippet {} ({} stages)",
+                                                            name.trim(),
+                                                            stages.len()
+                                                        )),
+                                                        Ok(true) => NotifyMessage::Warning(format!(
+                                                            "Overwrote the snippet {} ({} stages)",
+                                                            name.trim(),
+                                                            stages.len()
+                                                        )),
+                                                        Err(e) => NotifyMessage::Error(format!(
+                                                            "Cannot save the snippet: {}",
+                                                            e
+                                                        )),
+                                                    })
+                                                }
+                                                FormKind::Export => {
+                                                    let path = cur_form.text(FormField::ExportPath);
+                                                    let script = pipeline_export::to_shell_script(
+                                                        &shared_editors.lock().await.stage_specs(),
+                                                    );
+                                                    match pipeline_export::write_script(
+                                                        path.trim(),
+                                                        &script,
+                                                    ) {
+                                                        Ok(()) => {
+                                                            Some(NotifyMessage::Info(format!(
+                                                                "Exported the pipeline to {}",
+                                                                path.trim()
+                                                            )))
+                                                        }
+                                                        Err(e) => {
+                                                            Some(NotifyMessage::Error(format!(
+                                                                "Cannot export the pipeline: {}",
+                                                                e
+                                                            )))
+                                                        }
+                                                    }
+                                                }
+                                            };
+                                            if let Some(message) = message {
+                                                let _ = notify_tx.send(message).await;
+                                            }
+                                            shared_renderer
+                                                .lock()
+                                                .await
+                                                .remove(cur_form.pane_indices());
+                                            form = None;
+                                        }
+                                        EventStream::Buffer(Buffer::Other(
+                                            Event::Key(KeyEvent {
+                                                code: KeyCode::Esc,
+                                                modifiers: KeyModifiers::NONE,
+                                                kind: KeyEventKind::Press,
+                                                state: KeyEventState::NONE,
+                                            }),
+                                            _,
+                                        )) => {
+                                            shared_renderer
+                                                .lock()
+                                                .await
+                                                .remove(cur_form.pane_indices());
+                                            form = None;
+                                        }
+                                        event => {
+                                            edit(&event, cur_form.focused_mut());
+                                        }
+                                    }
+                                    if let Some(form) = &form {
+                                        shared_renderer
+                                            .lock()
+                                            .await
+                                            .update(form.panes(terminal_shape.0, terminal_shape.1));
+                                    }
+                                }
+                                EventStream::Buffer(Buffer::Other(
+                                    Event::Key(KeyEvent {
+                                        code: KeyCode::Char('h'),
+                                        modifiers: KeyModifiers::CONTROL,
+                                        kind: KeyEventKind::Press,
+                                        state: KeyEventState::NONE,
+                                    }),
+                                    _,
+                                )) => {
+                                    let new_form = Form::new(
+                                        FormKind::FindReplace,
+                                        cur_index.clone(),
+                                        &themes.1,
+                                    );
+                                    shared_renderer
+                                        .lock()
+                                        .await
+                                        .update(new_form.panes(terminal_shape.0, terminal_shape.1));
+                                    form = Some(new_form);
+                                }
+                                EventStream::Buffer(Buffer::Other(
+                                    Event::Key(KeyEvent {
+                                        code: KeyCode::Char('x' | 'X'),
+                                        modifiers,
+                                        kind: KeyEventKind::Press,
+                                        state: KeyEventState::NONE,
+                                    }),
+                                    _,
+                                )) if keys::is_ctrl_shift(modifiers) => {
+                                    let new_form =
+                                        Form::new(FormKind::Export, cur_index.clone(), &themes.1);
+                                    shared_renderer
+                                        .lock()
+                                        .await
+                                        .update(new_form.panes(terminal_shape.0, terminal_shape.1));
+                                    form = Some(new_form);
+                                }
+                                // Save the pipeline as a snippet under the name typed in the form.
+                                EventStream::Buffer(Buffer::Other(
+                                    Event::Key(KeyEvent {
+                                        code: KeyCode::Char('s' | 'S'),
+                                        modifiers,
+                                        kind: KeyEventKind::Press,
+                                        state: KeyEventState::NONE,
+                                    }),
+                                    _,
+                                )) if keys::is_ctrl_shift(modifiers) => {
+                                    let new_form =
+                                        Form::new(FormKind::Snippet, cur_index.clone(), &themes.1);
+                                    shared_renderer
+                                        .lock()
+                                        .await
+                                        .update(new_form.panes(terminal_shape.0, terminal_shape.1));
+                                    form = Some(new_form);
+                                }
+                                EventStream::Buffer(Buffer::Other(
+                                    Event::Key(KeyEvent {
+                                        code: KeyCode::Char(';'),
+                                        modifiers: KeyModifiers::ALT,
+                                        kind: KeyEventKind::Press,
+                                        state: KeyEventState::NONE,
+                                    }),
+                                    _,
+                                )) => {
+                                    let mut new_form =
+                                        Form::new(FormKind::Comment, cur_index.clone(), &themes.1);
+                                    if let Some(comment) = shared_editors
+                                        .lock()
+                                        .await
+                                        .get(&cur_index)
+                                        .and_then(|editor| editor.comment.as_ref())
+                                    {
+                                        new_form.focused_mut().texteditor.replace(comment);
+                                    }
+                                    shared_renderer
+                                        .lock()
+                                        .await
+                                        .update(new_form.panes(terminal_shape.0, terminal_shape.1));
+                                    form = Some(new_form);
+                                }
+                                EventStream::Buffer(Buffer::Paste(text)) => {
+                                    let mut editors = shared_editors.lock().await;
+                                    let cmds = pipeline::split_pipeline(&text);
+                                    let is_empty = editors
+                                        .try_get(&cur_index)?
                                         .state
-                                        .active_char_style
-                                        .attributes
-                                        .toggle(Attribute::CrossedOut);
-                                    cur_editor
+                                        .texteditor
+                                        .text_without_cursor()
+                                        .to_string()
+                                        .trim()
+                                        .is_empty();
+
+                                    // Split the pipeline into stages only when pasting into an empty editor
+                                    // to avoid surprising changes in the middle of editing.
+                                    if !is_empty || cmds.len() < 2 {
+                                        edit(
+                                            &EventStream::Buffer(Buffer::Paste(text)),
+                                            &mut editors.try_get_mut(&cur_index)?.state,
+                                        );
+                                        let _ = validate_tx.try_send(());
+                                        shared_renderer.lock().await.update([(
+                                            PaneIndex::Editor(cur_index.clone()),
+                                            editors
+                                                .try_get(&cur_index)?
+                                                .create_pane(terminal_shape.0, terminal_shape.1),
+                                        )]);
+                                    } else {
+                                        let mut new_index = cur_index.clone();
+                                        let mut inserts = HashSet::from([new_index.clone()]);
+                                        for (i, cmd) in cmds.iter().enumerate() {
+                                            if i > 0 {
+                                                new_index = Self::insert_editor(
+                                                    &new_index,
+                                                    &mut editors,
+                                                    &themes.1,
+                                                )?;
+                                                inserts.insert(new_index.clone());
+                                            }
+                                            editors
+                                                .try_get_mut(&new_index)?
+                                                .state
+                                                .texteditor
+                                                .replace(cmd);
+                                        }
+                                        let _ = validate_tx.try_send(());
+                                        // Change theme because of switching focus
+                                        Self::switch_theme(
+                                            &mut editors,
+                                            Some(&cur_index),
+                                            &new_index,
+                                            &themes,
+                                        )?;
+                                        // Update changes for rendering
+                                        let panes = inserts
+                                            .into_iter()
+                                            .map(|index| {
+                                                Ok((
+                                                    PaneIndex::Editor(index.clone()),
+                                                    editors.try_get(&index)?.create_pane(
+                                                        terminal_shape.0,
+                                                        terminal_shape.1,
+                                                    ),
+                                                ))
+                                            })
+                                            .collect::<anyhow::Result<Vec<_>>>()?;
+                                        shared_renderer.lock().await.update(panes);
+                                        // Update the current index
+                                        cur_index = new_index;
+                                    }
+                                }
+                                EventStream::Buffer(Buffer::Other(
+                                    Event::Key(KeyEvent {
+                                        code: KeyCode::Char('b'),
+                                        modifiers: KeyModifiers::CONTROL,
+                                        kind: KeyEventKind::Press,
+                                        state: KeyEventState::NONE,
+                                    }),
+                                    times,
+                                )) => {
+                                    let mut new_index = cur_index.clone();
+                                    let mut inserts = HashSet::from([new_index.clone()]);
+
+                                    let mut editors = shared_editors.lock().await;
+                                    // Insert new editors
+                                    for _ in 0..times {
+                                        new_index = Self::insert_editor(
+                                            &new_index,
+                                            &mut editors,
+                                            &themes.1,
+                                        )?;
+                                        inserts.insert(new_index.clone());
+                                    }
+                                    // Change theme because of switching focus
+                                    Self::switch_theme(
+                                        &mut editors,
+                                        Some(&cur_index),
+                                        &new_index,
+                                        &themes,
+                                    )?;
+                                    // Update changes for rendering
+                                    let panes = inserts
+                                        .into_iter()
+                                        .map(|index| {
+                                            Ok((
+                                                PaneIndex::Editor(index.clone()),
+                                                editors.try_get(&index)?.create_pane(
+                                                    terminal_shape.0,
+                                                    terminal_shape.1,
+                                                ),
+                                            ))
+                                        })
+                                        .collect::<anyhow::Result<Vec<_>>>()?;
+                                    shared_renderer.lock().await.update(panes);
+                                    // Update the current index
+                                    cur_index = new_index;
+                                }
+                                EventStream::Buffer(Buffer::Other(
+                                    Event::Key(KeyEvent {
+                                        code: KeyCode::Char('b' | 'B'),
+                                        modifiers,
+                                        kind: KeyEventKind::Press,
+                                        state: KeyEventState::NONE,
+                                    }),
+                                    times,
+                                )) if keys::is_ctrl_shift(modifiers) => {
+                                    let mut new_index = cur_index.clone();
+                                    let mut inserts = HashSet::from([new_index.clone()]);
+
+                                    let mut editors = shared_editors.lock().await;
+                                    // Insert new editors above
+                                    for _ in 0..times {
+                                        let moved_index;
+                                        (new_index, moved_index) =
+                                            Self::insert_editor_above(&new_index, &mut editors, &themes.1)?;
+                                        inserts.insert(new_index.clone());
+                                        inserts.insert(moved_index);
+                                    }
+                                    // Change theme because of switching focus
+                                    // (the focused stage may have moved from the head)
+                                    for index in &inserts {
+                                        Self::switch_theme(&mut editors, Some(index), &new_index, &themes)?;
+                                    }
+                                    // Update changes for rendering
+                                    let panes = inserts
+                                        .into_iter()
+                                        .map(|index| {
+                                            Ok((
+                                                PaneIndex::Editor(index.clone()),
+                                                editors.try_get(&index)?.create_pane(
+                                                    terminal_shape.0,
+                                                    terminal_shape.1,
+                                                ),
+                                            ))
+                                        })
+                                        .collect::<anyhow::Result<Vec<_>>>()?;
+                                    shared_renderer.lock().await.update(panes);
+                                    // Update the current index
+                                    cur_index = new_index;
+                                }
+                                EventStream::Buffer(Buffer::Other(
+                                    Event::Key(KeyEvent {
+                                        code: KeyCode::Char('d'),
+                                        modifiers: KeyModifiers::CONTROL,
+                                        kind: KeyEventKind::Press,
+                                        state: KeyEventState::NONE,
+                                    }),
+                                    times,
+                                )) => {
+                                    let mut prev_index = cur_index.clone();
+                                    let mut removals = HashSet::new();
+
+                                    {
+                                        let mut editors = shared_editors.lock().await;
+                                        // Remove editors
+                                        for _ in 0..times {
+                                            // Early return if the head editor is removed
+                                            if prev_index == HEAD_INDEX {
+                                                break;
+                                            }
+                                            removals.insert(prev_index.clone());
+                                            let editor = editors.try_get(&prev_index)?;
+                                            removed_stages.push(RemovedStage {
+                                                index: prev_index.clone(),
+                                                text: editor
+                                                    .state
+                                                    .texteditor
+                                                    .text_without_cursor()
+                                                    .to_string(),
+                                                ignore: editor.ignore,
+                                                comment: editor.comment.clone(),
+                                            });
+                                            if removed_stages.len() > MAX_REMOVED_STAGES {
+                                                removed_stages.remove(0);
+                                            }
+                                            prev_index =
+                                                Self::remove_editor(&prev_index, &mut editors)?;
+                                        }
+                                        // Change theme because of switching focus
+                                        Self::switch_theme(
+                                            &mut editors,
+                                            None,
+                                            &prev_index,
+                                            &themes,
+                                        )?;
+                                    }
+
+                                    // Update changes for rendering
+                                    {
+                                        let mut renderer = shared_renderer.lock().await;
+                                        let _ = renderer
+                                            .remove(removals.into_iter().map(PaneIndex::Editor))
+                                            .update([(
+                                                PaneIndex::Editor(prev_index.clone()),
+                                                shared_editors
+                                                    .lock()
+                                                    .await
+                                                    .try_get(&prev_index)?
+                                                    .create_pane(
+                                                        terminal_shape.0,
+                                                        terminal_shape.1,
+                                                    ),
+                                            )]);
+                                    }
+
+                                    // Update the current index
+                                    cur_index = prev_index;
+                                }
+                                EventStream::Buffer(Buffer::Other(
+                                    Event::Key(KeyEvent {
+                                        code,
+                                        modifiers,
+                                        kind: KeyEventKind::Press,
+                                        state: KeyEventState::NONE,
+                                    }),
+                                    times,
+                                )) if merge_separator(code, modifiers).is_some() => {
+                                    if cur_index == HEAD_INDEX {
+                                        let _ = notify_tx
+                                            .send(NotifyMessage::Error(String::from(
+                                                "The head stage has no previous stage to merge into",
+                                            )))
+                                            .await;
+                                        return Ok(false);
+                                    }
+                                    let separator = merge_separator(code, modifiers).unwrap_or(" ");
+                                    let mut prev_index = cur_index.clone();
+                                    let mut removals = HashSet::new();
+
+                                    let mut editors = shared_editors.lock().await;
+                                    for _ in 0..times {
+                                        if prev_index == HEAD_INDEX {
+                                            break;
+                                        }
+                                        removals.insert(prev_index.clone());
+                                        prev_index =
+                                            Self::merge_editor(&prev_index, &mut editors, separator)?;
+                                    }
+                                    // Change theme because of switching focus
+                                    Self::switch_theme(&mut editors, None, &prev_index, &themes)?;
+
+                                    // Update changes for rendering
+                                    let _ = shared_renderer
+                                        .lock()
+                                        .await
+                                        .remove(removals.into_iter().map(PaneIndex::Editor))
+                                        .update([(
+                                            PaneIndex::Editor(prev_index.clone()),
+                                            editors
+                                                .try_get(&prev_index)?
+                                                .create_pane(terminal_shape.0, terminal_shape.1),
+                                        )]);
+
+                                    // Update the current index
+                                    cur_index = prev_index;
+                                }
+                                EventStream::Buffer(Buffer::Key(chars))
+                                    if chars[..] == ['>'] && split_at.as_ref() == Some(&cur_index) =>
+                                {
+                                    let mut editors = shared_editors.lock().await;
+                                    let prev_index =
+                                        Self::merge_editor(&cur_index, &mut editors, " |> ")?;
+                                    editors
+                                        .try_get_mut(&prev_index)?
                                         .state
-                                        .inactive_char_style
-                                        .attributes
-                                        .toggle(Attribute::CrossedOut);
+                                        .texteditor
+                                        .move_to_tail();
+                                    // Change theme because of switching focus
+                                    Self::switch_theme(&mut editors, None, &prev_index, &themes)?;
+
+                                    // Update changes for rendering
+                                    let _ = shared_renderer
+                                        .lock()
+                                        .await
+                                        .remove([PaneIndex::Editor(cur_index.clone())])
+                                        .update([(
+                                            PaneIndex::Editor(prev_index.clone()),
+                                            editors
+                                                .try_get(&prev_index)?
+                                                .create_pane(terminal_shape.0, terminal_shape.1),
+                                        )]);
+
+                                    // Update the current index
+                                    cur_index = prev_index;
+                                }
+                                EventStream::Buffer(Buffer::Other(
+                                    Event::Key(KeyEvent {
+                                        code: KeyCode::Char('u'),
+                                        modifiers: KeyModifiers::ALT,
+                                        kind: KeyEventKind::Press,
+                                        state: KeyEventState::NONE,
+                                    }),
+                                    times,
+                                )) => {
+                                    let mut new_index = cur_index.clone();
+                                    let mut restores = HashSet::from([new_index.clone()]);
+
+                                    let mut editors = shared_editors.lock().await;
+                                    for _ in 0..times {
+                                        let Some(stage) = removed_stages.pop() else {
+                                            let _ = notify_tx
+                                                .send(NotifyMessage::Error(String::from(
+                                                    "No deleted stage to restore",
+                                                )))
+                                                .await;
+                                            break;
+                                        };
+                                        new_index =
+                                            Self::restore_editor(stage, &mut editors, &themes.1)?;
+                                        restores.insert(new_index.clone());
+                                    }
+                                    // Change theme because of switching focus
+                                    for index in &restores {
+                                        Self::switch_theme(&mut editors, Some(index), &new_index, &themes)?;
+                                    }
+                                    let _ = validate_tx.try_send(());
+                                    // Update changes for rendering
+                                    let panes = restores
+                                        .into_iter()
+                                        .map(|index| {
+                                            Ok((
+                                                PaneIndex::Editor(index.clone()),
+                                                editors.try_get(&index)?.create_pane(
+                                                    terminal_shape.0,
+                                                    terminal_shape.1,
+                                                ),
+                                            ))
+                                        })
+                                        .collect::<anyhow::Result<Vec<_>>>()?;
+                                    shared_renderer.lock().await.update(panes);
+                                    // Update the current index
+                                    cur_index = new_index;
+                                }
+                                EventStream::Buffer(Buffer::Other(
+                                    Event::Key(KeyEvent {
+                                        code: KeyCode::Char('x'),
+                                        modifiers: KeyModifiers::CONTROL,
+                                        kind: KeyEventKind::Press,
+                                        state: KeyEventState::NONE,
+                                    }),
+                                    times,
+                                )) => {
+                                    if times % 2 != 0 {
+                                        let mut editors = shared_editors.lock().await;
+                                        let cur_editor = editors.try_get_mut(&cur_index)?;
+                                        cur_editor.toggle_ignore();
+                                        shared_renderer.lock().await.update(vec![(
+                                            PaneIndex::Editor(cur_index.clone()),
+                                            cur_editor
+                                                .create_pane(terminal_shape.0, terminal_shape.1),
+                                        )]);
+                                    }
+                                }
+                                // Disable all the other stages to run the current one in isolation,
+                                // or enable them all again if they are already disabled.
+                                EventStream::Buffer(Buffer::Other(
+                                    Event::Key(KeyEvent {
+                                        code: KeyCode::Char('x'),
+                                        modifiers: KeyModifiers::ALT,
+                                        kind: KeyEventKind::Press,
+                                        state: KeyEventState::NONE,
+                                    }),
+                                    times,
+                                )) => {
+                                    if times % 2 != 0 {
+                                        let (mut editors, mut renderer) = tokio::join!(
+                                            shared_editors.lock(),
+                                            shared_renderer.lock()
+                                        );
+                                        let ignore = editors
+                                            .iter()
+                                            .any(|(index, editor)| *index != cur_index && !editor.ignore);
+                                        let panes: Vec<(PaneIndex, Pane)> = editors
+                                            .iter_mut()
+                                            .filter(|(index, editor)| {
+                                                **index != cur_index && editor.ignore != ignore
+                                            })
+                                            .filter_map(|(index, editor)| {
+                                                editor.toggle_ignore();
+                                                // The hidden editors are rendered when scrolled to.
+                                                let pane_index = PaneIndex::Editor(index.clone());
+                                                renderer.contains(&pane_index).then(|| {
+                                                    (
+                                                        pane_index,
+                                                        editor.create_pane(
+                                                            terminal_shape.0,
+                                                            terminal_shape.1,
+                                                        ),
+                                                    )
+                                                })
+                                            })
+                                            .collect();
+                                        renderer.update(panes);
+                                    }
+                                }
+                                // Show or hide the whole pipeline as a command line.
+                                EventStream::Buffer(Buffer::Other(
+                                    Event::Key(KeyEvent {
+                                        code: KeyCode::Char('a'),
+                                        modifiers: KeyModifiers::ALT,
+                                        kind: KeyEventKind::Press,
+                                        state: KeyEventState::NONE,
+                                    }),
+                                    times,
+                                )) => {
+                                    if times % 2 != 0 {
+                                        command_line_shown = !command_line_shown;
+                                    }
+                                }
+                                // Show the output of the current stage instead of the last one.
+                                EventStream::Buffer(Buffer::Other(
+                                    Event::Key(KeyEvent {
+                                        code: KeyCode::Char('p'),
+                                        modifiers: KeyModifiers::ALT,
+                                        kind: KeyEventKind::Press,
+                                        state: KeyEventState::NONE,
+                                    }),
+                                    times,
+                                )) => {
+                                    if times % 2 != 0 {
+                                        let mut editors = shared_editors.lock().await;
+                                        let preview = !editors.try_get(&cur_index)?.preview;
+                                        for (index, editor) in editors.iter_mut() {
+                                            editor.preview = preview && *index == cur_index;
+                                        }
+                                        let _ = notify_tx
+                                            .send(NotifyMessage::Info(String::from(if preview {
+                                                "preview: the output of this stage is shown on the next run"
+                                            } else {
+                                                "preview: off"
+                                            })))
+                                            .await;
+                                    }
+                                }
+                                EventStream::Buffer(Buffer::Other(
+                                    Event::Mouse(MouseEvent {
+                                        kind: MouseEventKind::Down(MouseButton::Left),
+                                        column,
+                                        row,
+                                        ..
+                                    }),
+                                    _,
+                                )) => {
+                                    // Ignore clicks on the panes other than editors.
+                                    let Some((PaneIndex::Editor(next_index), row_in_pane)) =
+                                        shared_renderer.lock().await.pane_at(row)
+                                    else {
+                                        return Ok(false);
+                                    };
+
+                                    let mut editors = shared_editors.lock().await;
+                                    let Some(next_editor) = editors.get_mut(&next_index) else {
+                                        return Ok(false);
+                                    };
+                                    // Place the text cursor near the clicked column.
+                                    let prefix_width =
+                                        StyledGraphemes::from(next_editor.prefix()).widths();
+                                    let position = (row_in_pane * terminal_shape.0 as usize
+                                        + column as usize)
+                                        .saturating_sub(prefix_width)
+                                        .min(next_editor.state.texteditor.text().len() - 1);
+                                    next_editor.state.texteditor.move_to_head();
+                                    next_editor.state.texteditor.shift(0, position);
+
+                                    // Change theme because of switching focus
+                                    Self::switch_theme(
+                                        &mut editors,
+                                        Some(&cur_index),
+                                        &next_index,
+                                        &themes,
+                                    )?;
+                                    // Update changes for rendering
+                                    shared_renderer.lock().await.update(vec![
+                                        (
+                                            PaneIndex::Editor(cur_index.clone()),
+                                            editors
+                                                .try_get(&cur_index)?
+                                                .create_pane(terminal_shape.0, terminal_shape.1),
+                                        ),
+                                        (
+                                            PaneIndex::Editor(next_index.clone()),
+                                            editors
+                                                .try_get(&next_index)?
+                                                .create_pane(terminal_shape.0, terminal_shape.1),
+                                        ),
+                                    ]);
+                                    // Update the current index
+                                    cur_index = next_index;
+                                }
+                                EventStream::FocusStage(n) => {
+                                    let mut editors = shared_editors.lock().await;
+                                    let next_index = match n {
+                                        0 => editors.last_index().cloned(),
+                                        n => editors
+                                            .iter()
+                                            .nth(n - 1)
+                                            .map(|(index, _)| index.clone()),
+                                    };
+                                    match next_index {
+                                        Some(next_index) => {
+                                            // Change theme because of switching focus
+                                            Self::switch_theme(
+                                                &mut editors,
+                                                Some(&cur_index),
+                                                &next_index,
+                                                &themes,
+                                            )?;
+                                            // Update changes for rendering
+                                            shared_renderer.lock().await.update([
+                                                (
+                                                    PaneIndex::Editor(cur_index.clone()),
+                                                    editors.try_get(&cur_index)?.create_pane(
+                                                        terminal_shape.0,
+                                                        terminal_shape.1,
+                                                    ),
+                                                ),
+                                                (
+                                                    PaneIndex::Editor(next_index.clone()),
+                                                    editors.try_get(&next_index)?.create_pane(
+                                                        terminal_shape.0,
+                                                        terminal_shape.1,
+                                                    ),
+                                                ),
+                                            ]);
+                                            // Update the current index
+                                            cur_index = next_index;
+                                        }
+                                        None => {
+                                            let _ = notify_tx
+                                                .send(NotifyMessage::Error(format!(
+                                                    "Stage {} does not exist (there are {} stages)",
+                                                    n,
+                                                    editors.len()
+                                                )))
+                                                .await;
+                                        }
+                                    }
+                                }
+                                EventStream::Buffer(Buffer::VerticalCursor(up, down)) => {
+                                    let mut editors = shared_editors.lock().await;
+                                    // Move cursor up or down
+                                    let next_index = editors.shift_index(&cur_index, up, down)?;
+                                    // Change theme because of switching focus
+                                    Self::switch_theme(
+                                        &mut editors,
+                                        Some(&cur_index),
+                                        &next_index,
+                                        &themes,
+                                    )?;
+                                    // Update changes for rendering
+                                    shared_renderer.lock().await.update(vec![
+                                        (
+                                            PaneIndex::Editor(cur_index.clone()),
+                                            editors
+                                                .try_get(&cur_index)?
+                                                .create_pane(terminal_shape.0, terminal_shape.1),
+                                        ),
+                                        (
+                                            PaneIndex::Editor(next_index.clone()),
+                                            editors
+                                                .try_get(&next_index)?
+                                                .create_pane(terminal_shape.0, terminal_shape.1),
+                                        ),
+                                    ]);
+                                    // Update the current index
+                                    cur_index = next_index;
+                                }
+                                event => {
+                                    let mut editors = shared_editors.lock().await;
+                                    let cur_editor = editors.try_get_mut(&cur_index)?;
+                                    cur_editor.apply_word_break_chars();
+                                    edit(&event, &mut cur_editor.state);
+                                    // Pending requests are enough, so ignore the error when full.
+                                    let _ = validate_tx.try_send(());
                                     shared_renderer.lock().await.update(vec![(
                                         PaneIndex::Editor(cur_index.clone()),
-                                        cur_editor.create_pane(terminal_shape.0, terminal_shape.1),
+                                        editors
+                                            .try_get(&cur_index)?
+                                            .create_pane(terminal_shape.0, terminal_shape.1),
                                     )]);
                                 }
+                            };
+                            Ok(true)
+                        }
+                        .await;
+                        if splitting && matches!(handled, Ok(true)) {
+                            auto_split_at = Some(cur_index.clone());
+                        }
+                        match handled {
+                            Ok(true) => {}
+                            Ok(false) => continue,
+                            Err(e) => {
+                                // Recover by focusing the head editor instead of killing the task,
+                                // which would stop responding to the inputs.
+                                let _ = notify_tx
+                                    .send(NotifyMessage::Error(format!(
+                                        "Failed to handle the input: {:#}",
+                                        e
+                                    )))
+                                    .await;
+                                cur_index = HEAD_INDEX.clone();
+                                let (mut editors, mut renderer) =
+                                    tokio::join!(shared_editors.lock(), shared_renderer.lock());
+                                Self::reset_focus(&mut editors, &themes);
+                                renderer.update(editors.iter().map(|(index, editor)| {
+                                    (
+                                        PaneIndex::Editor(index.clone()),
+                                        editor.create_pane(terminal_shape.0, terminal_shape.1),
+                                    )
+                                }));
                             }
-                            EventStream::Buffer(Buffer::VerticalCursor(up, down)) => {
-                                let mut editors = shared_editors.lock().await;
-                                // Move cursor up or down
-                                let next_index = editors.shift_index(&cur_index, up, down).unwrap();
-                                // Change theme because of switching focus
-                                Self::switch_theme(
-                                    &mut editors,
-                                    Some(&cur_index),
-                                    &next_index,
-                                    &themes,
+                        }
+
+                        // Renumber the editors before their indices grow too large.
+                        {
+                            let (mut editors, mut renderer) =
+                                tokio::join!(shared_editors.lock(), shared_renderer.lock());
+                            if editors.needs_renormalize() {
+                                let changes = editors.renormalize();
+                                let remap = |index: &mut EditorIndex| {
+                                    if let Some((_, new_index)) =
+                                        changes.iter().find(|(old_index, _)| old_index == index)
+                                    {
+                                        *index = new_index.clone();
+                                    }
+                                };
+                                renderer.remove(
+                                    changes
+                                        .iter()
+                                        .map(|(old_index, _)| PaneIndex::Editor(old_index.clone())),
                                 );
-                                // Update changes for rendering
-                                shared_renderer.lock().await.update(vec![
-                                    (
-                                        PaneIndex::Editor(cur_index.clone()),
-                                        editors
-                                            .get(&cur_index)
-                                            .unwrap()
-                                            .create_pane(terminal_shape.0, terminal_shape.1),
-                                    ),
+                                remap(&mut cur_index);
+                                if let Some((index, _)) = shown_comment.as_mut() {
+                                    remap(index);
+                                }
+                                if let Some(form) = form.as_mut() {
+                                    renderer.remove(form.pane_indices());
+                                    remap(&mut form.index);
+                                    renderer.update(form.panes(terminal_shape.0, terminal_shape.1));
+                                }
+                                renderer.update(editors.iter().map(|(index, editor)| {
                                     (
-                                        PaneIndex::Editor(next_index.clone()),
-                                        editors
-                                            .get(&next_index)
-                                            .unwrap()
-                                            .create_pane(terminal_shape.0, terminal_shape.1),
-                                    ),
-                                ]);
-                                // Update the current index
-                                cur_index = next_index;
-                            }
-                            event => {
-                                let mut editors = shared_editors.lock().await;
-                                edit(&event, &mut editors.get_mut(&cur_index).unwrap().state);
-                                shared_renderer.lock().await.update(vec![(
-                                    PaneIndex::Editor(cur_index.clone()),
-                                    editors
-                                        .get(&cur_index)
-                                        .unwrap()
-                                        .create_pane(terminal_shape.0, terminal_shape.1),
-                                )]);
+                                        PaneIndex::Editor(index.clone()),
+                                        editor.create_pane(terminal_shape.0, terminal_shape.1),
+                                    )
+                                }));
                             }
-                        };
+                        }
+
+                        // Render only the editors around the focused one which fit in the screen.
+                        {
+                            let (editors, mut renderer) =
+                                tokio::join!(shared_editors.lock(), shared_renderer.lock());
+                            let rows = (terminal_shape.1.saturating_sub(render::reserved_rows(
+                                *output_height.borrow(),
+                                min_output_height,
+                            )) as usize)
+                                .saturating_sub(form.as_ref().map_or(0, |form| form.fields.len()))
+                                .saturating_sub(command_line_shown as usize);
+                            let focus = editors
+                                .iter()
+                                .position(|(index, _)| *index == cur_index)
+                                .unwrap_or(0);
+                            let window = editor_window(editors.len(), focus, rows, &mut editor_top);
+
+                            renderer.remove(
+                                editors
+                                    .iter()
+                                    .enumerate()
+                                    .filter(|(i, _)| !window.contains(i))
+                                    .map(|(_, (index, _))| PaneIndex::Editor(index.clone())),
+                            );
+                            renderer.update(
+                                editors.iter().skip(window.start).take(window.len()).map(
+                                    |(index, editor)| {
+                                        (
+                                            PaneIndex::Editor(index.clone()),
+                                            editor.create_pane(terminal_shape.0, terminal_shape.1),
+                                        )
+                                    },
+                                ),
+                            );
+                            renderer.update([
+                                (
+                                    PaneIndex::EditorsAbove,
+                                    hidden_editors_pane("▲", window.start),
+                                ),
+                                (
+                                    PaneIndex::EditorsBelow,
+                                    hidden_editors_pane("▼", editors.len() - window.end),
+                                ),
+                                (
+                                    PaneIndex::Preview,
+                                    if command_line_shown {
+                                        command_line_pane(
+                                            &collect_texts(editors.values(), &aliases),
+                                            terminal_shape.0,
+                                        )
+                                    } else {
+                                        Pane::new(vec![], 0)
+                                    },
+                                ),
+                            ]);
+                        }
 
+                        *shared_cur_index.lock().await = cur_index.clone();
                         let _ = shared_renderer.lock().await.render();
+
+                        // Show the comment of the focused editor.
+                        let comment = shared_editors
+                            .lock()
+                            .await
+                            .get(&cur_index)
+                            .and_then(|editor| editor.comment.clone())
+                            .map(|comment| (cur_index.clone(), comment));
+                        if comment != shown_comment {
+                            let _ = notify_tx
+                                .send(match &comment {
+                                    Some((_, comment)) => NotifyMessage::Sticky(comment.clone()),
+                                    None => NotifyMessage::None,
+                                })
+                                .await;
+                            shown_comment = comment;
+                        }
                     }
                 }
             })
         };
 
-        Self {
+        Ok(Self {
             shared_editors,
+            aliases,
+            shared_cur_index,
+            shared_renderer,
+            loaded,
             background,
+        })
+    }
+
+    /// Returns the receiver notified each time the stages are replaced
+    /// by `EventStream::Load` or `EventStream::LoadStages`.
+    pub fn loaded(&self) -> watch::Receiver<usize> {
+        self.loaded.clone()
+    }
+
+    /// Returns the command name (the first word) of the focused editor
+    /// if the cursor is placed on it.
+    pub async fn get_command_under_cursor(&self) -> Option<String> {
+        let cur_index = self.shared_cur_index.lock().await.clone();
+        let editors = self.shared_editors.lock().await;
+        let texteditor = &editors.get(&cur_index)?.state.texteditor;
+
+        let text = texteditor.text_without_cursor().to_string();
+        let start = text.chars().take_while(|c| c.is_whitespace()).count();
+        let end = start
+            + text
+                .chars()
+                .skip(start)
+                .take_while(|c| !c.is_whitespace())
+                .count();
+
+        // Allow the cursor to be just after the command name.
+        if start == end || !(start..=end).contains(&texteditor.position()) {
+            return None;
         }
+        Some(text.chars().skip(start).take(end - start).collect())
     }
 
-    pub async fn get_all_texts(&mut self) -> Vec<String> {
+    /// Returns the stages as saved in the session, and the index of the focused one.
+    pub async fn session_stages(&self) -> (Vec<session::Stage>, usize) {
+        let cur_index = self.shared_cur_index.lock().await.clone();
+        let editors = self.shared_editors.lock().await;
+        let stages = editors
+            .values()
+            .map(|editor| session::Stage {
+                text: editor.state.texteditor.text_without_cursor().to_string(),
+                ignore: editor.ignore,
+                comment: editor.comment.clone(),
+            })
+            .collect();
+        let focus = editors
+            .iter()
+            .position(|(index, _)| *index == cur_index)
+            .unwrap_or(0);
+        (stages, focus)
+    }
+
+    /// Returns the number of the editors, including the ignored ones.
+    pub async fn editor_count(&self) -> usize {
+        self.shared_editors.lock().await.len()
+    }
+
+    /// Returns whether all the editors are empty.
+    pub async fn is_empty(&self) -> bool {
+        self.shared_editors.lock().await.values().all(|editor| {
+            editor
+                .state
+                .texteditor
+                .text_without_cursor()
+                .to_string()
+                .trim()
+                .is_empty()
+        })
+    }
+
+    /// Returns the position of the preview stage in the texts returned by `get_all_texts`,
+    /// not counting the `SET` stages.
+    pub async fn preview_stage(&self) -> Option<usize> {
         self.shared_editors
             .lock()
             .await
             .values()
-            .filter(|editor| !editor.ignore)
+            .filter(|editor| {
+                let text = editor.command_text();
+                !editor.ignore && !text.trim().is_empty() && !pipeline::is_set_command(&text)
+            })
+            .position(|editor| editor.preview)
+    }
+
+    pub async fn get_all_texts(&self) -> Vec<String> {
+        collect_texts(self.shared_editors.lock().await.values(), &self.aliases)
+    }
+
+    /// Returns the texts as `get_all_texts` does, but only of the stages
+    /// up to and including the focused one.
+    pub async fn get_texts_until_focused(&self) -> Vec<String> {
+        let cur_index = self.shared_cur_index.lock().await.clone();
+        collect_texts(
+            self.shared_editors
+                .lock()
+                .await
+                .iter()
+                .take_while(|(index, _)| **index <= cur_index)
+                .map(|(_, editor)| editor),
+            &self.aliases,
+        )
+    }
+
+    /// Returns the texts as `get_all_texts` does, each with the position (0-based)
+    /// of its editor, to report the stages as numbered in the prompt.
+    pub async fn get_all_stages(&self) -> Vec<(usize, String)> {
+        collect_stages(self.shared_editors.lock().await.values(), &self.aliases)
+    }
+
+    /// Returns the stages as `get_all_stages` does, but only up to and including
+    /// the focused one.
+    pub async fn get_stages_until_focused(&self) -> Vec<(usize, String)> {
+        let cur_index = self.shared_cur_index.lock().await.clone();
+        collect_stages(
+            self.shared_editors
+                .lock()
+                .await
+                .iter()
+                .take_while(|(index, _)| **index <= cur_index)
+                .map(|(_, editor)| editor),
+            &self.aliases,
+        )
+    }
+
+    /// Returns the text of the focused stage as `get_all_texts` does,
+    /// unless it is ignored or empty.
+    pub async fn get_focused_text(&self) -> Option<String> {
+        let cur_index = self.shared_cur_index.lock().await.clone();
+        collect_texts(
+            self.shared_editors.lock().await.get(&cur_index).into_iter(),
+            &self.aliases,
+        )
+        .pop()
+    }
+
+    /// Returns the text of the focused stage as typed, without expanding the aliases.
+    pub async fn get_focused_input(&self) -> String {
+        let cur_index = self.shared_cur_index.lock().await.clone();
+        self.shared_editors
+            .lock()
+            .await
+            .get(&cur_index)
             .map(|editor| editor.state.texteditor.text_without_cursor().to_string())
-            .filter(|cmd| !cmd.trim().is_empty())
-            .collect()
+            .unwrap_or_default()
+    }
+
+    /// Shows the `stages` (the positions not counting the `SET` stages) of the new run
+    /// as running (the first `reused` ones keep their status), and the ignored ones as such.
+    pub async fn start_stages(&self, stages: Range<usize>, reused: usize, frame: char) {
+        let mut stage = 0;
+        self.update_stages(|editor| {
+            let text = editor.state.texteditor.text_without_cursor().to_string();
+            let cmd = editor.command_text();
+            if editor.ignore {
+                editor.run = Some(StageRun {
+                    stage: None,
+                    status: StageStatus::Ignored,
+                    text,
+                    timing: None,
+                });
+            } else if cmd.trim().is_empty() || pipeline::is_set_command(&cmd) {
+                editor.run = None;
+            } else if !stages.contains(&stage) {
+                // Not run by the partial (or solo) run.
+                editor.run = None;
+                stage += 1;
+            } else {
+                let run_stage = stage - stages.start;
+                if run_stage >= reused
+                    || editor
+                        .run
+                        .as_ref()
+                        .is_none_or(|run| run.stage != Some(run_stage))
+                {
+                    editor.run = Some(StageRun {
+                        stage: Some(run_stage),
+                        status: StageStatus::Running(frame),
+                        text,
+                        timing: None,
+                    });
+                }
+                stage += 1;
+            }
+        })
+        .await;
+    }
+
+    /// Sets the status of the stage (0-based) in the last run.
+    pub async fn set_stage_status(&self, stage: usize, status: StageStatus) {
+        self.update_stages(|editor| {
+            if let Some(run) = editor.run.as_mut().filter(|run| run.stage == Some(stage)) {
+                run.status = status;
+            }
+        })
+        .await;
+    }
+
+    /// Advances the spinners of the running stages.
+    pub async fn animate_stages(&self, frame: char) {
+        self.update_stages(|editor| {
+            if let Some(run) = editor.run.as_mut()
+                && let StageStatus::Running(_) = run.status
+            {
+                run.status = StageStatus::Running(frame);
+            }
+        })
+        .await;
+    }
+
+    /// Sets the (elapsed time, finished) of each stage, in the order of the stages.
+    pub async fn set_stage_timings(&self, timings: &[(Duration, bool)]) {
+        self.update_stages(|editor| {
+            if let Some(run) = editor.run.as_mut()
+                && let Some(&(elapsed, finished)) = run.stage.and_then(|stage| timings.get(stage))
+            {
+                run.timing = Some(format_timing(elapsed, finished));
+            }
+        })
+        .await;
+    }
+
+    /// Replaces the text of the head editor, and returns the previous one.
+    pub async fn replace_head(&self, text: &str) -> String {
+        let (mut editors, mut renderer) =
+            tokio::join!(self.shared_editors.lock(), self.shared_renderer.lock());
+        let Some(head) = editors.get_mut(&HEAD_INDEX) else {
+            return String::new();
+        };
+        let prev = head.state.texteditor.text_without_cursor().to_string();
+        head.state.texteditor.replace(text);
+        // The head editor may be hidden by scrolling the editors.
+        if let Ok((width, height)) = crossterm::terminal::size()
+            && renderer.contains(&PaneIndex::Editor(HEAD_INDEX))
+        {
+            let _ = renderer
+                .update([(
+                    PaneIndex::Editor(HEAD_INDEX),
+                    head.create_pane(width, height),
+                )])
+                .render();
+        }
+        prev
+    }
+
+    /// Clears the status of the running stages, e.g. when the pipeline is cancelled.
+    pub async fn stop_stages(&self) {
+        self.update_stages(|editor| {
+            if editor
+                .run
+                .as_ref()
+                .is_some_and(|run| matches!(run.status, StageStatus::Running(_)))
+            {
+                editor.run = None;
+            }
+        })
+        .await;
+    }
+
+    /// Updates the editors by `f`, and renders the ones whose status has changed.
+    async fn update_stages<F: FnMut(&mut Editor)>(&self, mut f: F) {
+        let Ok((width, height)) = crossterm::terminal::size() else {
+            return;
+        };
+        let (mut editors, mut renderer) =
+            tokio::join!(self.shared_editors.lock(), self.shared_renderer.lock());
+        let panes: Vec<(PaneIndex, Pane)> = editors
+            .iter_mut()
+            .filter_map(|(index, editor)| {
+                let prev = editor.run.clone();
+                f(editor);
+                // The hidden editors are rendered when scrolled to.
+                let pane_index = PaneIndex::Editor(index.clone());
+                (editor.run != prev && renderer.contains(&pane_index))
+                    .then(|| (pane_index, editor.create_pane(width, height)))
+            })
+            .collect();
+        if !panes.is_empty() {
+            let _ = renderer.update(panes).render();
+        }
+    }
+
+    /// Marks the editors whose command is not found in `$PATH`,
+    /// after the edits have settled down.
+    fn spawn_validator(
+        mut rx: mpsc::Receiver<()>,
+        shared_editors: Arc<Mutex<EditorMap>>,
+        shared_renderer: SharedRenderer,
+        aliases: Arc<HashMap<String, String>>,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            while rx.recv().await.is_some() {
+                loop {
+                    tokio::select! {
+                        _ = tokio::time::sleep(VALIDATE_DEBOUNCE) => break,
+                        Some(()) = rx.recv() => {},
+                    }
+                }
+
+                let Ok((width, height)) = crossterm::terminal::size() else {
+                    continue;
+                };
+                let panes: Vec<(PaneIndex, Pane)> = {
+                    let mut editors = shared_editors.lock().await;
+                    editors
+                        .iter_mut()
+                        .filter_map(|(index, editor)| {
+                            let text = editor.command_text();
+                            let cmd =
+                                command_name(&aliases::expand(&text, &aliases)).map(str::to_string);
+                            if cmd == editor.validated_command {
+                                return None;
+                            }
+                            let not_found = cmd.as_deref().is_some_and(|cmd| !command_exists(cmd))
+                                && !pipeline::is_set_command(&text);
+                            editor.validated_command = cmd;
+                            if not_found == editor.command_not_found {
+                                return None;
+                            }
+                            editor.command_not_found = not_found;
+                            Some((
+                                PaneIndex::Editor(index.clone()),
+                                editor.create_pane(width, height),
+                            ))
+                        })
+                        .collect()
+                };
+
+                if !panes.is_empty() {
+                    let _ = shared_renderer.lock().await.update(panes).render();
+                }
+            }
+        })
     }
 
     fn insert_editor(
         cur_index: &EditorIndex,
         editors: &mut EditorMap,
         theme: &EditorTheme,
-    ) -> EditorIndex {
-        let new_index = editors.new_index(cur_index).unwrap();
-        editors.insert(
-            new_index.clone(),
-            text_editor::State {
-                prefix: theme.prefix.clone(),
-                prefix_style: StyleBuilder::new().fgc(theme.prefix_fg_color).build(),
-                active_char_style: StyleBuilder::new().bgc(theme.active_char_bg_color).build(),
-                word_break_chars: theme.word_break_chars.clone(),
-                ..Default::default()
-            },
-        );
-        new_index
+    ) -> anyhow::Result<EditorIndex> {
+        let new_index = editors.new_index(cur_index)?;
+        editors.insert(new_index.clone(), Editor::from(theme));
+        Ok(new_index)
     }
 
-    fn pop_editors(editors: &mut EditorMap, times: usize) -> Vec<EditorIndex> {
-        let mut popped = vec![];
-        for _ in 0..times {
-            if editors.last_index() == Some(&HEAD_INDEX) {
-                return popped;
-            }
-            popped.push(editors.pop_last().unwrap().0);
+    /// Inserts a new editor above the given one, and returns the indices of
+    /// the new editor and of the stage which was at the given one.
+    /// Since the head editor must stay first (no `EditorIndex` is below `HEAD_INDEX`),
+    /// a new editor above the head is inserted below it instead,
+    /// and the stage of the head is moved into it.
+    fn insert_editor_above(
+        cur_index: &EditorIndex,
+        editors: &mut EditorMap,
+        theme: &EditorTheme,
+    ) -> anyhow::Result<(EditorIndex, EditorIndex)> {
+        if cur_index != &HEAD_INDEX {
+            let prev_index = editors.seek_index(cur_index, Direction::Up(1))?;
+            let new_index = EditorIndex::mediant(&prev_index, cur_index);
+            editors.insert(new_index.clone(), Editor::from(theme));
+            return Ok((new_index, cur_index.clone()));
         }
-        popped
+
+        let moved_index = Self::insert_editor(&HEAD_INDEX, editors, theme)?;
+        let mut moved = editors
+            .remove(&moved_index)
+            .unwrap_or_else(|| Editor::from(theme));
+        let head = editors.try_get_mut(&HEAD_INDEX)?;
+        std::mem::swap(head, &mut moved);
+        // Keep the prefixes, which differ between the head and the others.
+        std::mem::swap(&mut head.state.prefix, &mut moved.state.prefix);
+        std::mem::swap(
+            &mut head.state.prefix_style.foreground_color,
+            &mut moved.state.prefix_style.foreground_color,
+        );
+        editors.insert(moved_index.clone(), moved);
+        Ok((HEAD_INDEX, moved_index))
     }
 
-    fn remove_editor(cur_index: &EditorIndex, editors: &mut EditorMap) -> EditorIndex {
+    fn remove_editor(
+        cur_index: &EditorIndex,
+        editors: &mut EditorMap,
+    ) -> anyhow::Result<EditorIndex> {
         // Do not remove the head editor
         if cur_index == &HEAD_INDEX {
-            return cur_index.clone();
+            return Ok(cur_index.clone());
         }
 
         // Note that we're moving the index to the previous one
         // because the given index is the focused editor.
         // If in the future we need to remove a non-focused editor,
         // this operation would be unnecessary.
-        let prev_index = editors.seek_index(cur_index, Direction::Up(1)).unwrap();
+        let prev_index = editors.seek_index(cur_index, Direction::Up(1))?;
 
         editors.remove(cur_index);
 
-        prev_index
+        Ok(prev_index)
+    }
+
+    /// Replaces all the editors with the stages, focusing the head one.
+    fn load_editors(
+        editors: &mut EditorMap,
+        stages: &[session::Stage],
+        themes: &(EditorTheme, EditorTheme), // (head, pipe)
+    ) -> anyhow::Result<()> {
+        *editors = EditorMap::from(Editor::from(&themes.0));
+        let mut new_index = HEAD_INDEX.clone();
+        for (i, stage) in stages.iter().enumerate() {
+            if i > 0 {
+                new_index = Self::insert_editor(&new_index, editors, &themes.1)?;
+                // Keep the focus on the head editor
+                Self::switch_theme(editors, Some(&new_index), &HEAD_INDEX, themes)?;
+            }
+            let editor = editors.try_get_mut(&new_index)?;
+            editor.state.texteditor.replace(&stage.text);
+            if stage.ignore {
+                editor.toggle_ignore();
+            }
+            editor.comment = stage.comment.clone();
+        }
+        Ok(())
+    }
+
+    /// Inserts the removed stage back at the index it was at,
+    /// or below the editor preceding that position if the index is taken.
+    fn restore_editor(
+        stage: RemovedStage,
+        editors: &mut EditorMap,
+        theme: &EditorTheme,
+    ) -> anyhow::Result<EditorIndex> {
+        let index = if stage.index > HEAD_INDEX && !editors.contains_key(&stage.index) {
+            stage.index
+        } else {
+            let prev_index = editors
+                .iter()
+                .map(|(index, _)| index)
+                .filter(|index| **index < stage.index)
+                .last()
+                .unwrap_or(&HEAD_INDEX)
+                .clone();
+            editors.new_index(&prev_index)?
+        };
+        let mut editor = Editor::from(theme);
+        editor.state.texteditor.replace(&stage.text);
+        if stage.ignore {
+            editor.toggle_ignore();
+        }
+        editor.comment = stage.comment;
+        editors.insert(index.clone(), editor);
+        Ok(index)
+    }
+
+    /// Appends the text of the editor to the previous one with the separator,
+    /// and removes it. Returns the index of the previous one,
+    /// whose cursor is placed at the join point.
+    fn merge_editor(
+        cur_index: &EditorIndex,
+        editors: &mut EditorMap,
+        separator: &str,
+    ) -> anyhow::Result<EditorIndex> {
+        let text = editors
+            .try_get(cur_index)?
+            .state
+            .texteditor
+            .text_without_cursor()
+            .to_string();
+        let prev_index = Self::remove_editor(cur_index, editors)?;
+        let prev = editors.try_get_mut(&prev_index)?;
+        let head = prev.state.texteditor.text_without_cursor().to_string();
+        let head = head.trim_end();
+        prev.state
+            .texteditor
+            .replace(&format!("{}{}{}", head, separator, text.trim_start()));
+        prev.state.texteditor.move_to_head();
+        prev.state.texteditor.shift(0, head.chars().count());
+        Ok(prev_index)
+    }
+
+    /// Focuses the head editor and dims all the others.
+    fn reset_focus(editors: &mut EditorMap, themes: &(EditorTheme, EditorTheme)) {
+        let indices: Vec<EditorIndex> = editors
+            .iter()
+            .map(|(index, _)| index.clone())
+            .filter(|index| index != &HEAD_INDEX)
+            .collect();
+        for index in indices {
+            // The head editor is never removed, and the others exist as just collected.
+            let _ = Self::switch_theme(editors, Some(&index), &HEAD_INDEX, themes);
+        }
     }
 
     fn switch_theme(
@@ -645,13 +2657,13 @@ impl Prompt {
         defocus_index: Option<&EditorIndex>,
         focus_index: &EditorIndex,
         themes: &(EditorTheme, EditorTheme), // (head, pipe)
-    ) {
+    ) -> anyhow::Result<()> {
         if Some(focus_index) == defocus_index {
-            return;
+            return Ok(());
         }
 
         if let Some(defocus_index) = defocus_index {
-            let defocus = editors.get_mut(defocus_index).unwrap();
+            let defocus = editors.try_get_mut(defocus_index)?;
             defocus.state.prefix_style.attributes.set(Attribute::Dim);
             defocus
                 .state
@@ -666,7 +2678,7 @@ impl Prompt {
                 .set(Attribute::Dim);
         }
 
-        let focus = editors.get_mut(focus_index).unwrap();
+        let focus = editors.try_get_mut(focus_index)?;
         let theme = match focus_index {
             &HEAD_INDEX => themes.0.clone(),
             _ => themes.1.clone(),
@@ -683,5 +2695,402 @@ impl Prompt {
             .active_char_style
             .attributes
             .unset(Attribute::Dim);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn themes() -> (EditorTheme, EditorTheme) {
+        let theme = EditorTheme {
+            prefix: String::from("❯❯ "),
+            prefix_fg_color: Color::DarkGreen,
+            active_char_bg_color: Color::DarkCyan,
+            word_break_chars: HashSet::new(),
+            highlight: None,
+        };
+        (theme.clone(), theme)
+    }
+
+    fn ctrl_k(times: usize) -> EventStream {
+        EventStream::Buffer(Buffer::Other(
+            Event::Key(KeyEvent {
+                code: KeyCode::Char('k'),
+                modifiers: KeyModifiers::CONTROL,
+                kind: KeyEventKind::Press,
+                state: KeyEventState::NONE,
+            }),
+            times,
+        ))
+    }
+
+    #[test]
+    fn test_kill_to_end_of_line() {
+        let mut state = text_editor::State::default();
+        state.texteditor.replace("grep foo | sort");
+        state.texteditor.shift(10, 0);
+        assert_eq!(state.texteditor.position(), 5);
+
+        edit(&ctrl_k(1), &mut state);
+        assert_eq!(state.texteditor.text_without_cursor().to_string(), "grep ");
+        assert_eq!(state.texteditor.position(), 5);
+
+        // Repeated kills do nothing on the empty tail.
+        edit(&ctrl_k(3), &mut state);
+        assert_eq!(state.texteditor.text_without_cursor().to_string(), "grep ");
+
+        // Killing at the head clears the line.
+        state.texteditor.move_to_head();
+        edit(&ctrl_k(1), &mut state);
+        assert_eq!(state.texteditor.text_without_cursor().to_string(), "");
+        assert_eq!(state.texteditor.position(), 0);
+
+        // Only the line of the cursor is killed in a multi-line stage.
+        state.texteditor.replace("grep foo \\\n| sort -n");
+        state.texteditor.move_to_head();
+        state.texteditor.shift(0, 5);
+        edit(&ctrl_k(1), &mut state);
+        assert_eq!(
+            state.texteditor.text_without_cursor().to_string(),
+            "grep \n| sort -n"
+        );
+        assert_eq!(state.texteditor.position(), 5);
+        edit(&ctrl_k(1), &mut state);
+        assert_eq!(
+            state.texteditor.text_without_cursor().to_string(),
+            "grep \n| sort -n"
+        );
+    }
+
+    #[test]
+    fn test_multi_line_pane() {
+        let mut editor = Editor::from(&themes().0);
+        editor.state.texteditor.replace("jq '\n  .name'");
+        editor.state.texteditor.move_to_head();
+        let rows: Vec<String> = editor
+            .create_pane(8, 10)
+            .extract(10)
+            .iter()
+            .map(|row| row.to_string())
+            .collect();
+        // The newline is shown as a space, and the long line is wrapped.
+        assert_eq!(rows, vec!["❯❯ jq ' ", "  .name'", " "]);
+    }
+
+    #[test]
+    fn test_word_break_chars() {
+        assert_eq!(
+            split_word_break_chars("!wbc=.: jq .a.b"),
+            (Some(HashSet::from(['.', ':'])), "jq .a.b")
+        );
+        assert_eq!(split_word_break_chars("!wbc="), (Some(HashSet::new()), ""));
+        assert_eq!(split_word_break_chars("jq !wbc=."), (None, "jq !wbc=."));
+
+        let mut editor = Editor::from(&themes().0);
+        editor.state.texteditor.replace("!wbc=. jq .a.b");
+        editor.apply_word_break_chars();
+        assert_eq!(editor.state.word_break_chars, HashSet::from(['.']));
+        assert_eq!(editor.command_text(), "jq .a.b");
+        editor.state.texteditor.replace("jq .a.b");
+        editor.apply_word_break_chars();
+        assert_eq!(editor.state.word_break_chars, HashSet::new());
+    }
+
+    #[test]
+    fn test_command_line_pane() {
+        let cmds = vec![String::from("cat foo"), String::from("grep 'a b'")];
+        let line = |width| {
+            command_line_pane(&cmds, width)
+                .extract(1)
+                .first()
+                .map(ToString::to_string)
+        };
+        assert_eq!(line(80), Some(String::from("$ cat foo | grep 'a b'")));
+        assert_eq!(line(10), Some(String::from("$ cat foo…")));
+        assert_eq!(command_line_pane(&[], 80).extract(1).len(), 0);
+    }
+
+    #[test]
+    fn test_status_glyph() {
+        let mut editor = Editor::from(&themes().0);
+        editor.state.texteditor.replace("grep foo");
+        editor.comment = Some(String::from("filter"));
+        assert_eq!(editor.prefix(), "❯❯ ⓘ ");
+
+        editor.run = Some(StageRun {
+            stage: Some(0),
+            status: StageStatus::Succeeded,
+            text: String::from("grep foo"),
+            timing: None,
+        });
+        assert_eq!(editor.prefix(), "❯❯ ✔ ⓘ ");
+
+        // The status no longer matches the edited text.
+        editor.state.texteditor.replace("grep bar");
+        assert_eq!(editor.prefix(), "❯❯ ⓘ ");
+    }
+
+    #[test]
+    fn test_format_timing() {
+        assert_eq!(format_timing(Duration::from_millis(300), false), "[0.3s]");
+        assert_eq!(
+            format_timing(Duration::from_millis(1234), true),
+            "[done 1.2s]"
+        );
+    }
+
+    #[test]
+    fn test_insert_editor_above() {
+        let themes = themes();
+        let mut editors = EditorMap::from(Editor::from(&themes.0));
+        editors
+            .try_get_mut(&HEAD_INDEX)
+            .unwrap()
+            .state
+            .texteditor
+            .replace("cat");
+        let second = Prompt::insert_editor(&HEAD_INDEX, &mut editors, &themes.1).unwrap();
+
+        let (above, moved) = Prompt::insert_editor_above(&second, &mut editors, &themes.1).unwrap();
+        assert_eq!(moved, second);
+        assert!(HEAD_INDEX < above && above < second);
+
+        // The stage of the head is moved below the new head.
+        let (head, moved) =
+            Prompt::insert_editor_above(&HEAD_INDEX, &mut editors, &themes.1).unwrap();
+        assert_eq!(head, HEAD_INDEX);
+        assert!(HEAD_INDEX < moved && moved < above);
+        let text = |index: &EditorIndex| {
+            editors
+                .try_get(index)
+                .unwrap()
+                .state
+                .texteditor
+                .text_without_cursor()
+                .to_string()
+        };
+        assert_eq!(text(&HEAD_INDEX), "");
+        assert_eq!(text(&moved), "cat");
+        assert_eq!(editors.len(), 4);
+    }
+
+    #[test]
+    fn test_merge_editor() {
+        let themes = themes();
+        let mut editors = EditorMap::from(Editor::from(&themes.0));
+        let second = Prompt::insert_editor(&HEAD_INDEX, &mut editors, &themes.1).unwrap();
+        let third = Prompt::insert_editor(&second, &mut editors, &themes.1).unwrap();
+        for (index, text) in [
+            (&HEAD_INDEX, "cat x"),
+            (&second, "grep foo "),
+            (&third, " -v bar"),
+        ] {
+            editors
+                .try_get_mut(index)
+                .unwrap()
+                .state
+                .texteditor
+                .replace(text);
+        }
+
+        assert_eq!(
+            Prompt::merge_editor(&third, &mut editors, " ").unwrap(),
+            second
+        );
+        let merged = &editors.try_get(&second).unwrap().state.texteditor;
+        assert_eq!(merged.text_without_cursor().to_string(), "grep foo -v bar");
+        // The cursor is at the join point.
+        assert_eq!(merged.position(), "grep foo".len());
+
+        assert_eq!(
+            Prompt::merge_editor(&second, &mut editors, " | ").unwrap(),
+            HEAD_INDEX
+        );
+        assert_eq!(
+            editors
+                .try_get(&HEAD_INDEX)
+                .unwrap()
+                .state
+                .texteditor
+                .text_without_cursor()
+                .to_string(),
+            "cat x | grep foo -v bar"
+        );
+        assert_eq!(editors.len(), 1);
+    }
+
+    #[test]
+    fn test_merge_separator() {
+        let ctrl_shift = KeyModifiers::CONTROL | KeyModifiers::SHIFT;
+        let alt_shift = KeyModifiers::ALT | KeyModifiers::SHIFT;
+        assert_eq!(merge_separator(KeyCode::Char('J'), ctrl_shift), Some(" "));
+        assert_eq!(
+            merge_separator(KeyCode::Char('j'), ctrl_shift | KeyModifiers::ALT),
+            Some(" | ")
+        );
+        assert_eq!(merge_separator(KeyCode::Char('K'), alt_shift), Some(" "));
+        assert_eq!(
+            merge_separator(KeyCode::Char('|'), KeyModifiers::ALT),
+            Some(" | ")
+        );
+        assert_eq!(merge_separator(KeyCode::Char('\\'), alt_shift), Some(" | "));
+        // Ctrl+J inserts a newline, and Alt+K is the previous bookmark.
+        assert_eq!(
+            merge_separator(KeyCode::Char('j'), KeyModifiers::CONTROL),
+            None
+        );
+        assert_eq!(merge_separator(KeyCode::Char('k'), KeyModifiers::ALT), None);
+    }
+
+    #[test]
+    fn test_restore_editor() {
+        let themes = themes();
+        let mut editors = EditorMap::from(Editor::from(&themes.0));
+        let second = Prompt::insert_editor(&HEAD_INDEX, &mut editors, &themes.1).unwrap();
+        let stage = |index: &EditorIndex, text: &str| RemovedStage {
+            index: index.clone(),
+            text: String::from(text),
+            ignore: true,
+            comment: None,
+        };
+
+        let third = EditorIndex(3, 1);
+        assert_eq!(
+            Prompt::restore_editor(stage(&third, "awk '{ print $1 }'"), &mut editors, &themes.1)
+                .unwrap(),
+            third
+        );
+        let restored = editors.try_get(&third).unwrap();
+        assert!(restored.ignore);
+        assert_eq!(
+            restored.state.texteditor.text_without_cursor().to_string(),
+            "awk '{ print $1 }'"
+        );
+
+        // Placed just above the editor which has taken the index.
+        let index =
+            Prompt::restore_editor(stage(&second, "sort"), &mut editors, &themes.1).unwrap();
+        assert!(HEAD_INDEX < index && index < second);
+        assert_eq!(editors.len(), 4);
+    }
+
+    #[test]
+    fn test_stale_index() {
+        let themes = themes();
+        let mut editors = EditorMap::from(Editor::from(&themes.0));
+        let second = Prompt::insert_editor(&HEAD_INDEX, &mut editors, &themes.1).unwrap();
+        let third = Prompt::insert_editor(&second, &mut editors, &themes.1).unwrap();
+        assert_eq!(
+            editors.seek_index(&third, Direction::Up(1)).unwrap(),
+            second
+        );
+
+        editors.remove(&second);
+        assert!(editors.seek_index(&second, Direction::Down(1)).is_err());
+        assert!(editors.shift_index(&second, 1, 0).is_err());
+        // Not moving from the stale index is also an error.
+        assert!(editors.shift_index(&second, 0, 0).is_err());
+        assert!(Prompt::remove_editor(&second, &mut editors).is_err());
+        assert!(Prompt::switch_theme(&mut editors, Some(&second), &third, &themes).is_err());
+
+        // Recover by focusing the head editor.
+        Prompt::reset_focus(&mut editors, &themes);
+        let dimmed = |index: &EditorIndex| {
+            editors
+                .get(index)
+                .unwrap()
+                .state
+                .prefix_style
+                .attributes
+                .has(Attribute::Dim)
+        };
+        assert!(!dimmed(&HEAD_INDEX));
+        assert!(dimmed(&third));
+        assert_eq!(editors.shift_index(&third, 1, 0).unwrap(), HEAD_INDEX);
+    }
+
+    #[test]
+    fn test_editor_window() {
+        let mut top = 0;
+        assert_eq!(editor_window(3, 2, 5, &mut top), 0..3);
+        // 10 editors in 5 rows: 3 editors and 2 indicators.
+        assert_eq!(editor_window(10, 0, 5, &mut top), 0..3);
+        assert_eq!(editor_window(10, 2, 5, &mut top), 0..3);
+        assert_eq!(editor_window(10, 3, 5, &mut top), 1..4);
+        assert_eq!(editor_window(10, 9, 5, &mut top), 7..10);
+        // Moving up within the window does not scroll.
+        assert_eq!(editor_window(10, 8, 5, &mut top), 7..10);
+        assert_eq!(editor_window(10, 6, 5, &mut top), 6..9);
+        // Shrinking the screen keeps the focused editor visible.
+        assert_eq!(editor_window(10, 8, 3, &mut top), 8..9);
+        assert_eq!(editor_window(10, 0, 1, &mut top), 0..1);
+        // Removing the editors clamps the window.
+        assert_eq!(editor_window(4, 3, 3, &mut top), 3..4);
+    }
+
+    #[test]
+    fn test_renormalize() {
+        let themes = themes();
+        let mut editors = EditorMap::from(Editor::from(&themes.0));
+        editors.try_get_mut(&HEAD_INDEX).unwrap().comment = Some(String::from("0"));
+        // The ids of the editors in order.
+        let mut expected = vec![String::from("0")];
+        let mut last = HEAD_INDEX.clone();
+        let mut renormalized = false;
+        // Linear congruential generator for the deterministic sequence.
+        let mut seed: u64 = 42;
+        let mut next = |n: usize| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            (seed >> 33) as usize % n
+        };
+
+        for id in 1..2000 {
+            let keys: Vec<EditorIndex> = editors.iter().map(|(index, _)| index.clone()).collect();
+            if keys.len() > 1 && next(4) == 0 {
+                let pos = 1 + next(keys.len() - 1);
+                let prev = Prompt::remove_editor(&keys[pos], &mut editors).unwrap();
+                assert_eq!(prev, keys[pos - 1]);
+                expected.remove(pos);
+                last = prev;
+            } else {
+                // Mostly insert just after the last inserted one or its previous one,
+                // which makes the fractions grow fastest.
+                let base = match next(3) {
+                    0 => keys[next(keys.len())].clone(),
+                    1 => last.clone(),
+                    _ => editors.seek_index(&last, Direction::Up(1)).unwrap(),
+                };
+                let pos = keys.iter().position(|index| *index == base).unwrap();
+                last = Prompt::insert_editor(&base, &mut editors, &themes.1).unwrap();
+                editors.try_get_mut(&last).unwrap().comment = Some(id.to_string());
+                expected.insert(pos + 1, id.to_string());
+            }
+
+            if editors.needs_renormalize() {
+                let changes = editors.renormalize();
+                if let Some((_, new_index)) =
+                    changes.iter().find(|(old_index, _)| *old_index == last)
+                {
+                    last = new_index.clone();
+                }
+                renormalized = true;
+            }
+
+            let keys: Vec<&EditorIndex> = editors.iter().map(|(index, _)| index).collect();
+            assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+            // The mediant with 0/0 is the reduced fraction of the index itself.
+            assert!(
+                keys.iter()
+                    .all(|index| EditorIndex::mediant(index, &EditorIndex(0, 0)) == **index)
+            );
+            let ids: Vec<String> = editors
+                .values()
+                .map(|editor| editor.comment.clone().unwrap())
+                .collect();
+            assert_eq!(ids, expected);
+        }
+        assert!(renormalized);
     }
 }
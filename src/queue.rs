@@ -1,10 +1,23 @@
 use std::collections::VecDeque;
 
-use promkit::{Cursor, PaneFactory, grapheme::StyledGraphemes, pane::Pane};
+use crossterm::style::{Attribute, Attributes, Color};
+use promkit::{Cursor, PaneFactory, grapheme::StyledGraphemes, pane::Pane, style::StyleBuilder};
+
+use crate::{csv, json_fmt, pipeline::Origin};
+
+/// The colors of the branches of the stage fanning out, in turn.
+const BRANCH_COLORS: [Color; 4] = [
+    Color::DarkGreen,
+    Color::DarkMagenta,
+    Color::DarkYellow,
+    Color::DarkBlue,
+];
 
 pub struct Queue {
     buf: Cursor<VecDeque<StyledGraphemes>>,
     capacity: usize,
+    // The total size of the items in bytes.
+    total_bytes: usize,
 }
 
 impl Queue {
@@ -12,13 +25,20 @@ impl Queue {
         Self {
             buf: Cursor::new(VecDeque::with_capacity(capacity), 0, false),
             capacity,
+            total_bytes: 0,
         }
     }
 
-    pub fn push(&mut self, item: StyledGraphemes) {
-        if self.buf.contents().len() > self.capacity {
-            self.buf.contents_mut().pop_front();
+    /// Pushes the item, and returns whether the oldest item was dropped.
+    pub fn push(&mut self, item: StyledGraphemes) -> bool {
+        let mut dropped = false;
+        if self.buf.contents().len() > self.capacity
+            && let Some(front) = self.buf.contents_mut().pop_front()
+        {
+            self.total_bytes -= Self::size_of(&front);
+            dropped = true;
         }
+        self.total_bytes += Self::size_of(&item);
         // Note: promkit::terminal::Terminal ignores empty items.
         // Therefore, it replace empty items with a null character.
         if item.is_empty() {
@@ -26,56 +46,769 @@ impl Queue {
         } else {
             self.buf.contents_mut().push_back(item);
         }
+        dropped
+    }
+
+    /// Returns whether the item is the same as the last one.
+    fn repeats_last(&self, item: &StyledGraphemes) -> bool {
+        self.buf.contents().back().is_some_and(|last| {
+            let last = last.to_string();
+            let item = item.to_string();
+            last == item || (last == "\0" && item.is_empty())
+        })
+    }
+
+    fn size_of(item: &StyledGraphemes) -> usize {
+        let text = item.to_string();
+        // The null character in place of the empty item.
+        if text == "\0" { 0 } else { text.len() }
     }
 }
 
+/// The output pretty-printed as JSON.
+struct JsonView {
+    queue: Queue,
+    // Whether the output is pretty-printed line by line (e.g. NDJSON),
+    // to which the new lines are added one by one.
+    ndjson: bool,
+}
+
+/// The numbers of the lines, words and bytes of the output, as `wc` counts them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Counts {
+    pub lines: usize,
+    pub words: usize,
+    pub bytes: usize,
+}
+
+impl Counts {
+    fn add(&mut self, line: &str) {
+        self.lines += 1;
+        self.words += line.split_whitespace().count();
+        // With the newline.
+        self.bytes += line.len() + 1;
+    }
+}
+
+/// A position in the queue as (line, column).
+pub type Position = (usize, usize);
+
+/// Shown before the bookmarked lines.
+const BOOKMARK_GUTTER: &str = "● ";
+
 pub struct State {
     queue: Queue,
     capacity: usize,
+    // The stage (0-based) which produced each line in the queue.
+    stages: VecDeque<Origin>,
+    // Whether to show the stage before each line.
+    badges: bool,
+    // Whether to fold the consecutive duplicate lines into one.
+    dedup: bool,
+    // Whether to render the CSV/TSV output as a table.
+    table: bool,
+    // Whether to pretty-print the JSON output.
+    json: bool,
+    // Shown instead of the output in the JSON mode while the output is JSON.
+    json_view: Option<JsonView>,
+    // The number of times each line in the queue is repeated consecutively (1 if not).
+    repeats: VecDeque<usize>,
+    // (anchor, head) of the selection made by mouse dragging.
+    selection: Option<(Position, Position)>,
+    // The number of the lines dropped from the queue,
+    // to keep the bookmarks on the same lines while the queue is shifted.
+    dropped: usize,
+    // The absolute line numbers (counting the dropped lines) in ascending order.
+    bookmarks: Vec<usize>,
+    // Of all the lines pushed, including the dropped and folded ones.
+    counts: Counts,
 }
 
 impl State {
-    pub fn new(capacity: usize) -> Self {
+    pub fn new(capacity: usize, badges: bool, json: bool) -> Self {
         Self {
             queue: Queue::new(capacity),
             capacity,
+            stages: VecDeque::with_capacity(capacity),
+            badges,
+            dedup: false,
+            table: false,
+            json,
+            json_view: None,
+            repeats: VecDeque::with_capacity(capacity),
+            selection: None,
+            dropped: 0,
+            bookmarks: vec![],
+            counts: Counts::default(),
         }
     }
 
     pub fn reset(&mut self) {
         self.queue = Queue::new(self.capacity);
+        self.stages.clear();
+        self.repeats.clear();
+        self.json_view = None;
+        self.selection = None;
+        self.dropped = 0;
+        self.bookmarks.clear();
+        self.counts = Counts::default();
+    }
+
+    /// Pushes the line with where it comes from.
+    /// In the dedup mode, the line same as the last one (from the same stage)
+    /// just counts up the repeats of it.
+    pub fn push(&mut self, origin: Origin, item: StyledGraphemes) {
+        self.counts.add(&item.to_string());
+        if self.dedup
+            && self.stages.back() == Some(&origin)
+            && self.queue.repeats_last(&item)
+            && let Some(consecutive_count) = self.repeats.back_mut()
+        {
+            *consecutive_count += 1;
+            return;
+        }
+        let line = self.json.then(|| Self::line(&item));
+        self.stages.push_back(origin);
+        self.repeats.push_back(1);
+        if self.queue.push(item) {
+            self.stages.pop_front();
+            self.repeats.pop_front();
+            self.dropped += 1;
+            // Forget the bookmark on the dropped line.
+            self.bookmarks.retain(|line| *line >= self.dropped);
+            // Follow the lines shifted by dropping the oldest one.
+            self.selection = self.selection.and_then(|((al, ac), (hl, hc))| {
+                Some(((al.checked_sub(1)?, ac), (hl.checked_sub(1)?, hc)))
+            });
+        }
+        if let Some(line) = line {
+            self.push_json(&line);
+        }
+    }
+
+    /// Updates the JSON view with the line just pushed.
+    /// The whole output is parsed again only when the line may complete or break it.
+    fn push_json(&mut self, line: &str) {
+        match &mut self.json_view {
+            Some(view) if view.ndjson => match json_fmt::parse(line) {
+                Some(value) => {
+                    for line in json_fmt::pretty_lines(&value) {
+                        view.queue.push(line);
+                    }
+                }
+                None => {
+                    view.queue.push(StyledGraphemes::from(line));
+                }
+            },
+            // The line after the JSON object or array spanning lines,
+            // which is shown line by line from now on if it is no longer JSON as a whole.
+            Some(_) => self.refresh_json(),
+            // The end of the JSON object or array spanning lines.
+            _ if matches!(line.trim(), "}" | "]") => self.refresh_json(),
+            None if json_fmt::parse(line).is_some() => self.refresh_json(),
+            _ => (),
+        }
+    }
+
+    /// Pretty-prints the whole output again, if it is JSON.
+    fn refresh_json(&mut self) {
+        let lines: Vec<String> = self.queue.buf.contents().iter().map(Self::line).collect();
+        self.json_view = json_fmt::format_lines(&lines).map(|(lines, ndjson)| {
+            let mut queue = Queue::new(self.capacity);
+            for line in lines {
+                queue.push(line);
+            }
+            JsonView { queue, ndjson }
+        });
+    }
+
+    /// Toggles the JSON mode, which pretty-prints the output while it is JSON.
+    /// Returns whether it is enabled.
+    pub fn toggle_json(&mut self) -> bool {
+        self.json = !self.json;
+        if self.json {
+            self.refresh_json();
+        } else {
+            self.json_view = None;
+        }
+        self.json
+    }
+
+    /// Returns whether the output is shown pretty-printed as JSON.
+    pub fn shows_json(&self) -> bool {
+        self.json_view.is_some()
+    }
+
+    /// Returns the lines shown in the pane, i.e. the pretty-printed ones in the JSON mode.
+    fn shown(&self) -> &Queue {
+        self.json_view
+            .as_ref()
+            .map_or(&self.queue, |view| &view.queue)
+    }
+
+    fn shown_mut(&mut self) -> &mut Queue {
+        self.json_view
+            .as_mut()
+            .map_or(&mut self.queue, |view| &mut view.queue)
     }
 
-    pub fn push(&mut self, item: StyledGraphemes) {
-        self.queue.push(item);
+    /// Toggles the dedup mode, which applies to the lines pushed from now on.
+    /// Returns whether it is enabled.
+    pub fn toggle_dedup(&mut self) -> bool {
+        self.dedup = !self.dedup;
+        self.dedup
+    }
+
+    /// Toggles the table mode, which renders the output as a table while it looks like CSV/TSV.
+    /// Returns whether it is enabled.
+    pub fn toggle_table(&mut self) -> bool {
+        self.table = !self.table;
+        self.table
+    }
+
+    /// Returns the delimiter of the output if it looks like CSV/TSV.
+    pub fn delimiter(&self) -> Option<char> {
+        let lines: Vec<String> = self
+            .queue
+            .buf
+            .contents()
+            .iter()
+            .take(csv::DETECT_LINES)
+            .map(Self::line)
+            .collect();
+        csv::detect_delimiter(&lines.iter().map(String::as_str).collect::<Vec<_>>())
+    }
+
+    /// Returns the text of the item without the null character in place of the empty one.
+    fn line(item: &StyledGraphemes) -> String {
+        item.chars().into_iter().filter(|ch| *ch != '\0').collect()
+    }
+
+    /// Renders the first line as the header, followed by the lines shown in the pane,
+    /// as a table if the output looks like CSV/TSV.
+    fn table_pane(&self, width: u16, height: u16) -> Option<Pane> {
+        let delimiter = self.delimiter()?;
+        let contents = self.queue.buf.contents();
+        let lines: Vec<String> = contents
+            .iter()
+            .take(1)
+            .chain(
+                contents
+                    .iter()
+                    .skip(self.queue.buf.position().max(1))
+                    .take((height as usize).saturating_sub(1)),
+            )
+            .map(Self::line)
+            .collect();
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        Some(Pane::new(csv::format_as_table(&lines, delimiter, width), 0))
     }
 
     pub fn shift(&mut self, up: usize, down: usize) -> bool {
-        self.queue.buf.shift(up, down)
+        self.shown_mut().buf.shift(up, down)
+    }
+
+    /// Scrolls to the line (1-based), or to the last line if it is not available.
+    /// Returns whether the line is available.
+    pub fn jump_to_line(&mut self, n: usize) -> bool {
+        let shown = self.shown_mut();
+        if shown.buf.move_to(n.saturating_sub(1)) {
+            true
+        } else {
+            shown.buf.move_to_tail();
+            false
+        }
+    }
+
+    /// Returns the indicator shown before the line if it is bookmarked,
+    /// followed by the badge of the stage which produced it if enabled,
+    /// the colored number of the branch if the stage fans out,
+    /// and the count of the repeats if the line is folded by the dedup mode.
+    fn gutter(&self, line: usize) -> StyledGraphemes {
+        let mut gutter = if self.bookmarks.binary_search(&(self.dropped + line)).is_ok() {
+            StyledGraphemes::from_str(
+                BOOKMARK_GUTTER,
+                StyleBuilder::new().fgc(Color::DarkYellow).build(),
+            )
+        } else {
+            StyledGraphemes::default()
+        };
+        let Some(origin) = self.stages.get(line) else {
+            return gutter;
+        };
+        if self.badges {
+            gutter.append(&mut StyledGraphemes::from_str(
+                format!("#{} ", origin.stage + 1),
+                StyleBuilder::new().fgc(Color::DarkCyan).build(),
+            ));
+        }
+        if let Some(branch) = origin.branch {
+            gutter.append(&mut StyledGraphemes::from_str(
+                format!("{}> ", branch + 1),
+                StyleBuilder::new()
+                    .fgc(BRANCH_COLORS[branch % BRANCH_COLORS.len()])
+                    .build(),
+            ));
+        }
+        if let Some(repeats) = self.repeats.get(line).filter(|repeats| **repeats > 1) {
+            gutter.append(&mut StyledGraphemes::from_str(
+                format!("(×{}) ", repeats),
+                StyleBuilder::new().fgc(Color::DarkGrey).build(),
+            ));
+        }
+        gutter
+    }
+
+    /// Bookmarks the top line shown in the pane, or removes the bookmark if already marked.
+    /// Returns whether the line is bookmarked.
+    pub fn toggle_bookmark(&mut self) -> bool {
+        // The lines pretty-printed as JSON are not the ones in the queue.
+        if self.shows_json() || self.queue.buf.contents().is_empty() {
+            return false;
+        }
+        let line = self.dropped + self.queue.buf.position();
+        match self.bookmarks.binary_search(&line) {
+            Ok(i) => {
+                self.bookmarks.remove(i);
+                false
+            }
+            Err(i) => {
+                self.bookmarks.insert(i, line);
+                true
+            }
+        }
+    }
+
+    /// Scrolls to the next (or previous) bookmark from the top line,
+    /// cycling around at both ends. Returns whether it scrolled.
+    pub fn jump_to_bookmark(&mut self, forward: bool) -> bool {
+        if self.shows_json() {
+            return false;
+        }
+        let cur = self.dropped + self.queue.buf.position();
+        let next = if forward {
+            self.bookmarks
+                .iter()
+                .find(|line| **line > cur)
+                .or(self.bookmarks.first())
+        } else {
+            self.bookmarks
+                .iter()
+                .rev()
+                .find(|line| **line < cur)
+                .or(self.bookmarks.last())
+        };
+        match next {
+            Some(line) if *line != cur => self.queue.buf.move_to(line - self.dropped),
+            _ => false,
+        }
+    }
+
+    pub fn clear_bookmarks(&mut self) -> bool {
+        let cleared = !self.bookmarks.is_empty();
+        self.bookmarks.clear();
+        cleared
+    }
+
+    /// Returns the bookmarked lines as the line numbers (0-based) in `text`,
+    /// which counts the lines folded by the dedup mode as many times as they came.
+    pub fn bookmarked_lines(&self) -> Vec<usize> {
+        let mut start = 0;
+        let mut lines = vec![];
+        for (i, repeats) in self.repeats.iter().enumerate() {
+            if self.bookmarks.binary_search(&(self.dropped + i)).is_ok() {
+                lines.push(start);
+            }
+            start += repeats;
+        }
+        lines
+    }
+
+    /// Bookmarks the lines given as the line numbers (0-based) in `text`,
+    /// e.g. restored with the output of the last session.
+    pub fn bookmark_lines(&mut self, lines: &[usize]) {
+        let mut start = 0;
+        for (i, repeats) in self.repeats.iter().enumerate() {
+            let line = self.dropped + i;
+            if lines
+                .iter()
+                .any(|bookmarked| (start..start + repeats).contains(bookmarked))
+                && let Err(at) = self.bookmarks.binary_search(&line)
+            {
+                self.bookmarks.insert(at, line);
+            }
+            start += repeats;
+        }
+    }
+
+    /// Returns the counts of all the lines pushed since the reset,
+    /// including the ones no longer kept in the queue.
+    pub fn counts(&self) -> Counts {
+        self.counts
+    }
+
+    pub fn total_bytes(&self) -> usize {
+        self.queue.total_bytes
+    }
+
+    /// Returns the (top line (1-based), total lines) of the output.
+    pub fn lines(&self) -> (usize, usize) {
+        let shown = self.shown();
+        (shown.buf.position() + 1, shown.buf.contents().len())
+    }
+
+    /// Converts the row (relative to the top of the pane) and column on the screen
+    /// into the position in the queue.
+    pub fn position_at(&self, row: usize, column: usize, width: u16) -> Option<Position> {
+        if self.shows_json() {
+            return None;
+        }
+        let mut top = 0;
+        for (line, item) in self
+            .queue
+            .buf
+            .contents()
+            .iter()
+            .enumerate()
+            .skip(self.queue.buf.position())
+        {
+            let mut shown = self.gutter(line);
+            let gutter = shown.len();
+            shown.append(&mut item.clone());
+            let rows = shown
+                .matrixify(width as usize, shown.len().max(1), 0)
+                .0
+                .len();
+            if row < top + rows {
+                let column = ((row - top) * width as usize + column).saturating_sub(gutter);
+                return Some((line, column.min(item.len().saturating_sub(1))));
+            }
+            top += rows;
+        }
+        None
+    }
+
+    pub fn start_selection(&mut self, pos: Position) {
+        self.selection = Some((pos, pos));
+    }
+
+    pub fn extend_selection(&mut self, pos: Position) -> bool {
+        match &mut self.selection {
+            Some((_, head)) if *head != pos => {
+                *head = pos;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn clear_selection(&mut self) -> bool {
+        self.selection.take().is_some()
+    }
+
+    /// Returns the (start, end) of the selection in order.
+    fn ordered_selection(&self) -> Option<(Position, Position)> {
+        self.selection.map(|(anchor, head)| {
+            if anchor <= head {
+                (anchor, head)
+            } else {
+                (head, anchor)
+            }
+        })
+    }
+
+    /// Returns the range of the selected columns in the line.
+    fn selected_range(&self, line: usize, len: usize) -> Option<std::ops::Range<usize>> {
+        let ((sl, sc), (el, ec)) = self.ordered_selection()?;
+        if line < sl || line > el {
+            return None;
+        }
+        let start = if line == sl { sc } else { 0 };
+        let end = if line == el { (ec + 1).min(len) } else { len };
+        Some(start..end)
+    }
+
+    /// Returns all the lines in the queue, each followed by a newline.
+    /// The lines folded by the dedup mode are repeated as many times as they came.
+    pub fn text(&self) -> String {
+        self.queue
+            .buf
+            .contents()
+            .iter()
+            .zip(&self.repeats)
+            .map(|(item, repeats)| {
+                let mut line = Self::line(item);
+                line.push('\n');
+                line.repeat(*repeats)
+            })
+            .collect()
+    }
+
+    pub fn selected_text(&self) -> Option<String> {
+        let ((sl, _), (el, _)) = self.ordered_selection()?;
+        Some(
+            self.queue
+                .buf
+                .contents()
+                .iter()
+                .enumerate()
+                .skip(sl)
+                .take(el - sl + 1)
+                .map(|(line, item)| {
+                    let chars = item.chars();
+                    let range = self.selected_range(line, chars.len()).unwrap_or_default();
+                    chars[range]
+                        .iter()
+                        .filter(|ch| **ch != '\0')
+                        .collect::<String>()
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        )
     }
 }
 
 impl PaneFactory for State {
     fn create_pane(&self, width: u16, height: u16) -> Pane {
+        if let Some(view) = &self.json_view {
+            let rows = view
+                .queue
+                .buf
+                .contents()
+                .iter()
+                .skip(view.queue.buf.position())
+                .take(height as usize)
+                .flat_map(|line| line.matrixify(width as usize, height as usize, 0).0)
+                .take(height as usize)
+                .collect();
+            return Pane::new(rows, 0);
+        }
+        // The table is built from the lines on each rendering not to keep a copy of them.
+        if self.table
+            && let Some(pane) = self.table_pane(width, height)
+        {
+            return pane;
+        }
+
+        let selected_style = StyleBuilder::new()
+            .attrs(Attributes::from(Attribute::Reverse))
+            .build();
+
         Pane::new(
             self.queue
                 .buf
                 .contents()
                 .iter()
                 .enumerate()
-                .filter(|(i, _)| {
-                    *i >= self.queue.buf.position()
-                        && *i < self.queue.buf.position() + height as usize
+                // Only the lines from the top one are scanned, not the whole queue.
+                .skip(self.queue.buf.position())
+                .take(height as usize)
+                .flat_map(|(i, item)| {
+                    let mut item = match self.selected_range(i, item.len()) {
+                        Some(range) => range.fold(item.clone(), |item, idx| {
+                            item.apply_style_at(idx, selected_style)
+                        }),
+                        None => item.clone(),
+                    };
+                    let mut shown = self.gutter(i);
+                    shown.append(&mut item);
+                    shown.matrixify(width as usize, height as usize, 0).0
                 })
-                .fold((vec![], 0), |(mut acc, pos), (_, item)| {
-                    let rows = item.matrixify(width as usize, height as usize, 0).0;
-                    if pos < self.queue.buf.position() + height as usize {
-                        acc.extend(rows);
-                    }
-                    (acc, pos + 1)
-                })
-                .0,
+                .collect(),
             0,
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jump_to_line() {
+        let mut state = State::new(10, false, false);
+        for i in 0..5 {
+            state.push(Origin::from(0), StyledGraphemes::from(i.to_string()));
+        }
+        assert!(state.jump_to_line(3));
+        assert_eq!(state.lines(), (3, 5));
+        assert!(!state.jump_to_line(8));
+        assert_eq!(state.lines(), (5, 5));
+        assert!(state.jump_to_line(0));
+        assert_eq!(state.lines(), (1, 5));
+    }
+
+    #[test]
+    fn test_create_pane() {
+        let mut state = State::new(100_000, false, false);
+        for i in 0..100_000 {
+            state.push(Origin::from(0), StyledGraphemes::from(i.to_string()));
+        }
+        state.jump_to_line(50_000);
+        let rows: Vec<String> = state
+            .create_pane(80, 3)
+            .extract(10)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(rows, vec!["49999", "50000", "50001"]);
+    }
+
+    #[test]
+    fn test_total_bytes() {
+        let mut state = State::new(1, false, false);
+        state.push(Origin::from(0), StyledGraphemes::from("abc"));
+        state.push(Origin::from(0), StyledGraphemes::from(""));
+        assert_eq!(state.total_bytes(), 3);
+        // The oldest one is dropped.
+        state.push(Origin::from(0), StyledGraphemes::from("あ"));
+        assert_eq!(state.total_bytes(), 3);
+        state.reset();
+        assert_eq!(state.total_bytes(), 0);
+    }
+
+    #[test]
+    fn test_counts() {
+        let mut state = State::new(1, false, false);
+        state.toggle_dedup();
+        for line in ["hello world", "hello world", "", "a  b\tc", "日本"] {
+            state.push(Origin::from(0), StyledGraphemes::from(line));
+        }
+        // The dropped and folded lines are also counted.
+        assert_eq!(
+            state.counts(),
+            Counts {
+                lines: 5,
+                words: 8,
+                bytes: 12 + 12 + 1 + 7 + 7,
+            }
+        );
+        state.reset();
+        assert_eq!(state.counts(), Counts::default());
+    }
+
+    #[test]
+    fn test_bookmarks() {
+        let mut state = State::new(3, false, false);
+        for i in 0..4 {
+            state.push(Origin::from(0), StyledGraphemes::from(i.to_string()));
+        }
+        assert!(state.toggle_bookmark());
+        state.jump_to_line(3);
+        assert!(state.toggle_bookmark());
+        state.jump_to_line(2);
+        assert!(state.toggle_bookmark());
+        assert!(!state.toggle_bookmark());
+        assert_eq!(state.bookmarks, vec![0, 2]);
+
+        assert!(state.jump_to_bookmark(true));
+        assert_eq!(state.lines().0, 3);
+        // Cycle to the first one.
+        assert!(state.jump_to_bookmark(true));
+        assert_eq!(state.lines().0, 1);
+        assert!(state.jump_to_bookmark(false));
+        assert_eq!(state.lines().0, 3);
+
+        // The bookmarks follow the lines shifted by dropping the oldest one.
+        state.push(Origin::from(0), StyledGraphemes::from("4"));
+        assert_eq!(state.gutter(0).len(), 0);
+        assert_eq!(state.gutter(1).len(), BOOKMARK_GUTTER.chars().count());
+        assert!(state.jump_to_bookmark(false));
+        assert_eq!(state.lines().0, 2);
+        state.push(Origin::from(0), StyledGraphemes::from("5"));
+        assert_eq!(state.bookmarks, vec![2]);
+
+        assert!(state.clear_bookmarks());
+        assert!(!state.jump_to_bookmark(true));
+    }
+
+    #[test]
+    fn test_bookmarked_lines() {
+        let mut state = State::new(3, false, false);
+        state.toggle_dedup();
+        for line in ["a", "a", "a", "b", "c"] {
+            state.push(Origin::from(0), StyledGraphemes::from(line));
+        }
+        assert_eq!(state.text(), "a\na\na\nb\nc\n");
+        // "b" and "c" come after the three folded "a".
+        state.bookmark_lines(&[1, 3, 4, 9]);
+        assert_eq!(state.bookmarks, vec![0, 1, 2]);
+        assert_eq!(state.bookmarked_lines(), vec![0, 3, 4]);
+
+        state.jump_to_line(2);
+        assert!(!state.toggle_bookmark());
+        assert_eq!(state.bookmarked_lines(), vec![0, 4]);
+    }
+
+    #[test]
+    fn test_stage_badges() {
+        let mut state = State::new(1, true, false);
+        state.push(Origin::from(0), StyledGraphemes::from("a"));
+        state.push(Origin::from(2), StyledGraphemes::from("b"));
+        state.push(Origin::from(1), StyledGraphemes::from("c"));
+        // The badges follow the lines shifted by dropping the oldest one.
+        assert_eq!(state.gutter(0).to_string(), "#3 ");
+        assert_eq!(state.gutter(1).to_string(), "#2 ");
+        // The column excludes the badge.
+        assert_eq!(state.position_at(0, 3, 10), Some((0, 0)));
+        // The text excludes the badges.
+        assert_eq!(state.text(), "b\nc\n");
+    }
+
+    #[test]
+    fn test_dedup() {
+        let mut state = State::new(10, false, false);
+        assert!(state.toggle_dedup());
+        for line in ["a", "a", "a", "b", "a", "", ""] {
+            state.push(Origin::from(0), StyledGraphemes::from(line));
+        }
+        // The same line from another stage is not folded.
+        state.push(Origin::from(1), StyledGraphemes::from(""));
+        assert_eq!(state.lines(), (1, 5));
+        assert_eq!(state.total_bytes(), 3);
+        assert_eq!(state.gutter(0).to_string(), "(×3) ");
+        assert_eq!(state.gutter(1).to_string(), "");
+        assert_eq!(state.gutter(3).to_string(), "(×2) ");
+        assert_eq!(state.text(), "a\na\na\nb\na\n\n\n\n");
+
+        assert!(!state.toggle_dedup());
+        state.push(Origin::from(1), StyledGraphemes::from(""));
+        assert_eq!(state.lines(), (1, 6));
+    }
+
+    #[test]
+    fn test_json() {
+        let mut state = State::new(10, false, true);
+        for line in ["{", "\"a\": 1,"] {
+            state.push(Origin::from(0), StyledGraphemes::from(line));
+        }
+        // Not JSON yet.
+        assert!(!state.shows_json());
+        state.push(Origin::from(0), StyledGraphemes::from("\"b\": [] }"));
+        assert!(!state.shows_json());
+        state.push(Origin::from(0), StyledGraphemes::from("}"));
+        assert!(!state.shows_json());
+
+        state.reset();
+        state.push(Origin::from(0), StyledGraphemes::from("{"));
+        state.push(Origin::from(0), StyledGraphemes::from("\"a\": [1, 2]"));
+        state.push(Origin::from(0), StyledGraphemes::from("}"));
+        assert!(state.shows_json());
+        assert_eq!(state.lines(), (1, 6));
+        // The text is not pretty-printed.
+        assert_eq!(state.text(), "{\n\"a\": [1, 2]\n}\n");
+
+        // NDJSON is pretty-printed line by line.
+        state.reset();
+        state.push(Origin::from(0), StyledGraphemes::from("{\"a\":1}"));
+        state.push(Origin::from(0), StyledGraphemes::from("[]"));
+        assert_eq!(state.lines(), (1, 4));
+        assert!(!state.toggle_json());
+        assert_eq!(state.lines(), (1, 2));
+        assert!(state.toggle_json());
+        assert_eq!(state.lines(), (1, 4));
+        // The other lines pass through unchanged.
+        state.push(Origin::from(0), StyledGraphemes::from("done"));
+        assert_eq!(state.lines(), (1, 5));
+    }
+}
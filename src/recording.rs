@@ -0,0 +1,219 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::json;
+
+/// Tees the bytes written to the terminal into the asciinema v2 file,
+/// as the output events with the elapsed times.
+/// Once writing to the file fails, the bytes are only written to the terminal.
+pub struct RecordingWriter<W: Write, C: Write = BufWriter<File>> {
+    inner: W,
+    // None once writing to it has failed.
+    cast: Option<C>,
+    started_at: Instant,
+    // The leading bytes of a UTF-8 character split across the writes.
+    pending: Vec<u8>,
+}
+
+impl<W: Write, C: Write> RecordingWriter<W, C> {
+    /// Writes the header of the asciinema v2 file for the terminal of the size.
+    pub fn new(inner: W, mut cast: C, (width, height): (u16, u16)) -> io::Result<Self> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let header = json!({
+            "version": 2,
+            "width": width,
+            "height": height,
+            "timestamp": timestamp,
+            "title": "epiq",
+        });
+        writeln!(cast, "{}", header)?;
+        Ok(Self {
+            inner,
+            cast: Some(cast),
+            started_at: Instant::now(),
+            pending: vec![],
+        })
+    }
+
+    /// Writes the bytes as an output event, keeping the incomplete character at the end
+    /// for the next event since the events are JSON strings.
+    fn record(&mut self, buf: &[u8]) -> io::Result<()> {
+        let Some(cast) = self.cast.as_mut() else {
+            return Ok(());
+        };
+        self.pending.extend_from_slice(buf);
+        let valid = match std::str::from_utf8(&self.pending) {
+            Ok(text) => text.len(),
+            // Wait for the rest of the character.
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            // Not UTF-8, which the terminal shows as the replacement characters.
+            Err(_) => self.pending.len(),
+        };
+        if valid == 0 {
+            return Ok(());
+        }
+        let rest = self.pending.split_off(valid);
+        let data = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending = rest;
+        let time = self.started_at.elapsed().as_micros() as f64 / 1_000_000.0;
+        writeln!(cast, "{}", json!([time, "o", data]))
+    }
+}
+
+impl<W: Write, C: Write> Write for RecordingWriter<W, C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if self.record(&buf[..written]).is_err() {
+            self.cast = None;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(cast) = self.cast.as_mut()
+            && cast.flush().is_err()
+        {
+            self.cast = None;
+        }
+        self.inner.flush()
+    }
+}
+
+/// Records everything written to stdout, including by promkit, into the asciinema v2 file
+/// by replacing stdout with a pipe, whose bytes are copied to the terminal and the file.
+#[cfg(unix)]
+pub struct Recording {
+    // The original stdout, put back when finished.
+    terminal: std::os::fd::OwnedFd,
+    copier: std::thread::JoinHandle<()>,
+}
+
+#[cfg(unix)]
+impl Recording {
+    /// Starts recording the terminal of the size into the file.
+    pub fn start(path: &Path, size: (u16, u16)) -> anyhow::Result<Self> {
+        use std::{
+            io::Read,
+            os::fd::{AsFd, AsRawFd},
+        };
+
+        let cast = File::create(path)?;
+        let terminal = io::stdout().as_fd().try_clone_to_owned()?;
+        let mut recorder = RecordingWriter::new(
+            File::from(terminal.try_clone()?),
+            BufWriter::new(cast),
+            size,
+        )?;
+        let (mut reader, writer) = io::pipe()?;
+
+        io::stdout().flush()?;
+        // SAFETY: Both are open file descriptors, and stdout is replaced atomically.
+        if unsafe { libc::dup2(writer.as_raw_fd(), libc::STDOUT_FILENO) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        drop(writer);
+
+        let copier = std::thread::spawn(move || {
+            let mut buf = [0; 8192];
+            // Ends once stdout is put back, which closes the pipe.
+            while let Ok(read @ 1..) = reader.read(&mut buf) {
+                if recorder
+                    .write_all(&buf[..read])
+                    .and_then(|()| recorder.flush())
+                    .is_err()
+                {
+                    break;
+                }
+            }
+            let _ = recorder.flush();
+        });
+        Ok(Self { terminal, copier })
+    }
+
+    /// Puts the original stdout back, and waits until all the bytes are recorded.
+    pub fn finish(self) -> anyhow::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        io::stdout().flush()?;
+        // SAFETY: Both are open file descriptors, and stdout is replaced atomically.
+        if unsafe { libc::dup2(self.terminal.as_raw_fd(), libc::STDOUT_FILENO) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        self.copier
+            .join()
+            .map_err(|_| anyhow::anyhow!("The recording thread panicked"))
+    }
+}
+
+#[cfg(not(unix))]
+pub struct Recording;
+
+#[cfg(not(unix))]
+impl Recording {
+    pub fn start(_path: &Path, _size: (u16, u16)) -> anyhow::Result<Self> {
+        Err(anyhow::anyhow!("recording is only supported on Unix"))
+    }
+
+    pub fn finish(self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("broken"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_recording_writer() {
+        let mut writer = RecordingWriter::new(vec![], vec![], (80, 24)).unwrap();
+        let bytes = "aあ".as_bytes();
+        // Split in the middle of `あ`.
+        writer.write_all(&bytes[..2]).unwrap();
+        writer.write_all(&bytes[2..]).unwrap();
+        writer.flush().unwrap();
+        assert_eq!(writer.inner, bytes);
+
+        let cast = String::from_utf8(writer.cast.unwrap()).unwrap();
+        let lines: Vec<serde_json::Value> = cast
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["version"], 2);
+        assert_eq!(lines[0]["width"], 80);
+        assert_eq!(lines[0]["height"], 24);
+        assert_eq!(lines[1][1], "o");
+        assert_eq!(lines[1][2], "a");
+        assert_eq!(lines[2][2], "あ");
+
+        // The terminal is still written after the file fails.
+        let mut writer = RecordingWriter {
+            inner: vec![],
+            cast: Some(Broken),
+            started_at: Instant::now(),
+            pending: vec![],
+        };
+        writer.write_all(b"abc").unwrap();
+        assert!(writer.cast.is_none());
+        writer.write_all(b"d").unwrap();
+        assert_eq!(writer.inner, b"abcd");
+    }
+}
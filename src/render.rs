@@ -1,10 +1,13 @@
 use std::{
     collections::BTreeMap,
+    io::Write,
     sync::{Arc, LazyLock},
 };
 
 use crossterm::style::{Attribute, Attributes, Color};
-use promkit::{pane::Pane, style::StyleBuilder, terminal::Terminal, text};
+use promkit::{
+    grapheme::StyledGraphemes, pane::Pane, style::StyleBuilder, terminal::Terminal, text,
+};
 use tokio::sync::{Mutex, MutexGuard};
 
 pub static EMPTY_PANE: LazyLock<Pane> = LazyLock::new(|| Pane::new(vec![], 0));
@@ -29,46 +32,188 @@ impl PartialOrd for EditorIndex {
 impl Ord for EditorIndex {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         // Comparing fractions: To compare a/b and c/d, compare ad and bc
-        let left = (self.0 as u64) * (other.1 as u64);
-        let right = (self.1 as u64) * (other.0 as u64);
+        // (in u128 not to overflow)
+        let left = (self.0 as u128) * (other.1 as u128);
+        let right = (self.1 as u128) * (other.0 as u128);
         left.cmp(&right)
     }
 }
 
 impl EditorIndex {
     pub fn mediant(a: &EditorIndex, b: &EditorIndex) -> Self {
-        // TODO: gcd to reduce the fraction
-        Self(a.0 + b.0, a.1 + b.1)
+        // Reduce the fraction so that equal indices are also structurally equal.
+        let (numerator, denominator) = (a.0 + b.0, a.1 + b.1);
+        let gcd = gcd(numerator, denominator).max(1);
+        Self(numerator / gcd, denominator / gcd)
     }
 }
 
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+/// Returns the rows reserved for the notify and output panes,
+/// given the maximum rows of the output pane (None means no limit)
+/// and the rows left for it at least.
+pub fn reserved_rows(output_height: Option<u16>, min_output_height: u16) -> u16 {
+    1 + output_height.unwrap_or(0).max(min_output_height.max(1))
+}
+
 #[derive(Clone, PartialEq, Eq)]
 pub enum NotifyMessage {
     None,
+    Info(String),
+    // Something worth noticing which has not failed, e.g. a stage stopped at the line limit.
+    Warning(String),
     Error(String),
+    // Kept until replaced, e.g. a question waiting for y/n (shown as the information).
+    Sticky(String),
 }
 
-impl From<NotifyMessage> for text::State {
-    fn from(val: NotifyMessage) -> Self {
-        match val {
-            NotifyMessage::None => text::State::default(),
-            NotifyMessage::Error(message) => text::State {
-                text: text::Text::from(message),
-                style: StyleBuilder::new()
-                    .fgc(Color::DarkRed)
-                    .attrs(Attributes::from(Attribute::Bold))
-                    .build(),
-                ..Default::default()
-            },
+/// The colors of the notifications.
+#[derive(Clone, Copy)]
+pub struct NotifyTheme {
+    pub info_fg_color: Color,
+    pub warning_fg_color: Color,
+    pub error_fg_color: Color,
+}
+
+impl NotifyTheme {
+    /// Replaces the RGB colors with the default ones on the terminals without true color.
+    pub fn fallback(self, true_color: bool) -> Self {
+        let default = Self::default();
+        Self {
+            info_fg_color: true_color_or(self.info_fg_color, default.info_fg_color, true_color),
+            warning_fg_color: true_color_or(
+                self.warning_fg_color,
+                default.warning_fg_color,
+                true_color,
+            ),
+            error_fg_color: true_color_or(self.error_fg_color, default.error_fg_color, true_color),
+        }
+    }
+}
+
+impl Default for NotifyTheme {
+    fn default() -> Self {
+        Self {
+            info_fg_color: Color::DarkCyan,
+            warning_fg_color: Color::DarkYellow,
+            error_fg_color: Color::DarkRed,
+        }
+    }
+}
+
+impl NotifyMessage {
+    /// Returns whether the message is cleared after the timeout.
+    pub fn expires(&self) -> bool {
+        matches!(
+            self,
+            NotifyMessage::Info(_) | NotifyMessage::Warning(_) | NotifyMessage::Error(_)
+        )
+    }
+
+    /// Returns the text of the message in bold with the color of the theme.
+    pub fn into_state(self, theme: &NotifyTheme) -> text::State {
+        let (message, color) = match self {
+            NotifyMessage::None => return text::State::default(),
+            NotifyMessage::Info(message) | NotifyMessage::Sticky(message) => {
+                (message, theme.info_fg_color)
+            }
+            NotifyMessage::Warning(message) => (message, theme.warning_fg_color),
+            NotifyMessage::Error(message) => (message, theme.error_fg_color),
+        };
+        text::State {
+            text: text::Text::from(message),
+            style: StyleBuilder::new()
+                .fgc(color)
+                .attrs(Attributes::from(Attribute::Bold))
+                .build(),
+            ..Default::default()
         }
     }
 }
 
+/// Parses the color of `#RRGGBB`, `#RGB`, or the name such as `red`, `dark_yellow`
+/// or `DarkYellow` as crossterm names it.
+pub fn color_from_str(s: &str) -> anyhow::Result<Color> {
+    let s = s.trim();
+    if let Some(hex) = s.strip_prefix('#') {
+        let digits = hex
+            .chars()
+            .map(|c| c.to_digit(16).map(|digit| digit as u8))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| anyhow::anyhow!("Invalid hex color: {}", s))?;
+        let (r, g, b) = match digits[..] {
+            // Each digit is repeated, i.e. `#ABC` is `#AABBCC`.
+            [r, g, b] => (r * 0x11, g * 0x11, b * 0x11),
+            [r1, r2, g1, g2, b1, b2] => (r1 << 4 | r2, g1 << 4 | g2, b1 << 4 | b2),
+            _ => anyhow::bail!("Invalid hex color (expected #RGB or #RRGGBB): {}", s),
+        };
+        return Ok(Color::Rgb { r, g, b });
+    }
+    // crossterm names the colors in snake_case, e.g. `DarkGreen` as `dark_green`.
+    let mut name = String::new();
+    for (i, c) in s.chars().enumerate() {
+        if c.is_ascii_uppercase() && i > 0 {
+            name.push('_');
+        }
+        name.push(c.to_ascii_lowercase());
+    }
+    Color::try_from(name.as_str()).map_err(|()| anyhow::anyhow!("Unknown color: {}", s))
+}
+
+/// Parses the color for the command-line arguments (see `color_from_str`).
+pub fn parse_color(s: &str) -> Result<Color, String> {
+    color_from_str(s).map_err(|e| e.to_string())
+}
+
+/// Returns whether the terminal reports the support of the 24-bit colors by `$COLORTERM`.
+pub fn supports_true_color() -> bool {
+    std::env::var("COLORTERM").is_ok_and(|value| value == "truecolor" || value == "24bit")
+}
+
+/// Replaces the RGB color with the default one on the terminals without true color.
+pub fn true_color_or(color: Color, default: Color, true_color: bool) -> Color {
+    match color {
+        Color::Rgb { .. } if !true_color => default,
+        color => color,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FormField {
+    Find,
+    Replace,
+    ExportPath,
+    Comment,
+    SnippetName,
+}
+
+impl FormField {
+    /// Whether the field is rendered just above the editor instead of below.
+    fn is_above(&self) -> bool {
+        matches!(self, FormField::Comment)
+    }
+}
+
 #[derive(Clone, PartialEq, Eq)]
 pub enum PaneIndex {
     Notify,
+    // The whole pipeline as a command line, above the editors.
+    Preview,
+    // The indicators of the editors hidden above and below the rendered ones.
+    EditorsAbove,
     Editor(EditorIndex),
+    // Rendered just below (or above) the editor of the given index.
+    Form(EditorIndex, FormField),
+    EditorsBelow,
+    DocPopup,
+    EnvPopup,
+    // The input console just above the output.
+    InputConsole,
     Output,
+    Status,
 }
 
 impl PartialOrd for PaneIndex {
@@ -84,11 +229,54 @@ impl Ord for PaneIndex {
             (PaneIndex::Notify, _) => std::cmp::Ordering::Less,
             (_, PaneIndex::Notify) => std::cmp::Ordering::Greater,
 
+            (PaneIndex::Preview, PaneIndex::Preview) => std::cmp::Ordering::Equal,
+            (PaneIndex::Preview, _) => std::cmp::Ordering::Less,
+            (_, PaneIndex::Preview) => std::cmp::Ordering::Greater,
+
+            (PaneIndex::EditorsAbove, PaneIndex::EditorsAbove) => std::cmp::Ordering::Equal,
+            (PaneIndex::EditorsAbove, _) => std::cmp::Ordering::Less,
+            (_, PaneIndex::EditorsAbove) => std::cmp::Ordering::Greater,
+
+            (PaneIndex::Status, PaneIndex::Status) => std::cmp::Ordering::Equal,
+            (PaneIndex::Status, _) => std::cmp::Ordering::Greater,
+            (_, PaneIndex::Status) => std::cmp::Ordering::Less,
+
             (PaneIndex::Output, PaneIndex::Output) => std::cmp::Ordering::Equal,
             (PaneIndex::Output, _) => std::cmp::Ordering::Greater,
             (_, PaneIndex::Output) => std::cmp::Ordering::Less,
 
+            (PaneIndex::InputConsole, PaneIndex::InputConsole) => std::cmp::Ordering::Equal,
+            (PaneIndex::InputConsole, _) => std::cmp::Ordering::Greater,
+            (_, PaneIndex::InputConsole) => std::cmp::Ordering::Less,
+
+            (PaneIndex::EnvPopup, PaneIndex::EnvPopup) => std::cmp::Ordering::Equal,
+            (PaneIndex::EnvPopup, _) => std::cmp::Ordering::Greater,
+            (_, PaneIndex::EnvPopup) => std::cmp::Ordering::Less,
+
+            (PaneIndex::DocPopup, PaneIndex::DocPopup) => std::cmp::Ordering::Equal,
+            (PaneIndex::DocPopup, _) => std::cmp::Ordering::Greater,
+            (_, PaneIndex::DocPopup) => std::cmp::Ordering::Less,
+
+            (PaneIndex::EditorsBelow, PaneIndex::EditorsBelow) => std::cmp::Ordering::Equal,
+            (PaneIndex::EditorsBelow, _) => std::cmp::Ordering::Greater,
+            (_, PaneIndex::EditorsBelow) => std::cmp::Ordering::Less,
+
             (PaneIndex::Editor(a), PaneIndex::Editor(b)) => a.cmp(b),
+            (PaneIndex::Editor(a), PaneIndex::Form(b, field)) => {
+                a.cmp(b).then(if field.is_above() {
+                    std::cmp::Ordering::Greater
+                } else {
+                    std::cmp::Ordering::Less
+                })
+            }
+            (PaneIndex::Form(a, field), PaneIndex::Editor(b)) => {
+                a.cmp(b).then(if field.is_above() {
+                    std::cmp::Ordering::Less
+                } else {
+                    std::cmp::Ordering::Greater
+                })
+            }
+            (PaneIndex::Form(a, x), PaneIndex::Form(b, y)) => a.cmp(b).then(x.cmp(y)),
         }
     }
 }
@@ -111,6 +299,11 @@ impl SharedRenderer {
 pub struct Renderer {
     terminal: Terminal,
     panes: BTreeMap<PaneIndex, Pane>,
+    // The rows on the screen, or None if the screen has to be drawn all over again.
+    front_buffer: Option<Vec<StyledGraphemes>>,
+    // The rows of the next frame, swapped with the front buffer once drawn
+    // (kept only to reuse the allocation).
+    back_buffer: Vec<StyledGraphemes>,
 }
 
 impl Renderer {
@@ -123,7 +316,10 @@ impl Renderer {
                 (PaneIndex::Notify, EMPTY_PANE.clone()),
                 (PaneIndex::Editor(EditorIndex(1, 1)), EMPTY_PANE.clone()),
                 (PaneIndex::Output, EMPTY_PANE.clone()),
+                (PaneIndex::Status, EMPTY_PANE.clone()),
             ]),
+            front_buffer: None,
+            back_buffer: vec![],
         })
     }
 
@@ -137,6 +333,10 @@ impl Renderer {
         self
     }
 
+    pub fn contains(&self, index: &PaneIndex) -> bool {
+        self.panes.contains_key(index)
+    }
+
     pub fn remove<I>(&mut self, items: I) -> &mut Self
     where
         I: IntoIterator<Item = PaneIndex>,
@@ -147,8 +347,169 @@ impl Renderer {
         self
     }
 
+    /// Returns the pane drawn at the given row of the terminal,
+    /// with the row relative to the top of the pane.
+    /// This follows how promkit::terminal::Terminal lays out the panes.
+    pub fn pane_at(&self, row: u16) -> Option<(PaneIndex, usize)> {
+        let height = crossterm::terminal::size().ok()?.1;
+        let (row, mut top) = (row as usize, self.terminal.position.1 as usize);
+        for (index, rows) in self.layout(height) {
+            if (top..top + rows.len()).contains(&row) {
+                return Some((index.clone(), row - top));
+            }
+            top += rows.len();
+        }
+        None
+    }
+
+    /// Returns the rows of each pane drawn within the height of the terminal.
+    /// This follows how promkit::terminal::Terminal lays out the panes.
+    fn layout(&self, height: u16) -> Vec<(&PaneIndex, Vec<StyledGraphemes>)> {
+        let viewable: Vec<(&PaneIndex, &Pane)> = self
+            .panes
+            .iter()
+            .filter(|(_, pane)| !pane.is_empty())
+            .collect();
+
+        let mut used = 0;
+        let mut layout = vec![];
+        for (i, (index, pane)) in viewable.iter().enumerate() {
+            let max_rows = 1.max((height as usize).saturating_sub(used + viewable.len() - 1 - i));
+            let rows = pane.extract(max_rows);
+            used += rows.len();
+            layout.push((*index, rows));
+        }
+        layout
+    }
+
+    /// Clears the whole screen, and draws the panes from the top at the next rendering.
+    pub fn clear(&mut self) -> anyhow::Result<()> {
+        crossterm::execute!(
+            std::io::stdout(),
+            crossterm::cursor::MoveTo(0, 0),
+            crossterm::terminal::Clear(crossterm::terminal::ClearType::All),
+        )?;
+        self.terminal.position = (0, 0);
+        self.front_buffer = None;
+        Ok(())
+    }
+
+    /// Keeps the position the panes are drawn from within the resized terminal.
+    /// Otherwise, after the terminal shrinks, the panes are drawn from below the bottom
+    /// and the rows scrolled out of the terminal leave the output of the shell overwritten.
+    pub fn resize(&mut self, width: u16, height: u16) {
+        let (column, row) = self.terminal.position;
+        self.terminal.position = (
+            column.min(width.saturating_sub(1)),
+            row.min(height.saturating_sub(1)),
+        );
+        // The rows on the screen may have been reflowed.
+        self.front_buffer = None;
+    }
+
     pub fn render(&mut self) -> anyhow::Result<()> {
-        self.terminal
-            .draw(&self.panes.values().cloned().collect::<Vec<Pane>>())
+        let height = crossterm::terminal::size()?.1;
+        let mut back_buffer = std::mem::take(&mut self.back_buffer);
+        back_buffer.clear();
+        back_buffer.extend(self.layout(height).into_iter().flat_map(|(_, rows)| rows));
+
+        // Let the terminal show the frame at once, not the half-drawn one
+        // (ignored by the terminals which do not support it).
+        crossterm::queue!(
+            std::io::stdout(),
+            crossterm::terminal::BeginSynchronizedUpdate
+        )?;
+        let drawn = match &self.front_buffer {
+            // Redraw only the changed rows unless the screen has to be scrolled for the panes.
+            Some(front)
+                if self.terminal.position.1 as usize + back_buffer.len() <= height as usize =>
+            {
+                self.render_diff(front, &back_buffer)
+            }
+            _ => self
+                .terminal
+                .draw(&self.panes.values().cloned().collect::<Vec<Pane>>()),
+        };
+        crossterm::execute!(
+            std::io::stdout(),
+            crossterm::terminal::EndSynchronizedUpdate
+        )?;
+        if drawn.is_err() {
+            // The rows on the screen are unknown.
+            self.front_buffer = None;
+        }
+        drawn?;
+
+        match &mut self.front_buffer {
+            Some(front_buffer) => {
+                std::mem::swap(front_buffer, &mut back_buffer);
+                self.back_buffer = back_buffer;
+            }
+            None => self.front_buffer = Some(back_buffer),
+        }
+        Ok(())
+    }
+
+    /// Draws the rows different from the previous ones at the same rows of the screen,
+    /// and clears the rows left below if there are fewer rows than before.
+    fn render_diff(
+        &self,
+        prev: &[StyledGraphemes],
+        rows: &[StyledGraphemes],
+    ) -> anyhow::Result<()> {
+        let mut stdout = std::io::stdout();
+        let (column, top) = self.terminal.position;
+        // The first row starts from the position, and the others from the left edge.
+        let column_at = |i: usize| if i == 0 { column } else { 0 };
+        for (i, row) in rows.iter().enumerate() {
+            if prev.get(i) != Some(row) {
+                crossterm::queue!(
+                    stdout,
+                    crossterm::cursor::MoveTo(column_at(i), top + i as u16),
+                    crossterm::terminal::Clear(crossterm::terminal::ClearType::UntilNewLine),
+                    crossterm::style::Print(row.styled_display()),
+                )?;
+            }
+        }
+        if rows.len() < prev.len() {
+            crossterm::queue!(
+                stdout,
+                crossterm::cursor::MoveTo(column_at(rows.len()), top + rows.len() as u16),
+                crossterm::terminal::Clear(crossterm::terminal::ClearType::FromCursorDown),
+            )?;
+        }
+        stdout.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_color_from_str() {
+        assert_eq!(
+            color_from_str("#FF8800").unwrap(),
+            Color::Rgb {
+                r: 0xff,
+                g: 0x88,
+                b: 0x00
+            }
+        );
+        assert_eq!(
+            color_from_str("#abc").unwrap(),
+            Color::Rgb {
+                r: 0xaa,
+                g: 0xbb,
+                b: 0xcc
+            }
+        );
+        assert_eq!(color_from_str("DarkGreen").unwrap(), Color::DarkGreen);
+        assert_eq!(color_from_str("dark_green").unwrap(), Color::DarkGreen);
+        assert_eq!(color_from_str("Red").unwrap(), Color::Red);
+        assert!(color_from_str("#12345").is_err());
+        assert!(color_from_str("#GGGGGG").is_err());
+        assert!(color_from_str("no_such_color").is_err());
     }
 }
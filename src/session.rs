@@ -0,0 +1,202 @@
+use std::path::Path;
+
+use serde_json::{Value, json};
+
+/// The version of the session file, bumped on incompatible changes of the format.
+const VERSION: u64 = 1;
+
+/// A stage saved in the session.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stage {
+    pub text: String,
+    pub ignore: bool,
+    pub comment: Option<String>,
+}
+
+/// Where the user was, saved to restore it on the next launch.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Session {
+    pub stages: Vec<Stage>,
+    // The index (0-based) of the focused stage.
+    pub focus: usize,
+    // The lines in the output pane.
+    pub output: Vec<String>,
+    // The bookmarked lines (0-based) in the output.
+    pub bookmarks: Vec<usize>,
+    // The rows of the output pane resized with Alt+=/Alt+-, or None to fill the terminal.
+    pub output_height: Option<u16>,
+}
+
+impl Session {
+    fn to_json(&self) -> Value {
+        json!({
+            "version": VERSION,
+            "stages": self
+                .stages
+                .iter()
+                .map(|stage| json!({
+                    "text": stage.text,
+                    "ignore": stage.ignore,
+                    "comment": stage.comment,
+                }))
+                .collect::<Vec<_>>(),
+            "focus": self.focus,
+            "output": self.output,
+            "bookmarks": self.bookmarks,
+            "output_height": self.output_height,
+        })
+    }
+
+    fn from_json(value: &Value) -> anyhow::Result<Self> {
+        match value.get("version").and_then(Value::as_u64) {
+            Some(VERSION) => {}
+            Some(version) => anyhow::bail!(
+                "version {} is not supported (expected {})",
+                version,
+                VERSION
+            ),
+            None => anyhow::bail!("`version` is missing"),
+        }
+        let stages = value
+            .get("stages")
+            .and_then(Value::as_array)
+            .ok_or_else(|| anyhow::anyhow!("`stages` must be an array"))?
+            .iter()
+            .map(|stage| {
+                Some(Stage {
+                    text: stage.get("text")?.as_str()?.to_string(),
+                    ignore: stage.get("ignore")?.as_bool()?,
+                    comment: match stage.get("comment")? {
+                        Value::Null => None,
+                        comment => Some(comment.as_str()?.to_string()),
+                    },
+                })
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| anyhow::anyhow!("`stages` has a malformed stage"))?;
+        let focus = value
+            .get("focus")
+            .and_then(Value::as_u64)
+            .ok_or_else(|| anyhow::anyhow!("`focus` must be a number"))?
+            as usize;
+        let output = value
+            .get("output")
+            .and_then(Value::as_array)
+            .and_then(|lines| {
+                lines
+                    .iter()
+                    .map(|line| line.as_str().map(String::from))
+                    .collect::<Option<Vec<_>>>()
+            })
+            .ok_or_else(|| anyhow::anyhow!("`output` must be an array of strings"))?;
+        // Missing in the files saved before the bookmarks were saved.
+        let bookmarks = match value.get("bookmarks") {
+            None => vec![],
+            Some(lines) => lines
+                .as_array()
+                .and_then(|lines| {
+                    lines
+                        .iter()
+                        .map(|line| line.as_u64().map(|line| line as usize))
+                        .collect::<Option<Vec<_>>>()
+                })
+                .ok_or_else(|| anyhow::anyhow!("`bookmarks` must be an array of line numbers"))?,
+        };
+        // Missing in the files saved before the split ratio was saved.
+        let output_height = match value.get("output_height") {
+            None | Some(Value::Null) => None,
+            Some(rows) => Some(
+                rows.as_u64()
+                    .and_then(|rows| u16::try_from(rows).ok())
+                    .ok_or_else(|| anyhow::anyhow!("`output_height` must be a number of rows"))?,
+            ),
+        };
+        Ok(Self {
+            stages,
+            focus,
+            output,
+            bookmarks,
+            output_height,
+        })
+    }
+}
+
+/// Loads the session from the file, or returns None if it does not exist yet.
+/// Refuses the file of another version instead of restoring a broken state.
+pub fn load(path: &Path) -> anyhow::Result<Option<Session>> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => anyhow::bail!("Failed to read the session {}: {}", path.display(), e),
+    };
+    serde_json::from_str(&text)
+        .map_err(anyhow::Error::from)
+        .and_then(|value| Session::from_json(&value))
+        .map(Some)
+        .map_err(|e| anyhow::anyhow!("Failed to load the session {}: {}", path.display(), e))
+}
+
+/// Saves the session to the file, creating the directories if missing.
+/// The file is replaced at once not to leave it half-written if interrupted.
+pub fn save(path: &Path, session: &Session) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, session.to_json().to_string())?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir()
+            .join(format!("epiq_test_session_{}", std::process::id()))
+            .join("session.json");
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(load(&path).unwrap(), None);
+
+        let session = Session {
+            stages: vec![
+                Stage {
+                    text: String::from("cat foo"),
+                    ..Default::default()
+                },
+                Stage {
+                    text: String::from("grep \"a b\""),
+                    ignore: true,
+                    comment: Some(String::from("not yet")),
+                },
+            ],
+            focus: 1,
+            output: vec![String::from("a"), String::new()],
+            bookmarks: vec![1],
+            output_height: Some(12),
+        };
+        save(&path, &session).unwrap();
+        assert_eq!(load(&path).unwrap(), Some(session));
+
+        // The bookmarks and the output height are optional.
+        std::fs::write(&path, r#"{"version":1,"stages":[],"focus":0,"output":[]}"#).unwrap();
+        assert_eq!(load(&path).unwrap(), Some(Session::default()));
+        std::fs::write(
+            &path,
+            r#"{"version":1,"stages":[],"focus":0,"output":[],"output_height":-1}"#,
+        )
+        .unwrap();
+        assert!(load(&path).is_err());
+
+        // Another version is refused.
+        std::fs::write(&path, r#"{"version":2,"stages":[],"focus":0,"output":[]}"#).unwrap();
+        assert!(load(&path).is_err());
+        std::fs::write(&path, r#"{"version":1,"stages":[{"text":1}],"focus":0}"#).unwrap();
+        assert!(load(&path).is_err());
+
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use crate::config;
+
+/// The pipelines saved by name, each as the texts of the stages.
+pub type Snippets = BTreeMap<String, Vec<String>>;
+
+/// Returns the file the snippets are saved in, `snippets.toml` in the config directory.
+pub fn default_path() -> Option<PathBuf> {
+    config::dir().map(|dir| dir.join("snippets.toml"))
+}
+
+/// Loads the snippets written as `name = ["stage", ...]`, or none if the file does not exist.
+pub fn load(path: &Path) -> anyhow::Result<Snippets> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Snippets::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let table: toml::Table = text.parse()?;
+    table
+        .into_iter()
+        .map(|(name, stages)| {
+            let stages = stages
+                .as_array()
+                .and_then(|stages| {
+                    stages
+                        .iter()
+                        .map(|stage| stage.as_str().map(String::from))
+                        .collect::<Option<Vec<_>>>()
+                })
+                .ok_or_else(|| anyhow::anyhow!("`{}` must be an array of strings", name))?;
+            Ok((name, stages))
+        })
+        .collect()
+}
+
+/// Saves the stages as the snippet of the name, and returns whether it has overwritten
+/// the one of the same name. The file is replaced at once not to leave it half-written.
+pub fn save(path: &Path, name: &str, stages: &[String]) -> anyhow::Result<bool> {
+    if name.is_empty() {
+        anyhow::bail!("The snippet name is empty");
+    }
+    let mut snippets = load(path)?;
+    let overwritten = snippets.insert(name.to_string(), stages.to_vec()).is_some();
+
+    let table: toml::Table = snippets
+        .into_iter()
+        .map(|(name, stages)| {
+            let stages = stages.into_iter().map(toml::Value::String).collect();
+            (name, toml::Value::Array(stages))
+        })
+        .collect();
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, toml::to_string(&table)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(overwritten)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir()
+            .join(format!("epiq_test_snippets_{}", std::process::id()))
+            .join("snippets.toml");
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(load(&path).unwrap(), Snippets::new());
+
+        let errors = vec![String::from("cat app.log"), String::from("grep \"ERROR\"")];
+        assert!(!save(&path, "errors", &errors).unwrap());
+        assert!(!save(&path, "top ips", &[String::from("cut -d' ' -f1")]).unwrap());
+        // The same name overwrites the snippet.
+        assert!(save(&path, "errors", &errors[..1]).unwrap());
+        assert_eq!(
+            load(&path).unwrap(),
+            Snippets::from([
+                (String::from("errors"), errors[..1].to_vec()),
+                (String::from("top ips"), vec![String::from("cut -d' ' -f1")]),
+            ])
+        );
+        assert!(save(&path, "", &errors).is_err());
+
+        std::fs::write(&path, "errors = [1]").unwrap();
+        assert!(load(&path).is_err());
+
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+}
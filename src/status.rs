@@ -0,0 +1,51 @@
+use std::collections::BTreeMap;
+
+use crossterm::style::{Attribute, Attributes};
+use promkit::{PaneFactory, grapheme::StyledGraphemes, pane::Pane, style::StyleBuilder};
+
+/// The items shown in the status bar, in the order of display.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum StatusItem {
+    Spinner,
+    Elapsed,
+    Throughput,
+    AutoRun,
+    OutputLines,
+    OutputCounts,
+}
+
+pub enum StatusMessage {
+    Set(StatusItem, String),
+    Clear(StatusItem),
+}
+
+#[derive(Default)]
+pub struct State(BTreeMap<StatusItem, String>);
+
+impl State {
+    pub fn apply(&mut self, message: StatusMessage) {
+        match message {
+            StatusMessage::Set(item, text) => {
+                self.0.insert(item, text);
+            }
+            StatusMessage::Clear(item) => {
+                self.0.remove(&item);
+            }
+        }
+    }
+}
+
+impl PaneFactory for State {
+    fn create_pane(&self, width: u16, _height: u16) -> Pane {
+        if self.0.is_empty() {
+            return Pane::new(vec![], 0);
+        }
+        let line = StyledGraphemes::from_str(
+            self.0.values().cloned().collect::<Vec<_>>().join(" │ "),
+            StyleBuilder::new()
+                .attrs(Attributes::from(Attribute::Dim))
+                .build(),
+        );
+        Pane::new(line.matrixify(width as usize, 1, 0).0, 0)
+    }
+}
@@ -0,0 +1,60 @@
+use std::{
+    fs::File,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Creates the temporary file named with the prefix and the extension, only readable
+/// by the user. The name is not predictable, and the file is never opened if it already
+/// exists, not to follow a symlink placed there by someone else.
+pub fn create(prefix: &str, extension: &str) -> anyhow::Result<(PathBuf, File)> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    for _ in 0..16 {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.subsec_nanos());
+        let path = std::env::temp_dir().join(format!(
+            "{}_{}_{:08x}.{}",
+            prefix,
+            std::process::id(),
+            nanos,
+            extension
+        ));
+        match options.open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => anyhow::bail!("Failed to create {}: {}", path.display(), e),
+        }
+    }
+    anyhow::bail!("Failed to create a temporary file")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create() {
+        let (path, _) = create("epiq_test", "sh").unwrap();
+        let (other, _) = create("epiq_test", "sh").unwrap();
+        assert_ne!(path, other);
+        assert_eq!(path.extension().unwrap(), "sh");
+        assert!(
+            path.file_name()
+                .unwrap()
+                .to_string_lossy()
+                .starts_with("epiq_test_")
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&other);
+    }
+}
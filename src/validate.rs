@@ -0,0 +1,155 @@
+use std::{fmt, path::Path};
+
+use crate::pipeline;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ValidationError {
+    CommandNotFound(String),
+    InvalidShellSyntax,
+    EmptyCommand,
+    InvalidSetCommand,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::CommandNotFound(cmd) => write!(f, "command '{}' not found", cmd),
+            ValidationError::InvalidShellSyntax => write!(f, "invalid shell syntax"),
+            ValidationError::EmptyCommand => write!(f, "empty command"),
+            ValidationError::InvalidSetCommand => write!(f, "expected SET KEY=VALUE"),
+        }
+    }
+}
+
+/// Checks whether the command is executable, looking up `$PATH` if it is not a path.
+pub fn command_exists(cmd: &str) -> bool {
+    let with_suffix = |path: &Path| {
+        path.is_file()
+            || path
+                .with_extension(std::env::consts::EXE_EXTENSION)
+                .is_file()
+    };
+
+    if cmd.contains(std::path::MAIN_SEPARATOR) {
+        return with_suffix(Path::new(cmd));
+    }
+    std::env::var_os("PATH")
+        .is_some_and(|paths| std::env::split_paths(&paths).any(|dir| with_suffix(&dir.join(cmd))))
+}
+
+fn check_command(cmd: &str) -> Result<(), ValidationError> {
+    if pipeline::is_set_command(cmd) {
+        return pipeline::parse_set_command(cmd)
+            .map(|_| ())
+            .map_err(|_| ValidationError::InvalidSetCommand);
+    }
+    let branches = pipeline::split_fan_out(cmd);
+    if branches.len() > 1 {
+        return branches.iter().try_for_each(|branch| check_command(branch));
+    }
+    let parts = shlex::split(cmd.trim()).ok_or(ValidationError::InvalidShellSyntax)?;
+    match parts.first() {
+        None => Err(ValidationError::EmptyCommand),
+        Some(program) if program.is_empty() => Err(ValidationError::EmptyCommand),
+        Some(program) if !command_exists(program) => {
+            Err(ValidationError::CommandNotFound(program.clone()))
+        }
+        Some(_) => Ok(()),
+    }
+}
+
+/// Validates the commands of the stages, given with their positions (0-based) in the prompt,
+/// in the same way as spawning them, and returns the (position, command, error)
+/// of the invalid ones.
+pub fn check_commands(stages: &[(usize, String)]) -> Vec<(usize, String, ValidationError)> {
+    stages
+        .iter()
+        .filter_map(|(i, cmd)| check_command(cmd).err().map(|e| (*i, cmd.clone(), e)))
+        .collect()
+}
+
+/// Summarizes the results as "Stage 1: OK, Stage 2: command 'jj' not found".
+pub fn summarize(
+    stages: &[(usize, String)],
+    errors: &[(usize, String, ValidationError)],
+) -> String {
+    stages
+        .iter()
+        .map(
+            |(i, _)| match errors.iter().find(|(index, _, _)| index == i) {
+                Some((_, _, e)) => format!("Stage {}: {}", i + 1, e),
+                None => format!("Stage {}: OK", i + 1),
+            },
+        )
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Reports the errors line by line as "Stage 2: command 'jj' not found (jj .)".
+pub fn report(errors: &[(usize, String, ValidationError)]) -> String {
+    errors
+        .iter()
+        .map(|(i, cmd, e)| format!("Stage {}: {} ({})", i + 1, e, cmd.trim()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_commands() {
+        let cmds = [
+            String::from("sh -c 'echo ok'"),
+            String::from("''"),
+            String::from("grep 'unterminated"),
+            String::from("no-such-command-for-epiq --flag"),
+            String::from("SET LANG=C"),
+            String::from("SET LANG"),
+            String::from("cat |> no-such-command-for-epiq"),
+        ];
+        let stages: Vec<(usize, String)> = cmds.iter().cloned().enumerate().collect();
+        let errors = check_commands(&stages);
+        assert_eq!(
+            errors,
+            vec![
+                (1, cmds[1].clone(), ValidationError::EmptyCommand),
+                (2, cmds[2].clone(), ValidationError::InvalidShellSyntax),
+                (
+                    3,
+                    cmds[3].clone(),
+                    ValidationError::CommandNotFound(String::from("no-such-command-for-epiq"))
+                ),
+                (5, cmds[5].clone(), ValidationError::InvalidSetCommand),
+                (
+                    6,
+                    cmds[6].clone(),
+                    ValidationError::CommandNotFound(String::from("no-such-command-for-epiq"))
+                ),
+            ]
+        );
+        assert_eq!(
+            summarize(&stages, &errors),
+            "Stage 1: OK, Stage 2: empty command, Stage 3: invalid shell syntax, \
+             Stage 4: command 'no-such-command-for-epiq' not found, Stage 5: OK, \
+             Stage 6: expected SET KEY=VALUE, \
+             Stage 7: command 'no-such-command-for-epiq' not found"
+        );
+        assert_eq!(
+            report(&errors[..3]),
+            "Stage 2: empty command ('')\n\
+             Stage 3: invalid shell syntax (grep 'unterminated)\n\
+             Stage 4: command 'no-such-command-for-epiq' not found \
+             (no-such-command-for-epiq --flag)"
+        );
+
+        // Numbered by the positions in the prompt, e.g. with the ignored stages skipped.
+        let stages = vec![(0, cmds[0].clone()), (2, cmds[3].clone())];
+        let errors = check_commands(&stages);
+        assert_eq!(
+            summarize(&stages, &errors),
+            "Stage 1: OK, Stage 3: command 'no-such-command-for-epiq' not found"
+        );
+    }
+}
@@ -0,0 +1,111 @@
+use std::path::{Component, Path, PathBuf};
+
+use glob::Pattern;
+use notify::{EventKind, RecursiveMode, Watcher, event::ModifyKind};
+use tokio::{sync::mpsc, task::JoinHandle};
+
+/// Notifies that the file matching the watched patterns has changed.
+#[derive(Debug)]
+pub struct WatchEvent(pub PathBuf);
+
+/// Watches the files matching the patterns, and sends the changed paths.
+pub fn spawn(
+    patterns: Vec<Pattern>,
+    tx: mpsc::Sender<WatchEvent>,
+) -> anyhow::Result<JoinHandle<()>> {
+    let (raw_tx, mut raw_rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |res| {
+        let _ = raw_tx.send(res);
+    })?;
+    for pattern in &patterns {
+        let (dir, mode) = watch_root(pattern);
+        watcher
+            .watch(&dir, mode)
+            .map_err(|e| anyhow::anyhow!("Failed to watch {}: {}", pattern, e))?;
+    }
+
+    let cwd = std::env::current_dir()?;
+    Ok(tokio::spawn(async move {
+        // Keep the watcher alive while the task is running.
+        let _watcher = watcher;
+        while let Some(res) = raw_rx.recv().await {
+            let Ok(event) = res else {
+                continue;
+            };
+            // Ignore the changes of only the metadata (e.g. permissions).
+            if !matches!(
+                event.kind,
+                EventKind::Create(_)
+                    | EventKind::Modify(
+                        ModifyKind::Data(_) | ModifyKind::Name(_) | ModifyKind::Any
+                    )
+            ) {
+                continue;
+            }
+            for path in event.paths {
+                let relative = path.strip_prefix(&cwd).unwrap_or(&path);
+                if patterns
+                    .iter()
+                    .any(|pattern| pattern.matches_path(relative) || pattern.matches_path(&path))
+                {
+                    let _ = tx.send(WatchEvent(relative.to_path_buf())).await;
+                }
+            }
+        }
+    }))
+}
+
+/// Returns the deepest directory which contains all the files matching the pattern,
+/// and whether it has to be watched recursively.
+fn watch_root(pattern: &Pattern) -> (PathBuf, RecursiveMode) {
+    let path = Path::new(pattern.as_str());
+    let components: Vec<Component> = path.components().collect();
+    let literal = components
+        .iter()
+        .take_while(|c| !c.as_os_str().to_string_lossy().contains(['*', '?', '[']))
+        .count();
+    // The last component is the file name, so its parent is watched at least.
+    let depth = literal.min(components.len().saturating_sub(1));
+    let dir: PathBuf = components[..depth].iter().collect();
+    let dir = if dir.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        dir
+    };
+    let mode = if components.len() - depth > 1 {
+        RecursiveMode::Recursive
+    } else {
+        RecursiveMode::NonRecursive
+    };
+    (dir, mode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watch_root() {
+        let root = |pattern| watch_root(&Pattern::new(pattern).unwrap());
+        assert_eq!(
+            root("*.json"),
+            (PathBuf::from("."), RecursiveMode::NonRecursive)
+        );
+        assert_eq!(
+            root("data.json"),
+            (PathBuf::from("."), RecursiveMode::NonRecursive)
+        );
+        assert_eq!(
+            root("data/*.json"),
+            (PathBuf::from("data"), RecursiveMode::NonRecursive)
+        );
+        assert_eq!(
+            root("data/**/*.json"),
+            (PathBuf::from("data"), RecursiveMode::Recursive)
+        );
+        assert_eq!(
+            root("/var/log/*/app.log"),
+            (PathBuf::from("/var/log"), RecursiveMode::Recursive)
+        );
+    }
+}